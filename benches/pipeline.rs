@@ -0,0 +1,145 @@
+//! Pack and unpack throughput over generated trees.
+//!
+//! The benches drive the built `zstar` binary, like the GUI does, so they
+//! measure exactly what users run. Fixtures come from `zstar gen-fixture`
+//! with fixed seeds and are kept in the target dir between runs.
+//!
+//!     cargo bench --bench pipeline
+//!     cargo bench --bench pipeline -- small-file-storm
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+const ZSTAR: &str = env!("CARGO_BIN_EXE_zstar");
+
+struct Fixture {
+    name: &'static str,
+    args: &'static [&'static str],
+}
+
+const FIXTURES: &[Fixture] = &[
+    // Per-file overhead: metadata, channel hops, tar headers
+    Fixture {
+        name: "small-file-storm",
+        args: &["--files", "50k", "--size-dist", "uniform", "--size", "2048"],
+    },
+    // Streaming path: chunk channels, buffer pool, compressor
+    Fixture {
+        name: "few-huge-files",
+        args: &[
+            "--files",
+            "4",
+            "--size-dist",
+            "fixed",
+            "--size",
+            "268435456",
+        ],
+    },
+    // Scanner and directory handling
+    Fixture {
+        name: "deep-tree",
+        args: &[
+            "--files", "20k", "--depth", "24", "--fanout", "2", "--size", "4096",
+        ],
+    },
+    // Realistic mix with links
+    Fixture {
+        name: "mixed",
+        args: &["--files", "20k", "--hardlinks", "5%", "--symlinks", "1%"],
+    },
+];
+
+fn zstar(args: &[&str]) {
+    let output = Command::new(ZSTAR)
+        .args(args)
+        .output()
+        .expect("Failed to run zstar");
+    assert!(
+        output.status.success(),
+        "zstar {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Generate the fixture once; a partial tree from an interrupted run is
+/// thrown away and made again.
+fn fixture(root: &Path, fixture: &Fixture) -> PathBuf {
+    let dir = root.join(fixture.name);
+    let done = root.join(format!("{}.done", fixture.name));
+    if !done.exists() {
+        let _ = fs::remove_dir_all(&dir);
+        let mut args = vec!["gen-fixture", dir.to_str().unwrap(), "--seed", "1"];
+        args.extend_from_slice(fixture.args);
+        zstar(&args);
+        fs::write(&done, "").unwrap();
+    }
+    dir
+}
+
+fn tree_size(dir: &Path) -> u64 {
+    let mut total = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_dir() {
+            total += tree_size(&entry.path());
+        } else if file_type.is_file() {
+            total += entry.metadata().unwrap().len();
+        }
+    }
+    total
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("zstar-bench");
+    fs::create_dir_all(&root).unwrap();
+
+    for spec in FIXTURES {
+        let input = fixture(&root, spec);
+        let archive = root.join(format!("{}.tar.zst", spec.name));
+        let extract = root.join(format!("{}.out", spec.name));
+        let input_arg = input.to_str().unwrap();
+        let archive_arg = archive.to_str().unwrap();
+        let extract_arg = extract.to_str().unwrap();
+
+        let mut group = c.benchmark_group(spec.name);
+        group.sample_size(10);
+        group.measurement_time(Duration::from_secs(20));
+        group.throughput(Throughput::Bytes(tree_size(&input)));
+
+        for backend in ["compio", "threaded"] {
+            group.bench_function(format!("pack/{}", backend), |b| {
+                b.iter(|| {
+                    zstar(&[
+                        "pack",
+                        input_arg,
+                        "-o",
+                        archive_arg,
+                        "-l",
+                        "3",
+                        "--io-backend",
+                        backend,
+                    ])
+                })
+            });
+        }
+
+        group.bench_function("unpack", |b| {
+            b.iter(|| {
+                let _ = fs::remove_dir_all(&extract);
+                zstar(&["unpack", archive_arg, "-o", extract_arg])
+            })
+        });
+        group.finish();
+
+        let _ = fs::remove_dir_all(&extract);
+        let _ = fs::remove_file(&archive);
+    }
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! Malformed tar streams: headers, sizes, PAX records, link names and path
+//! encodings, without zstd in the way.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// zstar is a binary crate; the checks are plain modules without other
+// crate dependencies, so they are compiled in directly
+#[path = "../../src/dialect/mod.rs"]
+mod dialect;
+#[path = "../../src/sanitize/mod.rs"]
+mod sanitize;
+#[allow(dead_code)]
+#[path = "../../src/validate/mod.rs"]
+mod validate;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = validate::validate_tar(data, &validate::ExtractLimits::untrusted());
+});
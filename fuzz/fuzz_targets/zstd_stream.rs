@@ -0,0 +1,30 @@
+//! Malformed tar.zst archives as unpack reads them, including the
+//! decompression memory limit.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/dialect/mod.rs"]
+mod dialect;
+#[path = "../../src/sanitize/mod.rs"]
+mod sanitize;
+#[allow(dead_code)]
+#[path = "../../src/validate/mod.rs"]
+mod validate;
+
+/// 64MB, so oversized windows are rejected instead of exhausting the fuzzer
+const MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut decoder) = zstd::Decoder::new(data) else {
+        return;
+    };
+    if decoder
+        .window_log_max(validate::window_log_for(MEMORY_LIMIT))
+        .is_err()
+    {
+        return;
+    }
+    let _ = validate::validate_tar(decoder, &validate::ExtractLimits::untrusted());
+});
@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
+use std::io::Read as _;
+use std::os::windows::io::AsRawHandle as _;
 use std::os::windows::process::CommandExt as _;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use tauri::{Emitter, Manager};
 use tokio::io::AsyncReadExt;
 use walkdir::WalkDir;
+use windows_sys::Win32::Foundation::{HANDLE, HANDLE_FLAG_INHERIT, SetHandleInformation};
 
 const CREATE_NO_WINDOW: u32 = 0x08000000;
 
+/// zstar 自带译文的语言
+const SUPPORTED_LOCALES: &[&str] = &["en", "zh-CN"];
+
+/// 校验排除规则时最多扫描的条目数，大目录只取样
+const PATTERN_SAMPLE_ENTRIES: u32 = 20_000;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FolderInfo {
     pub name: String,
@@ -15,6 +25,48 @@ pub struct FolderInfo {
     pub size_bytes: u64,
 }
 
+/// zstar `--progress-fd` 的一帧，以 `pack-progress` 事件发给前端
+#[derive(Debug, Clone, Serialize)]
+pub struct PackProgress {
+    pub entries: u64,
+    pub bytes: u64,
+    pub size: String,
+    pub elapsed_secs: u64,
+    /// 扫描完成（或有估计值）之前未知
+    pub percent: Option<f64>,
+    pub eta_secs: Option<u64>,
+    pub current_path: String,
+    pub done: bool,
+}
+
+/// 归档中的一个条目，显示在解压页的内容列表里
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    /// dir、file、symlink、hardlink 或 special，与 `zstar list` 相同
+    pub kind: String,
+    pub size: u64,
+    pub path: String,
+    pub link: Option<String>,
+    /// 打包时读取期间发生了变化
+    pub changed: bool,
+}
+
+/// `zstar list --json` 的输出
+#[derive(Debug, Deserialize)]
+struct ListOutput {
+    indexed: bool,
+    entries: Vec<ArchiveEntry>,
+    total_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveListing {
+    pub entries: Vec<ArchiveEntry>,
+    pub total_size: String,
+    /// 读自 `pack --seekable` 写入的条目索引，未解压归档
+    pub indexed: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PackResult {
     pub success: bool,
@@ -22,6 +74,8 @@ pub struct PackResult {
     pub error: Option<String>,
     pub duration: f64,
     pub output_size: String,
+    /// 任务完成但需要提醒用户的情况，例如自动排除了输出文件
+    pub warning: Option<String>,
 }
 
 fn find_zstar_exe() -> String {
@@ -54,6 +108,27 @@ fn find_zstar_exe() -> String {
     "zstar.exe".to_string()
 }
 
+fn locale_file(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_config_dir()
+        .ok()
+        .map(|dir| dir.join("locale"))
+}
+
+/// 用户选择的语言，未选择时跟随系统
+fn current_locale(app: &tauri::AppHandle) -> String {
+    if let Some(saved) = locale_file(app).and_then(|p| std::fs::read_to_string(p).ok()) {
+        let saved = saved.trim();
+        if SUPPORTED_LOCALES.contains(&saved) {
+            return saved.to_string();
+        }
+    }
+    match sys_locale::get_locale() {
+        Some(l) if l.to_ascii_lowercase().starts_with("zh") => "zh-CN".to_string(),
+        _ => "en".to_string(),
+    }
+}
+
 fn format_size(bytes: u64) -> String {
     if bytes == 0 {
         return "0 B".to_string();
@@ -65,6 +140,84 @@ fn format_size(bytes: u64) -> String {
     format!("{:.2} {}", size, sizes[i.min(sizes.len() - 1)])
 }
 
+/// 转义 glob 特殊字符，让路径按字面匹配
+fn glob_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' | '{' | '}' => format!("[{}]", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// 输出文件位于源文件夹内时返回它在归档中的路径（以源文件夹名开头），
+/// 否则打包时会试图把正在写入的归档打进自己
+fn output_inside_source(source: &Path, output: &Path) -> Option<String> {
+    let source = source.canonicalize().ok()?;
+    // 输出文件可能还不存在，只规范化它所在的目录
+    let parent = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let output = parent.canonicalize().ok()?.join(output.file_name()?);
+    if !output.starts_with(&source) {
+        return None;
+    }
+    let relative = output
+        .strip_prefix(source.parent().unwrap_or(&source))
+        .ok()?;
+    let parts: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    Some(parts.join("/"))
+}
+
+/// 创建进度管道，写端设为可继承，由 zstar 以 `--progress-fd <句柄值>` 接收。
+/// 同时启动的其他子进程也会继承写端，它们退出前读端收不到 EOF，只是晚一些结束读取
+fn progress_pipe() -> std::io::Result<(std::io::PipeReader, std::io::PipeWriter)> {
+    let (reader, writer) = std::io::pipe()?;
+    let handle = writer.as_raw_handle() as HANDLE;
+    if unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((reader, writer))
+}
+
+/// 解析一帧进度，格式见 zstar 的 src/status/pipe.rs；不认识的版本返回 None
+fn decode_progress(frame: &[u8]) -> Option<PackProgress> {
+    if frame.len() < 36 || frame[0] != 1 {
+        return None;
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(frame[i..i + 8].try_into().unwrap());
+    let permille = u16::from_le_bytes([frame[26], frame[27]]);
+    let eta_secs = u64_at(28);
+    Some(PackProgress {
+        entries: u64_at(2),
+        bytes: u64_at(10),
+        size: format_size(u64_at(10)),
+        elapsed_secs: u64_at(18),
+        percent: (permille != u16::MAX).then(|| permille as f64 / 10.0),
+        eta_secs: (eta_secs != u64::MAX).then_some(eta_secs),
+        current_path: String::from_utf8_lossy(&frame[36..]).to_string(),
+        done: frame[1] & 1 != 0,
+    })
+}
+
+/// 读取进度帧直到 zstar 关闭管道，逐帧发出 `pack-progress` 事件
+fn forward_progress(app: tauri::AppHandle, mut pipe: std::io::PipeReader) {
+    let mut len = [0u8; 4];
+    while pipe.read_exact(&mut len).is_ok() {
+        let mut frame = vec![0; u32::from_le_bytes(len) as usize];
+        if pipe.read_exact(&mut frame).is_err() {
+            break;
+        }
+        if let Some(progress) = decode_progress(&frame) {
+            let _ = app.emit("pack-progress", progress);
+        }
+    }
+}
+
 fn get_folder_size(path: &Path) -> u64 {
     WalkDir::new(path)
         .into_iter()
@@ -85,6 +238,23 @@ async fn check_zstar() -> Result<serde_json::Value, String> {
     }))
 }
 
+#[tauri::command]
+async fn get_locale(app: tauri::AppHandle) -> Result<String, String> {
+    Ok(current_locale(&app))
+}
+
+#[tauri::command]
+async fn set_locale(app: tauri::AppHandle, locale: String) -> Result<(), String> {
+    if !SUPPORTED_LOCALES.contains(&locale.as_str()) {
+        return Err(format!("Unsupported locale: {}", locale));
+    }
+    let file = locale_file(&app).ok_or("No config directory")?;
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(&file, &locale).map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn get_folder_info(path: String) -> Result<FolderInfo, String> {
     let path_obj = Path::new(&path);
@@ -113,14 +283,140 @@ async fn get_folder_info(path: String) -> Result<FolderInfo, String> {
     })
 }
 
+/// 不解压读取归档信息（`zstar info --json`），供解压页在选择输出目录前确认文件有效
+#[tauri::command]
+async fn inspect_archive(app: tauri::AppHandle, path: String) -> Result<serde_json::Value, String> {
+    let zstar_exe = find_zstar_exe();
+
+    if !Path::new(&zstar_exe).exists() {
+        return Err(format!("zstar.exe not found at: {}", zstar_exe));
+    }
+
+    if !Path::new(&path).is_file() {
+        return Err("Archive path does not exist".to_string());
+    }
+
+    let output = tokio::process::Command::new(&zstar_exe)
+        .args(["info", path.as_str(), "--json"])
+        .env("ZSTAR_LANG", current_locale(&app))
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let mut info: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
+    // 前端直接显示的格式化大小
+    if let Some(bytes) = info["compressed"].as_u64() {
+        info["compressed_size"] = format_size(bytes).into();
+    }
+    if let Some(bytes) = info["uncompressed"].as_u64() {
+        info["uncompressed_size"] = format_size(bytes).into();
+    }
+    Ok(info)
+}
+
+/// 用 `zstar list --json` 列出归档内容。有条目索引的归档只读索引，毫秒级完成；
+/// 其他归档流式解压列出
+#[tauri::command]
+async fn list_archive(app: tauri::AppHandle, path: String) -> Result<ArchiveListing, String> {
+    let zstar_exe = find_zstar_exe();
+    if !Path::new(&zstar_exe).exists() {
+        return Err(format!("zstar.exe not found at: {}", zstar_exe));
+    }
+
+    if !Path::new(&path).is_file() {
+        return Err("Archive path does not exist".to_string());
+    }
+
+    let output = tokio::process::Command::new(&zstar_exe)
+        .args(["list", path.as_str(), "--json"])
+        .env("ZSTAR_LANG", current_locale(&app))
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let listing: ListOutput = serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
+    Ok(ArchiveListing {
+        entries: listing.entries,
+        total_size: format_size(listing.total_bytes),
+        indexed: listing.indexed,
+    })
+}
+
+/// 用 `zstar check-excludes` 在源目录的扫描样本上试运行排除规则，返回每条规则的匹配数
+#[tauri::command]
+async fn validate_patterns(
+    patterns: Vec<String>,
+    sample_path: String,
+) -> Result<serde_json::Value, String> {
+    let zstar_exe = find_zstar_exe();
+
+    if !Path::new(&zstar_exe).exists() {
+        return Err(format!("zstar.exe not found at: {}", zstar_exe));
+    }
+
+    if !Path::new(&sample_path).is_dir() {
+        return Err("Path is not a directory".to_string());
+    }
+
+    let mut args = vec![
+        "check-excludes".to_string(),
+        sample_path,
+        "--json".to_string(),
+        "--limit".to_string(),
+        PATTERN_SAMPLE_ENTRIES.to_string(),
+    ];
+    for pattern in patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        // `--exclude=` 形式，以 `-` 开头的规则不会被当作选项
+        args.push(format!("--exclude={}", pattern));
+    }
+
+    let output = tokio::process::Command::new(&zstar_exe)
+        .args(&args)
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let mut report: serde_json::Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| e.to_string())?;
+    if let Some(patterns) = report["patterns"].as_array_mut() {
+        for pattern in patterns {
+            if let Some(bytes) = pattern["bytes"].as_u64() {
+                pattern["size"] = format_size(bytes).into();
+            }
+        }
+    }
+    if let Some(bytes) = report["bytes"].as_u64() {
+        report["size"] = format_size(bytes).into();
+    }
+    Ok(report)
+}
+
 #[tauri::command]
 async fn pack_folder(
+    app: tauri::AppHandle,
     source_path: String,
     output_path: String,
     level: Option<u32>,
     threads: Option<u32>,
     ignore_failed_read: Option<bool>,
     no_long: Option<bool>,
+    exclude: Option<Vec<String>>,
 ) -> Result<PackResult, String> {
     let zstar_exe = find_zstar_exe();
 
@@ -152,16 +448,49 @@ async fn pack_folder(
         args.push("--no-long".to_string());
     }
 
+    for pattern in exclude
+        .iter()
+        .flatten()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+    {
+        args.push(format!("--exclude={}", pattern));
+    }
+
+    // 预检：输出在源文件夹内时自动排除它（连同写入中的 .partial 文件）
+    let warning = output_inside_source(Path::new(&source_path), Path::new(&output_path)).map(
+        |archive_path| {
+            let escaped = glob_escape(&archive_path);
+            args.push(format!("--exclude={}", escaped));
+            args.push(format!("--exclude={}.partial", escaped));
+            format!(
+                "The output file is inside the source folder; {} was left out of the archive",
+                archive_path
+            )
+        },
+    );
+
+    let (progress_reader, progress_writer) = progress_pipe().map_err(|e| e.to_string())?;
+    args.push("--progress-fd".to_string());
+    args.push((progress_writer.as_raw_handle() as usize).to_string());
+
     let start = std::time::Instant::now();
 
     let mut cmd = tokio::process::Command::new(&zstar_exe);
+    // zstar 的摘要与错误信息使用界面语言
     cmd.args(&args)
+        .env("ZSTAR_LANG", current_locale(&app))
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .creation_flags(CREATE_NO_WINDOW);
 
     let mut child = cmd.spawn().map_err(|e| e.to_string())?;
 
+    // zstar 已继承写端；关闭本进程的副本，zstar 退出后读端才能读到 EOF
+    drop(progress_writer);
+    let progress_app = app.clone();
+    std::thread::spawn(move || forward_progress(progress_app, progress_reader));
+
     let mut stdout = String::new();
     let mut stderr = String::new();
 
@@ -192,6 +521,7 @@ async fn pack_folder(
             error: None,
             duration,
             output_size,
+            warning,
         })
     } else {
         Ok(PackResult {
@@ -200,12 +530,14 @@ async fn pack_folder(
             error: Some(stderr),
             duration,
             output_size,
+            warning,
         })
     }
 }
 
 #[tauri::command]
 async fn unpack_folder(
+    app: tauri::AppHandle,
     archive_path: String,
     output_path: String,
     threads: Option<u32>,
@@ -230,7 +562,9 @@ async fn unpack_folder(
     let start = std::time::Instant::now();
 
     let mut cmd = tokio::process::Command::new(&zstar_exe);
+    // zstar 的摘要与错误信息使用界面语言
     cmd.args(&args)
+        .env("ZSTAR_LANG", current_locale(&app))
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .creation_flags(CREATE_NO_WINDOW);
@@ -258,6 +592,7 @@ async fn unpack_folder(
             error: None,
             duration,
             output_size: "N/A".to_string(),
+            warning: None,
         })
     } else {
         Ok(PackResult {
@@ -266,6 +601,7 @@ async fn unpack_folder(
             error: Some(stderr),
             duration,
             output_size: "N/A".to_string(),
+            warning: None,
         })
     }
 }
@@ -297,7 +633,12 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .invoke_handler(tauri::generate_handler![
             check_zstar,
+            get_locale,
+            set_locale,
             get_folder_info,
+            inspect_archive,
+            list_archive,
+            validate_patterns,
             pack_folder,
             unpack_folder,
             minimize_window,
@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable overriding the catalog database location
+pub const CATALOG_ENV: &str = "ZSTAR_CATALOG";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS archives (
+    id         INTEGER PRIMARY KEY,
+    location   TEXT NOT NULL,
+    source     TEXT NOT NULL,
+    created    INTEGER NOT NULL,
+    size       INTEGER NOT NULL,
+    file_count INTEGER NOT NULL,
+    checksum   TEXT,
+    profile    TEXT,
+    sha256     TEXT
+);
+CREATE TABLE IF NOT EXISTS entries (
+    archive_id INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+    path       TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS entries_path ON entries(path);
+CREATE TABLE IF NOT EXISTS scrubs (
+    archive_id INTEGER NOT NULL REFERENCES archives(id) ON DELETE CASCADE,
+    checked    INTEGER NOT NULL,
+    status     TEXT NOT NULL,
+    detail     TEXT
+);
+";
+
+/// An archive as recorded in the catalog.
+#[derive(Debug, Clone)]
+pub struct ArchiveRecord {
+    pub id: i64,
+    /// Local path or remote URL of the archive
+    pub location: String,
+    pub source: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub size: u64,
+    pub file_count: u64,
+    /// BLAKE3 of the archive file
+    pub checksum: Option<String>,
+    pub profile: Option<String>,
+    /// SHA-256 of the archive file, recorded with `pack --hash-output sha256`
+    pub sha256: Option<String>,
+}
+
+/// Local SQLite database of every archive produced with `--catalog`.
+pub struct Catalog {
+    conn: Connection,
+}
+
+impl Catalog {
+    /// Default location: `$ZSTAR_CATALOG`, else `zstar/catalog.db` in the user data directory.
+    pub fn default_path() -> PathBuf {
+        if let Some(p) = std::env::var_os(CATALOG_ENV) {
+            return PathBuf::from(p);
+        }
+        let base = if cfg!(windows) {
+            std::env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            std::env::var_os("XDG_DATA_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/share")))
+        };
+        base.unwrap_or_else(|| PathBuf::from("."))
+            .join("zstar")
+            .join("catalog.db")
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn =
+            Connection::open(path).with_context(|| format!("Failed to open catalog {:?}", path))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        // Catalogs created before `--hash-output` lack the column
+        let has_sha256 = conn
+            .prepare("SELECT 1 FROM pragma_table_info('archives') WHERE name = 'sha256'")?
+            .exists([])?;
+        if !has_sha256 {
+            conn.execute_batch("ALTER TABLE archives ADD COLUMN sha256 TEXT;")?;
+        }
+        Ok(Self { conn })
+    }
+
+    /// Record a newly written archive and the paths it contains.
+    pub fn record(&mut self, record: &ArchiveRecord, entry_paths: &[PathBuf]) -> Result<i64> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO archives (location, source, created, size, file_count, checksum, profile, sha256)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                record.location,
+                record.source,
+                record.created as i64,
+                record.size as i64,
+                record.file_count as i64,
+                record.checksum,
+                record.profile,
+                record.sha256,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut stmt = tx.prepare("INSERT INTO entries (archive_id, path) VALUES (?1, ?2)")?;
+            for path in entry_paths {
+                stmt.execute(params![id, normalize(path)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Result<Vec<ArchiveRecord>> {
+        self.query("SELECT * FROM archives ORDER BY created", [])
+    }
+
+    /// Archives whose location, source or profile contains `term`.
+    pub fn search(&self, term: &str) -> Result<Vec<ArchiveRecord>> {
+        self.query(
+            "SELECT * FROM archives
+             WHERE location LIKE '%' || ?1 || '%' ESCAPE '\\'
+                OR source LIKE '%' || ?1 || '%' ESCAPE '\\'
+                OR profile LIKE '%' || ?1 || '%' ESCAPE '\\'
+             ORDER BY created",
+            [like_literal(term)],
+        )
+    }
+
+    /// Most recent record of the archive at `location`.
+    pub fn find_by_location(&self, location: &str) -> Result<Option<ArchiveRecord>> {
+        Ok(self
+            .query(
+                "SELECT * FROM archives WHERE location = ?1 ORDER BY created DESC LIMIT 1",
+                [location],
+            )?
+            .pop())
+    }
+
+    /// Most recent archive of `profile` created before `before` (seconds since epoch).
+    pub fn latest_for_profile(
+        &self,
+        profile: &str,
+        before: Option<u64>,
+    ) -> Result<Option<ArchiveRecord>> {
+        let before = before.map(|b| b as i64).unwrap_or(i64::MAX);
+        Ok(self
+            .query(
+                "SELECT * FROM archives WHERE profile = ?1 AND created < ?2
+                 ORDER BY created DESC LIMIT 1",
+                params![profile, before],
+            )?
+            .pop())
+    }
+
+    /// Archives containing `path`, either exactly or as a path suffix
+    /// (`where-is report.pdf` finds `docs/2024/report.pdf`).
+    pub fn where_is(&self, path: &str) -> Result<Vec<(ArchiveRecord, String)>> {
+        let path = normalize(Path::new(path));
+        let mut stmt = self.conn.prepare(
+            "SELECT a.*, e.path AS entry_path
+             FROM entries e JOIN archives a ON a.id = e.archive_id
+             WHERE e.path = ?1 OR e.path LIKE '%/' || ?2 ESCAPE '\\'
+             ORDER BY a.created",
+        )?;
+        let suffix = like_literal(&path);
+        let rows = stmt.query_map([&path, &suffix], |row| {
+            Ok((from_row(row)?, row.get("entry_path")?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Store the outcome of re-verifying an archive.
+    pub fn record_scrub(&self, archive_id: i64, status: &str, detail: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO scrubs (archive_id, checked, status, detail) VALUES (?1, ?2, ?3, ?4)",
+            params![archive_id, now() as i64, status, detail],
+        )?;
+        Ok(())
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<ArchiveRecord>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map(params, from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+fn from_row(row: &rusqlite::Row) -> rusqlite::Result<ArchiveRecord> {
+    Ok(ArchiveRecord {
+        id: row.get(0)?,
+        location: row.get(1)?,
+        source: row.get(2)?,
+        created: row.get::<_, i64>(3)? as u64,
+        size: row.get::<_, i64>(4)? as u64,
+        file_count: row.get::<_, i64>(5)? as u64,
+        checksum: row.get(6)?,
+        profile: row.get(7)?,
+        sha256: row.get(8)?,
+    })
+}
+
+/// Store archive paths with forward slashes so lookups work across platforms.
+fn normalize(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// `text` for a `LIKE ... ESCAPE '\'` pattern, matching only itself, so the
+/// `_` in a file name isn't a wildcard.
+fn like_literal(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
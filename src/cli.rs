@@ -1,13 +1,36 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+use crate::idmap::IdRange;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    /// Print tuning decisions (open file limit, worker counts)
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Language for summaries and common errors, e.g. `en` or `zh-CN` (default: the
+    /// system locale); warnings are always in English
+    #[arg(long, global = true, env = "ZSTAR_LANG")]
+    pub lang: Option<String>,
+
+    /// Passphrase for `pack --encrypt` and encrypted archives being read. Other users
+    /// may see it in the process list; prefer --key-file or typing it when asked
+    #[arg(long, global = true, env = "ZSTAR_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Use the contents of this file, less a trailing newline, as the passphrase (takes
+    /// precedence over --password)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+// Parsed once at startup; boxing `pack`'s options would only complicate matching on them
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Compress a directory into a tar.zst archive
@@ -15,13 +38,20 @@ pub enum Commands {
         /// Input directory to compress
         input: PathBuf,
 
-        /// Output file path (optional, defaults to directory_name.tar.zst)
+        /// Output file path (optional, defaults to directory_name.tar.zst, or the extension of
+        /// --compression or the --use-external-compressor program); `-` writes to stdout
         #[arg(short, long)]
         output: Option<PathBuf>,
 
         /// Compression level (default: 3)
-        #[arg(short, long, default_value_t = 3)]
-        level: i32,
+        #[arg(short, long)]
+        level: Option<i32>,
+
+        /// Choose the level from the destination instead: higher for network shares
+        /// and slow disks, lower for tmpfs and fast disks. Unless the filesystem
+        /// type decides, this writes and fsyncs a 16MB probe file next to the output
+        #[arg(long, conflicts_with = "level")]
+        auto_level: bool,
 
         /// Number of threads (default: num_cpus)
         #[arg(short, long)]
@@ -34,11 +64,211 @@ pub enum Commands {
         /// Ignore read errors (e.g., permission denied) instead of aborting
         #[arg(long)]
         ignore_failed_read: bool,
+
+        /// Write every skipped path and its error to this file; stderr only shows
+        /// the first few per directory and a summary
+        #[arg(long, value_name = "FILE")]
+        skip_log: Option<PathBuf>,
+
+        /// Retry reads failing with transient IO errors (flaky USB drives, network
+        /// mounts) this many times before skipping the file or aborting
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        read_retries: u32,
+
+        /// Wait before the first retry, doubled for every further one up to 30s (e.g. 500ms, 2s)
+        #[arg(long, value_name = "DURATION", default_value = "500ms", value_parser = parse_duration)]
+        retry_backoff: std::time::Duration,
+
+        /// What to do when a file's size or mtime changed while it was being read
+        #[arg(long, value_enum, default_value_t = IfChanged::Warn)]
+        if_changed: IfChanged,
+
+        /// Read from a Volume Shadow Copy so open/locked files are captured consistently (Windows, admin)
+        #[arg(long)]
+        vss: bool,
+
+        /// Read files through SeBackupPrivilege so ACLs don't block them, e.g. system
+        /// directories and other users' profiles (Windows, administrator; threaded reader)
+        #[arg(long)]
+        backup_privileges: bool,
+
+        /// Pack from a temporary read-only btrfs/LVM snapshot of the source (Linux, root)
+        #[arg(long, value_enum)]
+        snapshot: Option<SnapshotMode>,
+
+        /// Record the archive in the local catalog (location: $ZSTAR_CATALOG or the user data dir)
+        #[arg(long)]
+        catalog: bool,
+
+        /// Profile name stored with the catalog record
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Store macOS metadata (Finder info, quarantine, resource forks) as PAX xattr records
+        #[arg(long)]
+        mac_metadata: bool,
+
+        /// Store Linux file capabilities (`security.capability`, e.g. on ping) as PAX xattr records
+        #[arg(long)]
+        capabilities: bool,
+
+        /// Store SELinux contexts (`security.selinux`) as PAX xattr records
+        #[arg(long)]
+        selinux: bool,
+
+        /// Compress with gzip, xz or lz4 instead of zstd. --threads and --no-long only apply to
+        /// zstd, gzip and xz levels stop at 9, and lz4 has none
+        #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+        compression: Compression,
+
+        /// Write the archive as independent zstd frames of this many MB of tar data each
+        /// (default 32) plus an entry index, so `list` reads just the index and
+        /// `unpack ARCHIVE PATH...` decodes just the frames holding those paths.
+        /// Any zstd decoder still reads it; compresses a little worse
+        #[arg(long, value_name = "MB", num_args = 0..=1, require_equals = true, default_missing_value = "32")]
+        seekable: Option<u64>,
+
+        /// Encrypt the archive with AES-256-GCM under a key derived from a passphrase
+        /// (Argon2id), asked for unless --password or --key-file gives it. Every command
+        /// reading the archive then needs the same passphrase
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Don't end the archive with a BLAKE3 manifest of every file's contents, which
+        /// `unpack` checks the extracted files against (never written for ZIP or OCI layers)
+        #[arg(long)]
+        no_manifest: bool,
+
+        /// Compress by piping the tar stream through this command instead of zstd
+        /// (e.g. 'xz -T0'), split into arguments like a shell would but without
+        /// expansion; --level, --threads and --no-long are then ignored
+        #[arg(long, value_name = "COMMAND", conflicts_with = "compression")]
+        use_external_compressor: Option<String>,
+
+        /// Abort if no data has moved for this many seconds (hung NFS, stuck disk)
+        #[arg(long, value_name = "SECS")]
+        stall_timeout: Option<u64>,
+
+        /// Print time spent in scan, read, channel waits, tar serialization and compression,
+        /// and what the scan found (totals, largest directories), also when packing fails
+        #[arg(long)]
+        profile_pipeline: bool,
+
+        /// Store hardlinked files as independent copies instead of link entries
+        #[arg(long)]
+        hard_dereference: bool,
+
+        /// Only store a file as a hardlink if size and mtime match the earlier path
+        #[arg(long)]
+        paranoid_links: bool,
+
+        /// Store paths relative to INPUT itself instead of under its directory name
+        #[arg(long)]
+        no_top_dir: bool,
+
+        /// How files are read
+        #[arg(long, value_enum, env = "ZSTAR_IO_BACKEND", default_value_t = IoBackend::Auto)]
+        io_backend: IoBackend,
+
+        /// Read files of 128MB and more without going through the page cache
+        /// (O_DIRECT, F_NOCACHE, FILE_FLAG_NO_BUFFERING); uses the threaded reader
+        #[arg(long)]
+        direct_io: bool,
+
+        /// Memory-map 1-128MB files instead of copying them through buffers. Only
+        /// for sources nothing writes to while packing, such as snapshots: a file
+        /// truncated while it is mapped kills zstar with SIGBUS
+        #[arg(long)]
+        mmap: bool,
+
+        /// Let reading update the source files' access times. By default they are kept
+        /// with O_NOATIME (Linux, on files you own, or all of them as root)
+        #[arg(long)]
+        no_preserve_source_atime: bool,
+
+        /// Write progress (entries, bytes, current path, ETA) and what the scan has found so far
+        /// (totals, largest directories) as JSON to this file every few seconds
+        #[arg(long, value_name = "PATH")]
+        status_file: Option<PathBuf>,
+
+        /// Write progress as length-prefixed binary frames to this inherited pipe, several times
+        /// a second (a file descriptor on Unix, a handle value on Windows; see `src/status/pipe.rs`)
+        #[arg(long, value_name = "N")]
+        progress_fd: Option<u64>,
+
+        /// Don't warn when level 10+ is spent on data that doesn't compress
+        #[arg(long)]
+        no_ratio_warning: bool,
+
+        /// Write straight to the output path instead of `<output>.partial` renamed on success
+        /// (for destinations that can't rename, e.g. some FUSE mounts)
+        #[arg(long)]
+        no_partial: bool,
+
+        /// Keep file data buffered by the readers under roughly this many MB by streaming
+        /// medium files in chunks instead of reading them whole (default: stream from 128MB)
+        #[arg(long, value_name = "MB")]
+        memory_budget: Option<u64>,
+
+        /// Leave out paths matching a glob (repeatable), e.g. `node_modules` or `*.iso`.
+        /// Matches any trailing part of the archive path; excluding a directory skips its contents.
+        /// `zstar check-excludes` shows what a set of patterns would skip
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        /// Only store files and symlinks matching a glob (repeatable), e.g. `**/*.rs` or `Cargo.*`,
+        /// matched like --exclude. Directories are stored only as parents of included files
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+
+        /// Leave out files and symlinks owned by this user ID (repeatable; Unix).
+        /// Directories are kept, so other users' files stay reachable
+        #[arg(long, value_name = "UID")]
+        exclude_owner: Vec<u32>,
+
+        /// Only store files and symlinks owned by this user ID (repeatable; Unix).
+        /// Directories are kept, so other users' files stay reachable
+        #[arg(long, value_name = "UID")]
+        only_owner: Vec<u32>,
+
+        /// Leave out files and directories this user can't read, checked before opening them,
+        /// without a warning for each; only their number is reported at the end
+        #[arg(long)]
+        skip_unreadable: bool,
+
+        /// Leave out hidden files and directories: names starting with a dot, and on
+        /// Windows also anything with the hidden attribute
+        #[arg(long)]
+        skip_hidden: bool,
+
+        /// Store `$Recycle.Bin` and `System Volume Information` directories, which are
+        /// left out by default (they can't be read, even by administrators)
+        #[arg(long)]
+        no_skip_windows_system_dirs: bool,
+
+        /// Hash the archive as it is written and print the digest (also recorded in the
+        /// catalog and the webhook payload), so it can be published without re-reading it
+        #[arg(long, value_name = "ALGORITHM")]
+        hash_output: Option<HashAlgorithm>,
+
+        /// Layout of the archive. `oci-layer` implies --no-top-dir and reads files
+        /// one at a time so the layer comes out byte-identical for the same tree;
+        /// `zip` deflates each file itself and takes levels 0-9
+        #[arg(long, value_enum, default_value_t = PackFormat::Tar)]
+        format: PackFormat,
+
+        /// POST a JSON job summary (status, duration, ratio, skipped files, error) to this URL
+        /// when the job ends, successful or not
+        #[arg(long, value_name = "URL", env = "ZSTAR_NOTIFY_WEBHOOK")]
+        notify_webhook: Option<String>,
     },
     /// Decompress a tar.zst archive
     Unpack {
-        /// Input tar.zst file
+        /// Input tar.zst file; `-` reads it from stdin
         input: PathBuf,
+        /// Only extract these archive paths and everything below them (default: everything)
+        #[arg(conflicts_with_all = ["parse_only", "salvage", "compare_extract"])]
+        paths: Vec<PathBuf>,
         /// Output directory (optional, defaults to current directory)
         #[arg(short, long)]
         output: Option<PathBuf>,
@@ -46,5 +276,547 @@ pub enum Commands {
         /// Number of threads (default: num_cpus)
         #[arg(short, long)]
         threads: Option<u32>,
+
+        /// Restore hardlinked duplicates as reflink copies (btrfs, XFS, APFS) instead of hardlinks
+        #[arg(long)]
+        reflink_dups: bool,
+
+        /// Restore macOS metadata (Finder info, quarantine, resource forks) stored by `pack --mac-metadata`
+        #[arg(long)]
+        mac_metadata: bool,
+
+        /// Restore file capabilities stored by `pack --capabilities` (Linux, root only;
+        /// otherwise they are skipped with a warning)
+        #[arg(long)]
+        capabilities: bool,
+
+        /// Restore SELinux contexts stored by `pack --selinux` (Linux with SELinux enabled)
+        #[arg(long)]
+        selinux: bool,
+
+        /// Shift archived owners into a subordinate ID range, e.g. 100000:65536 from /etc/subuid,
+        /// for rootless container layers (Unix). Changing owners needs root or a user namespace;
+        /// otherwise they are recorded in the `user.containers.override_stat` xattr
+        #[arg(long, value_name = "START[:COUNT]", value_parser = parse_id_range)]
+        rootless_idmap: Option<IdRange>,
+
+        /// Abort on the first link that cannot be created (default: report them all at the end, exit code 3)
+        #[arg(long)]
+        strict_links: bool,
+
+        /// Extract into an existing non-empty output directory, replacing files that are in the archive
+        /// (always on when extracting into the current directory)
+        #[arg(long)]
+        merge: bool,
+
+        /// Extract on top of earlier layers: a `.wh.NAME` entry deletes NAME from the output and
+        /// `.wh..wh..opq` empties its directory of what was there before (OCI whiteouts; implies --merge)
+        #[arg(long, conflicts_with_all = ["parse_only", "salvage", "compare_extract"])]
+        apply_whiteouts: bool,
+
+        /// Refuse archives that need more than this many MB of decompression window,
+        /// so a crafted archive can't exhaust memory (default: zstd's 128MB)
+        #[arg(long, value_name = "MB")]
+        memory_limit_decompress: Option<u64>,
+
+        /// Apply safety limits for archives from unknown sources: 64GB of output,
+        /// 1,000,000 entries and 64 path levels unless set explicitly below
+        #[arg(long)]
+        untrusted: bool,
+
+        /// Abort once the extracted files would exceed this many MB
+        #[arg(long, value_name = "MB")]
+        max_output_size: Option<u64>,
+
+        /// Abort once the archive has more entries than this
+        #[arg(long)]
+        max_entries: Option<u64>,
+
+        /// Abort on entries nested deeper than this many path components
+        #[arg(long)]
+        max_path_depth: Option<usize>,
+
+        /// Skip archive entries matching a glob (repeatable), e.g. `node_modules` or `*.iso`.
+        /// Matches any trailing part of the path; excluding a directory skips its contents
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        /// Only decode and check the archive (headers, paths, links, data); write nothing.
+        /// The limits above still apply
+        #[arg(long)]
+        parse_only: bool,
+
+        /// Recover what can be read from a truncated or corrupt archive: skip damaged zstd
+        /// frames and tar records, resume at the next valid one, and list what was lost (exit code 4)
+        #[arg(long, conflicts_with = "parse_only")]
+        salvage: bool,
+
+        /// Write nothing; compare the archive with what is already in the output directory
+        /// (types, contents, links, modes, mtimes) and exit with code 5 on any difference
+        #[arg(long, conflicts_with_all = ["parse_only", "salvage"])]
+        compare_extract: bool,
+    },
+    /// Delete old archives using daily/weekly/monthly (GFS) retention
+    Rotate {
+        /// Directory containing the archives
+        #[arg(long)]
+        dir: PathBuf,
+
+        /// Archive naming template; {date} matches YYYY-MM-DD or YYYYMMDD, * matches anything
+        #[arg(long, default_value = "*{date}*.tar.zst")]
+        template: String,
+
+        /// Number of most recent days to keep one archive for
+        #[arg(long, default_value_t = 7)]
+        keep_daily: usize,
+
+        /// Number of most recent weeks to keep one archive for
+        #[arg(long, default_value_t = 4)]
+        keep_weekly: usize,
+
+        /// Number of most recent months to keep one archive for
+        #[arg(long, default_value_t = 6)]
+        keep_monthly: usize,
+
+        /// Allow every --keep-* to be 0, which deletes all matching archives
+        #[arg(long)]
+        keep_none: bool,
+
+        /// Only print what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Query the catalog of created archives
+    Catalog {
+        /// Catalog database (defaults to $ZSTAR_CATALOG or the user data dir)
+        #[arg(long, global = true)]
+        db: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CatalogCommands,
+    },
+    /// Extract the cataloged archive of a profile that was current at a given date
+    Restore {
+        /// Profile name given to `pack --profile`
+        #[arg(long)]
+        profile: String,
+
+        /// Restore the newest archive created on or before this date (YYYY-MM-DD, default: latest)
+        #[arg(long)]
+        as_of: Option<String>,
+
+        /// Output directory
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Number of threads (default: num_cpus)
+        #[arg(short, long)]
+        threads: Option<u32>,
+
+        /// Catalog database (defaults to $ZSTAR_CATALOG or the user data dir)
+        #[arg(long)]
+        db: Option<PathBuf>,
+
+        /// Extract into an existing non-empty output directory, replacing files that are in the archive
+        #[arg(long)]
+        merge: bool,
+    },
+    /// Deduplicating snapshot repository (content-defined chunks stored once)
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+    /// Combine several archives into one, keeping a single copy of each path
+    Merge {
+        /// Archives to merge, in order
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Which copy to keep when a path occurs in several archives
+        #[arg(long, value_enum, default_value_t = MergePolicy::Newest)]
+        prefer: MergePolicy,
+
+        /// Compression level (default: 3)
+        #[arg(short, long, default_value_t = 3)]
+        level: i32,
+
+        /// Number of threads (default: num_cpus)
+        #[arg(short, long)]
+        threads: Option<u32>,
     },
+    /// Build a standalone full archive from a base and its incrementals without extracting
+    Synthesize {
+        /// Full base archive
+        base: PathBuf,
+
+        /// Incremental archives, oldest first; later copies of a path replace earlier ones
+        #[arg(required = true)]
+        incrementals: Vec<PathBuf>,
+
+        /// Output file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Treat `.wh.NAME` entries in an incremental as NAME having been deleted, and
+        /// `.wh..wh..opq` as its directory having been emptied, since the archives before it
+        #[arg(long)]
+        apply_whiteouts: bool,
+
+        /// Compression level (default: 3)
+        #[arg(short, long, default_value_t = 3)]
+        level: i32,
+
+        /// Number of threads (default: num_cpus)
+        #[arg(short, long)]
+        threads: Option<u32>,
+    },
+    /// Show what `pack --exclude` patterns would leave out of a directory
+    CheckExcludes {
+        /// Directory that would be packed
+        dir: PathBuf,
+
+        /// Pattern to check (repeatable), as given to `pack --exclude`
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        /// Stop after this many entries, with an optional k/M suffix, for a quick sample
+        #[arg(long, value_parser = parse_count)]
+        limit: Option<u64>,
+
+        /// Print the counts as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show frame parameters, sizes and catalog details of an archive without extracting it
+    Info {
+        archive: PathBuf,
+
+        /// Catalog database (defaults to $ZSTAR_CATALOG or the user data dir)
+        #[arg(long)]
+        db: Option<PathBuf>,
+
+        /// Print the findings as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the entries of an archive (type, size, path) without extracting it
+    List {
+        /// Archive to list; `-` reads it from stdin
+        archive: PathBuf,
+
+        /// Refuse archives that need more than this many MB of decompression window
+        #[arg(long, value_name = "MB")]
+        memory_limit_decompress: Option<u64>,
+
+        /// Print the entries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Re-verify archives and report the ones that have gone bad
+    Scrub {
+        /// Archives to verify by decoding them completely
+        archives: Vec<PathBuf>,
+
+        /// Verify every archive registered in the catalog and record the results
+        #[arg(long)]
+        catalog: bool,
+
+        /// Catalog database (defaults to $ZSTAR_CATALOG or the user data dir)
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+    /// Generate a synthetic tree for reproducing performance issues
+    #[command(hide = true)]
+    GenFixture {
+        /// Directory to create (must be empty or missing)
+        output: PathBuf,
+
+        /// Number of entries, with an optional k/M suffix
+        #[arg(long, default_value = "10k", value_parser = parse_count)]
+        files: u64,
+
+        /// How file sizes are drawn
+        #[arg(long, value_enum, default_value_t = SizeDist::Zipf)]
+        size_dist: SizeDist,
+
+        /// Typical (mean) file size in bytes
+        #[arg(long, default_value_t = 16 * 1024)]
+        size: u64,
+
+        /// Largest file size in bytes
+        #[arg(long, default_value_t = 256 * 1024 * 1024)]
+        max_size: u64,
+
+        /// Share of entries that are hard links, e.g. 5%
+        #[arg(long, default_value = "0%", value_parser = parse_percent)]
+        hardlinks: f64,
+
+        /// Share of entries that are symlinks, e.g. 1%
+        #[arg(long, default_value = "0%", value_parser = parse_percent)]
+        symlinks: f64,
+
+        /// Directory levels above each file
+        #[arg(long, default_value_t = 3)]
+        depth: u32,
+
+        /// Subdirectories per directory
+        #[arg(long, default_value_t = 16)]
+        fanout: u32,
+
+        /// Seed; the same options and seed always produce the same tree
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CatalogCommands {
+    /// List every cataloged archive
+    List,
+    /// Find archives whose location, source or profile contains a term
+    Search { term: String },
+    /// Find which archives contain a file (exact path or trailing path components)
+    WhereIs { path: String },
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommands {
+    /// Create an empty repository
+    Init {
+        /// Repository directory (must not exist or be empty)
+        repo: PathBuf,
+    },
+    /// Store a new snapshot of a directory
+    Backup {
+        /// Directory to back up
+        input: PathBuf,
+
+        /// Repository directory
+        #[arg(short, long)]
+        repo: PathBuf,
+
+        /// Compression level for new chunks (default: 3)
+        #[arg(short, long, default_value_t = 3)]
+        level: i32,
+    },
+    /// Recreate a snapshot's directory tree
+    Restore {
+        /// Snapshot id (see `repo list`) or `latest`
+        snapshot: String,
+
+        /// Repository directory
+        #[arg(short, long)]
+        repo: PathBuf,
+
+        /// Output directory
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Forget snapshots outside the retention policy and delete unreferenced data
+    Prune {
+        /// Repository directory
+        #[arg(short, long)]
+        repo: PathBuf,
+
+        /// Number of most recent days to keep one snapshot for
+        #[arg(long, default_value_t = 7)]
+        keep_daily: usize,
+
+        /// Number of most recent weeks to keep one snapshot for
+        #[arg(long, default_value_t = 4)]
+        keep_weekly: usize,
+
+        /// Number of most recent months to keep one snapshot for
+        #[arg(long, default_value_t = 6)]
+        keep_monthly: usize,
+
+        /// Allow every --keep-* to be 0, which forgets all snapshots
+        #[arg(long)]
+        keep_none: bool,
+
+        /// Only print which snapshots would be forgotten
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rewrite sparse pack files to reclaim space left by prune
+    Compact {
+        /// Repository directory
+        #[arg(short, long)]
+        repo: PathBuf,
+    },
+    /// List snapshots
+    List {
+        /// Repository directory
+        #[arg(short, long)]
+        repo: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SnapshotMode {
+    /// Use btrfs if the source is on btrfs, otherwise LVM
+    Auto,
+    Btrfs,
+    Lvm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IoBackend {
+    /// Pick the best backend for this system
+    Auto,
+    /// Async reads through compio: io_uring on Linux, IOCP on Windows, polling elsewhere
+    #[value(alias = "uring")]
+    Compio,
+    /// Blocking reads on a pool of threads
+    Threaded,
+}
+
+impl IoBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            IoBackend::Auto => "auto",
+            IoBackend::Compio => "compio",
+            IoBackend::Threaded => "threaded",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+    Xz,
+    Lz4,
+}
+
+impl Compression {
+    /// File name extension of archives compressed this way
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "tar.zst",
+            Compression::Gzip => "tar.gz",
+            Compression::Xz => "tar.xz",
+            Compression::Lz4 => "tar.lz4",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PackFormat {
+    /// Plain tar under the input's directory name
+    Tar,
+    /// OCI image layer: paths from the layer root in sorted order, overlayfs
+    /// whiteouts converted, and the sha256 of the tar stream (diff_id) printed
+    OciLayer,
+    /// ZIP archive with deflated entries, which Windows opens without extra tools.
+    /// Owners and xattrs are not kept, and hard-linked files are stored once per path
+    Zip,
+}
+
+/// What `pack` does about a file that changed while it was read
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IfChanged {
+    /// Read it again, up to 3 times, then store it as with `warn`. Files
+    /// streamed in chunks are already partly written and can't be read again
+    Retry,
+    /// Store it as read, with a warning. `list` marks it and `unpack` warns
+    /// about it, from a PAX record or, for files streamed in chunks, the manifest
+    Warn,
+    /// Fail like a read error (skipped under --ignore-failed-read)
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MergePolicy {
+    /// Keep the copy with the latest mtime (ties go to the later archive)
+    Newest,
+    /// Keep the copy from the earliest archive that has the path
+    First,
+    /// Keep the copy from the latest archive that has the path
+    Last,
+    /// Fail if a file occurs in more than one archive
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SizeDist {
+    /// Every file has the typical size
+    Fixed,
+    /// Anywhere between empty and twice the typical size
+    Uniform,
+    /// Heavy tailed: mostly small files and a few very large ones
+    Zipf,
+}
+
+/// `10000`, `10k` or `1M`
+fn parse_count(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1_000),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 1_000_000),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid count {:?}", s))
+}
+
+/// `START[:COUNT]` of a subordinate ID range; COUNT defaults to 65536 as in /etc/subuid
+fn parse_id_range(s: &str) -> Result<IdRange, String> {
+    let (start, count) = s.split_once(':').unwrap_or((s, "65536"));
+    let invalid = || format!("invalid ID range {:?}", s);
+    let start = start.parse::<u32>().map_err(|_| invalid())?;
+    let count = count.parse::<u32>().map_err(|_| invalid())?;
+    if count == 0 || start.checked_add(count - 1).is_none() {
+        return Err(invalid());
+    }
+    Ok(IdRange { start, count })
+}
+
+/// `500ms`, `2s` or `1m`; a bare number is milliseconds
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "ms"),
+    };
+    let n = digits
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    match unit {
+        "ms" => Ok(std::time::Duration::from_millis(n)),
+        "s" => Ok(std::time::Duration::from_secs(n)),
+        "m" => Ok(std::time::Duration::from_secs(n * 60)),
+        _ => Err(format!(
+            "invalid duration {:?}, expected e.g. 500ms or 2s",
+            s
+        )),
+    }
+}
+
+/// `5%` or `5`, as a fraction between 0 and 1
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p / 100.0),
+        _ => Err(format!("invalid percentage {:?}", s)),
+    }
 }
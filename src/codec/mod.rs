@@ -0,0 +1,125 @@
+//! Compression of archives being read, told apart by their first bytes, so
+//! `unpack`, `list` and `--compare-extract` take `.tar`, `.tar.gz`,
+//! `.tar.xz` and `.tar.lz4`, whether `pack --compression` wrote them or not.
+//! ZIP archives are converted to a tar stream on the way in, and archives
+//! from `pack --encrypt` are decrypted first.
+
+use anyhow::{Context, Result, bail};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use crate::encryption::{self, Decryptor};
+use crate::utils::{is_stdio, open_input};
+use crate::validate::window_log_for;
+use crate::zip;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+    Xz,
+    Lz4,
+    Zip,
+    /// `pack --encrypt`, anything of the above inside
+    Encrypted,
+    /// Plain tar
+    Plain,
+}
+
+impl Codec {
+    /// Name shown by `info`
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+            Codec::Xz => "xz",
+            Codec::Lz4 => "lz4",
+            Codec::Zip => "zip",
+            Codec::Encrypted => "encrypted",
+            Codec::Plain => "tar",
+        }
+    }
+}
+
+/// Compression of a stream starting with `head`. Anything unrecognised is
+/// taken as plain tar and left to the tar parser to reject.
+pub fn detect(head: &[u8]) -> Codec {
+    // pzstd and seekable archives may open with a skippable frame (0x184D2A5?)
+    let skippable = head.len() >= 4 && head[0] & 0xF0 == 0x50 && head[1..4] == [0x2A, 0x4D, 0x18];
+    if head.starts_with(&ZSTD_MAGIC) || skippable {
+        Codec::Zstd
+    } else if head.starts_with(&GZIP_MAGIC) {
+        Codec::Gzip
+    } else if head.starts_with(&XZ_MAGIC) {
+        Codec::Xz
+    } else if head.starts_with(&LZ4_MAGIC) {
+        Codec::Lz4
+    } else if zip::is_zip(head) {
+        Codec::Zip
+    } else if encryption::is_encrypted(head) {
+        Codec::Encrypted
+    } else {
+        Codec::Plain
+    }
+}
+
+/// The tar stream inside `input`, decompressed as its first bytes say.
+/// `memory_limit` only applies to zstd, whose window is set by the archive.
+pub fn decompress<R: Read + Send + 'static>(
+    mut input: R,
+    memory_limit: Option<u64>,
+) -> Result<Box<dyn Read + Send>> {
+    // Pipes may hand out fewer bytes per read than the longest magic
+    let mut head = Vec::with_capacity(encryption::MAGIC.len());
+    input
+        .by_ref()
+        .take(encryption::MAGIC.len() as u64)
+        .read_to_end(&mut head)
+        .context("Failed to read input")?;
+    let codec = detect(&head);
+    let input = Cursor::new(head).chain(input);
+    Ok(match codec {
+        Codec::Zstd => {
+            let mut decoder = zstd::Decoder::new(input)?;
+            if let Some(limit) = memory_limit {
+                decoder
+                    .window_log_max(window_log_for(limit))
+                    .context("Failed to set decompression memory limit")?;
+            }
+            Box::new(decoder)
+        }
+        // pigz and `cat a.gz b.gz` produce several members
+        Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        Codec::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(input)),
+        Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(input)),
+        // Its directory is at the end, out of reach of a stream
+        Codec::Zip => bail!("ZIP archives can't be read from a pipe; pass the file itself"),
+        Codec::Encrypted => {
+            let passphrase = encryption::passphrase(false)?;
+            // Boxed, or every nesting would instantiate another `decompress`
+            let decrypted: Box<dyn Read + Send> = Box::new(Decryptor::new(input, &passphrase)?);
+            decompress(decrypted, memory_limit)?
+        }
+        Codec::Plain => Box::new(input),
+    })
+}
+
+/// Open an archive (`-` for stdin) and decompress it.
+pub fn open_archive(path: &Path, memory_limit: Option<u64>) -> Result<Box<dyn Read + Send>> {
+    if !is_stdio(path) {
+        let mut head = Vec::with_capacity(4);
+        File::open(path)
+            .and_then(|file| file.take(4).read_to_end(&mut head))
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        if detect(&head) == Codec::Zip {
+            return zip::open_as_tar(path);
+        }
+    }
+    decompress(open_input(path)?, memory_limit)
+}
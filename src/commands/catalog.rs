@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::catalog::{ArchiveRecord, Catalog};
+
+pub enum CatalogQuery {
+    List,
+    Search(String),
+    WhereIs(String),
+}
+
+pub fn execute(db: &Path, query: CatalogQuery) -> Result<()> {
+    let catalog = Catalog::open(db)?;
+    match query {
+        CatalogQuery::List => catalog.list()?.iter().for_each(print_record),
+        CatalogQuery::Search(term) => catalog.search(&term)?.iter().for_each(print_record),
+        CatalogQuery::WhereIs(path) => {
+            let hits = catalog.where_is(&path)?;
+            if hits.is_empty() {
+                println!("{} was not found in any cataloged archive", path);
+            }
+            for (record, entry) in hits {
+                println!("{}\t{}", record.location, entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_record(r: &ArchiveRecord) {
+    println!(
+        "#{}\t{}\t{} files\t{} bytes\tsource={}{}",
+        r.id,
+        r.location,
+        r.file_count,
+        r.size,
+        r.source,
+        r.profile
+            .as_ref()
+            .map(|p| format!("\tprofile={}", p))
+            .unwrap_or_default()
+    );
+}
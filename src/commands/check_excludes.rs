@@ -0,0 +1,137 @@
+use anyhow::Result;
+use globset::GlobSet;
+use jwalk::WalkDir;
+use std::path::Path;
+
+use crate::utils::{Rooting, build_excludes, is_excluded, relativize};
+
+/// Paths listed per pattern, enough to recognize what it catches
+const EXAMPLES_PER_PATTERN: usize = 5;
+
+#[derive(serde::Serialize)]
+pub struct PatternReport {
+    pub pattern: String,
+    /// Why the pattern can't be used; it matches nothing then
+    pub error: Option<String>,
+    /// Entries the pattern skips, including everything below skipped directories
+    pub entries: u64,
+    pub bytes: u64,
+    pub examples: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ExcludeReport {
+    /// Entries looked at
+    pub scanned: u64,
+    /// Whether the whole tree was scanned rather than the first `limit` entries
+    pub complete: bool,
+    /// Entries and bytes skipped by any pattern
+    pub entries: u64,
+    pub bytes: u64,
+    pub patterns: Vec<PatternReport>,
+}
+
+/// Walk `dir` the way `pack` does (archive paths under the directory's own
+/// name) and count what each `--exclude` pattern would leave out. Stops
+/// after `limit` entries, so a quick answer on a huge tree is a sample.
+pub fn check(dir: &Path, patterns: &[String], limit: Option<u64>) -> Result<ExcludeReport> {
+    let dir = dir.canonicalize()?;
+    let compiled: Vec<Result<GlobSet>> = patterns
+        .iter()
+        .map(|pattern| build_excludes(std::slice::from_ref(pattern)))
+        .collect();
+    let mut report = ExcludeReport {
+        scanned: 0,
+        complete: true,
+        entries: 0,
+        bytes: 0,
+        patterns: patterns
+            .iter()
+            .zip(&compiled)
+            .map(|(pattern, set)| PatternReport {
+                pattern: pattern.clone(),
+                error: set.as_ref().err().map(|e| format!("{:#}", e)),
+                entries: 0,
+                bytes: 0,
+                examples: Vec::new(),
+            })
+            .collect(),
+    };
+
+    for entry in WalkDir::new(&dir).skip_hidden(false) {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = entry.path();
+        if path == dir {
+            continue;
+        }
+        if limit.is_some_and(|limit| report.scanned >= limit) {
+            report.complete = false;
+            break;
+        }
+        report.scanned += 1;
+
+        let archive_path = relativize(&path, &dir, Rooting::TopDir);
+        let size = if entry.file_type().is_file() {
+            entry.metadata().map(|m| m.len()).unwrap_or(0)
+        } else {
+            0
+        };
+        let mut matched = false;
+        for (set, pattern) in compiled.iter().zip(&mut report.patterns) {
+            if let Ok(set) = set
+                && is_excluded(set, &archive_path)
+            {
+                matched = true;
+                pattern.entries += 1;
+                pattern.bytes += size;
+                if pattern.examples.len() < EXAMPLES_PER_PATTERN {
+                    pattern.examples.push(archive_path.display().to_string());
+                }
+            }
+        }
+        if matched {
+            report.entries += 1;
+            report.bytes += size;
+        }
+    }
+    Ok(report)
+}
+
+pub fn execute(dir: &Path, patterns: &[String], limit: Option<u64>, json: bool) -> Result<()> {
+    let report = check(dir, patterns, limit)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    for pattern in &report.patterns {
+        match &pattern.error {
+            Some(error) => println!("{:<24} invalid: {}", pattern.pattern, error),
+            None => {
+                println!(
+                    "{:<24} {} entries, {}",
+                    pattern.pattern,
+                    pattern.entries,
+                    indicatif::HumanBytes(pattern.bytes)
+                );
+                for example in &pattern.examples {
+                    println!("    {}", example);
+                }
+            }
+        }
+    }
+    println!(
+        "{} of {} entries excluded ({}){}",
+        report.entries,
+        report.scanned,
+        indicatif::HumanBytes(report.bytes),
+        if report.complete {
+            ""
+        } else {
+            ", sample of the first entries only"
+        }
+    );
+    Ok(())
+}
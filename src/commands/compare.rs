@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::codec;
+use crate::dialect::{self, EntryKind};
+use crate::sanitize;
+use crate::utils::{build_excludes, is_excluded};
+use crate::validate::ExtractLimits;
+
+/// Process exit code when the directory doesn't match the archive
+pub const EXIT_DIFFERENT: i32 = 5;
+
+const COMPARE_BUFFER_SIZE: usize = 256 * 1024;
+
+pub struct Difference {
+    pub path: PathBuf,
+    pub detail: String,
+}
+
+#[derive(Default)]
+pub struct CompareSummary {
+    /// Archive entries checked against the directory
+    pub entries: u64,
+    pub differences: Vec<Difference>,
+}
+
+/// Check that extracting `input` into `output` would change nothing: every
+/// entry unpack would write exists there with the same type, contents, link
+/// target, permissions and mtime. Files in `output` that aren't in the
+/// archive are not differences, as with `unpack --merge`. Nothing is written.
+pub fn execute(
+    input: &Path,
+    output: &Path,
+    exclude: &[String],
+    limits: &ExtractLimits,
+    memory_limit: Option<u64>,
+) -> Result<CompareSummary> {
+    let excludes = build_excludes(exclude)?;
+    let mut archive = tar::Archive::new(codec::open_archive(input, memory_limit)?);
+    let mut summary = CompareSummary::default();
+    let mut bytes = 0u64;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        if is_excluded(&excludes, &entry_path) {
+            continue;
+        }
+        let kind = dialect::classify(&mut entry)?;
+        // Unpack writes nothing for these either
+        if matches!(kind, EntryKind::Metadata | EntryKind::Unsupported(_)) {
+            continue;
+        }
+        summary.entries += 1;
+        limits.check_entry(summary.entries, &entry_path)?;
+        // Unpack skips these, so there is nothing to compare them with
+        if sanitize::reject_reason(&entry_path).is_some() {
+            continue;
+        }
+
+        let size = entry.size();
+        let header = entry.header();
+        let mode = header.mode()?;
+        let mtime = header.mtime()?;
+        let target_path = output.join(&entry_path);
+
+        let meta = match fs::symlink_metadata(&target_path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                summary.differences.push(Difference {
+                    path: entry_path,
+                    detail: "missing".to_string(),
+                });
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", target_path)),
+        };
+
+        let mut details = Vec::new();
+        let expected = match kind {
+            EntryKind::Directory => "directory",
+            EntryKind::Symlink => "symlink",
+            _ => "file",
+        };
+        if kind_of(&meta) != expected {
+            details.push(format!(
+                "is a {}, archive has a {}",
+                kind_of(&meta),
+                expected
+            ));
+        } else {
+            match kind {
+                EntryKind::Directory => {
+                    check_mode(&meta, mode, &mut details);
+                    check_mtime(&meta, mtime, &mut details);
+                }
+                EntryKind::Symlink => {
+                    let target = entry.link_name()?.unwrap_or_default().into_owned();
+                    let actual = fs::read_link(&target_path)?;
+                    if actual != target {
+                        details.push(format!("points to {:?}, archive has {:?}", actual, target));
+                    }
+                    // Symlink times are only restored on Unix
+                    #[cfg(unix)]
+                    check_mtime(&meta, mtime, &mut details);
+                }
+                EntryKind::Hardlink => {
+                    let target = entry.link_name()?.unwrap_or_default().into_owned();
+                    if sanitize::reject_reason(&target).is_some() {
+                        continue;
+                    }
+                    if !same_file(&target_path, &output.join(&target), &meta)? {
+                        details.push(format!("is not a link to {:?}", target));
+                    }
+                }
+                _ => {
+                    bytes += size;
+                    limits.check_output(bytes)?;
+                    if meta.len() != size {
+                        details.push(format!("size {}, archive has {}", meta.len(), size));
+                    } else if !same_contents(&mut entry, &target_path)? {
+                        details.push("contents differ".to_string());
+                    }
+                    check_mode(&meta, mode, &mut details);
+                    check_mtime(&meta, mtime, &mut details);
+                }
+            }
+        }
+        if !details.is_empty() {
+            summary.differences.push(Difference {
+                path: entry_path,
+                detail: details.join(", "),
+            });
+        }
+    }
+    Ok(summary)
+}
+
+fn kind_of(meta: &Metadata) -> &'static str {
+    if meta.file_type().is_symlink() {
+        "symlink"
+    } else if meta.is_dir() {
+        "directory"
+    } else {
+        "file"
+    }
+}
+
+/// Windows only keeps a read-only flag, so modes are compared on Unix only.
+#[cfg(unix)]
+fn check_mode(meta: &Metadata, mode: u32, details: &mut Vec<String>) {
+    use std::os::unix::fs::PermissionsExt;
+    let actual = meta.permissions().mode() & 0o777;
+    if actual != mode & 0o777 {
+        details.push(format!("mode {:o}, archive has {:o}", actual, mode & 0o777));
+    }
+}
+
+#[cfg(not(unix))]
+fn check_mode(_meta: &Metadata, _mode: u32, _details: &mut Vec<String>) {}
+
+fn check_mtime(meta: &Metadata, mtime: u64, details: &mut Vec<String>) {
+    let actual = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    if actual != Some(mtime) {
+        details.push(format!(
+            "mtime {}, archive has {}",
+            actual.map_or("unknown".to_string(), |t| t.to_string()),
+            mtime
+        ));
+    }
+}
+
+/// Whether `path` is a hardlink to `target`. Without inode numbers (or after
+/// `unpack --reflink-dups`) equal contents count.
+fn same_file(path: &Path, target: &Path, meta: &Metadata) -> Result<bool> {
+    let Ok(target_meta) = fs::metadata(target) else {
+        return Ok(false);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.dev() == target_meta.dev() && meta.ino() == target_meta.ino() {
+            return Ok(true);
+        }
+    }
+    if meta.len() != target_meta.len() {
+        return Ok(false);
+    }
+    same_contents(&mut File::open(target)?, path)
+}
+
+/// Compare a stream against a file of the same length, stopping at the first
+/// difference. The rest of `data` is left for the caller to skip.
+fn same_contents<R: Read>(data: &mut R, path: &Path) -> Result<bool> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut expected = vec![0u8; COMPARE_BUFFER_SIZE];
+    let mut actual = vec![0u8; COMPARE_BUFFER_SIZE];
+    loop {
+        let n = read_full(data, &mut expected)?;
+        if n == 0 {
+            return Ok(read_full(&mut file, &mut actual[..1])? == 0);
+        }
+        if read_full(&mut file, &mut actual[..n])? != n || expected[..n] != actual[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Read until `buf` is full or the stream ends.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
@@ -1,62 +1,42 @@
-use crate::commands::pack::{CHUNK_SIZE, MEMORY_FILE_THRESHOLD, TarEntry};
-use crate::utils::{FileId, FileMetadata, get_file_id, get_file_metadata};
+use crate::commands::pack::{
+    CHUNK_SIZE, LARGE_FILE_STREAM_CAPACITY, LinkTarget, Prepared, ReadOptions, TarEntry, map_file,
+    pooled_buffer, prepare_path, recheck, retry_delay, use_mmap, with_retries,
+};
+use crate::profiling::{PipelineProfile, Stage};
+use crate::skips::SkipLog;
+use crate::utils::FileId;
+use crate::watchdog::Heartbeat;
 use anyhow::Result;
 use crossbeam_channel::Receiver;
-use crossbeam_channel::Sender;
 use dashmap::DashMap;
 use indicatif::ProgressBar;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-/// Backpressure is handled via channel capacity (PATH_CHANNEL_CAPACITY = 1000 in pack.rs)
+// Backpressure: every hop is a bounded flume channel awaited with `recv_async`
+// / `send_async`, so a worker blocked on a full channel yields to the runtime
+// instead of stalling it. The only synchronous waits are the scanner and the
+// writer, which run on their own threads. The writer drains one entry at a
+// time and a large file's chunk stream is fed by a task that already got its
+// header into the content channel, so the writer never waits on a producer
+// that is itself waiting on the writer.
 
 /// Start compio worker pool - replaces thread-per-path pattern
+#[allow(clippy::too_many_arguments)]
 pub fn start_compio_worker(
-    path_rx: Receiver<PathBuf>,
-    content_tx: Sender<Result<TarEntry>>,
-    chunk_tx: Sender<Result<TarEntry>>,
+    path_rx: flume::Receiver<PathBuf>,
+    content_tx: flume::Sender<Result<TarEntry>>,
     pool_rx: Receiver<Vec<u8>>,
     input_dir: PathBuf,
     pb: Arc<ProgressBar>,
-    inode_cache: Arc<DashMap<FileId, PathBuf>>,
-    ignore_errors: bool,
+    inode_cache: Arc<DashMap<FileId, LinkTarget>>,
+    heartbeat: Heartbeat,
+    skips: SkipLog,
+    profile: Arc<PipelineProfile>,
+    options: ReadOptions,
 ) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
-        // Use flume for async-to-sync bridging
-        let (async_tx, async_rx) = flume::unbounded::<Result<TarEntry>>();
-
-        // Spawn Bridge Thread - forwards async results to sync channels
-        let bridge_handle = std::thread::spawn(move || {
-            while let Ok(res) = async_rx.recv() {
-                match res {
-                    Ok(entry) => match entry {
-                        TarEntry::LargeFileChunk(_) | TarEntry::LargeFileEnd => {
-                            if chunk_tx.send(Ok(entry)).is_err() {
-                                break;
-                            }
-                        }
-                        _ => {
-                            if content_tx.send(Ok(entry)).is_err() {
-                                break;
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        if content_tx.send(Err(e)).is_err() {
-                            break;
-                        }
-                    }
-                }
-            }
-        });
-
-        // Track active tasks for graceful shutdown
-        let active_tasks = Arc::new(AtomicUsize::new(0));
-        
-        // Large File Serializer - but with finer-grained locking
-        let large_file_in_progress = Arc::new(AtomicUsize::new(0));
-
         // Start compio Runtime
         let runtime = compio_runtime::Runtime::new()
             .expect("Failed to create compio runtime");
@@ -64,54 +44,39 @@ pub fn start_compio_worker(
         runtime.block_on(async move {
             // Spawn a fixed pool of workers - they compete for paths from the channel
             // This replaces the previous "spawn a thread per path" approach
-            let num_workers = std::cmp::max(1, num_cpus::get() as usize);
+            let num_workers = std::cmp::max(1, options.workers);
             
             // Track all spawned tasks
             let mut handles = Vec::new();
             
             for _worker_id in 0..num_workers {
                 let path_rx = path_rx.clone();
-                let async_tx = async_tx.clone();
+                let content_tx = content_tx.clone();
                 let pool_rx = pool_rx.clone();
                 let base_path = input_dir.clone();
                 let p_bar = pb.clone();
                 let i_cache = inode_cache.clone();
-                let active = active_tasks.clone();
-                let lf_in_progress = large_file_in_progress.clone();
+                let heartbeat = heartbeat.clone();
+                let skips = skips.clone();
+                let profile = profile.clone();
 
                 let handle = compio_runtime::spawn(async move {
-                    // Worker loop - continuously process paths until channel closes
-                    // Backpressure is handled by channel capacity (PATH_CHANNEL_CAPACITY = 1000)
-                    loop {
-                        // Try to get next path from channel (non-blocking)
-                        let path = match path_rx.try_recv() {
-                            Ok(p) => p,
-                            Err(crossbeam_channel::TryRecvError::Empty) => {
-                                // No work available, release permit and continue
-                                continue;
-                            }
-                            Err(crossbeam_channel::TryRecvError::Disconnected) => {
-                                // Channel closed, exit worker
-                                break;
-                            }
-                        };
-
-                        // Track active tasks
-                        active.fetch_add(1, Ordering::SeqCst);
-
-                        // Process the file
+                    // Worker loop - waits for paths until the scanner is done
+                    while let Ok(path) = path_rx.recv_async().await {
+                        let t = profile.start();
                         process_path_compio(
                             path,
                             base_path.clone(),
-                            async_tx.clone(),
+                            content_tx.clone(),
                             pool_rx.clone(),
                             p_bar.clone(),
                             i_cache.clone(),
-                            lf_in_progress.clone(),
-                            ignore_errors,
+                            &heartbeat,
+                            &skips,
+                            &profile,
+                            options,
                         ).await;
-
-                        active.fetch_sub(1, Ordering::SeqCst);
+                        profile.stop(Stage::Read, t);
                     }
                 });
                 handles.push(handle);
@@ -121,63 +86,59 @@ pub fn start_compio_worker(
             for handle in handles {
                 let _ = handle.await;
             }
-            
-            // Wait for all active tasks to complete
-            while active_tasks.load(Ordering::SeqCst) > 0 {
-                std::thread::sleep(std::time::Duration::from_millis(10));
-            }
         });
-
-        let _ = bridge_handle.join();
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_path_compio(
     path: PathBuf,
     base_path: PathBuf,
     content_tx: flume::Sender<Result<TarEntry>>,
     pool_rx: Receiver<Vec<u8>>,
     pb: Arc<ProgressBar>,
-    inode_cache: Arc<DashMap<FileId, PathBuf>>,
-    large_file_in_progress: Arc<AtomicUsize>,
-    ignore_errors: bool,
+    inode_cache: Arc<DashMap<FileId, LinkTarget>>,
+    heartbeat: &Heartbeat,
+    skips: &SkipLog,
+    profile: &PipelineProfile,
+    options: ReadOptions,
 ) {
     use compio::buf::BufResult;
-    use compio::io::AsyncReadAt;
 
-    let process = async {
-        let parent = base_path.parent().unwrap_or(&base_path);
-        let relative_path = match path.strip_prefix(parent) {
-            Ok(p) => p.to_path_buf(),
-            Err(_) => path
-                .file_name()
-                .map(PathBuf::from)
-                .unwrap_or_else(|| PathBuf::from("unknown")),
-        };
+    heartbeat.beat("read", &path);
 
+    let process = async {
         // Blocking metadata - use std::thread
-        let path_clone = path.clone();
-        let metadata_result = std::thread::spawn(move || -> Result<(std::fs::Metadata, FileMetadata, std::fs::FileType)> {
-            let m = std::fs::symlink_metadata(&path_clone)?;
-            let mo = get_file_metadata(&path_clone, &m);
-            let ft = m.file_type();
-            Ok((m, mo, ft))
-        }).join();
+        let (path_clone, inode_cache, prepare_skips) =
+            (path.clone(), inode_cache.clone(), skips.clone());
+        let prepared = std::thread::spawn(move || {
+            prepare_path(
+                &path_clone,
+                &base_path,
+                &inode_cache,
+                &prepare_skips,
+                options,
+            )
+        })
+        .join();
 
-        let (meta, metadata, file_type) = match metadata_result {
-            Ok(Ok(v)) => v,
-            Ok(Err(e)) => {
-                if ignore_errors {
-                    eprintln!("Warning: Skipping unreadable file {:?}: {}", path, e);
-                    return Ok(());
-                } else {
-                    return Err(e.into());
-                }
+        let (relative_path, mut stamp, mut metadata) = match prepared {
+            Ok(Ok(Some(Prepared::File(relative_path, stamp, metadata)))) => {
+                (relative_path, stamp, metadata)
             }
+            Ok(Ok(Some(Prepared::Entry(entry)))) => {
+                let relative_path = entry.path().to_path_buf();
+                send_timed(&content_tx, Ok(entry), profile).await?;
+                pb.inc(1);
+                pb.set_message(format!("{:?}", relative_path));
+                return Ok(());
+            }
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
             Err(_) => {
                 let err = anyhow::anyhow!("Thread panicked");
-                if ignore_errors {
-                    eprintln!("Warning: Skipping unreadable file {:?}: {}", path, err);
+                if options.ignore_errors {
+                    skips.skip("Skipping unreadable file", &path, err.as_ref());
                     return Ok(());
                 } else {
                     return Err(err);
@@ -185,111 +146,125 @@ async fn process_path_compio(
             }
         };
 
-        if file_type.is_dir() {
-            content_tx.send(Ok(TarEntry::Dir(relative_path.clone(), metadata)))
-                .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-        } else if file_type.is_symlink() {
-            let target = std::fs::read_link(&path)?;
-            content_tx.send(Ok(TarEntry::Symlink(
-                relative_path.clone(),
-                target,
-                metadata,
-            )))
-            .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-        } else {
-            // Check Hardlinks
-            if let Some(fid) = get_file_id(&path, &meta) {
-                if let Some(existing_entry) = inode_cache.get(&fid) {
-                    let target = existing_entry.value().clone();
-                    content_tx.send(Ok(TarEntry::HardLink(relative_path.clone(), target)))
-                        .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-                    pb.inc(1);
-                    pb.set_message(format!("{:?}", relative_path));
-                    return Ok(());
-                } else {
-                    inode_cache.insert(fid, relative_path.clone());
-                }
-            }
-
-            let len = meta.len();
-            if len >= MEMORY_FILE_THRESHOLD {
-                // Large File Chunking - use atomic instead of mutex for better concurrency
-                // Wait for any in-progress large file to finish
-                while large_file_in_progress.load(Ordering::SeqCst) > 0 {
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-                }
-                
-                // Mark as in-progress (we're now the one processing)
-                // Actually for large files, we can process them in parallel too
-                // but we need to ensure ordering in the chunk channel
-                // The key insight: each large file uses a dedicated chunk channel
-                // So we just need to ensure we serialize LARGE FILE STARTS
-                // But we can process chunks in parallel
-                
-                content_tx.send(Ok(TarEntry::LargeFileStart(
+        let len = stamp.len;
+        if len >= options.stream_threshold {
+            // Large files stream over their own bounded channel, so several
+            // can be read concurrently while the writer drains them one at a
+            // time in header order. Open first so an unreadable file can still
+            // be skipped before its header is emitted.
+            let file = open_retried(&path, options, skips).await?;
+            let (chunk_tx, chunk_rx) = flume::bounded(LARGE_FILE_STREAM_CAPACITY);
+            let changed = Arc::new(AtomicBool::new(false));
+            send_timed(
+                &content_tx,
+                Ok(TarEntry::LargeFile(
                     relative_path.clone(),
                     len,
-                    metadata,
-                )))
-                .map_err(|_| anyhow::anyhow!("Channel closed"))?;
+                    metadata.clone(),
+                    chunk_rx,
+                    changed.clone(),
+                )),
+                profile,
+            )
+            .await?;
 
-                let file = compio::fs::File::open(&path).await?;
-                let mut pos = 0;
-                while pos < len {
-                    let chunk_size = std::cmp::min(len - pos, CHUNK_SIZE);
-                    let mut buf = pool_rx
-                        .try_recv()
-                        .unwrap_or_else(|_| Vec::with_capacity(chunk_size as usize));
-                    if buf.capacity() < chunk_size as usize {
-                        buf.reserve(chunk_size as usize - buf.capacity());
-                    }
-                    if buf.len() < chunk_size as usize {
-                        buf.resize(chunk_size as usize, 0);
-                    }
+            let mut pos = 0;
+            while pos < len {
+                let chunk_size = std::cmp::min(len - pos, CHUNK_SIZE) as usize;
+                let buf = pooled_buffer(&pool_rx, chunk_size);
 
-                    let BufResult(res, buf_ret) = file.read_at(buf, pos).await;
-                    let mut valid_buf = buf_ret;
-                    res?;
-
-                    if valid_buf.len() > chunk_size as usize {
-                        valid_buf.truncate(chunk_size as usize);
+                let BufResult(res, mut valid_buf) =
+                    read_at_retried(&file, buf, pos, &path, options, skips).await;
+                let read = match res {
+                    Ok(0) => Err(anyhow::anyhow!("File shrank while reading")),
+                    Ok(n) => Ok(n),
+                    Err(e) => Err(e.into()),
+                };
+                let n = match read {
+                    Ok(n) => n,
+                    Err(e) => {
+                        // The header is already out; fail the stream so the writer aborts
+                        let _ = chunk_tx
+                            .send_async(Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)))
+                            .await;
+                        return Err(e);
                     }
+                };
+                // Short reads are fine, the next chunk continues where this one ended
+                valid_buf.truncate(n.min(chunk_size));
+                heartbeat.beat("read", &path);
 
-                    content_tx.send(Ok(TarEntry::LargeFileChunk(valid_buf)))
-                        .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-                    pos += chunk_size;
+                // Checked before the last chunk goes out, while a change can still fail the entry
+                if pos + n as u64 >= len
+                    && let Err(e) = recheck(&path, &mut stamp, &mut metadata, None, options, skips)
+                {
+                    let _ = chunk_tx
+                        .send_async(Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)))
+                        .await;
+                    return Err(e);
                 }
+                // Seen by the writer once it has the last chunk
+                changed.store(metadata.changed, Ordering::Relaxed);
+                send_timed(&chunk_tx, Ok(valid_buf), profile).await?;
+                pos += n as u64;
+            }
+        } else if use_mmap(len, options) {
+            // Mapping is a single syscall; the data is faulted in by the writer
+            let mut map = with_retries(&path, options, skips, || map_file(&path, options))?;
+            // The writer copies the mapping later; this only sees changes made until now
+            let mut reads = 1;
+            while recheck(
+                &path,
+                &mut stamp,
+                &mut metadata,
+                Some(reads),
+                options,
+                skips,
+            )? {
+                map = with_retries(&path, options, skips, || map_file(&path, options))?;
+                reads += 1;
+            }
+            send_timed(
+                &content_tx,
+                Ok(TarEntry::MappedFile(relative_path.clone(), map, metadata)),
+                profile,
+            )
+            .await?;
+        } else {
+            // Small File - can be processed in parallel freely
+            let file = open_retried(&path, options, skips).await?;
+            let mut reads = 1;
+            let valid_buf = loop {
+                let buf = pooled_buffer(&pool_rx, stamp.len as usize);
+                let BufResult(res, mut valid_buf) =
+                    read_at_retried(&file, buf, 0, &path, options, skips).await;
+                let n = res?;
 
-                content_tx.send(Ok(TarEntry::LargeFileEnd))
-                    .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-            } else {
-                // Small File - can be processed in parallel freely
-                let mut buf = pool_rx
-                    .try_recv()
-                    .unwrap_or_else(|_| Vec::with_capacity(len as usize));
-                if buf.capacity() < len as usize {
-                    buf.reserve(len as usize - buf.capacity());
-                }
-                if buf.len() < len as usize {
-                    buf.resize(len as usize, 0);
-                }
-
-                let file = compio::fs::File::open(&path).await?;
-                let BufResult(res, buf_ret) = file.read_at(buf, 0).await;
-                let mut valid_buf = buf_ret;
-                res?;
-
-                if valid_buf.len() > len as usize {
-                    valid_buf.truncate(len as usize);
+                // Drop stale pool bytes past what was actually read
+                valid_buf.truncate(n.min(stamp.len as usize));
+                if !recheck(
+                    &path,
+                    &mut stamp,
+                    &mut metadata,
+                    Some(reads),
+                    options,
+                    skips,
+                )? {
+                    break valid_buf;
                 }
+                reads += 1;
+            };
 
-                content_tx.send(Ok(TarEntry::SmallFile(
+            send_timed(
+                &content_tx,
+                Ok(TarEntry::SmallFile(
                     relative_path.clone(),
                     valid_buf,
                     metadata,
-                )))
-                .map_err(|_| anyhow::anyhow!("Channel closed"))?;
-            }
+                )),
+                profile,
+            )
+            .await?;
         }
         pb.inc(1);
         pb.set_message(format!("{:?}", relative_path));
@@ -297,11 +272,95 @@ async fn process_path_compio(
     };
 
     if let Err(e) = process.await {
-        if ignore_errors {
-            eprintln!("Warning: Failed to process {:?}: {}", path, e);
+        if options.ignore_errors {
+            skips.skip("Failed to process", &path, e.as_ref());
         } else {
             let _ = content_tx
-                .send(Err(anyhow::anyhow!("Failed to process {:?}: {}", path, e)));
+                .send_async(Err(anyhow::anyhow!("Failed to process {:?}: {}", path, e)))
+                .await;
         }
     }
 }
+
+/// Open `path`, trying again after transient errors (`--read-retries`).
+async fn open_retried(
+    path: &Path,
+    options: ReadOptions,
+    skips: &SkipLog,
+) -> std::io::Result<compio::fs::File> {
+    let mut attempt = 0;
+    loop {
+        match open_source(path, options).await {
+            Err(e) => match retry_delay(&e, attempt, options) {
+                Some(delay) => {
+                    skips.retry(path, &e, attempt, delay);
+                    compio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Open a source file, with `O_NOATIME` where allowed as in `pack::open_with_flags`.
+async fn open_source(path: &Path, options: ReadOptions) -> std::io::Result<compio::fs::File> {
+    #[cfg(target_os = "linux")]
+    if options.preserve_atime {
+        match compio::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+            .await
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = options;
+    compio::fs::File::open(path).await
+}
+
+/// `file.read_at(buf, pos)`, trying again after transient errors.
+async fn read_at_retried(
+    file: &compio::fs::File,
+    mut buf: Vec<u8>,
+    pos: u64,
+    path: &Path,
+    options: ReadOptions,
+    skips: &SkipLog,
+) -> compio::buf::BufResult<usize, Vec<u8>> {
+    use compio::buf::BufResult;
+    use compio::io::AsyncReadAt;
+
+    let mut attempt = 0;
+    loop {
+        let BufResult(res, returned) = file.read_at(buf, pos).await;
+        match res {
+            Err(e) => match retry_delay(&e, attempt, options) {
+                Some(delay) => {
+                    skips.retry(path, &e, attempt, delay);
+                    compio::time::sleep(delay).await;
+                    attempt += 1;
+                    buf = returned;
+                }
+                None => return BufResult(Err(e), returned),
+            },
+            res => return BufResult(res, returned),
+        }
+    }
+}
+
+/// Send on a bounded channel, booking the time spent waiting for room as
+/// `Stage::ReadBlocked`.
+async fn send_timed<T>(tx: &flume::Sender<T>, value: T, profile: &PipelineProfile) -> Result<()> {
+    let t = profile.start();
+    let res = tx
+        .send_async(value)
+        .await
+        .map_err(|_| anyhow::anyhow!("Channel closed"));
+    profile.stop(Stage::ReadBlocked, t);
+    res
+}
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cli::SizeDist;
+
+/// Files per leaf directory before the generator moves on to the next one
+const FILES_PER_DIR: u64 = 100;
+
+/// Words the file contents are made of, so fixtures compress like text
+/// rather than like noise or zeros
+const WORDS: &[&str] = &[
+    "archive", "block", "chunk", "data", "entry", "file", "header", "inode", "link", "mode",
+    "path", "record", "size", "stream", "tree", "value", "window", "zstd", "0x00", "\n",
+];
+
+pub struct FixtureOptions {
+    pub files: u64,
+    pub size: u64,
+    pub max_size: u64,
+    pub dist: SizeDist,
+    /// Share of entries that are hard links to an earlier file (0.0..=1.0)
+    pub hardlinks: f64,
+    /// Share of entries that are symlinks to an earlier file (0.0..=1.0)
+    pub symlinks: f64,
+    pub depth: u32,
+    pub fanout: u32,
+    pub seed: u64,
+}
+
+#[derive(Default)]
+struct Generated {
+    files: u64,
+    hardlinks: u64,
+    symlinks: u64,
+    dirs: u64,
+    bytes: u64,
+}
+
+/// Create a synthetic tree under `output`. The same options and seed always
+/// produce the same tree, so a slow or failing pack can be reproduced from
+/// the command line alone.
+pub fn execute(output: &Path, options: FixtureOptions) -> Result<()> {
+    if output.exists() && fs::read_dir(output)?.next().is_some() {
+        bail!("{:?} is not empty", output);
+    }
+    if options.hardlinks + options.symlinks > 1.0 {
+        bail!("--hardlinks and --symlinks add up to more than 100%");
+    }
+    fs::create_dir_all(output).with_context(|| format!("Failed to create {:?}", output))?;
+
+    let mut rng = SplitMix64(options.seed);
+    let mut generated = Generated::default();
+    let mut content = Vec::new();
+    let mut last_dir = None;
+    let mut first_file: Option<PathBuf> = None;
+
+    for i in 0..options.files {
+        let dir_index = i / FILES_PER_DIR;
+        let dir = output.join(dir_path(dir_index, options.depth, options.fanout));
+        if last_dir != Some(dir_index) {
+            fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+            generated.dirs += 1;
+            last_dir = Some(dir_index);
+        }
+        let name = format!("f{:07}", i);
+        let path = dir.join(&name);
+
+        // Links point at the first regular file, so every link is valid no
+        // matter how the tree is cut short or walked. Symlinks are relative
+        // so the fixture can be moved or packed from anywhere.
+        let roll = rng.next_f64();
+        if let Some(target) = &first_file {
+            if roll < options.hardlinks {
+                fs::hard_link(output.join(target), &path)
+                    .with_context(|| format!("Failed to link {:?}", path))?;
+                generated.hardlinks += 1;
+                continue;
+            }
+            if roll < options.hardlinks + options.symlinks {
+                let up: PathBuf = (0..options.depth).map(|_| "..").collect();
+                make_symlink(&up.join(target), &path)?;
+                generated.symlinks += 1;
+                continue;
+            }
+        }
+
+        let size = file_size(&mut rng, &options);
+        fill(&mut rng, &mut content, size as usize);
+        let mut file =
+            fs::File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+        file.write_all(&content)?;
+        generated.files += 1;
+        generated.bytes += size;
+        if first_file.is_none() {
+            first_file = Some(dir_path(dir_index, options.depth, options.fanout).join(name));
+        }
+    }
+
+    println!(
+        "Generated {:?}: {} files ({}), {} hardlinks, {} symlinks, {} directories (seed {})",
+        output,
+        generated.files,
+        indicatif::HumanBytes(generated.bytes),
+        generated.hardlinks,
+        generated.symlinks,
+        generated.dirs,
+        options.seed
+    );
+    Ok(())
+}
+
+/// Nested directory for the `index`th group of files: `depth` levels of at
+/// most `fanout` entries each, wrapping around once they are all used.
+fn dir_path(index: u64, depth: u32, fanout: u32) -> PathBuf {
+    let fanout = fanout.max(1) as u64;
+    let mut path = PathBuf::new();
+    let mut rest = index;
+    let mut parts = Vec::with_capacity(depth as usize);
+    for _ in 0..depth {
+        parts.push(rest % fanout);
+        rest /= fanout;
+    }
+    for part in parts.iter().rev() {
+        path.push(format!("d{:03}", part));
+    }
+    path
+}
+
+fn file_size(rng: &mut SplitMix64, options: &FixtureOptions) -> u64 {
+    let size = match options.dist {
+        SizeDist::Fixed => options.size,
+        SizeDist::Uniform => (rng.next_f64() * 2.0 * options.size as f64) as u64,
+        // Pareto with alpha 1.5 and a mean of `size`: most files are small,
+        // a few carry most of the bytes, as in home directories and source trees
+        SizeDist::Zipf => {
+            let u = 1.0 - rng.next_f64();
+            (options.size as f64 / 3.0 * u.powf(-1.0 / 1.5)) as u64
+        }
+    };
+    size.min(options.max_size)
+}
+
+fn fill(rng: &mut SplitMix64, buf: &mut Vec<u8>, len: usize) {
+    buf.clear();
+    while buf.len() < len {
+        let word = WORDS[(rng.next() % WORDS.len() as u64) as usize];
+        buf.extend_from_slice(word.as_bytes());
+        buf.push(b' ');
+    }
+    buf.truncate(len);
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)
+        .with_context(|| format!("Failed to create symlink {:?}", path))
+}
+
+#[cfg(windows)]
+fn make_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::windows::fs::symlink_file(target, path).with_context(|| {
+        format!(
+            "Failed to create symlink {:?} (needs Developer Mode or admin rights)",
+            path
+        )
+    })
+}
+
+/// Small deterministic generator; fixtures must not change with a
+/// dependency's algorithm.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
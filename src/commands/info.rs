@@ -0,0 +1,333 @@
+use anyhow::{Context, Result, bail};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek};
+use std::path::Path;
+
+use crate::catalog::Catalog;
+use crate::codec::{self, Codec};
+use crate::seekable::{SEEK_TABLE_FOOTER_MAGIC, SEEK_TABLE_MAGIC};
+use crate::utils::civil_from_days;
+
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+/// Skippable frames use 0x184D2A50..=0x184D2A5F
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+#[derive(Default)]
+struct FrameStats {
+    frames: u64,
+    skippable: u64,
+    seek_table: bool,
+    max_window: u64,
+    /// Sum of frame content sizes; `None` once a frame doesn't record its size
+    content_size: Option<u64>,
+    all_checksummed: bool,
+    dict_ids: Vec<u32>,
+    /// The last frame ends past the end of the file
+    truncated: bool,
+}
+
+/// What `info` reports, also printed as JSON for the GUI.
+#[derive(serde::Serialize)]
+pub struct ArchiveInfo {
+    pub path: String,
+    /// `Codec::name`; the frame fields below are only filled in for "zstd"
+    pub format: &'static str,
+    pub frames: u64,
+    pub skippable_frames: u64,
+    pub window: u64,
+    pub checksum: bool,
+    pub dict_ids: Vec<u32>,
+    pub seek_table: bool,
+    pub compressed: u64,
+    /// `None` unless every frame header records its content size
+    pub uncompressed: Option<u64>,
+    pub truncated: bool,
+    pub catalog: Option<CatalogInfo>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CatalogInfo {
+    pub id: i64,
+    pub entries: u64,
+    /// Unix time the archive was cataloged
+    pub created: u64,
+    pub source: String,
+    pub profile: Option<String>,
+    /// Size recorded at pack time; differs from `compressed` if the file changed since
+    pub size: u64,
+}
+
+/// Read what can be learned about an archive without decompressing it: its
+/// format, the zstd frame headers and, if the archive was packed with
+/// `--catalog`, its catalog record.
+pub fn inspect(archive: &Path, catalog_db: &Path) -> Result<ArchiveInfo> {
+    let mut file = File::open(archive).with_context(|| format!("Failed to open {:?}", archive))?;
+    let compressed = file.metadata()?.len();
+    // A whole tar header, to tell plain tar from anything else
+    let mut head = Vec::with_capacity(512);
+    file.by_ref().take(512).read_to_end(&mut head)?;
+    file.rewind()?;
+    let codec = codec::detect(&head);
+    let stats = match codec {
+        Codec::Zstd => scan_frames(BufReader::new(file), compressed)
+            .with_context(|| format!("{:?} is not a zstd archive", archive))?,
+        Codec::Plain if !matches!(tar::Archive::new(&head[..]).entries()?.next(), Some(Ok(_))) => {
+            bail!("{:?} is not an archive", archive)
+        }
+        _ => FrameStats::default(),
+    };
+
+    // Looking up an archive must not create a catalog as a side effect
+    let catalog = if catalog_db.exists() {
+        let location = archive.canonicalize()?;
+        Catalog::open(catalog_db)?
+            .find_by_location(&location.to_string_lossy())?
+            .map(|record| CatalogInfo {
+                id: record.id,
+                entries: record.file_count,
+                created: record.created,
+                source: record.source,
+                profile: record.profile,
+                size: record.size,
+            })
+    } else {
+        None
+    };
+
+    Ok(ArchiveInfo {
+        path: archive.display().to_string(),
+        format: codec.name(),
+        frames: stats.frames,
+        skippable_frames: stats.skippable,
+        window: stats.max_window,
+        checksum: stats.all_checksummed,
+        dict_ids: stats.dict_ids,
+        seek_table: stats.seek_table,
+        compressed,
+        uncompressed: stats.content_size.filter(|&size| size > 0),
+        truncated: stats.truncated,
+        catalog,
+    })
+}
+
+/// Print `inspect`'s findings, as text or as JSON.
+pub fn execute(archive: &Path, catalog_db: &Path, json: bool) -> Result<()> {
+    let info = inspect(archive, catalog_db)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    println!("Archive:      {:?}", archive);
+    match info.format {
+        "zstd" => print_frames(&info),
+        "tar" => println!("Format:       tar, uncompressed"),
+        "zip" => println!("Format:       ZIP"),
+        "encrypted" => println!("Format:       encrypted (pack --encrypt)"),
+        name => println!("Format:       tar + {}", name),
+    }
+    if info.format != "zstd" {
+        println!("Compressed:   {}", indicatif::HumanBytes(info.compressed));
+    }
+
+    if catalog_db.exists() {
+        match &info.catalog {
+            Some(record) => {
+                let (y, m, d) = civil_from_days((record.created / 86400) as i64);
+                println!("Entries:      {}", record.entries);
+                println!(
+                    "Cataloged:    #{} on {:04}-{:02}-{:02}, source {:?}{}",
+                    record.id,
+                    y,
+                    m,
+                    d,
+                    record.source,
+                    record
+                        .profile
+                        .as_ref()
+                        .map(|p| format!(", profile {:?}", p))
+                        .unwrap_or_default()
+                );
+                if record.size != info.compressed {
+                    println!(
+                        "Warning: Size differs from the catalog ({} recorded)",
+                        record.size
+                    );
+                }
+            }
+            None => println!("Entries:      unknown (not in the catalog)"),
+        }
+    }
+    Ok(())
+}
+
+/// The zstd frame findings, for zstd archives only.
+fn print_frames(info: &ArchiveInfo) {
+    println!(
+        "Format:       tar + zstd, {} frame(s){}",
+        info.frames,
+        if info.skippable_frames > 0 {
+            format!(" + {} skippable", info.skippable_frames)
+        } else {
+            String::new()
+        }
+    );
+    println!("Window:       {}", indicatif::HumanBytes(info.window));
+    println!(
+        "Checksum:     {}",
+        if info.checksum {
+            "yes (XXH64 per frame)"
+        } else {
+            "no"
+        }
+    );
+    println!(
+        "Dictionary:   {}",
+        if info.dict_ids.is_empty() {
+            "none".to_string()
+        } else {
+            format!("{:?}", info.dict_ids)
+        }
+    );
+    println!(
+        "Seek table:   {}",
+        if info.seek_table { "yes" } else { "no" }
+    );
+    println!("Compressed:   {}", indicatif::HumanBytes(info.compressed));
+    match info.uncompressed {
+        Some(size) => println!(
+            "Uncompressed: {} (ratio {:.3})",
+            indicatif::HumanBytes(size),
+            info.compressed as f64 / size as f64
+        ),
+        None => println!("Uncompressed: unknown (not recorded in the frame headers)"),
+    }
+
+    if info.truncated {
+        println!("Warning: Archive is truncated, its last frame ends past the end of the file");
+    }
+}
+
+/// Walk the frames of a zstd stream by their headers and block headers,
+/// seeking over the compressed data.
+fn scan_frames<R: Read + Seek>(mut reader: R, len: u64) -> Result<FrameStats> {
+    let mut stats = FrameStats {
+        content_size: Some(0),
+        all_checksummed: true,
+        ..Default::default()
+    };
+
+    loop {
+        let magic = match read_u32(&mut reader) {
+            Ok(magic) => magic,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && stats.frames > 0 => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if magic & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+            let size = read_u32(&mut reader)? as i64;
+            stats.skippable += 1;
+            if magic == SEEK_TABLE_MAGIC && size >= 9 {
+                // The footer's last four bytes identify a seek table
+                reader.seek_relative(size - 4)?;
+                stats.seek_table |= read_u32(&mut reader)? == SEEK_TABLE_FOOTER_MAGIC;
+            } else {
+                reader.seek_relative(size)?;
+            }
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            bail!(
+                "Unexpected magic {:#010x} after {} frame(s)",
+                magic,
+                stats.frames
+            );
+        }
+
+        stats.frames += 1;
+        let descriptor = read_u8(&mut reader)?;
+        let fcs_flag = descriptor >> 6;
+        let single_segment = descriptor & 0x20 != 0;
+        let checksum = descriptor & 0x04 != 0;
+        let dict_id_len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+
+        let window = if single_segment {
+            None
+        } else {
+            let byte = read_u8(&mut reader)?;
+            let base = 1u64 << (10 + (byte >> 3));
+            Some(base + (base / 8) * (byte & 0x07) as u64)
+        };
+        let dict_id = read_le(&mut reader, dict_id_len)? as u32;
+        let fcs_len = match fcs_flag {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        let content_size = match fcs_len {
+            0 => None,
+            2 => Some(read_le(&mut reader, 2)? + 256),
+            n => Some(read_le(&mut reader, n)?),
+        };
+
+        // A single-segment frame's window is its content
+        stats.max_window = stats.max_window.max(window.or(content_size).unwrap_or(0));
+        stats.content_size = stats.content_size.zip(content_size).map(|(a, b)| a + b);
+        stats.all_checksummed &= checksum;
+        if dict_id != 0 && !stats.dict_ids.contains(&dict_id) {
+            stats.dict_ids.push(dict_id);
+        }
+
+        loop {
+            let header = match read_le(&mut reader, 3) {
+                Ok(header) => header,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    stats.truncated = true;
+                    return Ok(stats);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let last = header & 1 != 0;
+            let block_type = (header >> 1) & 0x03;
+            let size = (header >> 3) as i64;
+            match block_type {
+                // RLE blocks store one byte that is repeated `size` times
+                1 => reader.seek_relative(1)?,
+                3 => bail!("Reserved block type in frame {}", stats.frames),
+                _ => reader.seek_relative(size)?,
+            }
+            if last {
+                break;
+            }
+        }
+        if checksum {
+            reader.seek_relative(4)?;
+        }
+        // Seeking past the end succeeds; reading the next magic would not
+        if reader.stream_position()? > len {
+            stats.truncated = true;
+            break;
+        }
+    }
+    Ok(stats)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> std::io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    Ok(read_le(reader, 4)? as u32)
+}
+
+/// Little-endian integer of `len` (at most 8) bytes.
+fn read_le<R: Read>(reader: &mut R, len: usize) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf[..len])?;
+    Ok(u64::from_le_bytes(buf))
+}
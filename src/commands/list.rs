@@ -0,0 +1,192 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Read, StdoutLock, Write};
+use std::path::Path;
+
+use crate::codec;
+use crate::dialect::{self, EntryKind};
+use crate::manifest;
+use crate::seekable;
+use crate::utils::{PAX_CHANGED, is_stdio};
+
+/// Print every entry of an archive (type, size, path, link target) by
+/// streaming it through the decoder. Nothing is written to disk and file
+/// data is skipped, not buffered. Archives from `pack --seekable` are
+/// listed from their entry index without decoding anything.
+///
+/// Files that changed while `pack` read them are marked, except in a listing
+/// from the index. Streamed files are only flagged in the manifest at the
+/// end, and get a warning there.
+///
+/// With `json` the listing is one JSON object, written as the entries come:
+/// `indexed`, then `entries` with `kind`, `size`, `path`, `link` and
+/// `changed` each, then `total_entries` and `total_bytes`.
+pub fn execute(input: &Path, memory_limit: Option<u64>, json: bool) -> Result<()> {
+    let mut listing = Listing {
+        out: BufWriter::new(io::stdout().lock()),
+        json,
+        entries: 0,
+        bytes: 0,
+    };
+
+    let index = if is_stdio(input) {
+        None
+    } else {
+        seekable::read_index(input)?
+    };
+    if !listing.start(index.is_some())? {
+        return Ok(());
+    }
+    if let Some(index) = index {
+        for entry in &index.entries {
+            let link = entry.link.as_deref().map(Path::new);
+            if !listing.line(entry.kind, entry.size, Path::new(&entry.path), link, false)? {
+                return Ok(());
+            }
+        }
+        return listing.total();
+    }
+
+    let mut archive = tar::Archive::new(codec::open_archive(input, memory_limit)?);
+    let mut flagged = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = dialect::classify(&mut entry)?;
+        if kind == EntryKind::Metadata && dialect::is_manifest(&mut entry)? {
+            for path in manifest::changed_files(&mut entry)? {
+                if !flagged.contains(&path) {
+                    eprintln!("Warning: {} changed while it was packed", path.display());
+                }
+            }
+            continue;
+        }
+        let size = if kind == EntryKind::File {
+            entry.size()
+        } else {
+            0
+        };
+        let path = entry.path()?.into_owned();
+        let target = match kind {
+            EntryKind::Symlink | EntryKind::Hardlink => {
+                Some(entry.link_name()?.unwrap_or_default().into_owned())
+            }
+            _ => None,
+        };
+        let changed = kind == EntryKind::File && has_changed_flag(&mut entry)?;
+        if changed {
+            flagged.insert(path.clone());
+        }
+        if !listing.line(kind, size, &path, target.as_deref(), changed)? {
+            return Ok(());
+        }
+    }
+    listing.total()
+}
+
+/// Whether `pack` flagged the entry as changed while it was read.
+fn has_changed_flag<R: Read>(entry: &mut tar::Entry<R>) -> Result<bool> {
+    for extension in entry.pax_extensions()?.into_iter().flatten() {
+        if extension?.key_bytes() == PAX_CHANGED.as_bytes() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// An entry as `list --json` prints it
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    kind: &'static str,
+    size: u64,
+    path: std::borrow::Cow<'a, str>,
+    link: Option<std::borrow::Cow<'a, str>>,
+    changed: bool,
+}
+
+struct Listing<'a> {
+    out: BufWriter<StdoutLock<'a>>,
+    json: bool,
+    entries: u64,
+    bytes: u64,
+}
+
+impl Listing<'_> {
+    /// Open the JSON object; `false` once the reader went away.
+    fn start(&mut self, indexed: bool) -> Result<bool> {
+        if !self.json {
+            return Ok(true);
+        }
+        self.write(format!("{{\"indexed\":{},\"entries\":[", indexed))
+    }
+
+    /// Print one entry; `false` once the reader went away.
+    fn line(
+        &mut self,
+        kind: EntryKind,
+        size: u64,
+        path: &Path,
+        target: Option<&Path>,
+        changed: bool,
+    ) -> Result<bool> {
+        let label = match kind {
+            EntryKind::Metadata => return Ok(true),
+            EntryKind::Directory => "dir",
+            EntryKind::File => "file",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Hardlink => "hardlink",
+            EntryKind::Unsupported(_) => "special",
+        };
+        self.entries += 1;
+        self.bytes += size;
+
+        if self.json {
+            let entry = JsonEntry {
+                kind: label,
+                size,
+                path: path.to_string_lossy(),
+                link: target.map(Path::to_string_lossy),
+                changed,
+            };
+            let separator = if self.entries > 1 { "," } else { "" };
+            return self.write(format!("{}\n{}", separator, serde_json::to_string(&entry)?));
+        }
+        let target = target.unwrap_or(Path::new("")).display();
+        let line = match kind {
+            EntryKind::Symlink => format!("{} -> {}", path.display(), target),
+            EntryKind::Hardlink => format!("{} link to {}", path.display(), target),
+            EntryKind::Unsupported(what) => format!("{} ({})", path.display(), what),
+            _ if changed => format!("{} (changed while packed)", path.display()),
+            _ => path.display().to_string(),
+        };
+        self.write(format!("{:<8} {:>14}  {}\n", label, size, line))
+    }
+
+    /// `false` once the reader went away.
+    fn write(&mut self, text: String) -> Result<bool> {
+        // `zstar list x | head` closes the pipe early; that isn't an error
+        match self.out.write_all(text.as_bytes()) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(false),
+            result => Ok(result.map(|()| true)?),
+        }
+    }
+
+    fn total(mut self) -> Result<()> {
+        let total = if self.json {
+            format!(
+                "\n],\"total_entries\":{},\"total_bytes\":{}}}",
+                self.entries, self.bytes
+            )
+        } else {
+            format!(
+                "{} entries, {}",
+                self.entries,
+                indicatif::HumanBytes(self.bytes)
+            )
+        };
+        match writeln!(self.out, "{}", total).and_then(|()| self.out.flush()) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
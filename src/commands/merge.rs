@@ -0,0 +1,252 @@
+use anyhow::{Context, Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::cli::MergePolicy;
+use crate::codec;
+use crate::dialect;
+use crate::i18n::t;
+use crate::oci::{self, Whiteout};
+
+pub struct MergeOptions {
+    pub policy: MergePolicy,
+    /// Drop what whiteout entries delete from the archives before theirs
+    pub apply_whiteouts: bool,
+    pub level: i32,
+    pub threads: u32,
+}
+
+/// Which copy of a path ends up in the merged archive.
+struct Winner {
+    archive: usize,
+    /// Position of the entry within its archive
+    index: u64,
+    mtime: u64,
+    is_dir: bool,
+    /// For a hardlink, the position of the entry holding its data
+    link_target: Option<u64>,
+}
+
+/// PAX records the builder writes itself from the header it is given.
+fn is_regenerated(key: &str) -> bool {
+    matches!(key, "path" | "linkpath" | "size")
+}
+
+/// Combine several archives into one. Every path is written once; when it
+/// occurs in more than one input the policy decides which copy is kept.
+/// Entries are streamed from archive to archive, nothing is extracted.
+pub fn execute(inputs: &[PathBuf], output: &Path, options: MergeOptions) -> Result<()> {
+    if inputs.is_empty() {
+        bail!("No input archives given");
+    }
+
+    // Pass 1: pick a winner for every path from the headers alone
+    let mut winners: HashMap<PathBuf, Winner> = HashMap::new();
+    let mut whiteouts = 0;
+    for (archive, input) in inputs.iter().enumerate() {
+        let mut tar = open_archive(input)?;
+        // Applied once the whole archive is read, so they only hit earlier archives
+        let (mut deleted, mut emptied) = (HashSet::new(), HashSet::new());
+        // Latest entry holding data under each path, which a hardlink refers to
+        let mut data_entries: HashMap<PathBuf, u64> = HashMap::new();
+        for (index, entry) in tar.entries()?.enumerate() {
+            let mut entry = entry.with_context(|| format!("Failed to read {:?}", input))?;
+            // Its hashes describe the input; files from other archives would mismatch
+            if dialect::is_manifest(&mut entry)? {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            if options.apply_whiteouts
+                && entry.header().entry_type().is_file()
+                && let Some(whiteout) = oci::whiteout(&path)
+            {
+                match whiteout {
+                    Whiteout::Path(hidden) => deleted.insert(hidden),
+                    Whiteout::Opaque(dir) => emptied.insert(dir.to_path_buf()),
+                };
+                whiteouts += 1;
+                continue;
+            }
+            let link_target = if entry.header().entry_type().is_hard_link() {
+                // A link to a link stands for the entry that one points at
+                entry
+                    .link_name()?
+                    .and_then(|target| data_entries.get(target.as_ref()).copied())
+            } else {
+                None
+            };
+            data_entries.insert(path.clone(), link_target.unwrap_or(index as u64));
+            let candidate = Winner {
+                archive,
+                index: index as u64,
+                mtime: entry.header().mtime()?,
+                is_dir: entry.header().entry_type().is_dir(),
+                link_target,
+            };
+            match winners.get(&path) {
+                None => {}
+                // Within one archive the later copy wins, as on extraction
+                Some(existing) if existing.archive == archive => {}
+                Some(existing) => {
+                    let replace = match options.policy {
+                        MergePolicy::First => false,
+                        MergePolicy::Last => true,
+                        // Ties go to the later archive, which is normally the newer backup
+                        MergePolicy::Newest => candidate.mtime >= existing.mtime,
+                        // Directories overlap whenever archives share a root
+                        MergePolicy::Error if existing.is_dir && candidate.is_dir => false,
+                        MergePolicy::Error => bail!(
+                            "{:?} exists in both {:?} and {:?}",
+                            path,
+                            inputs[existing.archive],
+                            input
+                        ),
+                    };
+                    if !replace {
+                        continue;
+                    }
+                }
+            }
+            winners.insert(path, candidate);
+        }
+        if !deleted.is_empty() || !emptied.is_empty() {
+            winners.retain(|path, winner| {
+                winner.archive == archive
+                    || !(path.ancestors().any(|p| deleted.contains(p))
+                        || path.ancestors().skip(1).any(|p| emptied.contains(p)))
+            });
+        }
+    }
+
+    // Hardlinks are written right after the entry they were linked to. If
+    // that entry lost its own path to another archive's copy, the first link
+    // takes its data instead and the others point at that one.
+    let mut keep: HashSet<(usize, u64)> = HashSet::new();
+    let mut relinked: HashMap<(usize, u64), Vec<PathBuf>> = HashMap::new();
+    for (path, winner) in &winners {
+        match winner.link_target {
+            Some(index) => relinked
+                .entry((winner.archive, index))
+                .or_default()
+                .push(path.clone()),
+            None => {
+                keep.insert((winner.archive, winner.index));
+            }
+        }
+    }
+
+    // Pass 2: copy the winning entries in input order
+    let _lock = crate::utils::lock_output(output)?;
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Failed to create output file {:?}", output))?;
+    let mut encoder = zstd::Encoder::new(file, options.level)?;
+    encoder.multithread(options.threads)?;
+    let mut builder = tar::Builder::new(encoder);
+
+    let mut written = 0;
+    for (archive, input) in inputs.iter().enumerate() {
+        let mut tar = open_archive(input)?;
+        for (index, entry) in tar.entries()?.enumerate() {
+            let mut entry = entry.with_context(|| format!("Failed to read {:?}", input))?;
+            let position = (archive, index as u64);
+            let mut links = relinked.remove(&position).unwrap_or_default();
+            links.sort();
+            let path = if keep.contains(&position) {
+                entry.path()?.into_owned()
+            } else if !links.is_empty() {
+                links.remove(0)
+            } else {
+                continue;
+            };
+            copy_entry(&mut builder, &mut entry, &path, &links)
+                .with_context(|| format!("Failed to copy entry from {:?}", input))?;
+            written += 1 + links.len();
+        }
+    }
+
+    let encoder = builder
+        .into_inner()
+        .context("Failed to finish writing archive")?;
+    encoder
+        .finish()
+        .context("Failed to finish writing archive")?;
+
+    if whiteouts > 0 {
+        eprintln!("Applied {} whiteouts", whiteouts);
+    }
+    println!(
+        "{}",
+        t!(
+            "merged",
+            archives = inputs.len(),
+            output = format!("{:?}", output),
+            entries = written
+        )
+    );
+    Ok(())
+}
+
+fn open_archive(path: &Path) -> Result<tar::Archive<Box<dyn Read + Send>>> {
+    Ok(tar::Archive::new(codec::open_archive(path, None)?))
+}
+
+/// Append `entry` as `path` with its original header and PAX records,
+/// keeping long names and link targets intact, then hardlink `links` to it.
+fn copy_entry<W: std::io::Write, R: Read>(
+    builder: &mut tar::Builder<W>,
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    links: &[PathBuf],
+) -> Result<()> {
+    let mut records = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for ext in extensions {
+            let ext = ext?;
+            if let Ok(key) = ext.key()
+                && !is_regenerated(key)
+            {
+                records.push((key.to_string(), ext.value_bytes().to_vec()));
+            }
+        }
+    }
+    let append_records = |builder: &mut tar::Builder<W>| -> Result<()> {
+        if !records.is_empty() {
+            builder.append_pax_extensions(
+                records
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_slice())),
+            )?;
+        }
+        Ok(())
+    };
+
+    append_records(builder)?;
+    let mut header = entry.header().clone();
+    match header.entry_type() {
+        tar::EntryType::Link | tar::EntryType::Symlink => {
+            let target = entry
+                .link_name()?
+                .context("Link entry without a target")?
+                .into_owned();
+            builder.append_link(&mut header, path, &target)?;
+        }
+        _ => {
+            // Past 8GB the size may only be in the dropped PAX record, and
+            // sparse files are read back expanded
+            header.set_size(entry.size());
+            if header.entry_type() == tar::EntryType::GNUSparse {
+                header.set_entry_type(tar::EntryType::Regular);
+            }
+            builder.append_data(&mut header, path, entry)?
+        }
+    }
+
+    for link in links {
+        append_records(builder)?;
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, link, path)?;
+    }
+    Ok(())
+}
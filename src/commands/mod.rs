@@ -1,3 +1,16 @@
+pub mod catalog;
+pub mod check_excludes;
+pub mod compare;
 pub mod compio_reader;
+pub mod fixture;
+pub mod info;
+pub mod list;
+pub mod merge;
 pub mod pack;
+pub mod repo;
+pub mod restore;
+pub mod rotate;
+pub mod salvage;
+pub mod scrub;
+pub mod threaded_reader;
 pub mod unpack;
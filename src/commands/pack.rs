@@ -1,59 +1,983 @@
 use anyhow::{Context, Result};
-use crossbeam_channel::{bounded, unbounded};
+use crossbeam_channel::unbounded;
 use dashmap::DashMap;
 use indicatif::{ProgressBar, ProgressStyle};
 use jwalk::WalkDir;
-use std::fs::File;
-use std::io::Read;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use crate::utils::{FileId, FileMetadata};
+use crate::cli::{Compression, HashAlgorithm, IfChanged, IoBackend, PackFormat};
+use crate::dialect::EntryKind;
+use crate::encryption::Encryptor;
+use crate::i18n::t;
+use crate::manifest::Manifest;
+use crate::oci;
+use crate::profiling::{PipelineProfile, Stage, TimedWriter};
+use crate::seekable::{IndexEntry, SeekableWriter, index_path};
+use crate::skips::SkipLog;
+use crate::status::{DirListing, Progress, ScanReport, StatusReporter};
+use crate::tuning;
+use crate::utils::{
+    CAPABILITY_XATTR, FileId, FileMetadata, MAC_XATTR_PREFIX, PAX_CHANGED, PAX_XATTR_PREFIX,
+    Rooting, SELINUX_XATTR, build_excludes, get_file_id, get_file_metadata, has_other_links,
+    is_excluded, is_included, is_stdio, read_xattrs, relativize,
+};
+use crate::watchdog::{Heartbeat, WATCHDOG_POLL, Watchdog};
+use crate::zip::ZipWriter;
 
 // ============== Constants ==============
 /// Chunk size for large file streaming (4MB)
 pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
 
-/// Files larger than this threshold are streamed in chunks (128MB)
+/// Files larger than this threshold are streamed in chunks (128MB). A
+/// `--memory-budget` lowers the threshold, never raises it
 pub const MEMORY_FILE_THRESHOLD: u64 = 128 * 1024 * 1024;
 
+/// With `--mmap`, files from this size up to the streaming threshold are
+/// memory-mapped instead of copied into a pooled buffer (1MB)
+pub const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
 /// Channel capacity for scanner -> reader (path distribution)
 pub const PATH_CHANNEL_CAPACITY: usize = 1000;
 
 /// Channel capacity for reader -> writer (metadata and small files)
 pub const CONTENT_CHANNEL_CAPACITY: usize = 100;
 
-/// Channel capacity for large file chunks (dedicated)
-pub const CHUNK_CHANNEL_CAPACITY: usize = 100;
+/// Chunks buffered per large file stream (8 x 4MB)
+pub const LARGE_FILE_STREAM_CAPACITY: usize = 8;
+
+/// Compressed output handed to the output thread at a time (8MB)
+pub const OUTPUT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Full output buffers queued for the output thread while the next one fills
+pub const OUTPUT_BUFFERS_IN_FLIGHT: usize = 2;
+
+/// The compression ratio is measured over the second slice of this much
+/// input, once the compressor's internal buffers are full (1GB)
+const RATIO_CHECK_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Output/input above this means the data is not compressing
+const INCOMPRESSIBLE_RATIO: f64 = 0.99;
+
+/// Levels slow enough that incompressible input is worth a warning
+const RATIO_WARNING_LEVEL: i32 = 10;
 
 pub enum TarEntry {
     SmallFile(PathBuf, Vec<u8>, FileMetadata),
-    LargeFileStart(PathBuf, u64 /* total_size */, FileMetadata),
-    LargeFileChunk(Vec<u8>),
-    LargeFileEnd,
+    /// A medium file the writer appends straight from the page cache
+    MappedFile(PathBuf, memmap2::Mmap, FileMetadata),
+    /// A file too large to buffer. Its data follows on a dedicated chunk
+    /// stream; the sender is dropped once all chunks were sent. The header
+    /// is out before the file is read through, so whether it changed
+    /// meanwhile is only set before the last chunk.
+    LargeFile(
+        PathBuf,
+        u64, /* total_size */
+        FileMetadata,
+        flume::Receiver<Result<Vec<u8>>>,
+        Arc<AtomicBool>, /* changed */
+    ),
     Symlink(PathBuf, PathBuf, FileMetadata),
-    HardLink(PathBuf, PathBuf),
+    HardLink(PathBuf, PathBuf, FileMetadata),
     Dir(PathBuf, FileMetadata),
 }
 
+impl TarEntry {
+    pub fn path(&self) -> &Path {
+        match self {
+            TarEntry::SmallFile(path, ..)
+            | TarEntry::MappedFile(path, ..)
+            | TarEntry::LargeFile(path, ..)
+            | TarEntry::Symlink(path, ..)
+            | TarEntry::HardLink(path, ..)
+            | TarEntry::Dir(path, _) => path,
+        }
+    }
+
+    /// Bytes of file content the entry carries.
+    pub fn content_len(&self) -> u64 {
+        match self {
+            TarEntry::SmallFile(_, buf, _) => buf.len() as u64,
+            TarEntry::MappedFile(_, map, _) => map.len() as u64,
+            TarEntry::LargeFile(_, len, ..) => *len,
+            TarEntry::Symlink(..) | TarEntry::HardLink(..) | TarEntry::Dir(..) => 0,
+        }
+    }
+
+    /// The entry as `--seekable` indexes it, starting at `offset` in the tar stream.
+    fn index_entry(&self, offset: u64) -> IndexEntry {
+        let (kind, link) = match self {
+            TarEntry::Dir(..) => (EntryKind::Directory, None),
+            TarEntry::Symlink(_, target, _) => (EntryKind::Symlink, Some(target)),
+            TarEntry::HardLink(_, target, _) => (EntryKind::Hardlink, Some(target)),
+            _ => (EntryKind::File, None),
+        };
+        IndexEntry {
+            kind,
+            size: self.content_len(),
+            path: index_path(self.path()),
+            link: link.map(|target| index_path(target)),
+            offset,
+        }
+    }
+}
+
 pub struct PackOptions {
     pub level: i32,
     pub threads: u32,
+    /// Number of concurrent file readers
+    pub readers: usize,
     pub long_distance: bool,
     pub ignore_errors: bool,
+    /// Record archived paths and a BLAKE3 digest of the output (for the catalog)
+    pub collect_catalog: bool,
+    /// Also hash the output with this algorithm for `PackSummary::digest`
+    pub hash_output: Option<HashAlgorithm>,
+    pub format: PackFormat,
+    /// Store macOS `com.apple.*` xattrs as PAX records
+    pub mac_metadata: bool,
+    /// Store Linux file capabilities as PAX records
+    pub capabilities: bool,
+    /// Store SELinux security contexts as PAX records
+    pub selinux: bool,
+    pub compression: Compression,
+    /// Write independent zstd frames of this many bytes of tar stream each,
+    /// plus an entry index and seek table (`--seekable`)
+    pub seekable: Option<u64>,
+    /// Filter program (split on whitespace) that compresses the tar stream instead of zstd
+    pub external_compressor: Option<String>,
+    /// Abort when nothing has moved for this long
+    pub stall_timeout: Option<Duration>,
+    /// Print how long each pipeline stage took
+    pub profile_pipeline: bool,
+    /// Store hardlinked files as independent regular files
+    pub hard_dereference: bool,
+    /// Check size and mtime before storing a file as a link to an earlier one
+    pub paranoid_links: bool,
+    pub rooting: Rooting,
+    pub io_backend: IoBackend,
+    /// Read large files around the page cache (threaded backend only)
+    pub direct_io: bool,
+    /// Memory-map medium files instead of copying them
+    pub mmap: bool,
+    /// Open source files with `O_NOATIME` where the kernel allows it (Linux)
+    pub preserve_atime: bool,
+    /// Files from this size up are streamed in chunks instead of buffered whole
+    pub stream_threshold: u64,
+    /// Open files with `FILE_FLAG_BACKUP_SEMANTICS` so SeBackupPrivilege
+    /// bypasses their ACLs (Windows, threaded backend only)
+    pub backup_semantics: bool,
+    /// Leave out paths matching these patterns (see `utils::build_excludes`)
+    pub exclude: Vec<String>,
+    /// Only store files and symlinks matching these patterns, plus their parents
+    pub include: Vec<String>,
+    pub filter: EntryFilter,
+    /// Periodically write progress as JSON to this file
+    pub status_file: Option<PathBuf>,
+    /// Inherited pipe for binary progress frames (see `status::pipe`)
+    pub progress_fd: Option<u64>,
+    /// Estimated entry count, for a percentage and ETA in the status
+    pub expected_entries: Option<u64>,
+    /// Stay quiet when a high level is spent on incompressible data
+    pub no_ratio_warning: bool,
+    /// Write to `<output>.partial` and rename it into place once complete
+    pub partial: bool,
+    /// Write every path skipped as unreadable to this file
+    pub skip_log: Option<PathBuf>,
+    /// Encrypt the archive with a key derived from this passphrase
+    pub encrypt: Option<Vec<u8>>,
+    /// Try failed reads this many more times before giving up on a file
+    pub read_retries: u32,
+    /// Wait before the first retry, doubled for every further one
+    pub retry_backoff: Duration,
+    pub if_changed: IfChanged,
+    /// End the tar stream with a BLAKE3 manifest of the files' contents
+    pub manifest: bool,
+}
+
+/// Options consumed by the reader workers.
+#[derive(Clone, Copy)]
+pub struct ReadOptions {
+    /// Number of concurrent reader tasks
+    pub workers: usize,
+    pub ignore_errors: bool,
+    pub mac_metadata: bool,
+    pub capabilities: bool,
+    pub selinux: bool,
+    pub hard_dereference: bool,
+    pub paranoid_links: bool,
+    pub rooting: Rooting,
+    pub direct_io: bool,
+    pub mmap: bool,
+    pub preserve_atime: bool,
+    pub stream_threshold: u64,
+    pub backup_semantics: bool,
+    /// Turn overlayfs whiteouts into OCI whiteout files
+    pub oci_whiteouts: bool,
+    pub read_retries: u32,
+    pub retry_backoff: Duration,
+    pub if_changed: IfChanged,
+}
+
+/// First path seen for an inode; later paths with the same `FileId` become links to it.
+#[derive(Clone)]
+pub struct LinkTarget {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// What a reader still has to do for one path, decided from its metadata.
+pub enum Prepared {
+    /// Complete entry (directory, symlink or hardlink), nothing left to read
+    Entry(TarEntry),
+    /// Regular file whose contents the reader has to supply
+    File(PathBuf, Stamp, FileMetadata),
+}
+
+/// Size and modification time of a source file, compared before and after
+/// reading it to notice writes in between (`--if-changed`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    pub fn of(meta: &fs::Metadata) -> Self {
+        Stamp {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+/// Times a file that keeps changing is read again under `--if-changed retry`
+const CHANGED_RETRIES: u32 = 3;
+
+/// Compare `path` with `stamp` from before it was read (`--if-changed`).
+/// `true` means read it again; `stamp` and `metadata` then describe the
+/// file as it is now. A changed file stored as read is flagged in
+/// `metadata`, one that mustn't be stored is an error. `reads` counts the
+/// reads so far, or is `None` for a large file, whose header is already
+/// out and which can't be read again.
+pub fn recheck(
+    path: &Path,
+    stamp: &mut Stamp,
+    metadata: &mut FileMetadata,
+    reads: Option<u32>,
+    options: ReadOptions,
+    skips: &SkipLog,
+) -> Result<bool> {
+    let meta = fs::metadata(path)?;
+    if Stamp::of(&meta) == *stamp {
+        return Ok(false);
+    }
+    match options.if_changed {
+        IfChanged::Error => anyhow::bail!("File changed while being read"),
+        IfChanged::Retry if reads.is_some_and(|reads| reads <= CHANGED_RETRIES) => {
+            skips.changed(path, false);
+            *stamp = Stamp::of(&meta);
+            metadata.mtime = get_file_metadata(path, &meta).mtime;
+            Ok(true)
+        }
+        _ => {
+            skips.changed(path, true);
+            metadata.changed = true;
+            Ok(false)
+        }
+    }
+}
+
+/// Stat `path` and resolve symlinks and hardlinks. Shared by the reader
+/// backends, which only differ in how they read file contents. Returns
+/// `None` for an unreadable file skipped under `ignore_errors`.
+pub fn prepare_path(
+    path: &Path,
+    base_path: &Path,
+    inode_cache: &DashMap<FileId, LinkTarget>,
+    skips: &SkipLog,
+    options: ReadOptions,
+) -> Result<Option<Prepared>> {
+    let relative_path = relativize(path, base_path, options.rooting);
+
+    let meta = match with_retries(path, options, skips, || std::fs::symlink_metadata(path)) {
+        Ok(m) => m,
+        Err(e) if options.ignore_errors => {
+            skips.skip("Skipping unreadable file", path, &e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut metadata = get_file_metadata(path, &meta);
+    let file_type = meta.file_type();
+    // The writer turns the opaque marker into a whiteout entry
+    let opaque = options.oci_whiteouts && file_type.is_dir();
+    if options.mac_metadata || options.capabilities || options.selinux || opaque {
+        metadata.xattrs = read_xattrs(path, |name| {
+            (options.mac_metadata && name.starts_with(MAC_XATTR_PREFIX))
+                || (options.capabilities && name == CAPABILITY_XATTR)
+                || (options.selinux && name == SELINUX_XATTR)
+                || (opaque && oci::OPAQUE_XATTRS.contains(&name))
+        });
+    }
+
+    if options.oci_whiteouts && oci::is_whiteout(&meta) {
+        metadata.mode &= 0o777;
+        return Ok(Some(Prepared::Entry(TarEntry::SmallFile(
+            oci::whiteout_path(&relative_path),
+            Vec::new(),
+            metadata,
+        ))));
+    }
+    if file_type.is_dir() {
+        return Ok(Some(Prepared::Entry(TarEntry::Dir(
+            relative_path,
+            metadata,
+        ))));
+    }
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        return Ok(Some(Prepared::Entry(TarEntry::Symlink(
+            relative_path,
+            target,
+            metadata,
+        ))));
+    }
+
+    // Check Hardlinks (unless every link should be stored as a regular file)
+    if !options.hard_dereference
+        && has_other_links(&meta)
+        && let Some(fid) = get_file_id(path, &meta)
+    {
+        let existing = inode_cache.get(&fid).map(|e| e.value().clone());
+        let verified = match &existing {
+            // Same identity but different content: an id collision, not a link
+            Some(target)
+                if options.paranoid_links
+                    && (target.size != meta.len() || target.mtime != metadata.mtime) =>
+            {
+                eprintln!(
+                    "Warning: {:?} shares its file id with {:?} but differs, storing a copy",
+                    relative_path, target.path
+                );
+                None
+            }
+            other => other.clone(),
+        };
+        if let Some(LinkTarget { path: target, .. }) = verified {
+            return Ok(Some(Prepared::Entry(TarEntry::HardLink(
+                relative_path,
+                target,
+                metadata,
+            ))));
+        } else if existing.is_none() {
+            inode_cache.insert(
+                fid,
+                LinkTarget {
+                    path: relative_path.clone(),
+                    size: meta.len(),
+                    mtime: metadata.mtime,
+                },
+            );
+        }
+    }
+
+    Ok(Some(Prepared::File(
+        relative_path,
+        Stamp::of(&meta),
+        metadata,
+    )))
+}
+
+/// Whether a file of `len` bytes should be memory-mapped.
+pub fn use_mmap(len: u64, options: ReadOptions) -> bool {
+    options.mmap && (MMAP_THRESHOLD..options.stream_threshold).contains(&len)
+}
+
+/// Size from which files are streamed, so that whole files buffered by
+/// the readers and queued for the writer stay within `budget` bytes.
+/// Streams add at most `LARGE_FILE_STREAM_CAPACITY` chunks per reader.
+pub fn stream_threshold(budget: Option<u64>, readers: usize) -> u64 {
+    match budget {
+        Some(budget) => (budget / (CONTENT_CHANNEL_CAPACITY + readers) as u64)
+            .clamp(MMAP_THRESHOLD, MEMORY_FILE_THRESHOLD),
+        None => MEMORY_FILE_THRESHOLD,
+    }
+}
+
+/// Open a source file for reading.
+pub fn open_source(path: &Path, options: ReadOptions) -> std::io::Result<File> {
+    #[cfg(windows)]
+    if options.backup_semantics {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_BACKUP_SEMANTICS;
+        return fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(path);
+    }
+    // Refused up front by `platform::enable_backup_privileges`
+    #[cfg(not(windows))]
+    debug_assert!(!options.backup_semantics);
+    #[cfg(target_os = "linux")]
+    {
+        open_with_flags(path, 0, options)
+    }
+    #[cfg(not(target_os = "linux"))]
+    File::open(path)
+}
+
+/// Open `path` for reading with the extra open(2) `flags`, plus `O_NOATIME`
+/// unless `--no-preserve-source-atime`. Only the file's owner (or root) may
+/// ask for that; other files are opened as usual and get their atime bumped.
+#[cfg(target_os = "linux")]
+pub fn open_with_flags(path: &Path, flags: i32, options: ReadOptions) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let open = |flags| {
+        fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(flags)
+            .open(path)
+    };
+    if options.preserve_atime {
+        match open(flags | libc::O_NOATIME) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    open(flags)
 }
 
-// ChannelReader for streaming large files from dedicated chunk channel
+/// Map `path` for reading.
+pub fn map_file(path: &Path, options: ReadOptions) -> std::io::Result<memmap2::Mmap> {
+    let file = open_source(path, options)?;
+    // SAFETY: the map is only read. A file truncated by another process
+    // while it is mapped raises SIGBUS, which is why mapping takes `--mmap`.
+    unsafe { memmap2::Mmap::map(&file) }
+}
+
+/// Longest wait between two tries of a read (`--retry-backoff`)
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait before trying a read that failed with `e` again, or
+/// `None` to give up: once `options.read_retries` retries are used up, or
+/// for errors a retry won't fix. The wait doubles with every retry, up to
+/// `MAX_RETRY_DELAY`.
+pub fn retry_delay(e: &std::io::Error, attempt: u32, options: ReadOptions) -> Option<Duration> {
+    use std::io::ErrorKind;
+    let permanent = matches!(
+        e.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::InvalidInput
+            | ErrorKind::IsADirectory
+            | ErrorKind::Unsupported
+    );
+    if permanent || attempt >= options.read_retries {
+        return None;
+    }
+    let delay = options.retry_backoff.saturating_mul(1 << attempt.min(16));
+    Some(delay.min(MAX_RETRY_DELAY.max(options.retry_backoff)))
+}
+
+/// Run `read`, trying it again after transient errors (`--read-retries`).
+pub fn with_retries<T>(
+    path: &Path,
+    options: ReadOptions,
+    skips: &SkipLog,
+    mut read: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match read() {
+            Err(e) => match retry_delay(&e, attempt, options) {
+                Some(delay) => {
+                    skips.retry(path, &e, attempt, delay);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+/// A buffer of exactly `len` bytes, recycled from the writer when one is available.
+pub fn pooled_buffer(pool_rx: &crossbeam_channel::Receiver<Vec<u8>>, len: usize) -> Vec<u8> {
+    let mut buf = pool_rx
+        .try_recv()
+        .unwrap_or_else(|_| Vec::with_capacity(len));
+    if buf.capacity() < len {
+        buf.reserve(len - buf.capacity());
+    }
+    if buf.len() < len {
+        buf.resize(len, 0);
+    }
+    buf
+}
+
+/// Directories Windows keeps on every NTFS and FAT volume, which even
+/// administrators can't read (`--no-skip-windows-system-dirs` stores them)
+const WINDOWS_SYSTEM_DIRS: [&str; 2] = ["$Recycle.Bin", "System Volume Information"];
+
+/// `--exclude-owner`, `--only-owner`, `--skip-unreadable`, `--skip-hidden` and
+/// the Windows system directories: decided from each entry's name and
+/// metadata while walking, so no reader ever opens a filtered file.
+#[derive(Default)]
+pub struct EntryFilter {
+    pub exclude_owners: Vec<u32>,
+    pub only_owners: Vec<u32>,
+    pub skip_unreadable: bool,
+    pub skip_hidden: bool,
+    pub skip_system_dirs: bool,
+}
+
+impl EntryFilter {
+    /// Whether the entry stays in the archive; unreadable ones are counted.
+    fn keep(&self, path: &Path, file_type: fs::FileType, unreadable: &AtomicU64) -> bool {
+        if self.skip_hidden && crate::platform::is_hidden(path) {
+            return false;
+        }
+        // Volumes mounted elsewhere have them too, and FAT spells the bin in capitals
+        if self.skip_system_dirs
+            && file_type.is_dir()
+            && path.file_name().is_some_and(|name| {
+                WINDOWS_SYSTEM_DIRS
+                    .iter()
+                    .any(|dir| name.eq_ignore_ascii_case(dir))
+            })
+        {
+            return false;
+        }
+        // Reading a symlink needs no permission on the link itself
+        if self.skip_unreadable
+            && !file_type.is_symlink()
+            && !crate::platform::is_readable(path, file_type.is_dir())
+        {
+            unreadable.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if file_type.is_dir() || (self.exclude_owners.is_empty() && self.only_owners.is_empty()) {
+            return true;
+        }
+        match owner(path) {
+            Some(uid) => {
+                !self.exclude_owners.contains(&uid)
+                    && (self.only_owners.is_empty() || self.only_owners.contains(&uid))
+            }
+            // Gone since it was listed; the reader reports that
+            None => true,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn owner(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|meta| meta.uid())
+}
+
+/// Owner filters are refused on other platforms before packing starts
+#[cfg(not(unix))]
+fn owner(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// What ended up in the archive, returned to the caller once writing succeeded.
+#[derive(Default)]
+pub struct PackSummary {
+    pub entries: u64,
+    /// Entries stored as links to an earlier path with the same inode
+    pub hardlinks: u64,
+    /// Paths left out because they couldn't be walked or read (`--ignore-failed-read`)
+    pub skipped: u64,
+    /// Failed reads that were tried again (`--read-retries`)
+    pub retries: u64,
+    /// Files stored although they changed while being read (`--if-changed warn`)
+    pub changed: u64,
+    /// File contents before compression
+    pub bytes: u64,
+    /// Size of the archive
+    pub compressed: u64,
+    pub entry_paths: Vec<PathBuf>,
+    /// BLAKE3 of the archive, for the catalog
+    pub checksum: Option<String>,
+    /// Hex digest of the archive in the `--hash-output` algorithm
+    pub digest: Option<String>,
+    /// SHA-256 of the uncompressed tar stream, the layer's diff_id (`--format oci-layer`)
+    pub diff_id: Option<String>,
+}
+
+/// Output sink that counts and optionally hashes everything written to it.
+struct HashingWriter<W: Write> {
+    inner: W,
+    blake3: Option<blake3::Hasher>,
+    sha256: Option<Sha256>,
+    written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(hasher) = self.blake3.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        if let Some(hasher) = self.sha256.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Output of the compressor before it is hashed: as is, or encrypted
+/// (`--encrypt`), so the digests cover the file as written.
+enum Sealed<W: Write> {
+    Plain(W),
+    Encrypted(Encryptor<W>),
+}
+
+type Sink<W> = Sealed<HashingWriter<W>>;
+
+impl<W: Write> Sealed<W> {
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            Sealed::Plain(inner) => Ok(inner),
+            Sealed::Encrypted(encryptor) => encryptor.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Sealed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sealed::Plain(inner) => inner.write(buf),
+            Sealed::Encrypted(encryptor) => encryptor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sealed::Plain(inner) => inner.flush(),
+            Sealed::Encrypted(encryptor) => encryptor.flush(),
+        }
+    }
+}
+
+/// Hands compressed output to a thread of its own, so a slow destination
+/// (USB disk, NAS) stalls the compressor only once every buffer is queued.
+struct BackgroundWriter<W: Write + Send + 'static> {
+    buf: Vec<u8>,
+    tx: Option<crossbeam_channel::Sender<Vec<u8>>>,
+    recycled: crossbeam_channel::Receiver<Vec<u8>>,
+    handle: Option<thread::JoinHandle<std::io::Result<W>>>,
+}
+
+impl<W: Write + Send + 'static> BackgroundWriter<W> {
+    fn new(mut inner: W) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded::<Vec<u8>>(OUTPUT_BUFFERS_IN_FLIGHT);
+        let (recycle_tx, recycled) = unbounded();
+        let handle = thread::spawn(move || {
+            for mut buf in rx {
+                inner.write_all(&buf)?;
+                buf.clear();
+                let _ = recycle_tx.send(buf);
+            }
+            inner.flush()?;
+            Ok(inner)
+        });
+        BackgroundWriter {
+            buf: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+            tx: Some(tx),
+            recycled,
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue the current buffer, surfacing the output thread's error if it stopped.
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let next = self
+            .recycled
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(OUTPUT_BUFFER_SIZE));
+        let full = std::mem::replace(&mut self.buf, next);
+        let sent = self.tx.as_ref().map(|tx| tx.send(full));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            _ => Err(self
+                .join()
+                .err()
+                .unwrap_or_else(|| std::io::Error::other("Output thread stopped"))),
+        }
+    }
+
+    fn join(&mut self) -> std::io::Result<W> {
+        self.tx = None;
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| std::io::Error::other("Output thread panicked"))?,
+            None => Err(std::io::Error::other("Output thread already finished")),
+        }
+    }
+
+    /// Write out everything still buffered and return the inner writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        self.send_buffer()?;
+        self.join()
+    }
+}
+
+impl<W: Write + Send + 'static> Write for BackgroundWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= OUTPUT_BUFFER_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Only hands the buffer over; the data reaches the file asynchronously.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// Where the writer puts entries: a tar stream on its way to the compressor,
+/// or a ZIP archive, which deflates every entry itself.
+enum ArchiveWriter<W: Write + Send + 'static> {
+    Tar(Box<tar::Builder<TimedWriter<HashingWriter<Compressor<W>>>>>),
+    Zip(ZipWriter<BackgroundWriter<Sink<W>>>),
+}
+
+/// File name extension of archives written through the external compressor
+/// `command`, if its program is one whose format is known.
+pub fn external_extension(command: &str) -> Result<Option<&'static str>> {
+    let words = crate::utils::split_command(command)?;
+    let program = words
+        .first()
+        .and_then(|program| Path::new(program).file_stem())
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase());
+    Ok(match program.as_deref() {
+        Some("zstd" | "zstdmt" | "pzstd") => Some("tar.zst"),
+        Some("gzip" | "pigz" | "igzip") => Some("tar.gz"),
+        Some("xz" | "pixz" | "pxz") => Some("tar.xz"),
+        Some("lz4") => Some("tar.lz4"),
+        Some("bzip2" | "pbzip2" | "lbzip2") => Some("tar.bz2"),
+        Some("lzip" | "plzip") => Some("tar.lz"),
+        Some("brotli") => Some("tar.br"),
+        _ => None,
+    })
+}
+
+/// The external compressor process, killed and reaped when dropped unless
+/// `Compressor::finish` already waited for it, which it doesn't when pack fails.
+struct ExternalChild(Child);
+
+impl Drop for ExternalChild {
+    fn drop(&mut self) {
+        // Both do nothing once the child was waited for
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Compression stage between the tar builder and the output.
+enum Compressor<W: Write + Send + 'static> {
+    /// Hashing and disk writes run on the output thread
+    Zstd(Box<zstd::Encoder<'static, BackgroundWriter<Sink<W>>>>),
+    Gzip(Box<flate2::write::GzEncoder<BackgroundWriter<Sink<W>>>>),
+    Xz(Box<xz2::write::XzEncoder<BackgroundWriter<Sink<W>>>>),
+    Lz4(Box<lz4_flex::frame::FrameEncoder<BackgroundWriter<Sink<W>>>>),
+    /// zstd in independent frames, closed with the entry index and seek table
+    Seekable(Box<SeekableWriter<BackgroundWriter<Sink<W>>>>),
+    /// The tar stream is piped into an external program whose stdout is
+    /// copied to the output by a helper thread.
+    External {
+        program: String,
+        child: ExternalChild,
+        stdin: ChildStdin,
+        copier: thread::JoinHandle<std::io::Result<Sink<W>>>,
+    },
+}
+
+impl<W: Write + Send + 'static> Compressor<W> {
+    fn new(compression: Compression, sink: Sink<W>, options: &PackOptions) -> Result<Self> {
+        let output = BackgroundWriter::new(sink);
+        // zstd levels go up to 22; the others stop at 9
+        let level = options.level.clamp(0, 9) as u32;
+        // Only zstd has a seekable format; main checks --compression
+        if let Some(frame_size) = options.seekable {
+            return Ok(Compressor::Seekable(Box::new(SeekableWriter::new(
+                output,
+                options.level,
+                options.threads,
+                options.long_distance,
+                frame_size,
+            ))));
+        }
+        Ok(match compression {
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, options.level)?;
+                encoder.multithread(options.threads)?;
+                let _ = encoder.long_distance_matching(options.long_distance);
+                Compressor::Zstd(Box::new(encoder))
+            }
+            Compression::Gzip => Compressor::Gzip(Box::new(flate2::write::GzEncoder::new(
+                output,
+                flate2::Compression::new(level),
+            ))),
+            Compression::Xz => Compressor::Xz(Box::new(xz2::write::XzEncoder::new(output, level))),
+            Compression::Lz4 => {
+                Compressor::Lz4(Box::new(lz4_flex::frame::FrameEncoder::new(output)))
+            }
+        })
+    }
+
+    fn external(command: &str, mut sink: Sink<W>) -> Result<Self> {
+        let words = crate::utils::split_command(command)?;
+        let (program, args) = words
+            .split_first()
+            .context("External compressor command is empty")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start external compressor {:?}", program))?;
+        let stdin = child
+            .stdin
+            .take()
+            .context("Failed to open compressor stdin")?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .context("Failed to open compressor stdout")?;
+        let copier = thread::spawn(move || {
+            std::io::copy(&mut stdout, &mut sink)?;
+            sink.flush()?;
+            Ok(sink)
+        });
+        Ok(Compressor::External {
+            program: program.to_string(),
+            child: ExternalChild(child),
+            stdin,
+            copier,
+        })
+    }
+
+    fn finish(self) -> Result<Sink<W>> {
+        match self {
+            Compressor::Zstd(encoder) => Ok((*encoder).finish()?.finish()?),
+            Compressor::Gzip(encoder) => Ok((*encoder).finish()?.finish()?),
+            Compressor::Xz(encoder) => Ok((*encoder).finish()?.finish()?),
+            Compressor::Lz4(encoder) => Ok((*encoder).finish()?.finish()?),
+            Compressor::Seekable(writer) => Ok((*writer).finish()?.finish()?),
+            Compressor::External {
+                program,
+                mut child,
+                stdin,
+                copier,
+            } => {
+                drop(stdin); // EOF lets the filter flush and exit
+                let sink = copier
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Compressor output thread panicked"))?
+                    .context("Failed to write compressor output")?;
+                let status = child.0.wait()?;
+                if !status.success() {
+                    anyhow::bail!("External compressor {:?} failed: {}", program, status);
+                }
+                Ok(sink)
+            }
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.write(buf),
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::Xz(encoder) => encoder.write(buf),
+            Compressor::Lz4(encoder) => encoder.write(buf),
+            Compressor::Seekable(writer) => writer.write(buf),
+            Compressor::External { stdin, .. } => stdin.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Compressor::Zstd(encoder) => encoder.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Xz(encoder) => encoder.flush(),
+            Compressor::Lz4(encoder) => encoder.flush(),
+            Compressor::Seekable(writer) => writer.flush(),
+            Compressor::External { stdin, .. } => stdin.flush(),
+        }
+    }
+}
+
+/// Spots a slow level being spent on data that does not compress.
+struct RatioWatch {
+    enabled: bool,
+    /// (uncompressed, compressed) once the first slice of input has gone in
+    mark: Option<(u64, u64)>,
+}
+
+impl RatioWatch {
+    /// Returns the ratio once, if it turns out to be incompressible. The
+    /// compressor holds input back (jobs in flight, output buffers), so
+    /// growth over the second slice is compared rather than totals.
+    fn observe(&mut self, uncompressed: u64, compressed: u64) -> Option<f64> {
+        if !self.enabled {
+            return None;
+        }
+        let Some((mark_in, mark_out)) = self.mark else {
+            if uncompressed >= RATIO_CHECK_BYTES {
+                self.mark = Some((uncompressed, compressed));
+            }
+            return None;
+        };
+        if uncompressed < mark_in + RATIO_CHECK_BYTES {
+            return None;
+        }
+        self.enabled = false;
+        let ratio = (compressed - mark_out) as f64 / (uncompressed - mark_in) as f64;
+        (ratio >= INCOMPRESSIBLE_RATIO).then_some(ratio)
+    }
+}
+
+// ChannelReader for streaming a large file from its chunk stream
 struct ChannelReader<'a> {
-    rx: &'a crossbeam_channel::Receiver<Result<TarEntry>>,
+    rx: &'a flume::Receiver<Result<Vec<u8>>>,
     buffer: Vec<u8>,
     cursor: usize,
     exhausted: bool,
     total_read: u64,
     expected: u64,
     pool_tx: &'a crossbeam_channel::Sender<Vec<u8>>,
+    path: &'a Path,
+    heartbeat: &'a Heartbeat,
+    watchdog: &'a Watchdog,
+    profile: &'a PipelineProfile,
 }
 
 impl<'a> Read for ChannelReader<'a> {
@@ -82,49 +1006,157 @@ impl<'a> Read for ChannelReader<'a> {
         }
 
         // Need new chunk
-        match self.rx.recv() {
-            Ok(Ok(entry)) => match entry {
-                TarEntry::LargeFileChunk(buf) => {
-                    self.buffer = buf;
-                    self.cursor = 0;
-                    self.read(out) // Recurse to copy
+        let t = self.profile.start();
+        let next = loop {
+            match self.rx.recv_timeout(WATCHDOG_POLL) {
+                Err(flume::RecvTimeoutError::Timeout) => {
+                    self.watchdog.check().map_err(std::io::Error::other)?;
                 }
-                TarEntry::LargeFileEnd => {
-                    self.exhausted = true;
-                    if self.total_read != self.expected {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::UnexpectedEof,
-                            format!(
-                                "Size mismatch: expected {}, got {}",
-                                self.expected, self.total_read
-                            ),
-                        ));
-                    }
-                    Ok(0)
+                Ok(chunk) => break Some(chunk),
+                Err(flume::RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+        self.profile.stop(Stage::StreamWait, t);
+        match next {
+            Some(Ok(buf)) => {
+                self.heartbeat.beat("write", self.path);
+                self.buffer = buf;
+                self.cursor = 0;
+                self.read(out) // Recurse to copy
+            }
+            Some(Err(e)) => Err(std::io::Error::other(e)),
+            None => {
+                // Sender dropped: the file is complete
+                self.exhausted = true;
+                if self.total_read != self.expected {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "Size mismatch: expected {}, got {}",
+                            self.expected, self.total_read
+                        ),
+                    ));
                 }
-                _ => Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Unexpected entry type, expected chunk from dedicated channel",
-                )),
-            },
-            Ok(Err(e)) => Err(std::io::Error::other(e)),
-            Err(_) => Err(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                "Chunk Channel closed unexpectedly",
-            )),
+                Ok(0)
+            }
         }
     }
 }
 
-pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()> {
-    // 1. Setup Zstd Encoder
-    let file = File::create(output).context("Failed to create output file")?;
-    let mut encoder = zstd::Encoder::new(file, options.level)?;
-    encoder.multithread(options.threads)?;
-    let _ = encoder.long_distance_matching(options.long_distance);
-    let encoder = encoder.auto_finish();
+/// Emit a PAX header carrying the entry's xattrs and whether it changed
+/// while being read, if there is anything to say.
+fn append_pax_records<W: Write>(tar: &mut tar::Builder<W>, metadata: &FileMetadata) -> Result<()> {
+    if metadata.xattrs.is_empty() && !metadata.changed {
+        return Ok(());
+    }
+    let keys: Vec<String> = metadata
+        .xattrs
+        .iter()
+        .map(|(name, _)| format!("{}{}", PAX_XATTR_PREFIX, name))
+        .collect();
+    let changed = metadata.changed.then_some((PAX_CHANGED, &b"1"[..]));
+    tar.append_pax_extensions(
+        keys.iter()
+            .map(String::as_str)
+            .zip(metadata.xattrs.iter().map(|(_, value)| value.as_slice()))
+            .chain(changed),
+    )?;
+    Ok(())
+}
+
+/// Where an archive is written before it is complete.
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// Pack `input` into the file `output`, or to stdout for `-`.
+pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<PackSummary> {
+    if is_stdio(output) {
+        let stdout = crate::utils::stdout_file()?;
+        let stdout = std::io::BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, stdout);
+        let (summary, mut stdout) = write_archive(input, stdout, options)?;
+        stdout.flush().context("Failed to flush stdout")?;
+        return Ok(summary);
+    }
+    // Sync tools and scripts watching `output` never see a half-written archive,
+    // and a leftover `.partial` marks a run that was killed before it finished
+    let partial = options.partial;
+    let target = if partial {
+        partial_path(output)
+    } else {
+        output.to_path_buf()
+    };
+    // Has to outlive removing a failed `.partial`
+    let lock = crate::utils::lock_output(output)?;
+    let file = fs::File::create(&target)
+        .with_context(|| format!("Failed to create output file {:?}", target))?;
+    let result = write_archive(input, file, options).and_then(|(summary, file)| {
+        if partial {
+            // Renamed while still locked, so no other run can claim the partial name in between
+            file.sync_all().context("Failed to flush archive to disk")?;
+            fs::rename(&target, output)
+                .with_context(|| format!("Failed to rename {:?} to {:?}", target, output))?;
+        }
+        Ok(summary)
+    });
+    if result.is_err() && partial {
+        let _ = fs::remove_file(&target);
+    }
+    drop(lock);
+    result
+}
+
+/// Pack `input` into any sink, which gets the compressed archive and is
+/// handed back once everything was written to it.
+pub fn write_archive<W: Write + Send + 'static>(
+    input: &Path,
+    output: W,
+    mut options: PackOptions,
+) -> Result<(PackSummary, W)> {
+    let started = std::time::Instant::now();
+    let profile = PipelineProfile::new(options.profile_pipeline);
 
-    let mut tar = tar::Builder::new(encoder);
+    // 1. Setup Zstd Encoder (or the user's filter program)
+    let compressed = Arc::new(AtomicU64::new(0));
+    let sink = HashingWriter {
+        inner: output,
+        // One BLAKE3 pass serves both the catalog and `--hash-output blake3`
+        blake3: (options.collect_catalog || options.hash_output == Some(HashAlgorithm::Blake3))
+            .then(blake3::Hasher::new),
+        sha256: (options.hash_output == Some(HashAlgorithm::Sha256)).then(Sha256::new),
+        written: compressed.clone(),
+    };
+    let sink = match options.encrypt.take() {
+        Some(passphrase) => Sealed::Encrypted(Encryptor::new(sink, &passphrase)?),
+        None => Sealed::Plain(sink),
+    };
+    let oci_layer = options.format == PackFormat::OciLayer;
+    let zip = options.format == PackFormat::Zip;
+    // Tar stream written so far, where the next entry starts for the seekable index
+    let tar_written = Arc::new(AtomicU64::new(0));
+    let mut index = options.seekable.map(|_| Vec::new());
+    let mut archive = if zip {
+        ArchiveWriter::Zip(ZipWriter::new(
+            BackgroundWriter::new(sink),
+            options.level.clamp(0, 9) as u32,
+        ))
+    } else {
+        let compressor = match &options.external_compressor {
+            Some(command) => Compressor::external(command, sink)?,
+            None => Compressor::new(options.compression, sink, &options)?,
+        };
+        ArchiveWriter::Tar(Box::new(tar::Builder::new(TimedWriter {
+            inner: HashingWriter {
+                inner: compressor,
+                blake3: None,
+                sha256: oci_layer.then(Sha256::new),
+                written: tar_written.clone(),
+            },
+            profile: profile.clone(),
+        })))
+    };
 
     // 2. Setup Progress Bar & Caches
     let pb = Arc::new(ProgressBar::new_spinner());
@@ -136,15 +1168,34 @@ pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()>
     );
     pb.enable_steady_tick(Duration::from_millis(100));
 
-    let inode_cache = Arc::new(DashMap::<FileId, PathBuf>::new());
+    let inode_cache = Arc::new(DashMap::<FileId, LinkTarget>::new());
+    let heartbeat = Heartbeat::new();
+    let watchdog = Watchdog::start(heartbeat.clone(), options.stall_timeout);
+    let skips = SkipLog::new(options.skip_log.as_deref())?;
+    let scan_skips = skips.clone();
+    // ZIP has a CRC per file, and OCI layers must stay plain tar streams
+    let mut manifest = Manifest::new(options.manifest && !zip && !oci_layer);
+    let progress = Progress::new();
+    // Dropped after the pipeline profile, or on the way out of a failed run
+    let _scan_report = options
+        .profile_pipeline
+        .then(|| ScanReport(progress.scan().clone()));
+    let progress_pipe = options
+        .progress_fd
+        .map(crate::status::pipe::open)
+        .transpose()?;
+    let status = StatusReporter::start(
+        progress.clone(),
+        options.status_file.clone(),
+        progress_pipe,
+        options.expected_entries,
+    );
 
     // 3. Setup Channels
     // Scanner -> Readers
-    let (path_tx, path_rx) = bounded::<PathBuf>(PATH_CHANNEL_CAPACITY);
-    // Readers -> Writer (Metadata & Small Files)
-    let (content_tx, content_rx) = bounded::<Result<TarEntry>>(CONTENT_CHANNEL_CAPACITY);
-    // Large File Data Channel (Dedicated to prevent interleaving)
-    let (chunk_tx, chunk_rx) = bounded::<Result<TarEntry>>(CHUNK_CHANNEL_CAPACITY);
+    let (path_tx, path_rx) = flume::bounded::<PathBuf>(PATH_CHANNEL_CAPACITY);
+    // Readers -> Writer (Metadata, Small Files & Large File Streams)
+    let (content_tx, content_rx) = flume::bounded::<Result<TarEntry>>(CONTENT_CHANNEL_CAPACITY);
 
     // Buffer Pool - Unbounded to prevent deadlocks.
     let (pool_tx, pool_rx) = unbounded::<Vec<u8>>();
@@ -152,56 +1203,227 @@ pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()>
     // 4. Start Scanner Thread
     let input_dir = input.canonicalize().unwrap_or_else(|_| input.to_path_buf());
     let input_dir_clone = input_dir.clone();
+    let scan_profile = profile.clone();
+    let excludes = build_excludes(&options.exclude)?;
+    let includes = build_excludes(&options.include)?;
+    let including = !includes.is_empty();
+    let (exclude_root, rooting) = (input_dir.clone(), options.rooting);
+    let scan_stats = progress.scan().clone();
+    let filter = std::mem::take(&mut options.filter);
+    let unreadable = Arc::new(AtomicU64::new(0));
+    let unreadable_count = unreadable.clone();
+    let finished_scan = scan_stats.clone();
+    // Nobody sees sizes without a status file or the profile report, so don't
+    // pay a stat per file for them
+    let scan_sizes = options.status_file.is_some() || options.profile_pipeline;
+    // Returns how many paths it sent and how many it couldn't walk
     let scanner_handle = thread::spawn(move || {
-        for entry in WalkDir::new(&input_dir_clone).skip_hidden(false) {
+        let mut walk = WalkDir::new(&input_dir_clone)
+            .skip_hidden(false)
+            // Together with a single reader, the same tree always gives the same layer
+            .sort(oci_layer)
+            // Runs on the walker's threads, in parallel and ahead of the scanner loop
+            .process_read_dir(move |depth, dir, _, children| {
+                // Excluded and filtered directories are never descended into
+                children.retain(|child| match child {
+                    Ok(entry) => {
+                        let path = entry.path();
+                        let archive_path = relativize(&path, &exclude_root, rooting);
+                        !is_excluded(&excludes, &archive_path)
+                            && (entry.file_type().is_dir() || is_included(&includes, &archive_path))
+                            && filter.keep(&path, entry.file_type(), &unreadable_count)
+                    }
+                    Err(_) => true,
+                });
+                // The first call lists the root itself
+                if depth.is_none() {
+                    return;
+                }
+                let mut listing = DirListing::default();
+                for child in children.iter().flatten() {
+                    let file_type = child.file_type();
+                    listing.entries += 1;
+                    if file_type.is_dir() {
+                        listing.dirs += 1;
+                    } else if file_type.is_symlink() {
+                        listing.symlinks += 1;
+                    } else if file_type.is_file() {
+                        listing.files += 1;
+                        if scan_sizes {
+                            listing.bytes += child.metadata().map(|m| m.len()).unwrap_or(0);
+                        }
+                    }
+                }
+                scan_stats.directory(&relativize(dir, &exclude_root, rooting), &listing);
+            })
+            .into_iter();
+        let (mut sent_paths, mut walk_errors) = (0u64, 0u64);
+        // With --include, directories are held back until an included file needs them
+        let mut parents_sent = HashSet::new();
+        'walk: loop {
+            let t = scan_profile.start();
+            let next = walk.next();
+            scan_profile.stop(Stage::Scan, t);
+            let Some(entry) = next else {
+                finished_scan.finish();
+                break;
+            };
             match entry {
                 Ok(entry) => {
                     let path = entry.path();
+                    // The directory itself is still stored, only without its contents
+                    if let Some(e) = &entry.read_children_error {
+                        skip_walk_error(&scan_skips, e, &path);
+                    }
                     if path == input_dir_clone {
                         continue;
                     }
-                    if path_tx.send(path).is_err() {
-                        break;
+                    let mut batch = Vec::new();
+                    if including {
+                        if entry.file_type().is_dir() {
+                            continue;
+                        }
+                        batch = path
+                            .ancestors()
+                            .skip(1)
+                            .take_while(|dir| *dir != input_dir_clone)
+                            .take_while(|dir| parents_sent.insert(dir.to_path_buf()))
+                            .map(Path::to_path_buf)
+                            .collect();
+                        batch.reverse();
+                    }
+                    batch.push(path);
+                    for path in batch {
+                        let t = scan_profile.start();
+                        let sent = path_tx.send(path);
+                        scan_profile.stop(Stage::ScanBlocked, t);
+                        if sent.is_err() {
+                            break 'walk;
+                        }
+                        sent_paths += 1;
                     }
                 }
                 Err(e) => {
-                    eprintln!("Error walking directory: {}", e);
+                    skip_walk_error(&scan_skips, &e, &input_dir_clone);
+                    walk_errors += 1;
                 }
             }
         }
+        (sent_paths, walk_errors)
     });
 
-    // 5. Start Reader Threads - Using Compio for unified cross-platform async I/O
-    let mut reader_handles = Vec::new();
-
-    // Use compio - unified API that automatically selects:
-    // - io_uring on Linux
-    // - IOCP on Windows
-    // - Polling on other Unix systems (macOS)
-    reader_handles.push(crate::commands::compio_reader::start_compio_worker(
-        path_rx,
-        content_tx.clone(),
-        chunk_tx.clone(),
-        pool_rx,
-        input_dir.clone(),
-        pb.clone(),
-        inode_cache,
-        options.ignore_errors,
-    ));
-
-    drop(content_tx);
-    drop(chunk_tx); // Important: drop writer's sender handle so rx can close
+    // 5. Start Reader Threads
+    let read_options = ReadOptions {
+        // Readers racing each other would reorder the entries
+        workers: if oci_layer { 1 } else { options.readers },
+        ignore_errors: options.ignore_errors,
+        mac_metadata: options.mac_metadata,
+        capabilities: options.capabilities,
+        selinux: options.selinux,
+        // ZIP has no hard links; every path gets its own copy
+        hard_dereference: options.hard_dereference || zip,
+        paranoid_links: options.paranoid_links,
+        rooting: options.rooting,
+        direct_io: options.direct_io,
+        mmap: options.mmap,
+        preserve_atime: options.preserve_atime,
+        stream_threshold: options.stream_threshold,
+        backup_semantics: options.backup_semantics,
+        oci_whiteouts: oci_layer,
+        read_retries: options.read_retries,
+        retry_backoff: options.retry_backoff,
+        if_changed: options.if_changed,
+    };
+    let backend = match options.io_backend {
+        // The compio reader completes reads out of order
+        _ if oci_layer => IoBackend::Threaded,
+        // Direct IO needs aligned buffers and backup semantics need custom
+        // open flags, which only the threaded reader manages
+        IoBackend::Auto if options.direct_io || options.backup_semantics => IoBackend::Threaded,
+        IoBackend::Auto => tuning::auto_io_backend(),
+        IoBackend::Compio if options.direct_io => {
+            anyhow::bail!("--direct-io is not supported with --io-backend compio")
+        }
+        IoBackend::Compio if options.backup_semantics => {
+            anyhow::bail!("--backup-privileges is not supported with --io-backend compio")
+        }
+        chosen => chosen,
+    };
+    let reader_handles = match backend {
+        IoBackend::Threaded => crate::commands::threaded_reader::start_threaded_workers(
+            path_rx,
+            content_tx.clone(),
+            pool_rx,
+            input_dir.clone(),
+            pb.clone(),
+            inode_cache,
+            heartbeat.clone(),
+            skips.clone(),
+            profile.clone(),
+            read_options,
+        ),
+        // Use compio - unified API that automatically selects:
+        // - io_uring on Linux
+        // - IOCP on Windows
+        // - Polling on other Unix systems (macOS)
+        _ => vec![crate::commands::compio_reader::start_compio_worker(
+            path_rx,
+            content_tx.clone(),
+            pool_rx,
+            input_dir.clone(),
+            pb.clone(),
+            inode_cache,
+            heartbeat.clone(),
+            skips.clone(),
+            profile.clone(),
+            read_options,
+        )],
+    };
+
+    drop(content_tx); // Important: drop writer's sender handle so rx can close
 
     // 6. Writer Current Thread
+    let mut summary = PackSummary::default();
+    let mut uncompressed = 0u64;
+    let mut ratio_watch = RatioWatch {
+        enabled: !options.no_ratio_warning
+            && options.external_compressor.is_none()
+            && options.compression == Compression::Zstd
+            && options.level >= RATIO_WARNING_LEVEL,
+        mark: None,
+    };
     loop {
-        let entry_result = content_rx.recv();
-        if entry_result.is_err() {
-            break; // Channel closed and empty
+        let t = profile.start();
+        let received = content_rx.recv_timeout(WATCHDOG_POLL);
+        profile.stop(Stage::WriterIdle, t);
+        let entry = match received {
+            Ok(entry) => entry?,
+            Err(flume::RecvTimeoutError::Timeout) => {
+                watchdog.check()?;
+                continue;
+            }
+            Err(flume::RecvTimeoutError::Disconnected) => break, // Channel closed and empty
+        };
+
+        heartbeat.beat("write", entry.path());
+        let content_len = entry.content_len();
+        progress.entry(entry.path(), content_len);
+        if options.collect_catalog {
+            summary.entry_paths.push(entry.path().to_path_buf());
+        }
+        summary.entries += 1;
+        if let Some(index) = index.as_mut() {
+            index.push(entry.index_entry(tar_written.load(Ordering::Relaxed)));
         }
-        let entry = entry_result.unwrap()?;
 
-        match entry {
-            TarEntry::Dir(path, metadata) => {
+        let t = profile.start();
+        match (&mut archive, entry) {
+            (ArchiveWriter::Tar(tar), TarEntry::Dir(path, mut metadata)) => {
+                let opaque = oci_layer && oci::is_opaque(&metadata.xattrs);
+                metadata
+                    .xattrs
+                    .retain(|(name, _)| !oci_layer || !oci::OPAQUE_XATTRS.contains(&name.as_str()));
+                append_pax_records(tar, &metadata)?;
                 let mut header = tar::Header::new_gnu();
                 header.set_entry_type(tar::EntryType::Directory);
                 header.set_mode(metadata.mode);
@@ -210,9 +1432,30 @@ pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()>
                 header.set_mtime(metadata.mtime);
                 header.set_size(0);
                 header.set_cksum();
-                tar.append_dir(&path, ".")?;
+                tar.append_data(&mut header, &path, std::io::empty())?;
+                if opaque {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_size(0);
+                    header.set_mode(metadata.mode & 0o777);
+                    header.set_uid(metadata.uid);
+                    header.set_gid(metadata.gid);
+                    header.set_mtime(metadata.mtime);
+                    header.set_cksum();
+                    let whiteout = path.join(oci::OPAQUE_WHITEOUT);
+                    if let Some(index) = index.as_mut() {
+                        index.push(IndexEntry {
+                            kind: EntryKind::File,
+                            size: 0,
+                            path: index_path(&whiteout),
+                            link: None,
+                            offset: tar_written.load(Ordering::Relaxed),
+                        });
+                    }
+                    tar.append_data(&mut header, whiteout, std::io::empty())?;
+                }
             }
-            TarEntry::SmallFile(path, buf, metadata) => {
+            (ArchiveWriter::Tar(tar), TarEntry::SmallFile(path, buf, metadata)) => {
+                append_pax_records(tar, &metadata)?;
                 let mut header = tar::Header::new_gnu();
                 header.set_size(buf.len() as u64);
                 header.set_mode(metadata.mode);
@@ -220,40 +1463,76 @@ pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()>
                 header.set_gid(metadata.gid);
                 header.set_mtime(metadata.mtime);
                 header.set_cksum();
-                tar.append_data(&mut header, &path, &buf[..])?;
+                let mut data = manifest.reader(&buf[..]);
+                tar.append_data(&mut header, &path, &mut data)?;
+                manifest.finish(&path, data);
+                if metadata.changed {
+                    manifest.changed(&path);
+                }
                 let _ = pool_tx.send(buf);
             }
-            TarEntry::LargeFileStart(path, len, metadata) => {
+            (ArchiveWriter::Tar(tar), TarEntry::MappedFile(path, map, metadata)) => {
+                append_pax_records(tar, &metadata)?;
                 let mut header = tar::Header::new_gnu();
-                header.set_size(len);
+                header.set_size(map.len() as u64);
                 header.set_mode(metadata.mode);
                 header.set_uid(metadata.uid);
                 header.set_gid(metadata.gid);
                 header.set_mtime(metadata.mtime);
                 header.set_cksum();
-
-
-
+                // Hashed as it is copied: the file may change under the mapping
+                let mut data = manifest.reader(&map[..]);
+                tar.append_data(&mut header, &path, &mut data)?;
+                manifest.finish(&path, data);
+                if metadata.changed {
+                    manifest.changed(&path);
+                }
+            }
+            (archive, TarEntry::LargeFile(path, len, metadata, chunks, changed)) => {
                 let mut reader = ChannelReader {
-                    rx: &chunk_rx, // Read from dedicated chunk channel
+                    rx: &chunks, // Read from this file's chunk stream
                     buffer: Vec::new(),
                     cursor: 0,
                     exhausted: false,
                     total_read: 0,
                     expected: len,
                     pool_tx: &pool_tx,
+                    path: &path,
+                    heartbeat: &heartbeat,
+                    watchdog: &watchdog,
+                    profile: &profile,
                 };
 
-                // If append_data returns error (e.g. read error), we should handle it.
-                // But we are in a loop handling entries.
-                tar.append_data(&mut header, &path, &mut reader)?;
-            }
-            TarEntry::LargeFileChunk(_) | TarEntry::LargeFileEnd => {
-                // We should NEVER receive Chunk/End on content_rx!
-                // This confirms separation works.
-                anyhow::bail!("Protocol Error: chunk received on metadata channel");
+                match archive {
+                    ArchiveWriter::Tar(tar) => {
+                        append_pax_records(tar, &metadata)?;
+                        let mut header = tar::Header::new_gnu();
+                        header.set_size(len);
+                        header.set_mode(metadata.mode);
+                        header.set_uid(metadata.uid);
+                        header.set_gid(metadata.gid);
+                        header.set_mtime(metadata.mtime);
+                        header.set_cksum();
+
+                        // If append_data returns error (e.g. read error), we should handle it.
+                        // But we are in a loop handling entries.
+                        let mut data = manifest.reader(&mut reader);
+                        tar.append_data(&mut header, &path, &mut data)?;
+                        manifest.finish(&path, data);
+                        if changed.load(Ordering::Relaxed) {
+                            manifest.changed(&path);
+                        }
+                    }
+                    ArchiveWriter::Zip(zip) => {
+                        zip.file(&path, &metadata, len, &mut reader)?;
+                        if changed.load(Ordering::Relaxed) {
+                            zip.mark_changed();
+                        }
+                    }
+                }
             }
-            TarEntry::Symlink(path, target, metadata) => {
+            (ArchiveWriter::Tar(tar), TarEntry::Symlink(path, target, metadata)) => {
+                append_pax_records(tar, &metadata)?;
                 let mut header = tar::Header::new_gnu();
                 header.set_entry_type(tar::EntryType::Symlink);
                 header.set_size(0);
@@ -265,25 +1544,130 @@ pub fn execute(input: &Path, output: &Path, options: PackOptions) -> Result<()>
                 header.set_cksum();
                 tar.append_data(&mut header, &path, &mut std::io::empty())?;
             }
-            TarEntry::HardLink(path, target) => {
+            (ArchiveWriter::Tar(tar), TarEntry::HardLink(path, target, metadata)) => {
                 let mut header = tar::Header::new_gnu();
                 header.set_entry_type(tar::EntryType::Link);
                 header.set_size(0);
-                header.set_mode(0o644);
+                header.set_mode(metadata.mode);
+                header.set_uid(metadata.uid);
+                header.set_gid(metadata.gid);
+                header.set_mtime(metadata.mtime);
                 header.set_link_name(&target).unwrap_or(());
                 header.set_cksum();
                 tar.append_data(&mut header, &path, &mut std::io::empty())?;
+                summary.hardlinks += 1;
+            }
+            (ArchiveWriter::Zip(zip), TarEntry::Dir(path, metadata)) => {
+                zip.directory(&path, &metadata)?;
+            }
+            (ArchiveWriter::Zip(zip), TarEntry::SmallFile(path, buf, metadata)) => {
+                zip.file(&path, &metadata, buf.len() as u64, &buf[..])?;
+                if metadata.changed {
+                    zip.mark_changed();
+                }
+                let _ = pool_tx.send(buf);
+            }
+            (ArchiveWriter::Zip(zip), TarEntry::MappedFile(path, map, metadata)) => {
+                zip.file(&path, &metadata, map.len() as u64, &map[..])?;
+                if metadata.changed {
+                    zip.mark_changed();
+                }
+            }
+            (ArchiveWriter::Zip(zip), TarEntry::Symlink(path, target, metadata)) => {
+                zip.symlink(&path, &target, &metadata)?;
+            }
+            (ArchiveWriter::Zip(_), TarEntry::HardLink(..)) => {
+                unreachable!("hard links are dereferenced for ZIP")
             }
         }
+        profile.stop(Stage::Append, t);
+
+        uncompressed += content_len;
+        if let Some(ratio) = ratio_watch.observe(uncompressed, compressed.load(Ordering::Relaxed)) {
+            pb.suspend(|| {
+                eprintln!(
+                    "Warning: Input barely compresses (ratio {:.2}) at level {}; it looks \
+                     already compressed (video, images, archives). A level like 3 would be \
+                     about as small and much faster (silence with --no-ratio-warning)",
+                    ratio, options.level
+                )
+            });
+        }
     }
 
-    pb.finish_with_message("Done");
-    scanner_handle.join().unwrap();
+    if summary.hardlinks > 0 {
+        pb.finish_with_message(t!(
+            "pack-done-hardlinks",
+            backend = backend.name(),
+            hardlinks = summary.hardlinks
+        ));
+    } else {
+        pb.finish_with_message(t!("pack-done", backend = backend.name()));
+    }
+    let (sent_paths, walk_errors) = scanner_handle.join().unwrap();
     for handle in reader_handles {
         handle.join().unwrap();
     }
+    skips.finish();
+    summary.retries = skips.retries();
+    summary.changed = skips.changed_files();
+    let unreadable = unreadable.load(Ordering::Relaxed);
+    if unreadable > 0 {
+        eprintln!(
+            "Left out {} unreadable entries (--skip-unreadable)",
+            unreadable
+        );
+    }
+    // Readers turn every path into exactly one entry unless they skip it
+    summary.skipped = walk_errors + sent_paths.saturating_sub(summary.entries);
 
-    tar.finish().context("Failed to finish writing archive")?;
+    let t = profile.start();
+    let sink = match archive {
+        ArchiveWriter::Tar(mut tar) => {
+            manifest.append_to(&mut tar)?;
+            // The trailer comes next
+            let end = tar_written.load(Ordering::Relaxed);
+            let stream = tar
+                .into_inner()
+                .context("Failed to finish writing archive")?
+                .inner;
+            summary.diff_id = stream.sha256.map(|h| format!("{:x}", h.finalize()));
+            let mut compressor = stream.inner;
+            if let (Compressor::Seekable(writer), Some(index)) = (&mut compressor, index) {
+                writer.set_index(index, end);
+            }
+            compressor
+                .finish()
+                .context("Failed to finish writing archive")?
+        }
+        ArchiveWriter::Zip(zip) => zip
+            .finish()
+            .and_then(BackgroundWriter::finish)
+            .context("Failed to finish writing archive")?,
+    };
+    let sink = sink.finish().context("Failed to finish writing archive")?;
+    profile.stop(Stage::Compress, t);
+    let blake3 = sink.blake3.map(|h| h.finalize().to_hex().to_string());
+    summary.digest = match options.hash_output {
+        Some(HashAlgorithm::Blake3) => blake3.clone(),
+        Some(HashAlgorithm::Sha256) => sink.sha256.map(|h| format!("{:x}", h.finalize())),
+        None => None,
+    };
+    summary.checksum = blake3.filter(|_| options.collect_catalog);
+    summary.bytes = uncompressed;
+    summary.compressed = compressed.load(Ordering::Relaxed);
 
-    Ok(())
+    profile.report(started.elapsed(), options.readers);
+    status.finish();
+
+    Ok((summary, sink.inner))
+}
+
+/// Hand a walker error to `skips`, under the path it names or `fallback`.
+fn skip_walk_error(skips: &SkipLog, e: &jwalk::Error, fallback: &Path) {
+    let path = e.path().unwrap_or(fallback);
+    match e.io_error() {
+        Some(io) => skips.skip("Failed to walk", path, io),
+        None => skips.skip("Failed to walk", path, e),
+    }
 }
@@ -0,0 +1,306 @@
+use anyhow::{Context, Result, bail};
+use jwalk::WalkDir;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path};
+
+use crate::commands::rotate::gfs_retain;
+use crate::commands::unpack::set_permissions_and_times;
+use crate::repo::chunker::Chunker;
+use crate::repo::snapshot::{EntryKind, Snapshot, SnapshotEntry};
+use crate::repo::{Access, Repository};
+use crate::utils::{Rooting, civil_from_days, get_file_metadata, relativize};
+
+pub fn init(repo: &Path) -> Result<()> {
+    Repository::init(repo)?;
+    println!("Initialized empty repository at {:?}", repo);
+    Ok(())
+}
+
+/// Chunk every file under `input` into the repository and record a snapshot.
+pub fn backup(repo: &Path, input: &Path, level: i32) -> Result<String> {
+    let mut repository = Repository::open(repo, level, Access::Shared)?;
+    let input = input
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {:?}", input))?;
+
+    let mut entries = Vec::new();
+    let (mut files, mut bytes, mut chunks, mut new_chunks) = (0u64, 0u64, 0u64, 0u64);
+
+    for entry in WalkDir::new(&input).skip_hidden(false).sort(true) {
+        let path = entry?.path();
+        if path == input {
+            continue;
+        }
+        let relative = relativize(&path, &input, Rooting::Contents)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let meta = fs::symlink_metadata(&path)?;
+        let metadata = get_file_metadata(&path, &meta);
+
+        let kind = if meta.is_dir() {
+            EntryKind::Dir
+        } else if meta.file_type().is_symlink() {
+            EntryKind::Symlink {
+                target: fs::read_link(&path)?.to_string_lossy().to_string(),
+            }
+        } else {
+            let file = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+            let mut chunker = Chunker::new(file);
+            let mut hashes = Vec::new();
+            let mut size = 0;
+            while let Some(chunk) = chunker.next_chunk()? {
+                let (hash, added) = repository.add_chunk(&chunk)?;
+                hashes.push(hash);
+                size += chunk.len() as u64;
+                chunks += 1;
+                if added {
+                    new_chunks += 1;
+                }
+            }
+            files += 1;
+            bytes += size;
+            EntryKind::File {
+                size,
+                chunks: hashes,
+            }
+        };
+
+        entries.push(SnapshotEntry {
+            path: relative,
+            kind,
+            mode: metadata.mode,
+            mtime: metadata.mtime,
+        });
+    }
+
+    repository.flush()?;
+
+    let created = crate::catalog::now();
+    let snapshots_dir = repository.snapshots_dir();
+    let mut id = snapshot_id(created);
+    if snapshots_dir.join(format!("{}.json.zst", id)).exists() {
+        id = format!("{}-{}", id, std::process::id());
+    }
+    Snapshot {
+        id: id.clone(),
+        source: input.to_string_lossy().to_string(),
+        created,
+        entries,
+    }
+    .save(&snapshots_dir)?;
+
+    println!(
+        "Snapshot {}: {} files, {} bytes, {} chunks ({} new)",
+        id, files, bytes, chunks, new_chunks
+    );
+    Ok(id)
+}
+
+/// Recreate the tree of snapshot `id` (or `latest`) under `output`.
+pub fn restore(repo: &Path, id: &str, output: &Path) -> Result<()> {
+    let repository = Repository::open(repo, 0, Access::Shared)?;
+    let snapshots_dir = repository.snapshots_dir();
+    let id = if id == "latest" {
+        Snapshot::list_ids(&snapshots_dir)?
+            .pop()
+            .context("Repository has no snapshots")?
+    } else {
+        id.to_string()
+    };
+    let snapshot = Snapshot::load(&snapshots_dir, &id)?;
+
+    fs::create_dir_all(output)?;
+    let mut dirs = Vec::new();
+    // Created last: a later entry's parent directories must not lead through one
+    let mut symlinks = Vec::new();
+    for entry in &snapshot.entries {
+        let relative = Path::new(&entry.path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("Snapshot contains unsafe path {:?}", entry.path);
+        }
+        let target = output.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match &entry.kind {
+            EntryKind::Dir => {
+                fs::create_dir_all(&target)?;
+                dirs.push((target, entry.mode, entry.mtime));
+            }
+            EntryKind::File { chunks, .. } => {
+                {
+                    let mut file = File::create(&target)?;
+                    for hash in chunks {
+                        file.write_all(&repository.read_chunk(hash)?)?;
+                    }
+                }
+                set_permissions_and_times(&target, entry.mode, entry.mtime)?;
+            }
+            EntryKind::Symlink { target: link } => symlinks.push((target, link)),
+        }
+    }
+
+    for (target, link) in symlinks {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(link, &target)
+            .with_context(|| format!("Failed to create symlink {:?}", target))?;
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_file(link, &target).ok();
+    }
+
+    // Deepest first so restoring a child doesn't bump its parent's mtime
+    dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+    for (path, mode, mtime) in dirs {
+        set_permissions_and_times(&path, mode, mtime).ok();
+    }
+
+    println!("Restored snapshot {} to {:?}", id, output);
+    Ok(())
+}
+
+/// Packs with more than this fraction of unreferenced bytes are rewritten by `compact`
+const COMPACT_UNUSED_RATIO: f64 = 0.2;
+
+pub struct PruneOptions {
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    /// Allow a policy that keeps no snapshot at all
+    pub keep_none: bool,
+    pub dry_run: bool,
+}
+
+/// Forget snapshots outside the GFS retention window and delete packs that
+/// no longer hold any referenced chunk.
+pub fn prune(repo: &Path, options: PruneOptions) -> Result<()> {
+    if options.keep_daily == 0
+        && options.keep_weekly == 0
+        && options.keep_monthly == 0
+        && !options.keep_none
+    {
+        bail!("This policy keeps no snapshot; pass --keep-none to forget them all");
+    }
+    let access = if options.dry_run {
+        Access::Shared
+    } else {
+        Access::Exclusive
+    };
+    let mut repository = Repository::open(repo, 0, access)?;
+    let snapshots_dir = repository.snapshots_dir();
+
+    let mut snapshots = Vec::new();
+    for id in Snapshot::list_ids(&snapshots_dir)? {
+        let created = Snapshot::load(&snapshots_dir, &id)?.created;
+        snapshots.push((id, created));
+    }
+    // Newest first; ids break ties between snapshots taken in the same second
+    snapshots.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0)));
+
+    let days: Vec<i64> = snapshots
+        .iter()
+        .map(|(_, created)| (*created / 86400) as i64)
+        .collect();
+    let keep = gfs_retain(
+        &days,
+        options.keep_daily,
+        options.keep_weekly,
+        options.keep_monthly,
+    );
+
+    for (i, (id, _)) in snapshots.iter().enumerate() {
+        if keep.contains(&i) {
+            println!("Keep:   {}", id);
+            continue;
+        }
+        println!("Forget: {}", id);
+        if !options.dry_run {
+            fs::remove_file(snapshots_dir.join(format!("{}.json.zst", id)))?;
+        }
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+
+    let live = repository.referenced_chunks()?;
+    let usage = repository.pack_usage(&live)?;
+    let dead: HashSet<u32> = usage.iter().filter(|u| u.live == 0).map(|u| u.id).collect();
+    let freed: u64 = usage.iter().filter(|u| u.live == 0).map(|u| u.total).sum();
+    repository.remove_packs(&dead)?;
+
+    let sparse = usage
+        .iter()
+        .filter(|u| u.live > 0 && is_sparse(u.live, u.total))
+        .count();
+    println!(
+        "Removed {} snapshots and {} unreferenced packs ({} bytes); {} packs are sparse (run `repo compact`)",
+        snapshots.len() - keep.len(),
+        dead.len(),
+        freed,
+        sparse
+    );
+    Ok(())
+}
+
+/// Rewrite packs that are mostly unreferenced data so the space is reclaimed.
+pub fn compact(repo: &Path) -> Result<()> {
+    let mut repository = Repository::open(repo, 0, Access::Exclusive)?;
+    let live = repository.referenced_chunks()?;
+    let usage = repository.pack_usage(&live)?;
+
+    let sparse: Vec<u32> = usage
+        .iter()
+        .filter(|u| is_sparse(u.live, u.total))
+        .map(|u| u.id)
+        .collect();
+    let before: u64 = usage.iter().map(|u| u.total).sum();
+    repository.rewrite_packs(&sparse, &live)?;
+    let after: u64 = repository.pack_usage(&live)?.iter().map(|u| u.total).sum();
+
+    println!(
+        "Rewrote {} packs, reclaimed {} bytes",
+        sparse.len(),
+        before.saturating_sub(after)
+    );
+    Ok(())
+}
+
+fn is_sparse(live: u64, total: u64) -> bool {
+    total > 0 && (total - live.min(total)) as f64 / total as f64 > COMPACT_UNUSED_RATIO
+}
+
+pub fn list(repo: &Path) -> Result<()> {
+    let repository = Repository::open(repo, 0, Access::Shared)?;
+    let snapshots_dir = repository.snapshots_dir();
+    for id in Snapshot::list_ids(&snapshots_dir)? {
+        let snapshot = Snapshot::load(&snapshots_dir, &id)?;
+        println!(
+            "{}\t{} entries\t{}",
+            id,
+            snapshot.entries.len(),
+            snapshot.source
+        );
+    }
+    Ok(())
+}
+
+/// `YYYYMMDDTHHMMSS` (UTC), so ids sort chronologically.
+fn snapshot_id(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (y, m, d) = civil_from_days(days);
+    let rem = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result, bail};
+use std::path::Path;
+
+use crate::catalog::Catalog;
+use crate::commands::rotate;
+use crate::commands::unpack::{self, UnpackOptions, UnpackSummary};
+use crate::i18n::t;
+
+/// Extract the newest cataloged archive of `profile` that was created on or
+/// before `as_of` (`YYYY-MM-DD`, UTC). Every archive is a standalone full
+/// backup, so restoring needs exactly one of them.
+pub fn execute(
+    db: &Path,
+    profile: &str,
+    as_of: Option<&str>,
+    output: &Path,
+    options: UnpackOptions,
+) -> Result<UnpackSummary> {
+    let before = match as_of {
+        Some(date) => {
+            let day = rotate::parse_date(date)
+                .with_context(|| format!("Invalid date {:?}, expected YYYY-MM-DD", date))?;
+            // Include everything created during that day
+            Some(((day + 1) * 86400).max(0) as u64)
+        }
+        None => None,
+    };
+
+    let catalog = Catalog::open(db)?;
+    let Some(record) = catalog.latest_for_profile(profile, before)? else {
+        match as_of {
+            Some(date) => bail!(
+                "No archive of profile {:?} created on or before {}",
+                profile,
+                date
+            ),
+            None => bail!("No archive of profile {:?} in the catalog", profile),
+        }
+    };
+
+    if record.location.contains("://") {
+        bail!(
+            "Archive {} is stored remotely, which restore does not support",
+            record.location
+        );
+    }
+    let archive = Path::new(&record.location);
+    if !archive.exists() {
+        bail!("Cataloged archive {:?} no longer exists", archive);
+    }
+
+    println!(
+        "{}",
+        t!(
+            "restoring",
+            archive = format!("{:?}", archive),
+            id = record.id
+        )
+    );
+    let options = UnpackOptions {
+        expected_entries: Some(record.file_count),
+        ..options
+    };
+    unpack::execute(archive, output, options)
+}
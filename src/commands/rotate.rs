@@ -0,0 +1,206 @@
+use anyhow::{Context, Result, bail};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::i18n::t;
+use crate::utils::{civil_from_days, days_from_civil};
+
+/// Placeholder in the naming template that matches `YYYY-MM-DD` or `YYYYMMDD`
+pub const DATE_PLACEHOLDER: &str = "{date}";
+
+pub struct RotateOptions {
+    pub template: String,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    /// Allow a policy that keeps no archive at all
+    pub keep_none: bool,
+    pub dry_run: bool,
+}
+
+struct Archive {
+    path: PathBuf,
+    /// Days since 1970-01-01
+    day: i64,
+    mtime: std::time::SystemTime,
+}
+
+/// Apply grandfather-father-son retention to the archives in `dir` whose
+/// names match the template: the newest archive of each of the last N days,
+/// weeks (Monday based) and months is kept, everything else is deleted.
+pub fn execute(dir: &Path, options: RotateOptions) -> Result<()> {
+    if options.keep_daily == 0
+        && options.keep_weekly == 0
+        && options.keep_monthly == 0
+        && !options.keep_none
+    {
+        bail!("This policy keeps no archive; pass --keep-none to delete them all");
+    }
+    let Some((prefix, suffix)) = options.template.split_once(DATE_PLACEHOLDER) else {
+        bail!("Template must contain {}", DATE_PLACEHOLDER);
+    };
+
+    let mut archives = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let Some(day) = name
+            .to_str()
+            .and_then(|n| match_template(n, prefix, suffix))
+        else {
+            continue;
+        };
+        archives.push(Archive {
+            path: entry.path(),
+            day,
+            mtime: entry.metadata()?.modified()?,
+        });
+    }
+
+    // Newest first
+    archives.sort_by(|a, b| b.day.cmp(&a.day).then(b.mtime.cmp(&a.mtime)));
+
+    let days: Vec<i64> = archives.iter().map(|a| a.day).collect();
+    let keep = gfs_retain(
+        &days,
+        options.keep_daily,
+        options.keep_weekly,
+        options.keep_monthly,
+    );
+
+    let mut deleted = 0;
+    for (i, archive) in archives.iter().enumerate() {
+        if keep.contains(&i) {
+            println!("Keep:   {:?}", archive.path);
+            continue;
+        }
+        println!("Delete: {:?}", archive.path);
+        if !options.dry_run {
+            fs::remove_file(&archive.path)
+                .with_context(|| format!("Failed to delete {:?}", archive.path))?;
+        }
+        deleted += 1;
+    }
+
+    let key = if options.dry_run {
+        "rotate-summary-dry-run"
+    } else {
+        "rotate-summary"
+    };
+    println!(
+        "{}",
+        t!(
+            key,
+            matched = archives.len(),
+            kept = keep.len(),
+            deleted = deleted
+        )
+    );
+    Ok(())
+}
+
+/// Indices of the items to keep under grandfather-father-son retention.
+/// `days` (days since epoch) must be sorted newest first; the first item of
+/// each of the newest `daily` days, `weekly` weeks and `monthly` months is kept.
+pub fn gfs_retain(days: &[i64], daily: usize, weekly: usize, monthly: usize) -> HashSet<usize> {
+    let mut keep = HashSet::new();
+    retain_buckets(days, daily, |d| d, &mut keep);
+    retain_buckets(days, weekly, |d| d - weekday(d), &mut keep);
+    retain_buckets(
+        days,
+        monthly,
+        |d| {
+            let (y, m, _) = civil_from_days(d);
+            y * 12 + m as i64
+        },
+        &mut keep,
+    );
+    keep
+}
+
+/// Keep the newest item of each of the first `count` distinct buckets.
+fn retain_buckets(
+    days: &[i64],
+    count: usize,
+    bucket: impl Fn(i64) -> i64,
+    keep: &mut HashSet<usize>,
+) {
+    let mut seen = HashSet::new();
+    for (i, &day) in days.iter().enumerate() {
+        if seen.len() >= count {
+            break;
+        }
+        if seen.insert(bucket(day)) {
+            keep.insert(i);
+        }
+    }
+}
+
+/// Match `name` against `<prefix>{date}<suffix>` and return the date as days since epoch.
+fn match_template(name: &str, prefix: &str, suffix: &str) -> Option<i64> {
+    for start in 0..name.len() {
+        if !name.is_char_boundary(start) || !wildcard_match(prefix, &name[..start]) {
+            continue;
+        }
+        for len in [10, 8] {
+            let end = start + len;
+            if end > name.len() || !name.is_char_boundary(end) {
+                continue;
+            }
+            if let Some(day) = parse_date(&name[start..end])
+                && wildcard_match(suffix, &name[end..])
+            {
+                return Some(day);
+            }
+        }
+    }
+    None
+}
+
+/// Parse `YYYY-MM-DD` or `YYYYMMDD` into days since 1970-01-01.
+pub fn parse_date(s: &str) -> Option<i64> {
+    let digits: String = match s.len() {
+        10 if s.as_bytes()[4] == b'-' && s.as_bytes()[7] == b'-' => s.replace('-', ""),
+        8 => s.to_string(),
+        _ => return None,
+    };
+    if digits.len() != 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let y: i64 = digits[0..4].parse().ok()?;
+    let m: u32 = digits[4..6].parse().ok()?;
+    let d: u32 = digits[6..8].parse().ok()?;
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    Some(days_from_civil(y, m, d))
+}
+
+/// Glob-style match supporting only `*`.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Day of week with Monday = 0 (1970-01-01 was a Thursday).
+fn weekday(day: i64) -> i64 {
+    (day + 3).rem_euclid(7)
+}
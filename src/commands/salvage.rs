@@ -0,0 +1,367 @@
+use anyhow::{Result, bail};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::commands::unpack::{is_non_empty_dir, is_plain_dir, set_permissions_and_times};
+use crate::dialect::{self, EntryKind};
+use crate::i18n::t;
+use crate::sanitize;
+use crate::utils::{build_excludes, is_excluded, open_input};
+use crate::validate::{ExtractLimits, window_log_for};
+
+/// Process exit code when salvaging finished but some data was lost
+pub const EXIT_DATA_LOST: i32 = 4;
+
+/// Little-endian zstd frame magic, the resync point in the compressed stream
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+const BLOCK: usize = 512;
+
+/// How much decompressed data is searched for a tar header per read
+const SCAN_CHUNK: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct SalvageReport {
+    pub restored: u64,
+    /// Entries whose header was read but whose data or file could not be written
+    pub lost: Vec<(PathBuf, String)>,
+    /// Unreadable stretches of the compressed stream: (approximate offset, error)
+    pub damaged_frames: Vec<(u64, String)>,
+    /// Decompressed bytes passed over while looking for the next tar header
+    pub skipped_bytes: u64,
+}
+
+impl SalvageReport {
+    pub fn is_complete(&self) -> bool {
+        self.lost.is_empty() && self.damaged_frames.is_empty() && self.skipped_bytes == 0
+    }
+
+    pub fn print(&self) {
+        for (offset, error) in &self.damaged_frames {
+            eprintln!(
+                "Damaged zstd data near byte {} (entries in it are lost): {}",
+                offset, error
+            );
+        }
+        for (path, error) in &self.lost {
+            eprintln!("Lost {:?}: {}", path, error);
+        }
+        if self.skipped_bytes > 0 {
+            eprintln!(
+                "Skipped {} of unreadable tar data (entries in there are lost without a trace)",
+                indicatif::HumanBytes(self.skipped_bytes)
+            );
+        }
+    }
+}
+
+/// Extract whatever can be recovered from a damaged archive. A zstd frame
+/// that fails to decode is skipped up to the next frame magic; a tar header
+/// that fails its checksum is skipped up to the next valid header. Archives
+/// written by `pack` are a single frame, so damaged compressed data loses
+/// everything after it; multi-frame archives (pzstd, concatenated archives)
+/// recover from the next frame on.
+///
+/// Entries are written one at a time in archive order; this is a recovery
+/// tool, not the fast path. `--exclude` and the extraction limits apply as
+/// they do to `unpack`.
+pub fn execute(
+    input: &Path,
+    output: &Path,
+    merge: bool,
+    exclude: &[String],
+    limits: &ExtractLimits,
+    memory_limit: Option<u64>,
+) -> Result<SalvageReport> {
+    if is_non_empty_dir(output)? && !merge {
+        bail!(t!("output-not-empty", output = format!("{:?}", output)));
+    }
+    fs::create_dir_all(output)?;
+
+    let excludes = build_excludes(exclude)?;
+    let mut reader = FrameSalvager::new(open_input(input)?, memory_limit)?;
+    let damage = reader.damage.clone();
+    let mut report = SalvageReport::default();
+    let mut dirs = Vec::new();
+    let mut pending = Vec::new();
+    let (mut entries, mut bytes) = (0u64, 0u64);
+
+    loop {
+        let mut archive = tar::Archive::new(Cursor::new(pending).chain(&mut reader));
+        // Concatenated archives have end-of-archive blocks in the middle
+        archive.set_ignore_zeros(true);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_overwrite(true);
+
+        let mut stream_broken = false;
+        for entry in archive.entries()? {
+            let damage_before = damage.load(Ordering::Relaxed);
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => {
+                    stream_broken = true;
+                    break;
+                }
+            };
+            let path = match entry.path() {
+                Ok(path) => path.into_owned(),
+                Err(e) => {
+                    report
+                        .lost
+                        .push((PathBuf::from("<unreadable name>"), e.to_string()));
+                    continue;
+                }
+            };
+            if is_excluded(&excludes, &path) {
+                continue;
+            }
+            match dialect::classify(&mut entry) {
+                Ok(EntryKind::Metadata | EntryKind::Unsupported(_)) => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    report.lost.push((path, e.to_string()));
+                    continue;
+                }
+            }
+            entries += 1;
+            limits.check_entry(entries, &path)?;
+            if let Some(reason) = sanitize::reject_reason(&path) {
+                eprintln!("Skipping unsafe path ({}): {:?}", reason, path);
+                continue;
+            }
+            if entry.header().entry_type().is_file() {
+                bytes += entry.size();
+                limits.check_output(bytes)?;
+            }
+
+            let result = if entry.header().entry_type().is_dir() {
+                // Applied at the end, once nothing is written into the directory anymore
+                let header = entry.header();
+                let mode = header.mode().unwrap_or(0o755);
+                let mtime = header.mtime().unwrap_or(0);
+                // Unlike unpack_in, create_dir_all would follow a symlink
+                // an earlier entry put in the way
+                if is_plain_dir(output, &path) {
+                    dirs.push((path.clone(), mode, mtime));
+                    fs::create_dir_all(output.join(&path)).map(|_| true)
+                } else {
+                    Err(io::Error::other("reached through a symlink"))
+                }
+            } else {
+                entry.unpack_in(output)
+            };
+            // The entry ran into damaged compressed data; what follows has to be resynced
+            if damage.load(Ordering::Relaxed) != damage_before {
+                report
+                    .lost
+                    .push((path, "data cut off by damaged compressed data".to_string()));
+                stream_broken = true;
+                break;
+            }
+            match result {
+                Ok(_) => report.restored += 1,
+                Err(e) => report.lost.push((path, e.to_string())),
+            }
+        }
+        if !stream_broken {
+            break;
+        }
+        // What the tar reader hadn't got to yet is searched like the rest
+        let (unread, _) = archive.into_inner().into_inner();
+        let rest = unread.get_ref()[unread.position() as usize..].to_vec();
+
+        match find_header(
+            &mut Cursor::new(rest).chain(&mut reader),
+            &mut report.skipped_bytes,
+        ) {
+            Some(found) => pending = found,
+            None => break,
+        }
+    }
+
+    // Deepest first, so setting a directory's mtime isn't undone by its children
+    dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+    for (path, mode, mtime) in dirs {
+        if !is_plain_dir(output, &path) {
+            continue;
+        }
+        let path = output.join(path);
+        if let Err(e) = set_permissions_and_times(&path, mode, mtime) {
+            eprintln!("Warning: Failed to set metadata on {:?}: {}", path, e);
+        }
+    }
+
+    report.damaged_frames = reader.damaged;
+    Ok(report)
+}
+
+/// Scan the decompressed stream for the next block that is a valid tar
+/// header. Returns it together with whatever was read after it.
+fn find_header<R: Read>(reader: &mut R, skipped: &mut u64) -> Option<Vec<u8>> {
+    let mut window = Vec::with_capacity(SCAN_CHUNK + BLOCK);
+    let mut chunk = vec![0u8; SCAN_CHUNK];
+    loop {
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => {
+                *skipped += window.len() as u64;
+                return None;
+            }
+            Ok(n) => n,
+            // Already recorded by the frame salvager, which has moved past it
+            Err(_) => continue,
+        };
+        window.extend_from_slice(&chunk[..n]);
+        if let Some(start) = (0..window.len().saturating_sub(BLOCK - 1))
+            .find(|&i| is_tar_header(&window[i..i + BLOCK]))
+        {
+            *skipped += start as u64;
+            return Some(window.split_off(start));
+        }
+        // Keep the tail, a header may straddle two reads
+        let keep = window.len().min(BLOCK - 1);
+        *skipped += (window.len() - keep) as u64;
+        window.drain(..window.len() - keep);
+    }
+}
+
+/// A ustar/GNU header whose checksum matches.
+fn is_tar_header(block: &[u8]) -> bool {
+    if &block[257..262] != b"ustar" {
+        return false;
+    }
+    let stored = std::str::from_utf8(&block[148..156])
+        .ok()
+        .map(|s| s.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|s| u32::from_str_radix(s, 8).ok());
+    // The checksum field itself counts as eight spaces
+    let sum: u32 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u32
+            } else {
+                b as u32
+            }
+        })
+        .sum();
+    stored == Some(sum)
+}
+
+/// Counts bytes taken from the file, to say roughly where damage is.
+struct Counted<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+type Source = Box<dyn BufRead>;
+
+/// Decompresses frame after frame. A read that hits undecodable data fails
+/// once, so the entry being read is known to be incomplete; the next read
+/// continues at the following frame.
+struct FrameSalvager {
+    decoder: Option<zstd::Decoder<'static, Source>>,
+    position: Arc<AtomicU64>,
+    /// Number of damaged stretches so far, for callers holding a clone
+    damage: Arc<AtomicU64>,
+    damaged: Vec<(u64, String)>,
+    /// Decompressed bytes since the last resync; a false frame magic fails before any
+    produced: u64,
+    /// `--memory-limit-decompress`; frames needing a larger window count as damaged
+    memory_limit: Option<u64>,
+}
+
+impl FrameSalvager {
+    fn new(file: impl Read + 'static, memory_limit: Option<u64>) -> Result<Self> {
+        let position = Arc::new(AtomicU64::new(0));
+        let source: Source = Box::new(BufReader::new(Counted {
+            inner: file,
+            count: position.clone(),
+        }));
+        Ok(FrameSalvager {
+            decoder: Some(decoder(source, memory_limit)?),
+            position,
+            damage: Arc::new(AtomicU64::new(0)),
+            damaged: Vec::new(),
+            produced: 0,
+            memory_limit,
+        })
+    }
+
+    /// Skip to the next frame magic after a decoding error.
+    fn resync(&mut self, error: io::Error) -> io::Result<()> {
+        let offset = self.position.load(Ordering::Relaxed);
+        // Garbage right after a resync belongs to the same damaged stretch
+        if self.produced > 0 || self.damaged.is_empty() {
+            self.damaged.push((offset, error.to_string()));
+            self.damage.fetch_add(1, Ordering::Relaxed);
+        }
+        self.produced = 0;
+
+        let mut source = self
+            .decoder
+            .take()
+            .expect("resync without decoder")
+            .finish();
+        let mut recent = [0u8; 4];
+        loop {
+            let mut byte = [0u8; 1];
+            if source.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            recent.rotate_left(1);
+            recent[3] = byte[0];
+            if recent == ZSTD_MAGIC {
+                let source: Source = Box::new(Cursor::new(ZSTD_MAGIC).chain(source));
+                self.decoder = Some(decoder(source, self.memory_limit)?);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Decoder for the frames from `source` on, within `--memory-limit-decompress`.
+fn decoder(
+    source: Source,
+    memory_limit: Option<u64>,
+) -> io::Result<zstd::Decoder<'static, Source>> {
+    let mut decoder = zstd::Decoder::with_buffer(source)?;
+    if let Some(limit) = memory_limit {
+        decoder.window_log_max(window_log_for(limit))?;
+    }
+    Ok(decoder)
+}
+
+impl Read for FrameSalvager {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(decoder) = self.decoder.as_mut() else {
+            return Ok(0);
+        };
+        match decoder.read(buf) {
+            Ok(n) => {
+                self.produced += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                let message = e.to_string();
+                self.resync(e)?;
+                Err(io::Error::other(format!(
+                    "damaged compressed data: {}",
+                    message
+                )))
+            }
+        }
+    }
+}
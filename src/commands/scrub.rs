@@ -0,0 +1,113 @@
+use anyhow::{Context, Result, bail};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::catalog::Catalog;
+use crate::codec;
+use crate::i18n::t;
+
+enum Outcome {
+    Ok,
+    Corrupt(String),
+    Missing,
+    Skipped(String),
+}
+
+/// Re-verify archives for bit rot. Cataloged archives are checked against
+/// their recorded BLAKE3 digest (or fully decoded when none was stored) and
+/// the result is written back to the catalog; explicit paths are decoded.
+pub fn execute(archives: &[impl AsRef<Path>], catalog_db: Option<&Path>) -> Result<()> {
+    let mut bad = 0;
+
+    for archive in archives {
+        let archive = archive.as_ref();
+        let outcome = if archive.exists() {
+            decode_check(archive)
+        } else {
+            Outcome::Missing
+        };
+        bad += report(&archive.to_string_lossy(), &outcome);
+    }
+
+    if let Some(db) = catalog_db {
+        let catalog = Catalog::open(db)?;
+        for record in catalog.list()? {
+            let path = Path::new(&record.location);
+            let outcome = if record.location.contains("://") {
+                Outcome::Skipped("remote locations are not supported".to_string())
+            } else if !path.exists() {
+                Outcome::Missing
+            } else if let Some(expected) = &record.checksum {
+                match hash_file(path) {
+                    Ok(actual) if &actual == expected => Outcome::Ok,
+                    Ok(actual) => Outcome::Corrupt(format!(
+                        "checksum mismatch: expected {}, got {}",
+                        expected, actual
+                    )),
+                    Err(e) => Outcome::Corrupt(e.to_string()),
+                }
+            } else {
+                decode_check(path)
+            };
+            bad += report(&record.location, &outcome);
+
+            let (status, detail) = match &outcome {
+                Outcome::Ok => ("ok", None),
+                Outcome::Corrupt(d) => ("corrupt", Some(d.as_str())),
+                Outcome::Missing => ("missing", None),
+                Outcome::Skipped(d) => ("skipped", Some(d.as_str())),
+            };
+            catalog.record_scrub(record.id, status, detail)?;
+        }
+    }
+
+    if bad > 0 {
+        bail!(t!("scrub-failed", count = bad));
+    }
+    Ok(())
+}
+
+fn report(name: &str, outcome: &Outcome) -> usize {
+    match outcome {
+        Outcome::Ok => {
+            println!("OK       {}", name);
+            0
+        }
+        Outcome::Corrupt(detail) => {
+            println!("CORRUPT  {} ({})", name, detail);
+            1
+        }
+        Outcome::Missing => {
+            println!("MISSING  {}", name);
+            1
+        }
+        Outcome::Skipped(detail) => {
+            println!("SKIPPED  {} ({})", name, detail);
+            0
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    io::copy(&mut BufReader::new(file), &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Decompress the whole archive and walk every tar entry, discarding the data.
+fn decode_check(path: &Path) -> Outcome {
+    let check = || -> Result<()> {
+        let mut archive = tar::Archive::new(codec::open_archive(path, None)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            io::copy(&mut entry.by_ref(), &mut io::sink())?;
+        }
+        Ok(())
+    };
+    match check() {
+        Ok(()) => Outcome::Ok,
+        Err(e) => Outcome::Corrupt(e.to_string()),
+    }
+}
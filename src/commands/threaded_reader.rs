@@ -0,0 +1,305 @@
+#[cfg(target_os = "linux")]
+use crate::commands::pack::open_with_flags;
+use crate::commands::pack::{
+    CHUNK_SIZE, LARGE_FILE_STREAM_CAPACITY, LinkTarget, MEMORY_FILE_THRESHOLD, Prepared,
+    ReadOptions, TarEntry, map_file, open_source, pooled_buffer, prepare_path, recheck, use_mmap,
+    with_retries,
+};
+use crate::profiling::{PipelineProfile, Stage};
+use crate::skips::SkipLog;
+use crate::utils::FileId;
+use crate::watchdog::Heartbeat;
+use anyhow::Result;
+use crossbeam_channel::Receiver;
+use dashmap::DashMap;
+use indicatif::ProgressBar;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Blocking counterpart of compio_reader: one OS thread per worker, plain
+// `read` calls and the same bounded channels, used with `--io-backend
+// threaded` or where async file IO is unavailable.
+
+/// Buffer address, file offset and length alignment for direct IO. 4KB
+/// covers the logical block size of every common disk.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Start `options.workers` reader threads competing for paths.
+#[allow(clippy::too_many_arguments)]
+pub fn start_threaded_workers(
+    path_rx: flume::Receiver<PathBuf>,
+    content_tx: flume::Sender<Result<TarEntry>>,
+    pool_rx: Receiver<Vec<u8>>,
+    input_dir: PathBuf,
+    pb: Arc<ProgressBar>,
+    inode_cache: Arc<DashMap<FileId, LinkTarget>>,
+    heartbeat: Heartbeat,
+    skips: SkipLog,
+    profile: Arc<PipelineProfile>,
+    options: ReadOptions,
+) -> Vec<std::thread::JoinHandle<()>> {
+    (0..std::cmp::max(1, options.workers))
+        .map(|_| {
+            let path_rx = path_rx.clone();
+            let content_tx = content_tx.clone();
+            let pool_rx = pool_rx.clone();
+            let input_dir = input_dir.clone();
+            let pb = pb.clone();
+            let inode_cache = inode_cache.clone();
+            let heartbeat = heartbeat.clone();
+            let skips = skips.clone();
+            let profile = profile.clone();
+            std::thread::spawn(move || {
+                while let Ok(path) = path_rx.recv() {
+                    let t = profile.start();
+                    process_path(
+                        &path,
+                        &input_dir,
+                        &content_tx,
+                        &pool_rx,
+                        &pb,
+                        &inode_cache,
+                        &heartbeat,
+                        &skips,
+                        &profile,
+                        options,
+                    );
+                    profile.stop(Stage::Read, t);
+                }
+            })
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_path(
+    path: &Path,
+    base_path: &Path,
+    content_tx: &flume::Sender<Result<TarEntry>>,
+    pool_rx: &Receiver<Vec<u8>>,
+    pb: &ProgressBar,
+    inode_cache: &DashMap<FileId, LinkTarget>,
+    heartbeat: &Heartbeat,
+    skips: &SkipLog,
+    profile: &PipelineProfile,
+    options: ReadOptions,
+) {
+    heartbeat.beat("read", path);
+
+    let process = || -> Result<()> {
+        let (relative_path, mut stamp, mut metadata) =
+            match prepare_path(path, base_path, inode_cache, skips, options)? {
+                Some(Prepared::File(relative_path, stamp, metadata)) => {
+                    (relative_path, stamp, metadata)
+                }
+                Some(Prepared::Entry(entry)) => {
+                    let relative_path = entry.path().to_path_buf();
+                    send_timed(content_tx, Ok(entry), profile)?;
+                    pb.inc(1);
+                    pb.set_message(format!("{:?}", relative_path));
+                    return Ok(());
+                }
+                None => return Ok(()),
+            };
+
+        let len = stamp.len;
+        // Open first so an unreadable file can still be skipped before its header is emitted
+        if len >= options.stream_threshold {
+            // Only huge files bypass the page cache; for small ones the
+            // alignment overhead isn't worth it
+            let (mut file, direct) = if options.direct_io && len >= MEMORY_FILE_THRESHOLD {
+                with_retries(path, options, skips, || open_direct(path, options))?
+            } else {
+                let file = with_retries(path, options, skips, || open_source(path, options))?;
+                (file, false)
+            };
+            let (chunk_tx, chunk_rx) = flume::bounded(LARGE_FILE_STREAM_CAPACITY);
+            let changed = Arc::new(AtomicBool::new(false));
+            send_timed(
+                content_tx,
+                Ok(TarEntry::LargeFile(
+                    relative_path.clone(),
+                    len,
+                    metadata.clone(),
+                    chunk_rx,
+                    changed.clone(),
+                )),
+                profile,
+            )?;
+
+            let mut pos = 0;
+            while pos < len {
+                let chunk_size = std::cmp::min(len - pos, CHUNK_SIZE) as usize;
+                let (mut buf, start, want) = if direct {
+                    // Over-allocate so an aligned window of whole blocks fits
+                    let want = chunk_size.next_multiple_of(DIRECT_IO_ALIGN);
+                    let buf = pooled_buffer(pool_rx, want + DIRECT_IO_ALIGN);
+                    let start = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+                    (buf, start, want)
+                } else {
+                    (pooled_buffer(pool_rx, chunk_size), 0, chunk_size)
+                };
+                // A failed read leaves the file position where it was
+                let read = with_retries(path, options, skips, || {
+                    file.read(&mut buf[start..start + want])
+                });
+                let n = match read {
+                    Ok(0) => Err(anyhow::anyhow!("File shrank while reading")),
+                    Ok(n) => Ok(n),
+                    Err(e) => Err(e.into()),
+                };
+                let n = match n {
+                    Ok(n) => n,
+                    Err(e) => {
+                        // The header is already out; fail the stream so the writer aborts
+                        let _ =
+                            chunk_tx.send(Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)));
+                        return Err(e);
+                    }
+                };
+                // A file that grew since it was stat'ed is cut at its archived size
+                let n = n.min(chunk_size);
+                if start > 0 {
+                    buf.copy_within(start..start + n, 0);
+                }
+                buf.truncate(n);
+                heartbeat.beat("read", path);
+
+                // Checked before the last chunk goes out, while a change can still fail the entry
+                if pos + n as u64 >= len
+                    && let Err(e) = recheck(path, &mut stamp, &mut metadata, None, options, skips)
+                {
+                    let _ = chunk_tx.send(Err(anyhow::anyhow!("Failed to read {:?}: {}", path, e)));
+                    return Err(e);
+                }
+                // Seen by the writer once it has the last chunk
+                changed.store(metadata.changed, Ordering::Relaxed);
+                send_timed(&chunk_tx, Ok(buf), profile)?;
+                pos += n as u64;
+            }
+        } else if use_mmap(len, options) {
+            let mut map = with_retries(path, options, skips, || map_file(path, options))?;
+            // The writer copies the mapping later; this only sees changes made until now
+            let mut reads = 1;
+            while recheck(path, &mut stamp, &mut metadata, Some(reads), options, skips)? {
+                map = with_retries(path, options, skips, || map_file(path, options))?;
+                reads += 1;
+            }
+            send_timed(
+                content_tx,
+                Ok(TarEntry::MappedFile(relative_path.clone(), map, metadata)),
+                profile,
+            )?;
+        } else {
+            let mut file = with_retries(path, options, skips, || open_source(path, options))?;
+            let mut reads = 1;
+            let buf = loop {
+                let mut buf = pooled_buffer(pool_rx, stamp.len as usize);
+                // Read the file again from the start after a failure part way through
+                let n = with_retries(path, options, skips, || {
+                    file.rewind()?;
+                    read_full(&mut file, &mut buf)
+                })?;
+                // Drop stale pool bytes past what was actually read
+                buf.truncate(n);
+                if !recheck(path, &mut stamp, &mut metadata, Some(reads), options, skips)? {
+                    break buf;
+                }
+                reads += 1;
+            };
+
+            send_timed(
+                content_tx,
+                Ok(TarEntry::SmallFile(relative_path.clone(), buf, metadata)),
+                profile,
+            )?;
+        }
+        pb.inc(1);
+        pb.set_message(format!("{:?}", relative_path));
+        Ok(())
+    };
+
+    if let Err(e) = process() {
+        if options.ignore_errors {
+            skips.skip("Failed to process", path, e.as_ref());
+        } else {
+            let _ = content_tx.send(Err(anyhow::anyhow!("Failed to process {:?}: {}", path, e)));
+        }
+    }
+}
+
+/// Open `path` bypassing the page cache. Filesystems without direct IO
+/// (tmpfs, some FUSE and network mounts) get a regular handle instead;
+/// the flag says which one it is.
+fn open_direct(path: &Path, options: ReadOptions) -> std::io::Result<(File, bool)> {
+    #[cfg(target_os = "linux")]
+    {
+        match open_with_flags(path, libc::O_DIRECT, options) {
+            Ok(file) => Ok((file, true)),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                Ok((open_source(path, options)?, false))
+            }
+            Err(e) => Err(e),
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // F_NOCACHE has no alignment requirements, so the buffers stay as they are
+        let file = open_source(path, options)?;
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+        Ok((file, false))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::{
+            FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_NO_BUFFERING,
+        };
+        let backup = if options.backup_semantics {
+            FILE_FLAG_BACKUP_SEMANTICS
+        } else {
+            0
+        };
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(FILE_FLAG_NO_BUFFERING | backup)
+            .open(path)
+        {
+            Ok(file) => Ok((file, true)),
+            Err(_) => Ok((open_source(path, options)?, false)),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        Ok((open_source(path, options)?, false))
+    }
+}
+
+/// Fill `buf` unless the file ends first; returns the bytes read.
+fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Send on a bounded channel, booking the time spent waiting for room as
+/// `Stage::ReadBlocked`.
+fn send_timed<T>(tx: &flume::Sender<T>, value: T, profile: &PipelineProfile) -> Result<()> {
+    let t = profile.start();
+    let res = tx
+        .send(value)
+        .map_err(|_| anyhow::anyhow!("Channel closed"));
+    profile.stop(Stage::ReadBlocked, t);
+    res
+}
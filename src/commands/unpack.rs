@@ -1,41 +1,169 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use crossbeam_channel::Receiver;
+use dashmap::{DashMap, DashSet};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tar::Archive;
 
+use crate::codec;
+use crate::dialect::{self, EntryKind};
+use crate::i18n::t;
+use crate::idmap::{IdMapper, IdRange};
+use crate::manifest;
+use crate::oci::{self, Whiteout};
+use crate::sanitize;
+use crate::seekable;
+use crate::utils::{
+    CAPABILITY_XATTR, MAC_XATTR_PREFIX, PAX_XATTR_PREFIX, SELINUX_XATTR, Xattrs, build_excludes,
+    free_space, is_excluded, is_stdio, write_xattrs,
+};
+pub use crate::validate::ExtractLimits;
+
 const LARGE_FILE_THRESHOLD: u64 = 10 * 1024 * 1024; // 10MB
 
+/// Manifest mismatches listed before the rest are only counted
+const MISMATCHES_SHOWN: usize = 20;
+
+/// Process exit code when extraction finished but some links could not be created
+pub const EXIT_LINK_FAILURES: i32 = 3;
+
 enum UnpackTask {
     File {
         path: PathBuf,
         data: Vec<u8>,
         mode: u32,
         mtime: u64,
+        owner: Option<Owner>,
+        xattrs: Xattrs,
     },
 }
 
+/// Archived owner IDs, applied only with `--rootless-idmap`. `None` where
+/// the archive has no numeric owner, which then isn't changed.
+#[derive(Clone, Copy)]
+struct Owner {
+    uid: u64,
+    gid: u64,
+}
+
 struct DirMetadata {
     path: PathBuf,
     mode: u32,
     mtime: u64,
+    owner: Option<Owner>,
+    xattrs: Xattrs,
+}
+
+struct HardlinkTask {
+    path: PathBuf,
+    target: PathBuf,
+    mode: u32,
+    mtime: u64,
+    /// Only for reflink copies, which are inodes of their own
+    owner: Option<Owner>,
 }
 
 struct SymlinkTask {
     path: PathBuf,
     target: PathBuf,
-    #[allow(dead_code)] // mtime for symlinks is hard to set portably
+    #[cfg_attr(not(unix), allow(dead_code))] // Not restored on Windows
     mtime: u64,
+    owner: Option<Owner>,
+}
+
+pub struct UnpackOptions {
+    pub threads: u32,
+    /// Materialize hardlinked duplicates as reflink clones instead of hardlinks
+    pub reflink_dups: bool,
+    /// Restore macOS `com.apple.*` xattrs from PAX records
+    pub mac_metadata: bool,
+    /// Restore Linux file capabilities from PAX records (root only)
+    pub capabilities: bool,
+    /// Restore SELinux contexts from PAX records (SELinux-enabled systems only)
+    pub selinux: bool,
+    /// Shift archived owners into this ID range (`--rootless-idmap`)
+    pub idmap: Option<IdRange>,
+    /// Abort on the first symlink/hardlink that cannot be created
+    pub strict_links: bool,
+    /// Allow extracting into a non-empty directory. Archived files replace
+    /// existing ones, archived directories get their archived metadata, and
+    /// everything not in the archive is left alone.
+    pub merge: bool,
+    /// Delete what `.wh.` whiteout entries name instead of extracting them
+    pub apply_whiteouts: bool,
+    /// Skip entries matching these patterns (see `utils::build_excludes`)
+    pub exclude: Vec<String>,
+    /// Only extract these archive paths and what is below them; empty for everything
+    pub paths: Vec<PathBuf>,
+    /// Number of entries in the archive, when known from the catalog
+    pub expected_entries: Option<u64>,
+    /// Refuse frames whose window needs more memory than this many bytes
+    pub memory_limit: Option<u64>,
+    pub limits: ExtractLimits,
+}
+
+pub struct LinkFailure {
+    pub kind: &'static str,
+    pub path: PathBuf,
+    pub target: PathBuf,
+    pub error: String,
+}
+
+#[derive(Default)]
+pub struct UnpackSummary {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Hardlinks created, or reflink copies with `reflink_dups`
+    pub hardlinks: u64,
+    /// Regular file contents written
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Links that could not be created; everything else was extracted
+    pub link_failures: Vec<LinkFailure>,
+}
+
+impl UnpackSummary {
+    /// One line of counts for the end of a run.
+    pub fn describe(&self) -> String {
+        t!(
+            "unpack-summary",
+            files = self.files,
+            bytes = indicatif::HumanBytes(self.bytes).to_string(),
+            dirs = self.dirs,
+            symlinks = self.symlinks,
+            hardlinks = self.hardlinks,
+            seconds = format!("{:.1}", self.elapsed.as_secs_f64())
+        )
+    }
 }
 
-pub fn execute(input: &Path, output: &Path, threads: u32) -> Result<()> {
-    let file = File::open(input).context("Failed to open input file")?;
-    let decoder = zstd::Decoder::new(file)?;
-    let mut archive = Archive::new(decoder);
+pub fn execute(input: &Path, output: &Path, options: UnpackOptions) -> Result<UnpackSummary> {
+    let started = Instant::now();
+    let threads = options.threads;
+    let merging = is_non_empty_dir(output)?;
+    if merging && !options.merge {
+        bail!(t!("output-not-empty", output = format!("{:?}", output)));
+    }
+    preflight(input, output, options.expected_entries, merging)?;
+    let excludes = build_excludes(&options.exclude)?;
+    let selection: Vec<PathBuf> = options
+        .paths
+        .iter()
+        .map(|path| without_cur_dir(path))
+        .collect();
+    let mut found = vec![false; selection.len()];
+    let mut archive = Archive::new(open_selection(
+        input,
+        &selection,
+        threads as usize,
+        options.memory_limit,
+    )?);
 
     // Bounded channel to prevent reading the whole archive into memory
     let (tx, rx) = crossbeam_channel::bounded::<UnpackTask>(threads as usize * 16);
@@ -43,67 +171,205 @@ pub fn execute(input: &Path, output: &Path, threads: u32) -> Result<()> {
 
     let mut handles = vec![];
 
+    // Directories known to exist, shared so parents are created once across all workers
+    let created_dirs = Arc::new(DashSet::<PathBuf>::new());
+    // Contents of the files the workers write, for the manifest at the end
+    let hashes = Arc::new(DashMap::<PathBuf, blake3::Hash>::new());
+
+    let idmap = options.idmap.map(|range| Arc::new(IdMapper::new(range)));
+
     // Spawn workers
     for _ in 0..threads {
         let rx_worker = rx.clone();
-        handles.push(thread::spawn(move || worker_loop(rx_worker)));
+        let dirs = created_dirs.clone();
+        let idmap = idmap.clone();
+        let hashes = hashes.clone();
+        handles.push(thread::spawn(move || {
+            worker_loop(rx_worker, dirs, hashes, merging, idmap)
+        }));
+    }
+
+    let mut summary = UnpackSummary::default();
+    let mut entries_seen = 0u64;
+    // Only root may set capabilities. Files come out owned by the extracting
+    // user, so capabilities restored by anyone else would be granted to them.
+    let restore_capabilities = options.capabilities && can_set_capabilities();
+    if options.capabilities && !restore_capabilities {
+        eprintln!("Warning: --capabilities needs root; file capabilities will not be restored");
+    }
+    let mut capabilities_skipped = 0u64;
+    let mut unknown_owners = 0u64;
+    // Contexts are meaningless without a loaded policy, and the kernel refuses them
+    let restore_selinux = options.selinux && selinux_enabled();
+    if options.selinux && !restore_selinux {
+        eprintln!("Warning: SELinux is not enabled on this system; contexts will not be restored");
     }
 
+    // What this archive has put in the output so far, which an opaque
+    // whiteout leaves alone
+    let mut extracted = HashSet::new();
+    let mut whiteouts = 0u64;
+
     // Deferred tasks
     let mut dirs_metadata = Vec::new();
     let mut symlinks = Vec::new();
     let mut hardlinks = Vec::new();
+    // Files written straight from the archive, hashed by reading them back
+    let mut streamed = Vec::new();
+    let mut expected = None;
 
     // Iterate entries
     for entry in archive.entries()? {
         let mut entry = entry?;
-        let entry_path = entry.path()?.to_path_buf();
+        // Checked whatever paths are selected, it comes last
+        if dialect::is_manifest(&mut entry)? {
+            let changed = manifest::changed_files(&mut entry);
+            for path in changed.context("Failed to read the manifest")? {
+                eprintln!("Warning: {:?} changed while it was packed", path);
+            }
+            expected = Some(manifest::read(&mut entry).context("Failed to read the manifest")?);
+            continue;
+        }
+        // `tar -C dir .` and bsdtar name entries "./x"; create_dir_all can't
+        // make "out/." when "out" doesn't exist yet
+        let entry_path = without_cur_dir(&entry.path()?);
+        // Skipped before anything touches the disk; the entry's data is
+        // consumed by the archive iterator
+        if is_excluded(&excludes, &entry_path) {
+            continue;
+        }
+        let kind = dialect::classify(&mut entry)?;
+        if kind == EntryKind::Metadata {
+            continue;
+        }
+        let is_dir = kind == EntryKind::Directory;
+        if !selection.is_empty() && !select(&selection, &mut found, &entry_path, is_dir) {
+            continue;
+        }
+        entries_seen += 1;
+        options.limits.check_entry(entries_seen, &entry_path)?;
+        // Capabilities are always looked for, so dropping them is never silent
+        let mut xattrs = read_pax_xattrs(&mut entry, |name| {
+            (options.mac_metadata && name.starts_with(MAC_XATTR_PREFIX))
+                || (cfg!(target_os = "linux") && name == CAPABILITY_XATTR)
+                || (restore_selinux && name == SELINUX_XATTR)
+        })?;
+        if !restore_capabilities && xattrs.iter().any(|(name, _)| name == CAPABILITY_XATTR) {
+            xattrs.retain(|(name, _)| name != CAPABILITY_XATTR);
+            capabilities_skipped += 1;
+        }
         let target_path = output.join(&entry_path);
 
+        // Only --rootless-idmap looks at owners
+        let owner = read_owner(&mut entry)?;
+        if owner.is_none() && idmap.is_some() {
+            unknown_owners += 1;
+        }
+        // Sparse files count their expanded size, not what the archive stores
+        let size = entry.size();
         let header = entry.header();
-        let entry_type = header.entry_type();
-        let size = header.size()?;
         let mode = header.mode()?;
         let mtime = header.mtime()?;
 
-        // Basic path sanitization check (tar-rs usually handles this)
-        if target_path.strip_prefix(output).is_err() {
-            eprintln!("Skipping unsafe path: {:?}", entry_path);
+        if let Some(reason) = sanitize::reject_reason(&entry_path) {
+            eprintln!("Skipping unsafe path ({}): {:?}", reason, entry_path);
             continue;
         }
+        if options.apply_whiteouts {
+            if kind == EntryKind::File
+                && let Some(whiteout) = oci::whiteout(&entry_path)
+            {
+                if apply_whiteout(output, &whiteout, &extracted)? {
+                    whiteouts += 1;
+                }
+                continue;
+            }
+            extracted.insert(target_path.clone());
+        }
 
-        match entry_type {
-            tar::EntryType::Directory => {
-                // Determine actual disk path (ensure it exists now so files can be written)
-                fs::create_dir_all(&target_path)?;
+        match kind {
+            EntryKind::Metadata => {}
+            EntryKind::Unsupported(what) => {
+                eprintln!("Skipping {} (not supported): {:?}", what, entry_path);
+            }
+            EntryKind::Directory => {
+                // Determine actual disk path (ensure it exists now so files can be written).
+                // The archived mode is only applied once everything is extracted, so a
+                // read-only directory (or one whose entry follows its children) still
+                // accepts files until then.
+                ensure_dir(&target_path, &created_dirs)?;
+                make_owner_writable(&target_path)?;
+                summary.dirs += 1;
                 dirs_metadata.push(DirMetadata {
                     path: target_path,
                     mode,
                     mtime,
+                    owner,
+                    xattrs,
                 });
             }
-            tar::EntryType::Link => {
+            EntryKind::Hardlink => {
                 if let Some(target) = entry.link_name()? {
+                    // A hardlink to a file outside the output would expose or clobber it
+                    if let Some(reason) = sanitize::reject_reason(&target) {
+                        eprintln!(
+                            "Skipping hardlink with unsafe target ({}): {:?} -> {:?}",
+                            reason, entry_path, target
+                        );
+                        continue;
+                    }
                     // Hardlinks must be created at the end to ensure targets exist
-                    hardlinks.push((target_path, output.join(target)));
+                    hardlinks.push(HardlinkTask {
+                        path: target_path,
+                        target: output.join(target),
+                        mode,
+                        mtime,
+                        owner,
+                    });
                 }
             }
-            tar::EntryType::Symlink => {
+            EntryKind::Symlink => {
                 if let Some(target) = entry.link_name()? {
                     symlinks.push(SymlinkTask {
                         path: target_path,
                         target: target.to_path_buf(),
                         mtime,
+                        owner,
                     });
                 }
             }
-            _ => {
-                // Regular file (or contiguous, etc.)
+            EntryKind::File => {
+                summary.files += 1;
+                summary.bytes += size;
+                options.limits.check_output(summary.bytes)?;
                 if size > LARGE_FILE_THRESHOLD {
                     // Process large files immediately in main thread to save memory
                     // We use entry.unpack_in which handles reading and writing
                     // Note: This relies on tar-rs internal logic, which is fine
+                    // tar-rs would restore every xattr in the archive; only
+                    // the ones asked for are written below
+                    entry.set_unpack_xattrs(false);
+                    // Its directory's entry may come later, or never
+                    if let Some(parent) = target_path.parent() {
+                        ensure_dir(parent, &created_dirs)?;
+                    }
                     entry.unpack_in(output)?;
+                    streamed.push(target_path.clone());
+                    if idmap.is_some() || !xattrs.is_empty() {
+                        // unpack_in already applied the mode, possibly read-only, and chown
+                        // drops set-user-ID bits and capabilities: redo them afterwards
+                        set_permissions_and_times(&target_path, mode | 0o200, mtime)?;
+                        if let (Some(idmap), Some(owner)) = (&idmap, owner) {
+                            idmap.apply(&target_path, owner.uid, owner.gid, mode, false);
+                        }
+                        if let Err(e) = write_xattrs(&target_path, &xattrs) {
+                            eprintln!(
+                                "Warning: Failed to restore xattrs on {:?}: {}",
+                                target_path, e
+                            );
+                        }
+                        set_permissions_and_times(&target_path, mode, mtime)?;
+                    }
                 } else {
                     // Small file: buffer and send to worker
                     let mut data = Vec::with_capacity(size as usize);
@@ -114,6 +380,8 @@ pub fn execute(input: &Path, output: &Path, threads: u32) -> Result<()> {
                         data,
                         mode,
                         mtime,
+                        owner,
+                        xattrs,
                     })
                     .context("Failed to send task to worker")?;
                 }
@@ -129,45 +397,96 @@ pub fn execute(input: &Path, output: &Path, threads: u32) -> Result<()> {
         handle.join().unwrap()?;
     }
 
+    // Checked before directory modes are restored, which may lock files away
+    let mismatches = match &expected {
+        Some(expected) => verify_manifest(expected, output, &hashes, &streamed, threads as usize),
+        None => Vec::new(),
+    };
+
     // --- Post Processing ---
 
     // 1. Create Symlinks
     for link in symlinks {
+        if !parent_is_plain(output, &link.path) {
+            let e = anyhow::anyhow!("its directory is reached through a symlink");
+            let (path, target) = (&link.path, &link.target);
+            link_failed(&mut summary, "symlink", path, target, e, &options)?;
+            continue;
+        }
         if let Some(parent) = link.path.parent() {
             fs::create_dir_all(parent)?;
         }
-        #[cfg(unix)]
-        {
-            std::os::unix::fs::symlink(&link.target, &link.path).or_else(|e| {
-                if e.kind() == std::io::ErrorKind::AlreadyExists {
-                    Ok(())
-                } else {
-                    Err(e)
-                }
-            })?;
+        if merging {
+            // Replace what is there, like files and hardlinks do
+            if let Ok(meta) = fs::symlink_metadata(&link.path)
+                && !meta.is_dir()
+            {
+                fs::remove_file(&link.path).ok();
+            }
         }
-        #[cfg(windows)]
-        {
-            // Windows symlinks are tricky. We try file symlink first.
-            // Note: This requires Developer Mode or Admin usually.
-            std::os::windows::fs::symlink_file(&link.target, &link.path)
-                .or_else(|_| std::os::windows::fs::symlink_dir(&link.target, &link.path))
-                .ok(); // Ignore failure for now to avoid crashing on non-admin Windows
+        if let Err(e) = create_symlink(&link.target, &link.path) {
+            link_failed(
+                &mut summary,
+                "symlink",
+                &link.path,
+                &link.target,
+                e.into(),
+                &options,
+            )?;
+            continue;
+        }
+        summary.symlinks += 1;
+        if let (Some(idmap), Some(owner)) = (&idmap, link.owner) {
+            idmap.apply(&link.path, owner.uid, owner.gid, 0o777, true);
+        }
+        #[cfg(unix)]
+        if let Err(e) = set_symlink_mtime(&link.path, link.mtime) {
+            eprintln!("Warning: Failed to set mtime on {:?}: {}", link.path, e);
         }
     }
 
     // 2. Create Hardlinks (Targets should exist now)
-    for (path, target) in hardlinks {
+    // Copies made for --reflink-dups, which count towards the output size
+    let mut cloned_bytes = 0u64;
+    for link in hardlinks {
+        let (path, target) = (&link.path, &link.target);
+        if !parent_is_plain(output, path) || !parent_is_plain(output, target) {
+            let e = anyhow::anyhow!("its directory is reached through a symlink");
+            link_failed(&mut summary, "hardlink", path, target, e, &options)?;
+            continue;
+        }
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
-        // Remove existing file if any (tar overwrite behavior)
-        if path.exists() {
-            fs::remove_file(&path).ok();
+        // Remove existing file if any (tar overwrite behavior), a symlink
+        // itself rather than what it points to
+        if fs::symlink_metadata(path).is_ok_and(|meta| !meta.is_dir()) {
+            fs::remove_file(path).ok();
+        }
+        let result = if options.reflink_dups {
+            // The filesystem may not share extents, in which case it is a full copy
+            if let Ok(meta) = fs::symlink_metadata(target) {
+                cloned_bytes += meta.len();
+                options.limits.check_output(summary.bytes + cloned_bytes)?;
+            }
+            // Independent file sharing the target's extents where the filesystem allows.
+            // A clone is its own inode, so it takes the link's metadata
+            crate::utils::clone_file(target, path)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    if let (Some(idmap), Some(owner)) = (&idmap, link.owner) {
+                        idmap.apply(path, owner.uid, owner.gid, link.mode, false);
+                    }
+                    set_permissions_and_times(path, link.mode, link.mtime)
+                })
+        } else {
+            // A hardlink shares the target's inode and therefore its metadata
+            fs::hard_link(target, path).map_err(anyhow::Error::from)
+        };
+        match result {
+            Ok(()) => summary.hardlinks += 1,
+            Err(e) => link_failed(&mut summary, "hardlink", path, target, e, &options)?,
         }
-        fs::hard_link(&target, &path).with_context(|| {
-            format!("Failed to create hardlink from {:?} to {:?}", target, path)
-        })?;
     }
 
     // 3. Restore Directory Metadata (Deepest first to avoid modifying parent mtimes by accident)
@@ -180,15 +499,379 @@ pub fn execute(input: &Path, output: &Path, threads: u32) -> Result<()> {
     });
 
     for dir in dirs_metadata {
+        if let (Some(idmap), Some(owner)) = (&idmap, dir.owner) {
+            idmap.apply(&dir.path, owner.uid, owner.gid, dir.mode, false);
+        }
+        if let Err(e) = write_xattrs(&dir.path, &dir.xattrs) {
+            eprintln!("Warning: Failed to restore xattrs on {:?}: {}", dir.path, e);
+        }
         set_permissions_and_times(&dir.path, dir.mode, dir.mtime).ok();
         // Ignore errors for dirs (e.g. if removed or permission issues)
     }
 
+    if let Some(idmap) = &idmap {
+        idmap.report();
+    }
+    if unknown_owners > 0 {
+        eprintln!(
+            "Warning: {} entries have no numeric owner in the archive; they kept the extracting user as owner",
+            unknown_owners
+        );
+    }
+
+    if whiteouts > 0 {
+        eprintln!("Applied {} whiteouts", whiteouts);
+    }
+
+    let missing: Vec<&PathBuf> = selection
+        .iter()
+        .zip(&found)
+        .filter(|(_, found)| !**found)
+        .map(|(path, _)| path)
+        .collect();
+    for path in &missing {
+        eprintln!("Not found in archive: {:?}", path);
+    }
+
+    if capabilities_skipped > 0 {
+        eprintln!(
+            "Warning: {} file(s) have Linux file capabilities that were not restored (extract as root with --capabilities)",
+            capabilities_skipped
+        );
+    }
+
+    if !summary.link_failures.is_empty() {
+        eprintln!(
+            "{}",
+            t!("link-failures", count = summary.link_failures.len())
+        );
+        for failure in &summary.link_failures {
+            eprintln!(
+                "  {:<8} {:?} -> {:?}: {}",
+                failure.kind, failure.path, failure.target, failure.error
+            );
+        }
+    }
+
+    if !mismatches.is_empty() {
+        eprintln!(
+            "{} extracted files don't match the archive's manifest:",
+            mismatches.len()
+        );
+        for (path, problem) in mismatches.iter().take(MISMATCHES_SHOWN) {
+            eprintln!("  {:?}: {}", path, problem);
+        }
+        if mismatches.len() > MISMATCHES_SHOWN {
+            eprintln!("  ... and {} more", mismatches.len() - MISMATCHES_SHOWN);
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "{} of the requested paths are not in the archive",
+            missing.len()
+        );
+    }
+    if !mismatches.is_empty() {
+        bail!("{} extracted files are damaged", mismatches.len());
+    }
+
+    summary.elapsed = started.elapsed();
+    Ok(summary)
+}
+
+/// Extracted files whose contents differ from what the manifest says `pack`
+/// read, with what is wrong. Files the workers wrote were hashed from
+/// memory; streamed ones are read back, on `threads` threads. Files the
+/// manifest doesn't list, or that weren't extracted, aren't checked.
+fn verify_manifest(
+    expected: &HashMap<PathBuf, blake3::Hash>,
+    output: &Path,
+    hashes: &DashMap<PathBuf, blake3::Hash>,
+    streamed: &[PathBuf],
+    threads: usize,
+) -> Vec<(PathBuf, String)> {
+    let expected: HashMap<PathBuf, &blake3::Hash> = expected
+        .iter()
+        .map(|(path, hash)| (output.join(without_cur_dir(path)), hash))
+        .collect();
+    let streamed: Vec<&PathBuf> = streamed
+        .iter()
+        .filter(|path| expected.contains_key(*path))
+        .collect();
+    let read_back: Vec<(PathBuf, std::io::Result<blake3::Hash>)> = thread::scope(|scope| {
+        let per_thread = streamed.len().div_ceil(threads.max(1)).max(1);
+        let handles: Vec<_> = streamed
+            .chunks(per_thread)
+            .map(|paths| {
+                scope.spawn(move || {
+                    paths
+                        .iter()
+                        .map(|path| (path.to_path_buf(), manifest::hash_file(path)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let mut mismatches: Vec<(PathBuf, String)> = hashes
+        .iter()
+        .map(|hashed| (hashed.key().clone(), Ok(*hashed.value())))
+        .chain(read_back)
+        .filter_map(|(path, actual)| {
+            let expected = expected.get(&path)?;
+            match actual {
+                Ok(actual) if actual == **expected => None,
+                Ok(_) => Some((path, "contents differ from what was archived".to_string())),
+                Err(e) => Some((path, format!("couldn't read it back: {}", e))),
+            }
+        })
+        .collect();
+    mismatches.sort();
+    mismatches
+}
+
+/// Delete what a whiteout hides from the lower layers. Nothing is removed
+/// through a symlink, which a lower layer may point anywhere; such whiteouts
+/// are skipped and `false` returned.
+fn apply_whiteout(
+    output: &Path,
+    whiteout: &Whiteout,
+    extracted: &HashSet<PathBuf>,
+) -> Result<bool> {
+    let (dir, hidden) = match whiteout {
+        Whiteout::Path(path) => (path.parent().unwrap_or(Path::new("")), Some(path)),
+        Whiteout::Opaque(dir) => (*dir, None),
+    };
+    // The entry path passed, but `.wh..\x` only becomes `..\x` here
+    if hidden.is_some_and(|path| sanitize::reject_reason(path).is_some())
+        || !is_plain_dir(output, dir)
+    {
+        eprintln!(
+            "Skipping whiteout that leads outside the output: {:?}",
+            whiteout_entry(whiteout)
+        );
+        return Ok(false);
+    }
+    let dir = output.join(dir);
+    let doomed: Vec<PathBuf> = match hidden {
+        Some(path) => vec![output.join(path)],
+        None => match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()
+                .with_context(|| format!("Failed to read {:?}", dir))?
+                .into_iter()
+                .filter(|path| !extracted.contains(path))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+        },
+    };
+    for path in doomed {
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            // Whiteouts may name what no lower layer has
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        };
+        removed.with_context(|| format!("Failed to remove {:?}", path))?;
+    }
+    Ok(true)
+}
+
+/// Archive path of the entry `whiteout` came from, for messages.
+fn whiteout_entry(whiteout: &Whiteout) -> PathBuf {
+    match whiteout {
+        Whiteout::Path(path) => oci::whiteout_path(path),
+        Whiteout::Opaque(dir) => dir.join(oci::OPAQUE_WHITEOUT),
+    }
+}
+
+/// Whether `output/dir` is reached without passing through a symlink.
+pub fn is_plain_dir(output: &Path, dir: &Path) -> bool {
+    let mut path = output.to_path_buf();
+    dir.components().all(|component| {
+        path.push(component);
+        !fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink())
+    })
+}
+
+/// Whether the directory of `path`, an entry in `output`, is reached without
+/// going through a symlink. Paths are only checked lexically when they are
+/// read, and a symlink extracted earlier could lead a link created after it
+/// out of the output.
+fn parent_is_plain(output: &Path, path: &Path) -> bool {
+    path.strip_prefix(output)
+        .is_ok_and(|path| path.parent().is_none_or(|dir| is_plain_dir(output, dir)))
+}
+
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// The archive's tar stream. A `pack --seekable` archive has its frames
+/// decoded on up to `threads` threads, or with a selection, only the
+/// entries `select` takes, decoded from the frames holding them.
+fn open_selection(
+    input: &Path,
+    selection: &[PathBuf],
+    threads: usize,
+    memory_limit: Option<u64>,
+) -> Result<Box<dyn Read + Send>> {
+    if is_stdio(input) {
+        return codec::open_archive(input, memory_limit);
+    }
+    if selection.is_empty() {
+        return match seekable::parallel::open(input, threads, memory_limit)? {
+            Some(stream) => Ok(stream),
+            None => codec::open_archive(input, memory_limit),
+        };
+    }
+    let Some(index) = seekable::read_index(input)? else {
+        return codec::open_archive(input, memory_limit);
+    };
+    // Marked again as the entries come through
+    let mut found = vec![false; selection.len()];
+    let ranges = index.ranges(|entry| {
+        let is_dir = entry.kind == EntryKind::Directory;
+        select(selection, &mut found, Path::new(&entry.path), is_dir)
+    });
+    seekable::open_ranges(input, index, ranges, memory_limit)
+}
+
+/// Whether `unpack ARCHIVE PATH...` extracts `entry_path`: it is one of the
+/// requested paths or below one, marked in `found`, or a directory leading to
+/// one, extracted so it gets its archived metadata. Paths compare by whole
+/// components, so `a/b` doesn't select `a/bc`.
+fn select(selection: &[PathBuf], found: &mut [bool], entry_path: &Path, is_dir: bool) -> bool {
+    let mut selected = false;
+    for (wanted, found) in selection.iter().zip(found) {
+        if entry_path.starts_with(wanted) {
+            *found = true;
+            selected = true;
+        } else if is_dir && wanted.starts_with(entry_path) {
+            selected = true;
+        }
+    }
+    selected
+}
+
+/// Whether a policy is loaded, as libselinux's `is_selinux_enabled` decides it.
+fn selinux_enabled() -> bool {
+    cfg!(target_os = "linux") && Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+/// Setting `security.capability` needs CAP_SETFCAP, which in practice means root.
+fn can_set_capabilities() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    // Windows symlinks are tricky. We try file symlink first.
+    // Note: This requires Developer Mode or Admin usually.
+    std::os::windows::fs::symlink_file(target, path)
+        .or_else(|_| std::os::windows::fs::symlink_dir(target, path))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Record a link that could not be created, or abort with `--strict-links`.
+fn link_failed(
+    summary: &mut UnpackSummary,
+    kind: &'static str,
+    path: &Path,
+    target: &Path,
+    error: anyhow::Error,
+    options: &UnpackOptions,
+) -> Result<()> {
+    if options.strict_links {
+        bail!(
+            "Failed to create {} {:?} -> {:?}: {}",
+            kind,
+            path,
+            target,
+            error
+        );
+    }
+    summary.link_failures.push(LinkFailure {
+        kind,
+        path: path.to_path_buf(),
+        target: target.to_path_buf(),
+        error: error.to_string(),
+    });
     Ok(())
 }
 
-fn worker_loop(rx: Arc<Receiver<UnpackTask>>) -> Result<()> {
-    let mut created_dirs = std::collections::HashSet::new();
+/// Xattrs stored as `SCHILY.xattr.<name>` PAX records whose names are accepted by `filter`.
+fn read_pax_xattrs<R: Read>(
+    entry: &mut tar::Entry<R>,
+    filter: impl Fn(&str) -> bool,
+) -> Result<Xattrs> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+    let mut xattrs = Vec::new();
+    for ext in extensions {
+        let ext = ext?;
+        if let Ok(key) = ext.key()
+            && let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX)
+            && filter(name)
+        {
+            xattrs.push((name.to_string(), ext.value_bytes().to_vec()));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Archived owner of `entry`: the pax `uid`/`gid` records, which hold IDs
+/// too large for the header, else the header fields. `None` if an ID is
+/// blank or not a number, rather than a guess like root.
+fn read_owner<R: Read>(entry: &mut tar::Entry<R>) -> Result<Option<Owner>> {
+    let header = entry.header();
+    let (mut uid, mut gid) = (header.uid().ok(), header.gid().ok());
+    for ext in entry.pax_extensions()?.into_iter().flatten() {
+        let ext = ext?;
+        let id = || ext.value().ok().and_then(|value| value.parse().ok());
+        match ext.key_bytes() {
+            b"uid" => uid = id(),
+            b"gid" => gid = id(),
+            _ => {}
+        }
+    }
+    Ok(uid.zip(gid).map(|(uid, gid)| Owner { uid, gid }))
+}
+
+fn worker_loop(
+    rx: Arc<Receiver<UnpackTask>>,
+    created_dirs: Arc<DashSet<PathBuf>>,
+    hashes: Arc<DashMap<PathBuf, blake3::Hash>>,
+    merging: bool,
+    idmap: Option<Arc<IdMapper>>,
+) -> Result<()> {
     while let Ok(task) = rx.recv() {
         match task {
             UnpackTask::File {
@@ -196,17 +879,33 @@ fn worker_loop(rx: Arc<Receiver<UnpackTask>>) -> Result<()> {
                 data,
                 mode,
                 mtime,
+                owner,
+                xattrs,
             } => {
-                if let Some(parent) = path.parent()
-                    && !created_dirs.contains(parent) {
-                        fs::create_dir_all(parent)?;
-                        created_dirs.insert(parent.to_path_buf());
-                    }
+                if let Some(parent) = path.parent() {
+                    ensure_dir(parent, &created_dirs)?;
+                }
+
+                // Never write through a symlink that was already in the target
+                if merging {
+                    remove_existing_symlink(&path)?;
+                }
 
                 {
                     let mut file = File::create(&path)?;
                     file.write_all(&data)?;
                 } // File closed here
+                hashes.insert(path.clone(), blake3::hash(&data));
+
+                // chown resets set-user-ID bits and capabilities, so it goes first
+                if let (Some(idmap), Some(owner)) = (&idmap, owner) {
+                    idmap.apply(&path, owner.uid, owner.gid, mode, false);
+                }
+
+                // Before permissions: a read-only mode would block setting xattrs
+                if let Err(e) = write_xattrs(&path, &xattrs) {
+                    eprintln!("Warning: Failed to restore xattrs on {:?}: {}", path, e);
+                }
 
                 set_permissions_and_times(&path, mode, mtime)?;
             }
@@ -215,7 +914,141 @@ fn worker_loop(rx: Arc<Receiver<UnpackTask>>) -> Result<()> {
     Ok(())
 }
 
-fn set_permissions_and_times(path: &Path, mode: u32, mtime: u64) -> Result<()> {
+/// Set the mtime of the link itself rather than of what it points to.
+#[cfg(unix)]
+fn set_symlink_mtime(path: &Path, mtime: u64) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        // Access time: leave as is
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        },
+    ];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Fail before extracting anything when the destination can't hold the
+/// archive. The uncompressed size is unknown, so the archive's own size is
+/// the lower bound; inodes are only checked when the entry count is known.
+/// When merging, replaced files free space again, so this only warns.
+fn preflight(input: &Path, output: &Path, entries: Option<u64>, merging: bool) -> Result<()> {
+    let Some(free) = free_space(output) else {
+        return Ok(());
+    };
+    // A pipe's size isn't known up front
+    let archive_size = if is_stdio(input) {
+        0
+    } else {
+        fs::metadata(input)
+            .context("Failed to open input file")?
+            .len()
+    };
+
+    let mut problems = Vec::new();
+    if free.bytes < archive_size {
+        problems.push(format!(
+            "{} free, but the compressed archive alone is {}",
+            indicatif::HumanBytes(free.bytes),
+            indicatif::HumanBytes(archive_size)
+        ));
+    }
+    if let (Some(entries), Some(inodes)) = (entries, free.inodes)
+        && inodes < entries
+    {
+        problems.push(format!("{} free inodes for {} entries", inodes, entries));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let message = format!("Not enough room in {:?}: {}", output, problems.join("; "));
+    if merging {
+        eprintln!("Warning: {}", message);
+        Ok(())
+    } else {
+        bail!(message)
+    }
+}
+
+/// Whether `dir` exists and already has entries.
+pub(crate) fn is_non_empty_dir(dir: &Path) -> Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+    }
+}
+
+fn remove_existing_symlink(path: &Path) -> Result<()> {
+    if let Ok(meta) = fs::symlink_metadata(path)
+        && meta.file_type().is_symlink()
+    {
+        fs::remove_file(path).with_context(|| format!("Failed to replace symlink {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Give the owner full access to an existing directory, e.g. a read-only one
+/// left behind by an earlier extraction, so children can be created in it.
+/// Another user's directory stays as it is; writing into it may still work.
+#[cfg(unix)]
+fn make_owner_writable(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(dir)?.permissions();
+    if perms.mode() & 0o700 != 0o700 {
+        perms.set_mode(perms.mode() | 0o700);
+        match fs::set_permissions(dir, perms) {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                eprintln!("Warning: Can't make {:?} writable: {}", dir, e);
+            }
+            result => result.with_context(|| format!("Failed to make {:?} writable", dir))?,
+        }
+    }
+    Ok(())
+}
+
+/// Windows' read-only attribute does not stop files from being created in a directory.
+#[cfg(not(unix))]
+fn make_owner_writable(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Create `dir` and its parents unless it is already known to exist.
+fn ensure_dir(dir: &Path, created: &DashSet<PathBuf>) -> std::io::Result<()> {
+    if created.contains(dir) {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    // create_dir_all made sure every ancestor exists as well
+    for ancestor in dir.ancestors() {
+        if !created.insert(ancestor.to_path_buf()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn set_permissions_and_times(path: &Path, mode: u32, mtime: u64) -> Result<()> {
     // 1. Set mtime FIRST (before permissions, as setting readonly may block the file)
     let mtime_system = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime);
     if let Ok(file) = File::open(path) {
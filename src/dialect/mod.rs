@@ -0,0 +1,82 @@
+//! Entry types of the tar dialects zstar reads (GNU, pax, BSD/libarchive,
+//! star, old V7) mapped to what extraction does with them. tar-rs already
+//! applies GNU long names, pax records and old-GNU sparse maps; what is left
+//! to decide is which typeflags produce a file and which are skipped.
+
+use anyhow::Result;
+use std::io::Read;
+
+/// Archive path of the manifest `pack` ends its archives with (see `manifest`)
+pub const MANIFEST_NAME: &str = ".zstar-manifest";
+
+/// PAX record telling the manifest apart from a file that happens to have its name
+pub const PAX_MANIFEST: &str = "ZSTAR.manifest";
+
+/// What an archive entry extracts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    /// Regular file; sparse files are read back with their holes filled in
+    File,
+    Symlink,
+    Hardlink,
+    /// Describes the archive rather than a file (pax global header, GNU
+    /// volume label, zstar's manifest); nothing to extract
+    Metadata,
+    /// A file zstar can't recreate; skipped with a warning naming it
+    Unsupported(&'static str),
+}
+
+pub fn classify<R: Read>(entry: &mut tar::Entry<R>) -> Result<EntryKind> {
+    Ok(match entry.header().entry_type().as_byte() {
+        // 'D' is a GNU incremental dumpdir: a directory whose data lists its contents
+        b'5' | b'D' => EntryKind::Directory,
+        b'2' => EntryKind::Symlink,
+        b'1' => EntryKind::Hardlink,
+        // `git archive` and libarchive write a global header first. 'X' is the
+        // Solaris extended header, which tar-rs doesn't apply; the entry it
+        // describes still follows with its ustar name. Long names and local pax
+        // headers are normally folded into the next entry already.
+        b'g' | b'V' | b'X' | b'x' | b'L' | b'K' => EntryKind::Metadata,
+        b'3' => EntryKind::Unsupported("character device"),
+        b'4' => EntryKind::Unsupported("block device"),
+        b'6' => EntryKind::Unsupported("FIFO"),
+        b'M' => EntryKind::Unsupported("multi-volume continuation"),
+        // Pre-POSIX archives mark directories only with a trailing slash
+        _ if entry.path_bytes().ends_with(b"/") => EntryKind::Directory,
+        _ if is_manifest(entry)? => EntryKind::Metadata,
+        // GNU tar's pax sparse formats keep the map inside the data, which
+        // would be extracted as file contents
+        _ if is_pax_sparse(entry)? => EntryKind::Unsupported("pax sparse file"),
+        // Unknown vendor types are extracted as regular files, as GNU tar does
+        _ => EntryKind::File,
+    })
+}
+
+fn is_pax_sparse<R: Read>(entry: &mut tar::Entry<R>) -> Result<bool> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(false);
+    };
+    for ext in extensions {
+        if ext?.key_bytes().starts_with(b"GNU.sparse.") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `entry` is the manifest `pack` ends its archives with.
+pub fn is_manifest<R: Read>(entry: &mut tar::Entry<R>) -> Result<bool> {
+    if entry.path_bytes().as_ref() != MANIFEST_NAME.as_bytes() {
+        return Ok(false);
+    }
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(false);
+    };
+    for extension in extensions {
+        if extension?.key_bytes() == PAX_MANIFEST.as_bytes() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,314 @@
+//! Archives encrypted with `pack --encrypt`: the compressed archive cut into
+//! 1MB chunks, each sealed with AES-256-GCM under a key derived from the
+//! passphrase with Argon2id. The header carries the KDF parameters, salt
+//! and nonce prefix; every chunk's nonce ends in its number and a flag for
+//! the last one, so reordered, dropped or truncated chunks fail to open.
+//!
+//! Layout: `ZSTARENC`, version (1 byte), Argon2 memory in KiB, passes and
+//! lanes (u32 LE each), salt (16 bytes), nonce prefix (7 bytes), then the
+//! chunks, all but the last holding exactly `CHUNK_SIZE` bytes plus a tag.
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result, anyhow, bail};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+pub const MAGIC: [u8; 8] = *b"ZSTARENC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 8 + 1 + 12 + SALT_LEN + NONCE_PREFIX_LEN;
+const SALT_LEN: usize = 16;
+/// The rest of the 12-byte nonce is the chunk number (u32 BE) and the last-chunk flag
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+
+/// Plaintext per chunk (1MB)
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Argon2id cost for new archives: 64MB, 3 passes, 4 lanes (RFC 9106)
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_PASSES: u32 = 3;
+const ARGON2_LANES: u32 = 4;
+/// Archives asking for more than this (1GB, 16 passes, 16 lanes) are refused, not derived
+const ARGON2_MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const ARGON2_MAX_PASSES: u32 = 16;
+const ARGON2_MAX_LANES: u32 = 16;
+
+/// Where the passphrase comes from, set once from `--password` / `--key-file`.
+struct Source {
+    password: Option<String>,
+    key_file: Option<PathBuf>,
+}
+
+static SOURCE: OnceLock<Source> = OnceLock::new();
+
+/// Remember the passphrase options for whichever command ends up needing them.
+pub fn init(password: Option<String>, key_file: Option<PathBuf>) {
+    let _ = SOURCE.set(Source { password, key_file });
+}
+
+/// The passphrase: the contents of `--key-file` (less a trailing newline),
+/// `--password`, or typed at the terminal, twice if `confirm` (for a new
+/// archive).
+pub fn passphrase(confirm: bool) -> Result<Vec<u8>> {
+    let source = SOURCE.get();
+    if let Some(path) = source.and_then(|s| s.key_file.as_ref()) {
+        let mut key =
+            std::fs::read(path).with_context(|| format!("Failed to read key file {:?}", path))?;
+        // `echo secret > key` must give the same key as `--password secret`
+        if key.ends_with(b"\n") {
+            key.pop();
+            if key.ends_with(b"\r") {
+                key.pop();
+            }
+        }
+        if key.is_empty() {
+            bail!("Key file {:?} is empty", path);
+        }
+        return Ok(key);
+    }
+    if let Some(password) = source.and_then(|s| s.password.as_ref()) {
+        return Ok(password.clone().into_bytes());
+    }
+
+    let password =
+        rpassword::prompt_password("Passphrase: ").context("Failed to read passphrase")?;
+    if password.is_empty() {
+        bail!("Empty passphrase");
+    }
+    if confirm {
+        let again = rpassword::prompt_password("Repeat passphrase: ")
+            .context("Failed to read passphrase")?;
+        if again != password {
+            bail!("Passphrases don't match");
+        }
+    }
+    Ok(password.into_bytes())
+}
+
+/// Whether a stream starting with `head` was written by `Encryptor`.
+pub fn is_encrypted(head: &[u8]) -> bool {
+    head.starts_with(&MAGIC)
+}
+
+fn derive_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+) -> Result<Aes256Gcm> {
+    let params = argon2::Params::new(memory_kib, passes, lanes, Some(32))
+        .map_err(|e| anyhow!("Invalid key derivation parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key: {}", e))?;
+    Ok(Aes256Gcm::new_from_slice(&key).expect("AES-256 takes a 32-byte key"))
+}
+
+fn nonce(prefix: &[u8], chunk: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&chunk.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Encrypts everything written to it into `inner`. `finish` seals the last
+/// chunk; without it the archive reads as truncated.
+pub struct Encryptor<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    chunk: u32,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    /// Derive a key from `passphrase` with a fresh salt and write the header.
+    pub fn new(mut inner: W, passphrase: &[u8]) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        getrandom::fill(&mut salt)
+            .and_then(|()| getrandom::fill(&mut prefix))
+            .map_err(|e| anyhow!("Failed to get random bytes: {}", e))?;
+        let cipher = derive_key(
+            passphrase,
+            &salt,
+            ARGON2_MEMORY_KIB,
+            ARGON2_PASSES,
+            ARGON2_LANES,
+        )?;
+
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(&MAGIC);
+        header[8] = VERSION;
+        header[9..13].copy_from_slice(&ARGON2_MEMORY_KIB.to_le_bytes());
+        header[13..17].copy_from_slice(&ARGON2_PASSES.to_le_bytes());
+        header[17..21].copy_from_slice(&ARGON2_LANES.to_le_bytes());
+        header[21..21 + SALT_LEN].copy_from_slice(&salt);
+        header[21 + SALT_LEN..].copy_from_slice(&prefix);
+        inner
+            .write_all(&header)
+            .context("Failed to write archive")?;
+
+        Ok(Encryptor {
+            inner,
+            cipher,
+            header,
+            chunk: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_LEN),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let nonce = nonce(&self.header[21 + SALT_LEN..], self.chunk, last);
+        self.cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), &self.header, &mut self.buf)
+            .map_err(|_| io::Error::other("Failed to encrypt archive"))?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.chunk = self
+            .chunk
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Archive too large to encrypt"))?;
+        Ok(())
+    }
+
+    /// Seal what is left as the last chunk and hand back the sink.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full chunk waits for more data, so the last one is never sealed as a middle one
+        if self.buf.len() == CHUNK_SIZE && !data.is_empty() {
+            self.seal(false)?;
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an archive written by `Encryptor`.
+pub struct Decryptor<R: Read> {
+    inner: BufReader<R>,
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    chunk: u32,
+    done: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// Read the header and derive the key from `passphrase`.
+    pub fn new(inner: R, passphrase: &[u8]) -> Result<Self> {
+        let mut inner = BufReader::new(inner);
+        let mut header = [0u8; HEADER_LEN];
+        inner
+            .read_exact(&mut header)
+            .context("Encrypted archive is truncated")?;
+        if !is_encrypted(&header) {
+            bail!("Not an encrypted archive");
+        }
+        if header[8] != VERSION {
+            bail!("Unsupported encrypted archive version {}", header[8]);
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let (memory_kib, passes, lanes) = (field(9), field(13), field(17));
+        if memory_kib > ARGON2_MAX_MEMORY_KIB {
+            bail!(
+                "Encrypted archive asks for {} MB to derive its key",
+                memory_kib / 1024
+            );
+        }
+        if passes > ARGON2_MAX_PASSES || lanes > ARGON2_MAX_LANES {
+            bail!(
+                "Encrypted archive asks for {} passes over {} lanes to derive its key",
+                passes,
+                lanes
+            );
+        }
+        let cipher = derive_key(
+            passphrase,
+            &header[21..21 + SALT_LEN],
+            memory_kib,
+            passes,
+            lanes,
+        )?;
+
+        Ok(Decryptor {
+            inner,
+            cipher,
+            header,
+            chunk: 0,
+            done: false,
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_LEN),
+            pos: 0,
+        })
+    }
+
+    /// Decrypt the next chunk into `buf`; the last one is shorter than a full
+    /// chunk or followed by the end of the stream.
+    fn open_chunk(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        (&mut self.inner)
+            .take((CHUNK_SIZE + TAG_LEN) as u64)
+            .read_to_end(&mut self.buf)?;
+        if self.buf.len() < TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Encrypted archive is truncated",
+            ));
+        }
+        let last = self.buf.len() < CHUNK_SIZE + TAG_LEN || self.inner.fill_buf()?.is_empty();
+
+        let nonce = nonce(&self.header[21 + SALT_LEN..], self.chunk, last);
+        if self
+            .cipher
+            .decrypt_in_place(Nonce::from_slice(&nonce), &self.header, &mut self.buf)
+            .is_err()
+        {
+            let message = if self.chunk == 0 {
+                "Wrong passphrase, or the archive is damaged".to_string()
+            } else {
+                format!(
+                    "Encrypted archive is damaged or truncated at chunk {}",
+                    self.chunk
+                )
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        self.chunk = self.chunk.wrapping_add(1);
+        self.pos = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,40 @@
+# Messages printed by the CLI. Paths arrive already quoted.
+
+## pack
+
+compression-level = Compression level { $level }: { $reason }
+pack-done = Done via { $backend } reader
+pack-done-hardlinks = Done via { $backend } reader, { $hardlinks } hardlinks
+output-locked = Another zstar is writing { $output }; not touching it
+
+## unpack and restore
+
+unpack-summary =
+    { $files } files ({ $bytes }), { $dirs } directories, { $symlinks } symlinks, { $hardlinks } hardlinks in { $seconds }s
+unpacked = Unpacked { $input } to { $output }: { $summary }
+restoring = Restoring { $archive } (archive #{ $id })
+restored = Restored profile { $profile } to { $output }: { $summary }
+link-failures = { $count } link(s) could not be restored:
+output-not-empty = Output directory { $output } is not empty; pass --merge to extract into it
+mac-metadata-unsupported = --mac-metadata is only supported on macOS
+capabilities-unsupported = --capabilities is only supported on Linux
+selinux-unsupported = --selinux is only supported on Linux
+owner-filter-unsupported = --exclude-owner and --only-owner are only supported on Unix
+idmap-unsupported = --rootless-idmap is only supported on Unix
+
+## unpack --parse-only, --salvage and --compare-extract
+
+stream-summary =
+    { $entries } entries: { $files } files ({ $bytes }), { $dirs } directories, { $symlinks } symlinks, { $hardlinks } hardlinks, { $unsafe_paths } unsafe paths
+parsed = Parsed { $input }: { $summary }
+not-an-archive = { $input } is not a valid archive
+salvaged = Salvaged { $input } to { $output }: { $restored } entries restored, { $lost } lost
+compared = Compared { $input } with { $output }: { $entries } entries, { $differences } differences
+
+## merge, rotate, scrub
+
+merged = Merged { $archives } archives into { $output } ({ $entries } entries)
+rotate-summary = { $matched } archives matched, { $kept } kept, { $deleted } deleted
+rotate-summary-dry-run = { $matched } archives matched, { $kept } kept, { $deleted } would be deleted
+scrub-nothing = Nothing to scrub: pass archive paths and/or --catalog
+scrub-failed = { $count } archive(s) failed verification
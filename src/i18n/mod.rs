@@ -0,0 +1,75 @@
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use std::sync::OnceLock;
+use unic_langid::LanguageIdentifier;
+
+const EN_US: &str = include_str!("en-US.ftl");
+const ZH_CN: &str = include_str!("zh-CN.ftl");
+
+struct Catalog {
+    selected: Option<FluentBundle<FluentResource>>,
+    /// Used for anything missing from the selected translation
+    fallback: FluentBundle<FluentResource>,
+}
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+/// Pick the message language: `lang` if given (`--lang` / `ZSTAR_LANG`),
+/// otherwise the system locale. Anything but Chinese gets English.
+pub fn init(lang: Option<&str>) {
+    let lang = lang.map(str::to_string).or_else(sys_locale::get_locale);
+    let selected = match lang {
+        Some(lang) if lang.to_ascii_lowercase().starts_with("zh") => Some(bundle("zh-CN", ZH_CN)),
+        _ => None,
+    };
+    let _ = CATALOG.set(Catalog {
+        selected,
+        fallback: bundle("en-US", EN_US),
+    });
+}
+
+fn bundle(id: &str, source: &'static str) -> FluentBundle<FluentResource> {
+    let langid: LanguageIdentifier = id.parse().expect("Invalid built-in language id");
+    let resource = FluentResource::try_new(source.to_string())
+        .unwrap_or_else(|(_, errors)| panic!("Invalid {} messages: {:?}", id, errors));
+    let mut bundle = FluentBundle::new_concurrent(vec![langid]);
+    // Output goes to terminals and logs, where bidi isolation marks show up as junk
+    bundle.set_use_isolating(false);
+    bundle
+        .add_resource(resource)
+        .unwrap_or_else(|errors| panic!("Duplicate {} messages: {:?}", id, errors));
+    bundle
+}
+
+/// Format message `key`; use the `t!` macro rather than calling this.
+pub fn message(key: &str, args: Option<&FluentArgs>) -> String {
+    let catalog = CATALOG.get_or_init(|| Catalog {
+        selected: None,
+        fallback: bundle("en-US", EN_US),
+    });
+    for bundle in catalog.selected.iter().chain([&catalog.fallback]) {
+        if let Some(pattern) = bundle.get_message(key).and_then(|m| m.value()) {
+            let mut errors = Vec::new();
+            return bundle
+                .format_pattern(pattern, args, &mut errors)
+                .into_owned();
+        }
+    }
+    key.to_string()
+}
+
+/// `t!("key")` or `t!("key", name = value, ...)`: the message for `key` in
+/// the current language. Values are numbers or strings; format paths with
+/// `{:?}` first so they read the same as elsewhere.
+macro_rules! t {
+    ($key:expr) => {
+        $crate::i18n::message($key, None)
+    };
+    ($key:expr, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = fluent_bundle::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::message($key, Some(&args))
+    }};
+}
+
+pub(crate) use t;
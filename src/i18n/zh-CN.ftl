@@ -0,0 +1,40 @@
+# 命令行输出的中文消息。路径参数已带引号。
+
+## pack
+
+compression-level = 压缩级别 { $level }：{ $reason }
+pack-done = 完成（{ $backend } 读取器）
+pack-done-hardlinks = 完成（{ $backend } 读取器），{ $hardlinks } 个硬链接
+output-locked = 另一个 zstar 正在写入 { $output }，未做任何改动
+
+## unpack 与 restore
+
+unpack-summary =
+    { $files } 个文件（{ $bytes }），{ $dirs } 个目录，{ $symlinks } 个符号链接，{ $hardlinks } 个硬链接，耗时 { $seconds } 秒
+unpacked = 已将 { $input } 解压到 { $output }：{ $summary }
+restoring = 正在恢复 { $archive }（归档 #{ $id }）
+restored = 已将配置 { $profile } 恢复到 { $output }：{ $summary }
+link-failures = { $count } 个链接无法恢复：
+output-not-empty = 输出目录 { $output } 不为空；如需解压到其中请使用 --merge
+mac-metadata-unsupported = --mac-metadata 仅支持 macOS
+capabilities-unsupported = --capabilities 仅支持 Linux
+selinux-unsupported = --selinux 仅支持 Linux
+owner-filter-unsupported = --exclude-owner 与 --only-owner 仅支持 Unix
+idmap-unsupported = --rootless-idmap 仅支持 Unix
+
+## unpack --parse-only、--salvage 与 --compare-extract
+
+stream-summary =
+    { $entries } 个条目：{ $files } 个文件（{ $bytes }），{ $dirs } 个目录，{ $symlinks } 个符号链接，{ $hardlinks } 个硬链接，{ $unsafe_paths } 个不安全路径
+parsed = 已解析 { $input }：{ $summary }
+not-an-archive = { $input } 不是有效的归档
+salvaged = 已从 { $input } 抢救到 { $output }：恢复 { $restored } 个条目，丢失 { $lost } 个
+compared = 已比较 { $input } 与 { $output }：{ $entries } 个条目，{ $differences } 处差异
+
+## merge、rotate、scrub
+
+merged = 已将 { $archives } 个归档合并为 { $output }（{ $entries } 个条目）
+rotate-summary = 匹配 { $matched } 个归档，保留 { $kept } 个，删除 { $deleted } 个
+rotate-summary-dry-run = 匹配 { $matched } 个归档，保留 { $kept } 个，将删除 { $deleted } 个
+scrub-nothing = 没有可校验的归档：请指定归档路径和/或 --catalog
+scrub-failed = { $count } 个归档校验失败
@@ -0,0 +1,134 @@
+//! `unpack --rootless-idmap`: archived owners are shifted into a subordinate
+//! ID range (as listed in /etc/subuid), the layout rootless container
+//! runtimes expect of an image layer. Where the owner can't be changed, the
+//! archived one is kept in the `user.containers.override_stat` xattr that
+//! fuse-overlayfs and containers/storage read in its place.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// `uid:gid:mode` as seen inside the container, for files the extracting
+/// user owns
+pub const OVERRIDE_STAT_XATTR: &str = "user.containers.override_stat";
+
+/// IDs a user namespace can't map show up as the overflow ID, nobody
+const OVERFLOW_ID: u64 = 65534;
+
+/// Host IDs `start..start + count`, container ID 0 being `start`.
+#[derive(Clone, Copy, Debug)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Applies an `IdRange` to extracted entries and counts what happened, from
+/// any number of workers.
+pub struct IdMapper {
+    range: IdRange,
+    /// Only root, possibly inside a user namespace, may hand files to other IDs
+    #[cfg_attr(not(unix), allow(dead_code))]
+    can_chown: bool,
+    chowned: AtomicU64,
+    recorded: AtomicU64,
+    /// Symlinks and files where neither worked
+    unchanged: AtomicU64,
+    /// Entries whose archived owner lies beyond the range
+    overflowed: AtomicU64,
+}
+
+impl IdMapper {
+    pub fn new(range: IdRange) -> Self {
+        #[cfg(unix)]
+        let can_chown = unsafe { libc::geteuid() == 0 };
+        #[cfg(not(unix))]
+        let can_chown = false;
+        IdMapper {
+            range,
+            can_chown,
+            chowned: AtomicU64::new(0),
+            recorded: AtomicU64::new(0),
+            unchanged: AtomicU64::new(0),
+            overflowed: AtomicU64::new(0),
+        }
+    }
+
+    /// Host ID for an archived one; IDs beyond the range get the overflow ID.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    fn map(&self, id: u64) -> (u32, bool) {
+        if id < self.range.count as u64 {
+            (self.range.start + id as u32, false)
+        } else {
+            (self.overflow_id(), true)
+        }
+    }
+
+    fn overflow_id(&self) -> u32 {
+        self.range.start + OVERFLOW_ID.min(self.range.count as u64 - 1) as u32
+    }
+
+    /// Give `path` its archived owner shifted into the range, or record the
+    /// owner in an xattr. Call before restoring mode and xattrs: chown clears
+    /// set-user-ID bits and file capabilities.
+    pub fn apply(&self, path: &Path, uid: u64, gid: u64, mode: u32, symlink: bool) {
+        #[cfg(unix)]
+        if self.can_chown {
+            let ((host_uid, uid_overflow), (host_gid, gid_overflow)) =
+                (self.map(uid), self.map(gid));
+            // Fails for IDs the user namespace doesn't map; fall back to the xattr
+            if std::os::unix::fs::lchown(path, Some(host_uid), Some(host_gid)).is_ok() {
+                self.chowned.fetch_add(1, Ordering::Relaxed);
+                if uid_overflow || gid_overflow {
+                    self.overflowed.fetch_add(1, Ordering::Relaxed);
+                }
+                return;
+            }
+        }
+        // Linux allows no user xattrs on symlinks
+        if symlink {
+            self.unchanged.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let value = format!("{}:{}:0{:o}", uid, gid, mode & 0o7777);
+        let xattr = [(OVERRIDE_STAT_XATTR.to_string(), value.into_bytes())];
+        match crate::utils::write_xattrs(path, &xattr) {
+            Ok(()) => self.recorded.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.unchanged.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Print where ownership ended up, once extraction is over.
+    pub fn report(&self) {
+        let chowned = self.chowned.load(Ordering::Relaxed);
+        let recorded = self.recorded.load(Ordering::Relaxed);
+        let unchanged = self.unchanged.load(Ordering::Relaxed);
+        let overflowed = self.overflowed.load(Ordering::Relaxed);
+        if chowned > 0 {
+            eprintln!(
+                "Mapped the owners of {} entries into IDs {}-{}",
+                chowned,
+                self.range.start,
+                self.range.start as u64 + self.range.count as u64 - 1
+            );
+        }
+        if recorded > 0 {
+            eprintln!(
+                "Recorded the owners of {} entries in {} (changing owners needs root or a user namespace)",
+                recorded, OVERRIDE_STAT_XATTR
+            );
+        }
+        if unchanged > 0 {
+            eprintln!(
+                "Warning: {} entries (symlinks, or on filesystems without user xattrs) kept the extracting user as owner",
+                unchanged
+            );
+        }
+        if overflowed > 0 {
+            eprintln!(
+                "Warning: {} entries have owners beyond the {} mapped IDs; they were given ID {}",
+                overflowed,
+                self.range.count,
+                self.overflow_id()
+            );
+        }
+    }
+}
@@ -2,24 +2,145 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
 
+mod catalog;
 mod cli;
+mod codec;
 mod commands;
+mod dialect;
+mod encryption;
+mod i18n;
+mod idmap;
+mod manifest;
+mod notify;
+mod oci;
+mod platform;
+mod profiling;
+mod repo;
+mod sanitize;
+mod seekable;
+mod skips;
+mod status;
+mod tuning;
 mod utils;
+mod validate;
+mod watchdog;
+mod zip;
 
-use cli::{Cli, Commands};
+use cli::{CatalogCommands, Cli, Commands, RepoCommands};
+use i18n::t;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    i18n::init(cli.lang.as_deref());
+    encryption::init(cli.password.clone(), cli.key_file.clone());
+
+    // Huge trees with many concurrent readers can exhaust descriptors (EMFILE)
+    let fd_limit = tuning::raise_fd_limit();
+    if cli.verbose
+        && let Some(limit) = &fd_limit
+    {
+        eprintln!(
+            "Open file limit: {} (started at {})",
+            limit.current, limit.original
+        );
+    }
+    let cap_workers = |requested: usize| {
+        let workers = tuning::cap_workers(requested, fd_limit.as_ref());
+        if cli.verbose {
+            eprintln!("Using {} workers (requested {})", workers, requested);
+        }
+        workers
+    };
 
     match cli.command {
         Commands::Pack {
             input,
             output,
             level,
+            auto_level,
             threads,
             no_long,
             ignore_failed_read,
+            skip_log,
+            read_retries,
+            retry_backoff,
+            if_changed,
+            vss,
+            backup_privileges,
+            snapshot,
+            catalog,
+            profile,
+            mac_metadata,
+            capabilities,
+            selinux,
+            compression,
+            seekable,
+            encrypt,
+            no_manifest,
+            use_external_compressor,
+            stall_timeout,
+            profile_pipeline,
+            hard_dereference,
+            paranoid_links,
+            no_top_dir,
+            io_backend,
+            direct_io,
+            mmap,
+            no_preserve_source_atime,
+            status_file,
+            progress_fd,
+            no_ratio_warning,
+            no_partial,
+            notify_webhook,
+            memory_budget,
+            exclude,
+            include,
+            exclude_owner,
+            only_owner,
+            skip_unreadable,
+            skip_hidden,
+            no_skip_windows_system_dirs,
+            hash_output,
+            format,
         } => {
+            if mac_metadata && !cfg!(target_os = "macos") {
+                anyhow::bail!(t!("mac-metadata-unsupported"));
+            }
+            if capabilities && !cfg!(target_os = "linux") {
+                anyhow::bail!(t!("capabilities-unsupported"));
+            }
+            if selinux && !cfg!(target_os = "linux") {
+                anyhow::bail!(t!("selinux-unsupported"));
+            }
+            if (!exclude_owner.is_empty() || !only_owner.is_empty()) && !cfg!(unix) {
+                anyhow::bail!(t!("owner-filter-unsupported"));
+            }
+            if backup_privileges {
+                platform::enable_backup_privileges()?;
+            }
+            let zip = format == cli::PackFormat::Zip;
+            if zip && (compression != cli::Compression::Zstd || use_external_compressor.is_some()) {
+                anyhow::bail!(
+                    "--format zip deflates every file itself; it can't be combined with --compression or --use-external-compressor"
+                );
+            }
+            if let Some(mb) = seekable {
+                if zip || compression != cli::Compression::Zstd || use_external_compressor.is_some()
+                {
+                    anyhow::bail!(
+                        "--seekable writes zstd frames; it can't be combined with --format zip, --compression or --use-external-compressor"
+                    );
+                }
+                // Seek table entries hold 32-bit frame sizes
+                if !(1..=1024).contains(&mb) {
+                    anyhow::bail!("--seekable frame size must be 1 to 1024 MB");
+                }
+            }
+            // Both are read from the end of the file, which encryption seals
+            if encrypt && (zip || seekable.is_some()) {
+                anyhow::bail!("--encrypt can't be combined with --format zip or --seekable");
+            }
+            let encrypt = encrypt.then(|| encryption::passphrase(true)).transpose()?;
             let output_path = match output {
                 Some(p) => p,
                 None => {
@@ -27,34 +148,539 @@ fn main() -> Result<()> {
                         .file_name()
                         .context("Invalid input path")?
                         .to_string_lossy();
-                    PathBuf::from(format!("{}.tar.zst", file_stem))
+                    let extension = match &use_external_compressor {
+                        _ if zip => "zip",
+                        Some(command) => commands::pack::external_extension(command)?
+                            .with_context(|| {
+                                format!("No known extension for {:?}; pass --output", command)
+                            })?,
+                        None => compression.extension(),
+                    };
+                    PathBuf::from(format!("{}.{}", file_stem, extension))
+                }
+            };
+            // With the archive on stdout, everything else goes to stderr
+            let streaming = utils::is_stdio(&output_path);
+            if streaming && catalog {
+                anyhow::bail!("--catalog needs an output file, not stdout");
+            }
+            let report = |line: String| {
+                if streaming {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
                 }
             };
 
             let threads_count = threads.unwrap_or_else(|| num_cpus::get() as u32);
+            let readers = cap_workers(num_cpus::get());
             let long_distance = !no_long;
 
-            commands::pack::execute(
+            let level = match level {
+                Some(level) => level,
+                None if use_external_compressor.is_some() || !auto_level => 3,
+                None => {
+                    let choice = tuning::auto_level(&output_path);
+                    report(t!(
+                        "compression-level",
+                        level = choice.level,
+                        reason = choice.reason
+                    ));
+                    choice.level
+                }
+            };
+
+            // The previous archive of the same profile is the best guess at the total
+            let expected_entries = match &profile {
+                Some(profile) if catalog => {
+                    catalog::Catalog::open(&catalog::Catalog::default_path())?
+                        .latest_for_profile(profile, None)?
+                        .map(|record| record.file_count)
+                }
+                _ => None,
+            };
+
+            let started = std::time::Instant::now();
+            let result = platform::prepare_source(&input, vss, snapshot).and_then(|source| {
+                commands::pack::execute(
+                    &source.path,
+                    &output_path,
+                    commands::pack::PackOptions {
+                        level,
+                        threads: threads_count,
+                        readers,
+                        long_distance,
+                        ignore_errors: ignore_failed_read,
+                        collect_catalog: catalog,
+                        hash_output,
+                        format,
+                        mac_metadata,
+                        capabilities,
+                        selinux,
+                        compression,
+                        seekable: seekable.map(|mb| mb * 1024 * 1024),
+                        external_compressor: use_external_compressor,
+                        stall_timeout: stall_timeout.map(std::time::Duration::from_secs),
+                        profile_pipeline,
+                        hard_dereference,
+                        paranoid_links,
+                        // Layer paths start at the root of the image filesystem
+                        rooting: if no_top_dir || format == cli::PackFormat::OciLayer {
+                            utils::Rooting::Contents
+                        } else {
+                            utils::Rooting::TopDir
+                        },
+                        io_backend,
+                        direct_io,
+                        mmap,
+                        preserve_atime: !no_preserve_source_atime,
+                        stream_threshold: commands::pack::stream_threshold(
+                            memory_budget.map(|mb| mb * 1024 * 1024),
+                            readers,
+                        ),
+                        backup_semantics: backup_privileges,
+                        exclude,
+                        include,
+                        filter: commands::pack::EntryFilter {
+                            exclude_owners: exclude_owner,
+                            only_owners: only_owner,
+                            skip_unreadable,
+                            skip_hidden,
+                            skip_system_dirs: !no_skip_windows_system_dirs,
+                        },
+                        status_file,
+                        progress_fd,
+                        expected_entries,
+                        no_ratio_warning,
+                        partial: !no_partial,
+                        skip_log,
+                        encrypt,
+                        read_retries,
+                        retry_backoff,
+                        if_changed,
+                        manifest: !no_manifest,
+                    },
+                )
+            });
+
+            let result = result.and_then(|summary| {
+                if catalog {
+                    let location = output_path.canonicalize().unwrap_or(output_path.clone());
+                    let source = input.canonicalize().unwrap_or(input.clone());
+                    let mut db = catalog::Catalog::open(&catalog::Catalog::default_path())?;
+                    db.record(
+                        &catalog::ArchiveRecord {
+                            id: 0,
+                            location: location.to_string_lossy().to_string(),
+                            source: source.to_string_lossy().to_string(),
+                            created: catalog::now(),
+                            size: std::fs::metadata(&output_path)?.len(),
+                            file_count: summary.entries,
+                            checksum: summary.checksum.clone(),
+                            profile: profile.clone(),
+                            sha256: summary
+                                .digest
+                                .clone()
+                                .filter(|_| hash_output == Some(cli::HashAlgorithm::Sha256)),
+                        },
+                        &summary.entry_paths,
+                    )?;
+                }
+                Ok(summary)
+            });
+
+            if let Some(url) = &notify_webhook {
+                let report = notify::JobReport::pack(
+                    &input,
+                    &output_path,
+                    profile.as_deref(),
+                    hash_output,
+                    started.elapsed(),
+                    &result,
+                );
+                notify::send(url, &report);
+            }
+            let summary = result?;
+            if let Some(digest) = &summary.digest {
+                // The line `sha256sum` and `b3sum` print, so `-c` can check it later
+                report(format!("{}  {}", digest, output_path.display()));
+            }
+            if let Some(diff_id) = &summary.diff_id {
+                report(format!("diff_id: sha256:{}", diff_id));
+            }
+        }
+        Commands::Unpack {
+            input,
+            paths,
+            output,
+            threads,
+            reflink_dups,
+            mac_metadata,
+            capabilities,
+            selinux,
+            rootless_idmap,
+            strict_links,
+            merge,
+            apply_whiteouts,
+            memory_limit_decompress,
+            untrusted,
+            max_output_size,
+            max_entries,
+            max_path_depth,
+            exclude,
+            parse_only,
+            salvage,
+            compare_extract,
+        } => {
+            if mac_metadata && !cfg!(target_os = "macos") {
+                anyhow::bail!(t!("mac-metadata-unsupported"));
+            }
+            if capabilities && !cfg!(target_os = "linux") {
+                anyhow::bail!(t!("capabilities-unsupported"));
+            }
+            if selinux && !cfg!(target_os = "linux") {
+                anyhow::bail!(t!("selinux-unsupported"));
+            }
+            if rootless_idmap.is_some() && !cfg!(unix) {
+                anyhow::bail!(t!("idmap-unsupported"));
+            }
+            // The current directory is rarely empty; archives bring their own top-level folder.
+            // Whiteouts only mean something on top of an earlier layer
+            let merge = merge || output.is_none() || apply_whiteouts;
+            let output_path = output.unwrap_or_else(|| PathBuf::from("."));
+            let threads_count = threads.unwrap_or_else(|| num_cpus::get() as u32);
+            let threads_count = cap_workers(threads_count as usize) as u32;
+            let defaults = if untrusted {
+                commands::unpack::ExtractLimits::untrusted()
+            } else {
+                commands::unpack::ExtractLimits::default()
+            };
+            let limits = commands::unpack::ExtractLimits {
+                max_output_size: max_output_size
+                    .map(|mb| mb * 1024 * 1024)
+                    .or(defaults.max_output_size),
+                max_entries: max_entries.or(defaults.max_entries),
+                max_path_depth: max_path_depth.or(defaults.max_path_depth),
+            };
+            let memory_limit = memory_limit_decompress.map(|mb| mb * 1024 * 1024);
+            if parse_only {
+                // Decompressed here: the fuzz targets compile `validate` on its own
+                let summary = codec::open_archive(&input, memory_limit)
+                    .and_then(|tar| validate::validate_tar(tar, &limits))
+                    .with_context(|| t!("not-an-archive", input = format!("{:?}", input)))?;
+                let summary = t!(
+                    "stream-summary",
+                    entries = summary.entries,
+                    files = summary.files,
+                    bytes = indicatif::HumanBytes(summary.bytes).to_string(),
+                    dirs = summary.dirs,
+                    symlinks = summary.symlinks,
+                    hardlinks = summary.hardlinks,
+                    unsafe_paths = summary.unsafe_paths
+                );
+                println!(
+                    "{}",
+                    t!("parsed", input = format!("{:?}", input), summary = summary)
+                );
+                return Ok(());
+            }
+            if compare_extract {
+                let summary = commands::compare::execute(
+                    &input,
+                    &output_path,
+                    &exclude,
+                    &limits,
+                    memory_limit,
+                )?;
+                for difference in &summary.differences {
+                    println!("{:?}: {}", difference.path, difference.detail);
+                }
+                println!(
+                    "{}",
+                    t!(
+                        "compared",
+                        input = format!("{:?}", input),
+                        output = format!("{:?}", output_path),
+                        entries = summary.entries,
+                        differences = summary.differences.len()
+                    )
+                );
+                if !summary.differences.is_empty() {
+                    std::process::exit(commands::compare::EXIT_DIFFERENT);
+                }
+                return Ok(());
+            }
+            if salvage {
+                let report = commands::salvage::execute(
+                    &input,
+                    &output_path,
+                    merge,
+                    &exclude,
+                    &limits,
+                    memory_limit,
+                )?;
+                report.print();
+                println!(
+                    "{}",
+                    t!(
+                        "salvaged",
+                        input = format!("{:?}", input),
+                        output = format!("{:?}", output_path),
+                        restored = report.restored,
+                        lost = report.lost.len()
+                    )
+                );
+                if !report.is_complete() {
+                    std::process::exit(commands::salvage::EXIT_DATA_LOST);
+                }
+                return Ok(());
+            }
+            // A seekable archive's index tells how many inodes the output needs
+            let expected_entries = if utils::is_stdio(&input) {
+                None
+            } else {
+                seekable::read_index(&input)
+                    .ok()
+                    .flatten()
+                    .map(|index| index.entries.len() as u64)
+            };
+            let summary = commands::unpack::execute(
                 &input,
                 &output_path,
-                commands::pack::PackOptions {
+                commands::unpack::UnpackOptions {
+                    threads: threads_count,
+                    reflink_dups,
+                    mac_metadata,
+                    capabilities,
+                    selinux,
+                    idmap: rootless_idmap,
+                    strict_links,
+                    merge,
+                    apply_whiteouts,
+                    exclude,
+                    paths,
+                    expected_entries,
+                    memory_limit,
+                    limits,
+                },
+            )?;
+            println!(
+                "{}",
+                t!(
+                    "unpacked",
+                    input = format!("{:?}", input),
+                    output = format!("{:?}", output_path),
+                    summary = summary.describe()
+                )
+            );
+            if !summary.link_failures.is_empty() {
+                std::process::exit(commands::unpack::EXIT_LINK_FAILURES);
+            }
+        }
+        Commands::Rotate {
+            dir,
+            template,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_none,
+            dry_run,
+        } => {
+            commands::rotate::execute(
+                &dir,
+                commands::rotate::RotateOptions {
+                    template,
+                    keep_daily,
+                    keep_weekly,
+                    keep_monthly,
+                    keep_none,
+                    dry_run,
+                },
+            )?;
+        }
+        Commands::Catalog { db, command } => {
+            let db = db.unwrap_or_else(catalog::Catalog::default_path);
+            let query = match command {
+                CatalogCommands::List => commands::catalog::CatalogQuery::List,
+                CatalogCommands::Search { term } => commands::catalog::CatalogQuery::Search(term),
+                CatalogCommands::WhereIs { path } => commands::catalog::CatalogQuery::WhereIs(path),
+            };
+            commands::catalog::execute(&db, query)?;
+        }
+        Commands::Restore {
+            profile,
+            as_of,
+            output,
+            threads,
+            db,
+            merge,
+        } => {
+            let db = db.unwrap_or_else(catalog::Catalog::default_path);
+            let threads_count = threads.unwrap_or_else(|| num_cpus::get() as u32);
+            let threads_count = cap_workers(threads_count as usize) as u32;
+            let summary = commands::restore::execute(
+                &db,
+                &profile,
+                as_of.as_deref(),
+                &output,
+                commands::unpack::UnpackOptions {
+                    threads: threads_count,
+                    reflink_dups: false,
+                    mac_metadata: false,
+                    capabilities: false,
+                    selinux: false,
+                    idmap: None,
+                    strict_links: false,
+                    merge,
+                    apply_whiteouts: false,
+                    exclude: Vec::new(),
+                    paths: Vec::new(),
+                    expected_entries: None,
+                    memory_limit: None,
+                    limits: commands::unpack::ExtractLimits::default(),
+                },
+            )?;
+            println!(
+                "{}",
+                t!(
+                    "restored",
+                    profile = format!("{:?}", profile),
+                    output = format!("{:?}", output),
+                    summary = summary.describe()
+                )
+            );
+            if !summary.link_failures.is_empty() {
+                std::process::exit(commands::unpack::EXIT_LINK_FAILURES);
+            }
+        }
+        Commands::Repo { command } => match command {
+            RepoCommands::Init { repo } => commands::repo::init(&repo)?,
+            RepoCommands::Backup { input, repo, level } => {
+                commands::repo::backup(&repo, &input, level)?;
+            }
+            RepoCommands::Restore {
+                snapshot,
+                repo,
+                output,
+            } => commands::repo::restore(&repo, &snapshot, &output)?,
+            RepoCommands::Prune {
+                repo,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_none,
+                dry_run,
+            } => commands::repo::prune(
+                &repo,
+                commands::repo::PruneOptions {
+                    keep_daily,
+                    keep_weekly,
+                    keep_monthly,
+                    keep_none,
+                    dry_run,
+                },
+            )?,
+            RepoCommands::Compact { repo } => commands::repo::compact(&repo)?,
+            RepoCommands::List { repo } => commands::repo::list(&repo)?,
+        },
+        Commands::Merge {
+            inputs,
+            output,
+            prefer,
+            level,
+            threads,
+        } => {
+            let threads_count = threads.unwrap_or_else(|| num_cpus::get() as u32);
+            commands::merge::execute(
+                &inputs,
+                &output,
+                commands::merge::MergeOptions {
+                    policy: prefer,
+                    apply_whiteouts: false,
                     level,
                     threads: threads_count,
-                    long_distance,
-                    ignore_errors: ignore_failed_read,
                 },
             )?;
         }
-        Commands::Unpack {
-            input,
+        Commands::Synthesize {
+            base,
+            incrementals,
             output,
+            apply_whiteouts,
+            level,
             threads,
         } => {
-            let output_path = output.unwrap_or_else(|| PathBuf::from("."));
+            // Incrementals only carry what changed, so the newest copy of each path wins
+            let inputs: Vec<PathBuf> = std::iter::once(base).chain(incrementals).collect();
             let threads_count = threads.unwrap_or_else(|| num_cpus::get() as u32);
-            commands::unpack::execute(&input, &output_path, threads_count)?;
-            println!("Successfully unpacked {:?} to {:?}", input, output_path);
+            commands::merge::execute(
+                &inputs,
+                &output,
+                commands::merge::MergeOptions {
+                    policy: cli::MergePolicy::Last,
+                    apply_whiteouts,
+                    level,
+                    threads: threads_count,
+                },
+            )?;
         }
+        Commands::CheckExcludes {
+            dir,
+            exclude,
+            limit,
+            json,
+        } => {
+            commands::check_excludes::execute(&dir, &exclude, limit, json)?;
+        }
+        Commands::Info { archive, db, json } => {
+            let db = db.unwrap_or_else(catalog::Catalog::default_path);
+            commands::info::execute(&archive, &db, json)?;
+        }
+        Commands::List {
+            archive,
+            memory_limit_decompress,
+            json,
+        } => {
+            let memory_limit = memory_limit_decompress.map(|mb| mb * 1024 * 1024);
+            commands::list::execute(&archive, memory_limit, json)
+                .with_context(|| t!("not-an-archive", input = format!("{:?}", archive)))?;
+        }
+        Commands::Scrub {
+            archives,
+            catalog,
+            db,
+        } => {
+            if archives.is_empty() && !catalog {
+                anyhow::bail!(t!("scrub-nothing"));
+            }
+            let db = catalog.then(|| db.unwrap_or_else(catalog::Catalog::default_path));
+            commands::scrub::execute(&archives, db.as_deref())?;
+        }
+        Commands::GenFixture {
+            output,
+            files,
+            size_dist,
+            size,
+            max_size,
+            hardlinks,
+            symlinks,
+            depth,
+            fanout,
+            seed,
+        } => commands::fixture::execute(
+            &output,
+            commands::fixture::FixtureOptions {
+                files,
+                size,
+                max_size,
+                dist: size_dist,
+                hardlinks,
+                symlinks,
+                depth,
+                fanout,
+                seed,
+            },
+        )?,
     }
 
     Ok(())
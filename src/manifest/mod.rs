@@ -0,0 +1,195 @@
+//! Per-file BLAKE3 manifest: `pack` hashes every file as the writer copies
+//! it into the archive and ends the archive with a list of the hashes, which
+//! `unpack` checks the extracted files against. Tar checksums only cover headers;
+//! this is what notices damaged file contents.
+//!
+//! The list is a regular file named `MANIFEST_NAME` at the archive root,
+//! marked with a `ZSTAR.manifest` pax record. zstar treats it as metadata;
+//! other tar tools extract it, and `b3sum -c` run where the archive was
+//! extracted checks the files with it. It is in `b3sum` format: per file
+//! the hex digest, two spaces and the archive path. A path with a backslash
+//! or newline has them escaped (`\\`, `\n`) and its line starts with a
+//! backslash, as with b3sum.
+//!
+//! Files stored although they changed while `pack` read them (`--if-changed
+//! warn`) are listed in `ZSTAR.changed-files` pax records on the manifest,
+//! one escaped path each. Only the writer knows about all of them: a
+//! streamed file's header is out before it was read through.
+
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dialect::{MANIFEST_NAME, PAX_MANIFEST};
+use crate::seekable::index_path;
+
+/// PAX record on the manifest naming a file that changed while being read
+const PAX_CHANGED_FILES: &str = "ZSTAR.changed-files";
+
+/// File hashes collected by the writer. Disabled (`--no-manifest`, ZIP and
+/// OCI layers), it records nothing.
+pub struct Manifest {
+    /// Archive path and hash of every file so far
+    files: Option<Vec<(String, blake3::Hash)>>,
+    /// Archive paths of the files that changed while being read
+    changed: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(enabled: bool) -> Self {
+        Manifest {
+            files: enabled.then(Vec::new),
+            changed: Vec::new(),
+        }
+    }
+
+    /// Wrap the data of a file going into the archive, so it is hashed
+    /// exactly as written, whatever happens to the source meanwhile.
+    pub fn reader<R: Read>(&self, inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: self.files.as_ref().map(|_| blake3::Hasher::new()),
+        }
+    }
+
+    /// Record what went through `data` as the contents of the file stored as `path`.
+    pub fn finish<R>(&mut self, path: &Path, data: HashingReader<R>) {
+        if let (Some(files), Some(hasher)) = (self.files.as_mut(), data.hasher) {
+            files.push((index_path(path), hasher.finalize()));
+        }
+    }
+
+    /// Record that the file stored as `path` changed while it was read.
+    pub fn changed(&mut self, path: &Path) {
+        if self.files.is_some() {
+            self.changed.push(index_path(path));
+        }
+    }
+
+    /// Append the manifest to `tar`, once every file is in it.
+    pub fn append_to<W: Write>(mut self, tar: &mut tar::Builder<W>) -> Result<()> {
+        let Some(files) = self.files.as_mut() else {
+            return Ok(());
+        };
+        files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut value = String::new();
+        for (path, hash) in files.iter() {
+            if path.contains(['\\', '\n']) {
+                value.push_str(&format!("\\{}  {}\n", hash.to_hex(), escape(path)));
+            } else {
+                value.push_str(&format!("{}  {}\n", hash.to_hex(), path));
+            }
+        }
+
+        // Readers split pax data at newlines, even inside values
+        let changed: Vec<String> = self.changed.iter().map(|path| escape(path)).collect();
+        let mut records = vec![(PAX_MANIFEST, &b"1"[..])];
+        for path in &changed {
+            records.push((PAX_CHANGED_FILES, path.as_bytes()));
+        }
+        tar.append_pax_extensions(records)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(value.len() as u64);
+        header.set_mode(0o644);
+        // Blank owner fields don't parse as numbers in other readers
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        header.set_cksum();
+        tar.append_data(&mut header, MANIFEST_NAME, value.as_bytes())
+            .context("Failed to write the manifest")?;
+        Ok(())
+    }
+}
+
+/// File data on its way into the archive, hashed as it is read.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Option<blake3::Hasher>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(out)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&out[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// The hashes in a manifest entry, by archive path.
+pub fn read<R: Read>(entry: &mut tar::Entry<R>) -> Result<HashMap<PathBuf, blake3::Hash>> {
+    let mut value = String::new();
+    entry
+        .read_to_string(&mut value)
+        .context("Manifest is not UTF-8")?;
+    let mut files = HashMap::new();
+    for line in value.split_terminator('\n') {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let Some((hex, path)) = line.split_once("  ") else {
+            bail!("Malformed manifest line: {:?}", line);
+        };
+        let hash = blake3::Hash::from_hex(hex)
+            .with_context(|| format!("Malformed manifest line: {:?}", line))?;
+        let path = if escaped {
+            unescape(path)
+        } else {
+            path.to_string()
+        };
+        files.insert(PathBuf::from(path), hash);
+    }
+    Ok(files)
+}
+
+/// The files a manifest entry lists as changed while they were read.
+pub fn changed_files<R: Read>(entry: &mut tar::Entry<R>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for extension in entry.pax_extensions()?.into_iter().flatten() {
+        let extension = extension?;
+        if extension.key_bytes() == PAX_CHANGED_FILES.as_bytes() {
+            let path = extension.value().context("Malformed manifest")?;
+            files.push(PathBuf::from(unescape(path)));
+        }
+    }
+    Ok(files)
+}
+
+fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// BLAKE3 of the file at `path`, for files extracted without passing
+/// through memory.
+pub fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    let file = std::fs::File::open(path)?;
+    Ok(blake3::Hasher::new().update_reader(file)?.finalize())
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::cli::HashAlgorithm;
+use crate::commands::pack::PackSummary;
+
+/// Give up on a webhook that doesn't answer; the job itself is already done
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON body posted to `--notify-webhook` when a job ends.
+#[derive(serde::Serialize)]
+pub struct JobReport {
+    pub job: &'static str,
+    /// `"success"` or `"failure"`
+    pub status: &'static str,
+    pub input: String,
+    pub output: String,
+    pub profile: Option<String>,
+    pub duration_secs: f64,
+    pub entries: Option<u64>,
+    pub bytes: Option<u64>,
+    pub compressed_bytes: Option<u64>,
+    /// Compressed size over uncompressed size
+    pub ratio: Option<f64>,
+    pub skipped: Option<u64>,
+    /// Failed reads tried again (`--read-retries`)
+    pub read_retries: Option<u64>,
+    /// Files stored although they changed while being read
+    pub changed: Option<u64>,
+    /// `<algorithm>:<hex>` digest of the archive with `--hash-output`
+    pub digest: Option<String>,
+    pub error: Option<String>,
+    pub host: Option<String>,
+}
+
+impl JobReport {
+    pub fn pack(
+        input: &Path,
+        output: &Path,
+        profile: Option<&str>,
+        hash_output: Option<HashAlgorithm>,
+        duration: Duration,
+        result: &anyhow::Result<PackSummary>,
+    ) -> Self {
+        let summary = result.as_ref().ok();
+        JobReport {
+            job: "pack",
+            status: if summary.is_some() {
+                "success"
+            } else {
+                "failure"
+            },
+            input: input.display().to_string(),
+            output: output.display().to_string(),
+            profile: profile.map(str::to_string),
+            duration_secs: duration.as_secs_f64(),
+            entries: summary.map(|s| s.entries),
+            bytes: summary.map(|s| s.bytes),
+            compressed_bytes: summary.map(|s| s.compressed),
+            ratio: summary
+                .filter(|s| s.bytes > 0)
+                .map(|s| s.compressed as f64 / s.bytes as f64),
+            skipped: summary.map(|s| s.skipped),
+            read_retries: summary.map(|s| s.retries),
+            changed: summary.map(|s| s.changed),
+            digest: hash_output
+                .zip(summary.and_then(|s| s.digest.as_ref()))
+                .map(|(algorithm, digest)| format!("{}:{}", algorithm.name(), digest)),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            host: hostname(),
+        }
+    }
+}
+
+/// POST `report` as JSON to `url`. A notification that can't be delivered is
+/// a warning; it never changes the job's outcome.
+pub fn send(url: &str, report: &JobReport) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(WEBHOOK_TIMEOUT))
+        .build()
+        .into();
+    let body = match serde_json::to_string(report) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("Warning: Failed to encode notification: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(&body)
+    {
+        eprintln!("Warning: Failed to notify {}: {}", url, e);
+    }
+}
+
+fn hostname() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|s| s.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+}
@@ -0,0 +1,69 @@
+//! `pack --format oci-layer`: the tar stream laid out as an OCI image layer.
+//! Paths are relative to the layer root, entries come in a fixed order, and
+//! overlayfs deletions in the source (an upper directory) become whiteout
+//! files: a 0/0 character device `x` is stored as an empty `.wh.x`, and an
+//! opaque directory gets a `.wh..wh..opq` entry hiding the lower layers' files.
+//! `unpack --apply-whiteouts` reads them back when layers are extracted on
+//! top of each other, and `synthesize --apply-whiteouts` when incrementals
+//! are folded into one archive.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Prefix of a file marking `name` as deleted in the layer
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Entry marking its directory as opaque
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// overlayfs marks opaque directories with one of these set to "y"; the
+/// `user.` one on mounts with `userxattr` (rootless)
+pub const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// Whether `meta` is an overlayfs whiteout, a character device numbered 0/0.
+#[cfg(unix)]
+pub fn is_whiteout(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+#[cfg(not(unix))]
+pub fn is_whiteout(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Archive path of the whiteout file for `path`: `a/b` becomes `a/.wh.b`.
+pub fn whiteout_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(WHITEOUT_PREFIX);
+    name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(name)
+}
+
+/// Whether the directory xattrs read for it mark it opaque.
+pub fn is_opaque(xattrs: &[(String, Vec<u8>)]) -> bool {
+    xattrs
+        .iter()
+        .any(|(name, value)| OPAQUE_XATTRS.contains(&name.as_str()) && value == b"y")
+}
+
+/// What a whiteout entry deletes from the layers extracted before it.
+pub enum Whiteout<'a> {
+    /// `dir/.wh.name` deletes `dir/name`
+    Path(PathBuf),
+    /// `dir/.wh..wh..opq` empties `dir`
+    Opaque(&'a Path),
+}
+
+/// The whiteout an archive entry stands for, if its name is one. `.wh.`,
+/// `.wh..` and `.wh...` name no file and are left as ordinary entries.
+pub fn whiteout(entry_path: &Path) -> Option<Whiteout<'_>> {
+    let name = entry_path.file_name()?.to_str()?;
+    let dir = entry_path.parent().unwrap_or(Path::new(""));
+    if name == OPAQUE_WHITEOUT {
+        return Some(Whiteout::Opaque(dir));
+    }
+    match name.strip_prefix(WHITEOUT_PREFIX)? {
+        "" | "." | ".." => None,
+        hidden => Some(Whiteout::Path(dir.join(hidden))),
+    }
+}
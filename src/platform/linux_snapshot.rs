@@ -0,0 +1,316 @@
+use anyhow::{Context, Result, bail};
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cli::SnapshotMode;
+
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683E;
+/// Inode number of every btrfs subvolume root (BTRFS_FIRST_FREE_OBJECTID)
+const BTRFS_SUBVOL_ROOT_INO: u64 = 256;
+/// Copy-on-write space reserved for LVM snapshots, relative to the origin LV
+const LVM_SNAPSHOT_EXTENTS: &str = "10%ORIGIN";
+
+enum Kind {
+    Btrfs {
+        holder: PathBuf,
+        subvolume: PathBuf,
+    },
+    Lvm {
+        holder: PathBuf,
+        mountpoint: PathBuf,
+        lv: String,
+    },
+}
+
+/// A read-only snapshot of the filesystem holding the pack source.
+/// The snapshot is unmounted and removed again when dropped.
+pub struct Snapshot {
+    kind: Kind,
+    source: PathBuf,
+}
+
+impl Snapshot {
+    /// Snapshot the btrfs subvolume or LVM logical volume containing `path`.
+    pub fn create(path: &Path, mode: SnapshotMode) -> Result<Self> {
+        let abs = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {:?}", path))?;
+
+        let on_btrfs = is_btrfs(&abs)?;
+        match mode {
+            SnapshotMode::Btrfs if !on_btrfs => bail!("{:?} is not on a btrfs filesystem", abs),
+            SnapshotMode::Btrfs => return create_btrfs(&abs),
+            SnapshotMode::Lvm => return create_lvm(&abs),
+            SnapshotMode::Auto if on_btrfs => return create_btrfs(&abs),
+            SnapshotMode::Auto => {}
+        }
+
+        create_lvm(&abs).context("Source is neither on btrfs nor on an LVM logical volume")
+    }
+
+    /// Path of the pack source inside the snapshot.
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        match &self.kind {
+            Kind::Btrfs { holder, subvolume } => {
+                if let Err(e) = run("btrfs", &["subvolume", "delete", &path_str(subvolume)]) {
+                    eprintln!("Warning: Failed to delete snapshot {:?}: {}", subvolume, e);
+                    return;
+                }
+                let _ = fs::remove_dir(holder);
+            }
+            Kind::Lvm {
+                holder,
+                mountpoint,
+                lv,
+            } => {
+                if let Err(e) = run("umount", &[&path_str(mountpoint)]) {
+                    eprintln!(
+                        "Warning: Failed to unmount snapshot {:?}: {}",
+                        mountpoint, e
+                    );
+                    return;
+                }
+                let _ = fs::remove_dir(mountpoint);
+                let _ = fs::remove_dir(holder);
+                if let Err(e) = run("lvremove", &["-f", lv]) {
+                    eprintln!("Warning: Failed to remove snapshot volume {}: {}", lv, e);
+                }
+            }
+        }
+    }
+}
+
+fn create_btrfs(abs: &Path) -> Result<Snapshot> {
+    let root = subvolume_root(abs)?;
+    let name = snapshot_dir_name(abs);
+    let holder = root.join(format!(".zstar-snapshot-{}", std::process::id()));
+    fs::create_dir(&holder).with_context(|| format!("Failed to create {:?}", holder))?;
+    let subvolume = holder.join(&name);
+
+    // Snapshot writable first so the holder directory (which exists in the
+    // origin at snapshot time) can be removed from it, then flip to read-only.
+    if let Err(e) = run(
+        "btrfs",
+        &[
+            "subvolume",
+            "snapshot",
+            &path_str(&root),
+            &path_str(&subvolume),
+        ],
+    ) {
+        let _ = fs::remove_dir(&holder);
+        return Err(e);
+    }
+    let snapshot = Snapshot {
+        source: subvolume.join(abs.strip_prefix(&root)?),
+        kind: Kind::Btrfs {
+            holder: holder.clone(),
+            subvolume: subvolume.clone(),
+        },
+    };
+    let _ = fs::remove_dir(subvolume.join(holder.file_name().unwrap_or_default()));
+    run(
+        "btrfs",
+        &[
+            "property",
+            "set",
+            "-ts",
+            &path_str(&subvolume),
+            "ro",
+            "true",
+        ],
+    )?;
+
+    Ok(snapshot)
+}
+
+fn create_lvm(abs: &Path) -> Result<Snapshot> {
+    let mount = find_mount(abs)?;
+    let lvs = run(
+        "lvs",
+        &["--noheadings", "-o", "vg_name,lv_name", &mount.source],
+    )
+    .with_context(|| format!("{} is not an LVM logical volume", mount.source))?;
+    let mut fields = lvs.split_whitespace();
+    let (Some(vg), Some(lv)) = (fields.next(), fields.next()) else {
+        bail!("{} is not an LVM logical volume", mount.source);
+    };
+
+    let snap_name = format!("zstar-snap-{}", std::process::id());
+    run(
+        "lvcreate",
+        &[
+            "--snapshot",
+            "--extents",
+            LVM_SNAPSHOT_EXTENTS,
+            "--name",
+            &snap_name,
+            &format!("{}/{}", vg, lv),
+        ],
+    )?;
+    let snap_lv = format!("{}/{}", vg, snap_name);
+
+    let holder = std::env::temp_dir().join(&snap_name);
+    let mountpoint = holder.join(snapshot_dir_name(&mount.target));
+    let mut options = String::from("ro");
+    if mount.fstype == "xfs" {
+        // The snapshot shares the origin's UUID
+        options.push_str(",nouuid");
+    }
+    let mounted = fs::create_dir_all(&mountpoint)
+        .context("Failed to create snapshot mount point")
+        .and_then(|_| {
+            run(
+                "mount",
+                &[
+                    "-o",
+                    &options,
+                    &format!("/dev/{}", snap_lv),
+                    &path_str(&mountpoint),
+                ],
+            )
+        });
+    if let Err(e) = mounted {
+        let _ = fs::remove_dir(&mountpoint);
+        let _ = fs::remove_dir(&holder);
+        let _ = run("lvremove", &["-f", &snap_lv]);
+        return Err(e);
+    }
+
+    let inner = mount.root.strip_prefix("/").unwrap_or(&mount.root);
+    Ok(Snapshot {
+        source: mountpoint
+            .join(inner)
+            .join(abs.strip_prefix(&mount.target)?),
+        kind: Kind::Lvm {
+            holder,
+            mountpoint,
+            lv: snap_lv,
+        },
+    })
+}
+
+fn is_btrfs(path: &Path) -> Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to statfs {:?}", path));
+    }
+    Ok(buf.f_type as i64 == BTRFS_SUPER_MAGIC)
+}
+
+/// Walk up from `path` to the root of the btrfs subvolume containing it.
+fn subvolume_root(path: &Path) -> Result<PathBuf> {
+    let dev = fs::metadata(path)?.dev();
+    for ancestor in path.ancestors() {
+        let meta = fs::metadata(ancestor)?;
+        if meta.dev() != dev {
+            break;
+        }
+        if meta.ino() == BTRFS_SUBVOL_ROOT_INO {
+            return Ok(ancestor.to_path_buf());
+        }
+    }
+    bail!("Could not find the btrfs subvolume containing {:?}", path)
+}
+
+struct MountInfo {
+    root: PathBuf,
+    target: PathBuf,
+    fstype: String,
+    source: String,
+}
+
+/// Find the most specific mount containing `path` in /proc/self/mountinfo.
+fn find_mount(path: &Path) -> Result<MountInfo> {
+    let info = fs::read_to_string("/proc/self/mountinfo")?;
+    let mut best: Option<MountInfo> = None;
+    for line in info.lines() {
+        // <id> <parent> <maj:min> <root> <target> <options> [optional...] - <fstype> <source> <super>
+        let Some((left, right)) = line.split_once(" - ") else {
+            continue;
+        };
+        let left: Vec<&str> = left.split(' ').collect();
+        let right: Vec<&str> = right.split(' ').collect();
+        if left.len() < 5 || right.len() < 2 {
+            continue;
+        }
+        let target = PathBuf::from(unescape_mountinfo(left[4]));
+        if !path.starts_with(&target) {
+            continue;
+        }
+        let longer = best
+            .as_ref()
+            .is_none_or(|b| target.components().count() >= b.target.components().count());
+        if longer {
+            best = Some(MountInfo {
+                root: PathBuf::from(unescape_mountinfo(left[3])),
+                target,
+                fstype: right[0].to_string(),
+                source: unescape_mountinfo(right[1]),
+            });
+        }
+    }
+    best.with_context(|| format!("No mount found for {:?}", path))
+}
+
+/// mountinfo escapes space, tab, newline and backslash as octal (`\040`).
+fn unescape_mountinfo(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 3 < bytes.len()
+            && let Some(v) = std::str::from_utf8(&bytes[i + 1..i + 4])
+                .ok()
+                .and_then(|o| u8::from_str_radix(o, 8).ok())
+        {
+            out.push(v);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Name the snapshot root after the source so archive entry names are unchanged.
+fn snapshot_dir_name(path: &Path) -> PathBuf {
+    path.file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("root"))
+}
+
+fn path_str(path: &Path) -> Cow<'_, str> {
+    path.to_string_lossy()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let out = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !out.status.success() {
+        bail!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
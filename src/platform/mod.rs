@@ -0,0 +1,140 @@
+#[cfg(target_os = "linux")]
+pub mod linux_snapshot;
+#[cfg(windows)]
+pub mod windows_backup;
+#[cfg(windows)]
+pub mod windows_vss;
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use crate::cli::SnapshotMode;
+
+/// The directory actually read while packing. When a snapshot was requested
+/// `path` points into it, and the snapshot is released when this is dropped.
+pub struct PackSource {
+    pub path: PathBuf,
+    #[cfg(windows)]
+    _shadow: Option<windows_vss::ShadowCopy>,
+    #[cfg(target_os = "linux")]
+    _snapshot: Option<linux_snapshot::Snapshot>,
+}
+
+/// Enable SeBackupPrivilege so files can be read regardless of their ACLs
+/// (`pack --backup-privileges`). Without the privilege files are still read,
+/// with the usual access checks.
+pub fn enable_backup_privileges() -> Result<()> {
+    #[cfg(windows)]
+    {
+        if !windows_backup::enable_backup_privilege()? {
+            eprintln!(
+                "Warning: SeBackupPrivilege is not available (run elevated as an administrator or Backup Operator); protected files will still be denied"
+            );
+        }
+        Ok(())
+    }
+    #[cfg(not(windows))]
+    anyhow::bail!("--backup-privileges is only supported on Windows")
+}
+
+pub fn prepare_source(
+    input: &Path,
+    vss: bool,
+    snapshot: Option<SnapshotMode>,
+) -> Result<PackSource> {
+    #[cfg(not(windows))]
+    if vss {
+        anyhow::bail!("--vss is only supported on Windows");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if snapshot.is_some() {
+        anyhow::bail!("--snapshot is only supported on Linux");
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = snapshot;
+        if vss {
+            let shadow = windows_vss::ShadowCopy::create(input)?;
+            return Ok(PackSource {
+                path: shadow.translate(input)?,
+                _shadow: Some(shadow),
+            });
+        }
+        Ok(PackSource {
+            path: input.to_path_buf(),
+            _shadow: None,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(mode) = snapshot {
+            let snap = linux_snapshot::Snapshot::create(input, mode)?;
+            return Ok(PackSource {
+                path: snap.source().to_path_buf(),
+                _snapshot: Some(snap),
+            });
+        }
+        Ok(PackSource {
+            path: input.to_path_buf(),
+            _snapshot: None,
+        })
+    }
+
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        let _ = snapshot;
+        Ok(PackSource {
+            path: input.to_path_buf(),
+        })
+    }
+}
+
+/// Whether `path` is hidden: its name starts with a dot, or on Windows it
+/// has the hidden attribute.
+pub fn is_hidden(path: &Path) -> bool {
+    if path
+        .file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            return meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+        }
+    }
+    false
+}
+
+/// Whether this process may read `path` (and for a directory, list and
+/// enter it), decided without opening it.
+pub fn is_readable(path: &Path, is_dir: bool) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        let mode = if is_dir {
+            libc::R_OK | libc::X_OK
+        } else {
+            libc::R_OK
+        };
+        // AT_EACCESS checks the effective IDs, the ones opening the file uses
+        unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        // Windows has no cheap access check; the ACL is evaluated by opening
+        if is_dir {
+            std::fs::read_dir(path).is_ok()
+        } else {
+            std::fs::File::open(path).is_ok()
+        }
+    }
+}
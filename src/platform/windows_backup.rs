@@ -0,0 +1,65 @@
+use anyhow::{Result, bail};
+use windows_sys::Win32::Foundation::{
+    CloseHandle, ERROR_NOT_ALL_ASSIGNED, GetLastError, HANDLE, LUID,
+};
+use windows_sys::Win32::Security::{
+    AdjustTokenPrivileges, LUID_AND_ATTRIBUTES, LookupPrivilegeValueW, SE_BACKUP_NAME,
+    SE_PRIVILEGE_ENABLED, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES,
+};
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+/// Enable SeBackupPrivilege for this process. Files opened afterwards with
+/// `FILE_FLAG_BACKUP_SEMANTICS` can be read whatever their ACLs say.
+///
+/// Returns `false` when the account doesn't hold the privilege at all (not an
+/// administrator or Backup Operator, or the process isn't elevated).
+pub fn enable_backup_privilege() -> Result<bool> {
+    let mut token: HANDLE = 0;
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES, &mut token) } == 0 {
+        bail!(
+            "Failed to open the process token: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    let result = (|| {
+        let mut luid = LUID {
+            LowPart: 0,
+            HighPart: 0,
+        };
+        if unsafe { LookupPrivilegeValueW(std::ptr::null(), SE_BACKUP_NAME, &mut luid) } == 0 {
+            bail!(
+                "Failed to look up SeBackupPrivilege: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            Privileges: [LUID_AND_ATTRIBUTES {
+                Luid: luid,
+                Attributes: SE_PRIVILEGE_ENABLED,
+            }],
+        };
+        let ok = unsafe {
+            AdjustTokenPrivileges(
+                token,
+                0,
+                &privileges,
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            bail!(
+                "Failed to enable SeBackupPrivilege: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // Success is also reported when the token lacks the privilege
+        Ok(unsafe { GetLastError() } != ERROR_NOT_ALL_ASSIGNED)
+    })();
+
+    unsafe { CloseHandle(token) };
+    result
+}
@@ -0,0 +1,99 @@
+use anyhow::{Context, Result, bail};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::Command;
+
+/// A Volume Shadow Copy of the volume holding the pack source.
+///
+/// The snapshot is created through WMI (`Win32_ShadowCopy`) so no extra
+/// dependencies are needed, and is deleted again when this value is dropped.
+pub struct ShadowCopy {
+    id: String,
+    device: PathBuf,
+}
+
+impl ShadowCopy {
+    /// Create a client-accessible shadow copy of the volume containing `path`.
+    /// Requires an elevated (administrator) process.
+    pub fn create(path: &Path) -> Result<Self> {
+        let abs = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {:?}", path))?;
+        let (drive, _) = split_drive(&abs)?;
+
+        let script = format!(
+            "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}:\\', 'ClientAccessible'); \
+             if ($r.ReturnValue -ne 0) {{ exit $r.ReturnValue }}; \
+             $s = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+             Write-Output $s.ID; Write-Output $s.DeviceObject",
+            drive
+        );
+        let out = run_powershell(&script)?;
+        if !out.status.success() {
+            bail!(
+                "Failed to create shadow copy of {}:\\ (code {:?}, administrator rights required): {}",
+                drive,
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let mut lines = stdout.lines().map(str::trim).filter(|l| !l.is_empty());
+        let (Some(id), Some(device)) = (lines.next(), lines.next()) else {
+            bail!(
+                "Unexpected output while creating shadow copy: {}",
+                stdout.trim()
+            );
+        };
+
+        Ok(Self {
+            id: id.to_string(),
+            device: PathBuf::from(format!("{}\\", device)),
+        })
+    }
+
+    /// Map a path on the live volume to the same path inside the snapshot.
+    pub fn translate(&self, path: &Path) -> Result<PathBuf> {
+        let abs = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {:?}", path))?;
+        let (_, relative) = split_drive(&abs)?;
+        Ok(self.device.join(relative))
+    }
+}
+
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | ForEach-Object {{ $_.Delete() }}",
+            self.id
+        );
+        match run_powershell(&script) {
+            Ok(out) if out.status.success() => {}
+            _ => eprintln!("Warning: Failed to delete shadow copy {}", self.id),
+        }
+    }
+}
+
+/// Split an absolute path into its drive letter and the path below the volume root.
+fn split_drive(path: &Path) -> Result<(char, PathBuf)> {
+    let mut components = path.components();
+    let drive = match components.next() {
+        Some(Component::Prefix(p)) => match p.kind() {
+            Prefix::Disk(d) | Prefix::VerbatimDisk(d) => d as char,
+            _ => bail!("VSS snapshots require a path on a local drive: {:?}", path),
+        },
+        _ => bail!("VSS snapshots require an absolute path: {:?}", path),
+    };
+    let relative = components
+        .filter(|c| !matches!(c, Component::RootDir))
+        .collect();
+    Ok((drive, relative))
+}
+
+fn run_powershell(script: &str) -> Result<std::process::Output> {
+    Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .context("Failed to run powershell")
+}
@@ -0,0 +1,120 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Where the pack pipeline spends its time.
+#[derive(Clone, Copy)]
+pub enum Stage {
+    /// Walking the directory tree
+    Scan,
+    /// Scanner waiting for room in the path channel
+    ScanBlocked,
+    /// Readers processing files, including `ReadBlocked` (summed over all workers)
+    Read,
+    /// Readers waiting for room in the content channel or a chunk stream
+    ReadBlocked,
+    /// Writer waiting for the next entry
+    WriterIdle,
+    /// Writer waiting for the next chunk of a large file
+    StreamWait,
+    /// Writer appending entries, including compression and stream waits
+    Append,
+    /// Compressor consuming the tar stream
+    Compress,
+}
+
+const STAGES: usize = 8;
+
+/// Accumulated time per stage. Recording is a no-op unless enabled, so the
+/// timers can stay in the hot paths.
+pub struct PipelineProfile {
+    enabled: bool,
+    nanos: [AtomicU64; STAGES],
+}
+
+impl PipelineProfile {
+    pub fn new(enabled: bool) -> Arc<Self> {
+        Arc::new(PipelineProfile {
+            enabled,
+            nanos: Default::default(),
+        })
+    }
+
+    /// Start timing; pass the result to `stop`.
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled.then(Instant::now)
+    }
+
+    pub fn stop(&self, stage: Stage, started: Option<Instant>) {
+        if let Some(started) = started {
+            self.nanos[stage as usize]
+                .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.nanos[stage as usize].load(Ordering::Relaxed))
+    }
+
+    /// Print the per-stage breakdown to stderr, away from an archive on stdout.
+    pub fn report(&self, wall: Duration, workers: usize) {
+        if !self.enabled {
+            return;
+        }
+        let append = self.get(Stage::Append);
+        let serialize = append
+            .saturating_sub(self.get(Stage::Compress))
+            .saturating_sub(self.get(Stage::StreamWait));
+        let secs = |d: Duration| d.as_secs_f64();
+
+        eprintln!("Pipeline profile (wall {:.2}s):", secs(wall));
+        eprintln!(
+            "  scan            {:>8.2}s  (+{:.2}s blocked on readers)",
+            secs(self.get(Stage::Scan)),
+            secs(self.get(Stage::ScanBlocked))
+        );
+        eprintln!(
+            "  read            {:>8.2}s  summed over {} workers (+{:.2}s blocked on writer)",
+            secs(
+                self.get(Stage::Read)
+                    .saturating_sub(self.get(Stage::ReadBlocked))
+            ),
+            workers,
+            secs(self.get(Stage::ReadBlocked))
+        );
+        eprintln!(
+            "  writer waiting  {:>8.2}s  (entries {:.2}s, large file chunks {:.2}s)",
+            secs(self.get(Stage::WriterIdle) + self.get(Stage::StreamWait)),
+            secs(self.get(Stage::WriterIdle)),
+            secs(self.get(Stage::StreamWait))
+        );
+        eprintln!("  tar serialize   {:>8.2}s", secs(serialize));
+        eprintln!(
+            "  compress        {:>8.2}s",
+            secs(self.get(Stage::Compress))
+        );
+    }
+}
+
+/// Writer adapter that books the time spent in `inner` under `Stage::Compress`.
+pub struct TimedWriter<W: Write> {
+    pub inner: W,
+    pub profile: Arc<PipelineProfile>,
+}
+
+impl<W: Write> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let t = self.profile.start();
+        let res = self.inner.write(buf);
+        self.profile.stop(Stage::Compress, t);
+        res
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let t = self.profile.start();
+        let res = self.inner.flush();
+        self.profile.stop(Stage::Compress, t);
+        res
+    }
+}
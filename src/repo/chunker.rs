@@ -0,0 +1,85 @@
+use std::io::{self, Read};
+
+/// Chunks are never cut before this many bytes
+pub const MIN_CHUNK_SIZE: usize = 256 * 1024;
+/// Chunks are always cut at this size
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// A cut point is found on average every 2^20 bytes (1MB) past the minimum
+const CUT_MASK: u64 = (1 << 20) - 1;
+
+/// Random table for the gear rolling hash, generated with splitmix64 so it is
+/// stable across builds (chunk boundaries must never change for a repository).
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x7a73_7461_7220_6364; // "zstar cd"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Content-defined chunker (gear hash, FastCDC style): boundaries depend on
+/// the data itself, so an insertion only changes the chunks around it.
+pub struct Chunker<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.fill()?;
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut cut = self.buf.len();
+        if self.buf.len() > MIN_CHUNK_SIZE {
+            let mut hash: u64 = 0;
+            for (i, &b) in self.buf.iter().enumerate().skip(MIN_CHUNK_SIZE) {
+                hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+                if hash & CUT_MASK == 0 {
+                    cut = i + 1;
+                    break;
+                }
+            }
+        }
+
+        let rest = self.buf.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let start = self.buf.len();
+            self.buf.resize(MAX_CHUNK_SIZE, 0);
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(0) => {
+                    self.buf.truncate(start);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(start + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(start),
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
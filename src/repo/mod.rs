@@ -0,0 +1,329 @@
+pub mod chunker;
+pub mod snapshot;
+
+use anyhow::{Context, Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Marker file identifying a repository and its format version
+const REPO_MARKER: &str = "zstar-repo";
+const REPO_VERSION: &str = "1";
+
+/// Locked by every command for as long as it has the repository open
+const LOCK_FILE: &str = "lock";
+
+/// A pack file is closed and a new one started once it grows past this (64MB)
+pub const PACK_TARGET_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a chunk lives: compressed bytes `offset..offset + len` of a pack file.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkLocation {
+    pub pack: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Compressed bytes stored in a pack file and how many of them are still referenced.
+#[derive(Debug, Clone, Copy)]
+pub struct PackUsage {
+    pub id: u32,
+    pub total: u64,
+    pub live: u64,
+}
+
+/// How a command holds the repository lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Backups, restores and listings, which only add files or read them
+    Shared,
+    /// Prune and compact, which delete packs the others may be reading or writing
+    Exclusive,
+}
+
+struct PackWriter {
+    id: u32,
+    pack: File,
+    idx: File,
+    offset: u64,
+}
+
+/// Content-addressed chunk store.
+///
+/// Layout:
+/// - `zstar-repo`: marker with the format version
+/// - `packs/<id>.pack`: concatenated zstd-compressed chunks
+/// - `packs/<id>.idx`: one `<blake3> <offset> <len>` line per chunk in the pack
+/// - `snapshots/<id>.json.zst`: snapshot manifests
+/// - `lock`: advisory lock, see `Access`
+pub struct Repository {
+    root: PathBuf,
+    /// Held until the repository is dropped
+    _lock: File,
+    level: i32,
+    index: HashMap<String, ChunkLocation>,
+    writer: Option<PackWriter>,
+    next_pack: u32,
+}
+
+impl Repository {
+    pub fn init(root: &Path) -> Result<()> {
+        if root.join(REPO_MARKER).exists() {
+            bail!("{:?} is already a repository", root);
+        }
+        if root.exists() && fs::read_dir(root)?.next().is_some() {
+            bail!("{:?} exists and is not empty", root);
+        }
+        fs::create_dir_all(root.join("packs"))?;
+        fs::create_dir_all(root.join("snapshots"))?;
+        fs::write(root.join(REPO_MARKER), format!("{}\n", REPO_VERSION))?;
+        Ok(())
+    }
+
+    pub fn open(root: &Path, level: i32, access: Access) -> Result<Self> {
+        let marker = fs::read_to_string(root.join(REPO_MARKER))
+            .with_context(|| format!("{:?} is not a zstar repository", root))?;
+        if marker.trim() != REPO_VERSION {
+            bail!("Unsupported repository version {:?}", marker.trim());
+        }
+        let lock = lock(root, access)?;
+
+        let mut index = HashMap::new();
+        let mut next_pack = 0;
+        for id in pack_ids(&root.join("packs"))? {
+            next_pack = next_pack.max(id + 1);
+            let idx = File::open(pack_path(root, id, "idx"))?;
+            for line in BufReader::new(idx).lines() {
+                let line = line?;
+                let mut fields = line.split(' ');
+                let (Some(hash), Some(offset), Some(len)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    continue; // Torn write from an interrupted backup
+                };
+                let (Ok(offset), Ok(len)) = (offset.parse(), len.parse()) else {
+                    continue;
+                };
+                index.insert(
+                    hash.to_string(),
+                    ChunkLocation {
+                        pack: id,
+                        offset,
+                        len,
+                    },
+                );
+            }
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            _lock: lock,
+            level,
+            index,
+            writer: None,
+            next_pack,
+        })
+    }
+
+    pub fn snapshots_dir(&self) -> PathBuf {
+        self.root.join("snapshots")
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Store a chunk unless it is already present. Returns its hash and
+    /// whether it was newly added.
+    pub fn add_chunk(&mut self, data: &[u8]) -> Result<(String, bool)> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        if self.contains(&hash) {
+            return Ok((hash, false));
+        }
+        let compressed = zstd::bulk::compress(data, self.level)?;
+        self.append_compressed(&hash, &compressed)?;
+        Ok((hash, true))
+    }
+
+    /// Append an already compressed chunk to the current pack.
+    fn append_compressed(&mut self, hash: &str, compressed: &[u8]) -> Result<()> {
+        if self.writer.is_none() {
+            let id = self.next_pack;
+            self.next_pack += 1;
+            let open = |ext| {
+                OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(pack_path(&self.root, id, ext))
+            };
+            self.writer = Some(PackWriter {
+                id,
+                pack: open("pack")?,
+                idx: open("idx")?,
+                offset: 0,
+            });
+        }
+        let writer = self.writer.as_mut().unwrap();
+
+        writer.pack.write_all(compressed)?;
+        let location = ChunkLocation {
+            pack: writer.id,
+            offset: writer.offset,
+            len: compressed.len() as u64,
+        };
+        writeln!(writer.idx, "{} {} {}", hash, location.offset, location.len)?;
+        writer.offset += location.len;
+        self.index.insert(hash.to_string(), location);
+
+        if writer.offset >= PACK_TARGET_SIZE {
+            self.close_pack()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        let data = zstd::decode_all(&self.read_compressed(hash)?[..])?;
+        if blake3::hash(&data).to_hex().as_str() != hash {
+            bail!("Chunk {} is corrupt", hash);
+        }
+        Ok(data)
+    }
+
+    fn read_compressed(&self, hash: &str) -> Result<Vec<u8>> {
+        let loc = self
+            .index
+            .get(hash)
+            .with_context(|| format!("Chunk {} missing from repository", hash))?;
+        let mut pack = File::open(pack_path(&self.root, loc.pack, "pack"))?;
+        pack.seek(SeekFrom::Start(loc.offset))?;
+        let mut buf = vec![0u8; loc.len as usize];
+        pack.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn close_pack(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.pack.sync_all()?;
+            writer.idx.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Hashes of every chunk referenced by a snapshot.
+    pub fn referenced_chunks(&self) -> Result<HashSet<String>> {
+        let dir = self.snapshots_dir();
+        let mut live = HashSet::new();
+        for id in snapshot::Snapshot::list_ids(&dir)? {
+            for entry in snapshot::Snapshot::load(&dir, &id)?.entries {
+                if let snapshot::EntryKind::File { chunks, .. } = entry.kind {
+                    live.extend(chunks);
+                }
+            }
+        }
+        Ok(live)
+    }
+
+    pub fn pack_usage(&self, live: &HashSet<String>) -> Result<Vec<PackUsage>> {
+        let mut usage = Vec::new();
+        for id in pack_ids(&self.root.join("packs"))? {
+            let total = fs::metadata(pack_path(&self.root, id, "pack"))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            let live_bytes = self
+                .index
+                .iter()
+                .filter(|(hash, loc)| loc.pack == id && live.contains(*hash))
+                .map(|(_, loc)| loc.len)
+                .sum();
+            usage.push(PackUsage {
+                id,
+                total,
+                live: live_bytes,
+            });
+        }
+        Ok(usage)
+    }
+
+    /// Copy the live chunks of `ids` into fresh packs, then delete the old
+    /// packs. Chunks are durable in their new pack before the old one is removed.
+    pub fn rewrite_packs(&mut self, ids: &[u32], live: &HashSet<String>) -> Result<()> {
+        let ids: HashSet<u32> = ids.iter().copied().collect();
+        let mut moving: Vec<(String, ChunkLocation)> = self
+            .index
+            .iter()
+            .filter(|(hash, loc)| ids.contains(&loc.pack) && live.contains(*hash))
+            .map(|(hash, loc)| (hash.clone(), *loc))
+            .collect();
+        // Sequential reads through each old pack
+        moving.sort_by_key(|(_, loc)| (loc.pack, loc.offset));
+
+        for (hash, _) in moving {
+            let compressed = self.read_compressed(&hash)?;
+            self.append_compressed(&hash, &compressed)?;
+        }
+        self.flush()?;
+        self.remove_packs(&ids)
+    }
+
+    /// Delete pack files and forget the chunks they hold.
+    pub fn remove_packs(&mut self, ids: &HashSet<u32>) -> Result<()> {
+        for &id in ids {
+            // Index first: a pack without index is invisible, the reverse is not
+            fs::remove_file(pack_path(&self.root, id, "idx"))?;
+            fs::remove_file(pack_path(&self.root, id, "pack"))?;
+        }
+        self.index.retain(|_, loc| !ids.contains(&loc.pack));
+        Ok(())
+    }
+
+    /// Make all stored chunks durable. Must be called before saving a
+    /// snapshot that references them.
+    pub fn flush(&mut self) -> Result<()> {
+        self.close_pack()
+    }
+}
+
+/// Take the repository lock without waiting: a prune blocked behind an
+/// hours-long backup is better started again later.
+fn lock(root: &Path, access: Access) -> Result<File> {
+    let path = root.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let locked = match access {
+        Access::Shared => file.try_lock_shared(),
+        Access::Exclusive => file.try_lock(),
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => match access {
+            Access::Shared => bail!("Repository {:?} is being pruned or compacted", root),
+            Access::Exclusive => bail!("Repository {:?} is in use by another zstar process", root),
+        },
+        Err(fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to lock {:?}", path))
+        }
+    }
+}
+
+fn pack_path(root: &Path, id: u32, ext: &str) -> PathBuf {
+    root.join("packs").join(format!("{:08x}.{}", id, ext))
+}
+
+fn pack_ids(dir: &Path) -> Result<Vec<u32>> {
+    let mut ids: Vec<u32> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            e.file_name()
+                .to_str()
+                .and_then(|n| n.strip_suffix(".idx"))
+                .and_then(|n| u32::from_str_radix(n, 16).ok())
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
@@ -0,0 +1,72 @@
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::codec;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EntryKind {
+    Dir,
+    File { size: u64, chunks: Vec<String> },
+    Symlink { target: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// Path relative to the backed-up directory, `/` separated
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+/// Manifest of one backup run, stored zstd-compressed under `snapshots/`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub source: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", self.id));
+        {
+            let mut encoder = zstd::Encoder::new(File::create(&tmp)?, 3)?;
+            serde_json::to_writer(&mut encoder, self)?;
+            encoder.finish()?.sync_all()?;
+        }
+        fs::rename(&tmp, dir.join(format!("{}.json.zst", self.id)))?;
+        Ok(())
+    }
+
+    pub fn load(dir: &Path, id: &str) -> Result<Self> {
+        // Ids become file names; `../` would read from anywhere
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            bail!("Invalid snapshot id {:?}", id);
+        }
+        let path = dir.join(format!("{}.json.zst", id));
+        let file = File::open(&path).with_context(|| format!("Snapshot {:?} not found", id))?;
+        let snapshot = serde_json::from_reader(codec::decompress(file, None)?)
+            .with_context(|| format!("Failed to read snapshot {:?}", id))?;
+        Ok(snapshot)
+    }
+
+    /// Ids of all snapshots in the repository, oldest first.
+    pub fn list_ids(dir: &Path) -> Result<Vec<String>> {
+        let mut ids: Vec<String> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                e.file_name()
+                    .to_str()
+                    .and_then(|n| n.strip_suffix(".json.zst"))
+                    .map(String::from)
+            })
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+}
@@ -0,0 +1,45 @@
+use std::path::Path;
+
+/// Names Windows maps to devices regardless of directory or extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why an archive path may not be extracted, or `None` if it stays inside
+/// the output directory. Both `/` and `\` count as separators so a path is
+/// judged the same on every platform; device names are only rejected on
+/// Windows, where they are unusable (`aux.c` is an ordinary file elsewhere).
+pub fn reject_reason(path: &Path) -> Option<&'static str> {
+    let path = path.to_string_lossy();
+    if path.starts_with('/') || path.starts_with('\\') {
+        return Some("absolute path");
+    }
+    for (i, component) in path.split(['/', '\\']).enumerate() {
+        if component == ".." {
+            return Some("parent directory component");
+        }
+        if i == 0 && is_drive_prefix(component) {
+            return Some("drive letter");
+        }
+        if cfg!(windows) && is_reserved_name(component) {
+            return Some("reserved device name");
+        }
+    }
+    None
+}
+
+/// `C:`, or `C:foo`, which Windows resolves against that drive's current directory
+fn is_drive_prefix(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+fn is_reserved_name(component: &str) -> bool {
+    // Windows ignores extensions and trailing dots/spaces: "nul.txt", "CON ."
+    let stem = component.split('.').next().unwrap_or(component);
+    let stem = stem.trim_end_matches([' ', '.']);
+    RESERVED_NAMES
+        .iter()
+        .any(|name| stem.eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,536 @@
+//! `pack --seekable`: the archive as independent zstd frames of a fixed
+//! amount of tar data each, in the zstd seekable format, so a reader can
+//! start decoding at any frame. Ahead of the format's seek table sits a
+//! skippable frame with an index of the entries (kind, size, path, link and
+//! where the entry starts in the tar stream), which `list` prints without
+//! decompressing anything and `unpack ARCHIVE PATH...` uses to decode only
+//! the frames holding the requested entries.
+//!
+//! Any zstd decoder reads the archive as one stream; the index frame is
+//! listed in the seek table as a frame without content, which seekable
+//! readers skip. The index payload is `ZSIX`, a version byte, then these
+//! records compressed as one zstd frame, integers little-endian:
+//!
+//! | bytes | field                                             |
+//! |-------|---------------------------------------------------|
+//! | 8     | offset of the tar trailer (end of the last entry) |
+//! | 8     | number of entries                                 |
+//!
+//! and for each entry, in archive order:
+//!
+//! | bytes | field                                             |
+//! |-------|---------------------------------------------------|
+//! | 8     | offset of its first header in the tar stream      |
+//! | 1     | kind: 0 directory, 1 file, 2 symlink, 3 hardlink  |
+//! | 8     | file size                                         |
+//! | 4 + n | path, UTF-8, after its length                     |
+//! | 4 + n | link target, UTF-8, after its length (empty if none) |
+
+use anyhow::{Context, Result, bail};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::dialect::EntryKind;
+use crate::utils::{put_u32, put_u64, u32_at};
+use crate::validate::window_log_for;
+
+pub mod parallel;
+
+/// Skippable frame of the zstd seekable format and the magic ending it
+pub const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+pub const SEEK_TABLE_FOOTER_MAGIC: u32 = 0x8F92_EAB1;
+/// Seek table descriptor bit: every entry carries a checksum
+const SEEK_TABLE_CHECKSUMS: u8 = 0x80;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+
+/// Skippable frame holding the entry index
+const INDEX_MAGIC: u32 = 0x184D_2A5A;
+const INDEX_TAG: &[u8; 4] = b"ZSIX";
+const INDEX_VERSION: u8 = 1;
+
+/// One archive entry as the index records it.
+pub struct IndexEntry {
+    pub kind: EntryKind,
+    /// File size; 0 for everything else
+    pub size: u64,
+    pub path: String,
+    /// Symlink or hardlink target
+    pub link: Option<String>,
+    /// Where its headers (pax records included) start in the tar stream
+    pub offset: u64,
+}
+
+/// The entry index of a seekable archive and where its frames start.
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+    /// Offset of the tar trailer, where the last entry ends
+    pub end: u64,
+    frames: Vec<Frame>,
+}
+
+/// A frame as the seek table lists it: where it starts in the file and
+/// in the tar stream, and its size in each.
+#[derive(Clone, Copy)]
+struct Frame {
+    compressed: u64,
+    compressed_size: u32,
+    decompressed: u64,
+    decompressed_size: u32,
+}
+
+impl Index {
+    /// Tar stream ranges holding the entries `keep` accepts, adjacent ones joined.
+    pub fn ranges(&self, mut keep: impl FnMut(&IndexEntry) -> bool) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if !keep(entry) {
+                continue;
+            }
+            let end = self.entries.get(i + 1).map_or(self.end, |next| next.offset);
+            match ranges.last_mut() {
+                Some(last) if last.end == entry.offset => last.end = end,
+                _ => ranges.push(entry.offset..end),
+            }
+        }
+        ranges
+    }
+}
+
+/// Archive path as the index stores it: UTF-8 with forward slashes.
+pub fn index_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    #[cfg(windows)]
+    let path = path.replace('\\', "/");
+    path.into_owned()
+}
+
+/// Compressed output, counted so each frame's size is known.
+struct Counted<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// zstd compressor that ends a frame after every `frame_size` bytes of
+/// input and closes the archive with the entry index and the seek table.
+pub struct SeekableWriter<W: Write> {
+    /// Compressing a frame, or `None` between frames with the output in `idle`
+    encoder: Option<Box<zstd::Encoder<'static, Counted<W>>>>,
+    idle: Option<Counted<W>>,
+    level: i32,
+    threads: u32,
+    long_distance: bool,
+    frame_size: u64,
+    /// Input taken into the current frame
+    pending: u64,
+    /// Output written before the current frame
+    frame_start: u64,
+    /// (compressed, decompressed) size of every frame so far
+    frames: Vec<(u32, u32)>,
+    entries: Vec<IndexEntry>,
+    /// Offset of the tar trailer
+    end: u64,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    pub fn new(inner: W, level: i32, threads: u32, long_distance: bool, frame_size: u64) -> Self {
+        SeekableWriter {
+            encoder: None,
+            idle: Some(Counted { inner, count: 0 }),
+            level,
+            threads,
+            long_distance,
+            frame_size,
+            pending: 0,
+            frame_start: 0,
+            frames: Vec::new(),
+            entries: Vec::new(),
+            end: 0,
+        }
+    }
+
+    /// Set the entries to index and where the tar trailer starts, before `finish`.
+    pub fn set_index(&mut self, entries: Vec<IndexEntry>, end: u64) {
+        self.entries = entries;
+        self.end = end;
+    }
+
+    fn start_frame(&mut self) -> io::Result<()> {
+        let output = self.idle.take().expect("seekable writer lost its output");
+        let mut encoder = zstd::Encoder::new(output, self.level)?;
+        encoder.multithread(self.threads)?;
+        if self.long_distance {
+            let _ = encoder.long_distance_matching(true);
+            // Its 128MB window would only make decoders reserve memory no frame uses
+            let _ =
+                encoder.window_log(self.frame_size.next_power_of_two().trailing_zeros().min(27));
+        }
+        self.encoder = Some(Box::new(encoder));
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+        let Some(encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+        let output = encoder.finish()?;
+        // Both fit: frames hold at most `frame_size` (a few hundred MB) of input
+        let compressed = (output.count - self.frame_start) as u32;
+        self.frames.push((compressed, self.pending as u32));
+        self.frame_start = output.count;
+        self.pending = 0;
+        self.idle = Some(output);
+        Ok(())
+    }
+
+    /// End the last frame, then write the entry index and the seek table.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_frame()?;
+        let mut output = self.idle.take().expect("seekable writer lost its output");
+
+        let index = encode_index(&self.entries, self.end)?;
+        output.write_all(&index)?;
+        self.frames.push((index.len() as u32, 0));
+
+        let mut table = Vec::with_capacity(8 * self.frames.len() + 17);
+        put_u32(&mut table, SEEK_TABLE_MAGIC);
+        put_u32(
+            &mut table,
+            (8 * self.frames.len() as u64 + SEEK_TABLE_FOOTER_SIZE) as u32,
+        );
+        for (compressed, decompressed) in &self.frames {
+            put_u32(&mut table, *compressed);
+            put_u32(&mut table, *decompressed);
+        }
+        put_u32(&mut table, self.frames.len() as u32);
+        table.push(0); // No checksums
+        put_u32(&mut table, SEEK_TABLE_FOOTER_MAGIC);
+        output.write_all(&table)?;
+        Ok(output.inner)
+    }
+}
+
+impl<W: Write> Write for SeekableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.encoder.is_none() {
+            self.start_frame()?;
+        }
+        // Frames end exactly at `frame_size`, so each holds the same span of the tar stream
+        let room = (self.frame_size - self.pending) as usize;
+        let encoder = self.encoder.as_mut().expect("frame was just started");
+        let n = encoder.write(&buf[..buf.len().min(room)])?;
+        self.pending += n as u64;
+        if self.pending == self.frame_size {
+            self.end_frame()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match (&mut self.encoder, &mut self.idle) {
+            (Some(encoder), _) => encoder.flush(),
+            (None, Some(output)) => output.flush(),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The index as a skippable frame.
+fn encode_index(entries: &[IndexEntry], end: u64) -> io::Result<Vec<u8>> {
+    let mut records = Vec::new();
+    put_u64(&mut records, end);
+    put_u64(&mut records, entries.len() as u64);
+    for entry in entries {
+        put_u64(&mut records, entry.offset);
+        records.push(match entry.kind {
+            EntryKind::Directory => 0,
+            EntryKind::File => 1,
+            EntryKind::Symlink => 2,
+            EntryKind::Hardlink => 3,
+            kind => unreachable!("pack writes no {:?} entries", kind),
+        });
+        put_u64(&mut records, entry.size);
+        put_str(&mut records, &entry.path);
+        put_str(&mut records, entry.link.as_deref().unwrap_or(""));
+    }
+    let records = zstd::encode_all(&records[..], 3)?;
+
+    let size = u32::try_from(INDEX_TAG.len() + 1 + records.len())
+        .map_err(|_| io::Error::other("Entry index is too large for a skippable frame"))?;
+    let mut frame = Vec::with_capacity(8 + size as usize);
+    put_u32(&mut frame, INDEX_MAGIC);
+    put_u32(&mut frame, size);
+    frame.extend_from_slice(INDEX_TAG);
+    frame.push(INDEX_VERSION);
+    frame.extend_from_slice(&records);
+    Ok(frame)
+}
+
+/// The frames of a seekable archive, or `None` if it has no seek table.
+fn read_seek_table(file: &mut File) -> Result<Option<Vec<Frame>>> {
+    let len = file.metadata()?.len();
+    if len < 8 + SEEK_TABLE_FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(len - SEEK_TABLE_FOOTER_SIZE))?;
+    let mut footer = [0u8; SEEK_TABLE_FOOTER_SIZE as usize];
+    file.read_exact(&mut footer)?;
+    if u32_at(&footer, 5) != SEEK_TABLE_FOOTER_MAGIC {
+        return Ok(None);
+    }
+    let count = u32_at(&footer, 0) as u64;
+    let entry_size = if footer[4] & SEEK_TABLE_CHECKSUMS != 0 {
+        12
+    } else {
+        8
+    };
+    let table_size = 8 + count * entry_size + SEEK_TABLE_FOOTER_SIZE;
+    if table_size > len {
+        bail!("Seek table is larger than the archive");
+    }
+
+    file.seek(SeekFrom::Start(len - table_size))?;
+    let mut table = vec![0u8; (table_size - SEEK_TABLE_FOOTER_SIZE) as usize];
+    file.read_exact(&mut table)?;
+    if u32_at(&table, 0) != SEEK_TABLE_MAGIC {
+        bail!("Seek table footer without a seek table");
+    }
+
+    let mut frames = Vec::with_capacity(count as usize);
+    let (mut compressed, mut decompressed) = (0u64, 0u64);
+    for record in table[8..].chunks_exact(entry_size as usize) {
+        let frame = Frame {
+            compressed,
+            compressed_size: u32_at(record, 0),
+            decompressed,
+            decompressed_size: u32_at(record, 4),
+        };
+        compressed += frame.compressed_size as u64;
+        decompressed += frame.decompressed_size as u64;
+        frames.push(frame);
+    }
+    if compressed != len - table_size {
+        bail!("Seek table doesn't match the frames of the archive");
+    }
+    Ok(Some(frames))
+}
+
+/// The entry index of the archive at `path`, or `None` if it wasn't
+/// written with `pack --seekable` (or by a zstar that knows no newer index).
+pub fn read_index(path: &Path) -> Result<Option<Index>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let Some(mut frames) = read_seek_table(&mut file)? else {
+        return Ok(None);
+    };
+
+    // Other seekable writers leave the index out; their archives stream as usual
+    let Some(&Frame {
+        compressed: offset,
+        compressed_size: size,
+        decompressed_size: 0,
+        ..
+    }) = frames.last()
+    else {
+        return Ok(None);
+    };
+    if size < 8 + INDEX_TAG.len() as u32 + 1 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut frame = vec![0u8; size as usize];
+    file.read_exact(&mut frame)?;
+    if u32_at(&frame, 0) != INDEX_MAGIC || &frame[8..12] != INDEX_TAG || frame[12] != INDEX_VERSION
+    {
+        return Ok(None);
+    }
+
+    let records = zstd::decode_all(&frame[13..]).context("Entry index is corrupt")?;
+    let (end, entries) = decode_records(&records).context("Entry index is corrupt")?;
+    frames.retain(|frame| frame.decompressed_size > 0);
+    Ok(Some(Index {
+        entries,
+        end,
+        frames,
+    }))
+}
+
+fn decode_records(mut records: &[u8]) -> io::Result<(u64, Vec<IndexEntry>)> {
+    let end = read_u64(&mut records)?;
+    let count = read_u64(&mut records)?;
+    // Each record takes at least 25 bytes; a bogus count can't allocate more than that
+    let mut entries = Vec::with_capacity(count.min(records.len() as u64 / 25) as usize);
+    for _ in 0..count {
+        let offset = read_u64(&mut records)?;
+        // Ranges run from each offset to the next, so they have to be in order
+        let previous = entries.last().map_or(0, |entry: &IndexEntry| entry.offset);
+        if offset < previous || offset > end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("entry offset {} out of order", offset),
+            ));
+        }
+        let mut kind = [0u8];
+        records.read_exact(&mut kind)?;
+        let kind = match kind[0] {
+            0 => EntryKind::Directory,
+            1 => EntryKind::File,
+            2 => EntryKind::Symlink,
+            3 => EntryKind::Hardlink,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown entry kind {}", other),
+                ));
+            }
+        };
+        let size = read_u64(&mut records)?;
+        let path = read_str(&mut records)?;
+        let link = Some(read_str(&mut records)?).filter(|link| !link.is_empty());
+        entries.push(IndexEntry {
+            kind,
+            size,
+            path,
+            link,
+            offset,
+        });
+    }
+    Ok((end, entries))
+}
+
+/// The parts of the tar stream in `ranges`, one after the other, decoded
+/// from the frames holding them. Together they are a tar stream of just
+/// those entries, without a trailer.
+pub fn open_ranges(
+    path: &Path,
+    index: Index,
+    ranges: Vec<Range<u64>>,
+    memory_limit: Option<u64>,
+) -> Result<Box<dyn Read + Send>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    Ok(Box::new(RangeReader {
+        file,
+        frames: index.frames,
+        ranges: ranges.into(),
+        decoder: None,
+        position: 0,
+        remaining: 0,
+        memory_limit,
+    }))
+}
+
+struct RangeReader {
+    file: File,
+    frames: Vec<Frame>,
+    ranges: VecDeque<Range<u64>>,
+    decoder: Option<zstd::Decoder<'static, BufReader<File>>>,
+    /// Where the decoder is in the tar stream
+    position: u64,
+    /// Left to read of the current range
+    remaining: u64,
+    memory_limit: Option<u64>,
+}
+
+impl RangeReader {
+    /// Get the decoder to `target`: read on if it is in the frame being
+    /// decoded, otherwise start over at the frame holding it.
+    fn seek_to(&mut self, target: u64) -> io::Result<()> {
+        let holding = self.frames.partition_point(|f| f.decompressed <= target);
+        let frame = holding
+            .checked_sub(1)
+            .and_then(|i| self.frames.get(i))
+            .ok_or_else(truncated)?;
+        let decoder = match self.decoder.take() {
+            Some(decoder) if self.position >= frame.decompressed && self.position <= target => {
+                decoder
+            }
+            _ => {
+                let mut file = self.file.try_clone()?;
+                file.seek(SeekFrom::Start(frame.compressed))?;
+                let mut decoder = zstd::Decoder::new(file)?;
+                if let Some(limit) = self.memory_limit {
+                    decoder.window_log_max(window_log_for(limit))?;
+                }
+                self.position = frame.decompressed;
+                decoder
+            }
+        };
+        let decoder = self.decoder.insert(decoder);
+        let skip = target - self.position;
+        if io::copy(&mut decoder.take(skip), &mut io::sink())? < skip {
+            return Err(truncated());
+        }
+        self.position = target;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            let Some(range) = self.ranges.pop_front() else {
+                return Ok(0);
+            };
+            self.seek_to(range.start)?;
+            self.remaining = range.end - range.start;
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let decoder = self.decoder.as_mut().expect("positioned before reading");
+        let n = decoder.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(truncated());
+        }
+        self.position += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "archive ends before the entries its index lists",
+    )
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn read_u64(records: &mut &[u8]) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    records.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_str(records: &mut &[u8]) -> io::Result<String> {
+    let mut len = [0u8; 4];
+    records.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > records.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (value, rest) = records.split_at(len);
+    *records = rest;
+    String::from_utf8(value.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,140 @@
+//! Decoding the frames of a seekable archive on several threads at once.
+//! Each frame starts without history, so decoder `i` of `n` takes frames
+//! `i`, `i + n`, ... on its own file handle, and the reader collects the
+//! decoded frames round-robin, which puts them back in archive order.
+
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, bounded};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+
+use super::{Frame, read_seek_table};
+use crate::validate::window_log_for;
+
+/// Decoded frames held at once, queued or being decoded, stay under this (512MB)
+const DECODE_MEMORY: u64 = 512 * 1024 * 1024;
+
+/// The tar stream of the archive at `path`, decoded by up to `threads`
+/// threads, or `None` if it isn't a seekable archive of several frames.
+pub fn open(
+    path: &Path,
+    threads: usize,
+    memory_limit: Option<u64>,
+) -> Result<Option<Box<dyn Read + Send>>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let Some(mut frames) = read_seek_table(&mut file)? else {
+        return Ok(None);
+    };
+    frames.retain(|frame| frame.decompressed_size > 0);
+
+    // Every decoder holds one frame it is decoding and one waiting for the reader
+    let largest = frames
+        .iter()
+        .map(|frame| frame.decompressed_size as u64)
+        .max()
+        .unwrap_or(0);
+    let decoders = threads
+        .min(frames.len())
+        .min((DECODE_MEMORY / (2 * largest.max(1))) as usize);
+    if decoders < 2 {
+        return Ok(None);
+    }
+
+    let mut decoded = Vec::with_capacity(decoders);
+    for first in 0..decoders {
+        let (tx, rx) = bounded(1);
+        let path = path.to_path_buf();
+        let frames: Vec<Frame> = frames
+            .iter()
+            .skip(first)
+            .step_by(decoders)
+            .copied()
+            .collect();
+        thread::spawn(move || {
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            for frame in frames {
+                let result = decode_frame(&mut file, &frame, memory_limit);
+                let failed = result.is_err();
+                // A closed channel means the reader was dropped
+                if tx.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        decoded.push(rx);
+    }
+
+    Ok(Some(Box::new(ParallelReader {
+        decoded,
+        next: 0,
+        frames: frames.len(),
+        buf: Vec::new(),
+        pos: 0,
+    })))
+}
+
+fn decode_frame(file: &mut File, frame: &Frame, memory_limit: Option<u64>) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![0u8; frame.compressed_size as usize];
+    file.seek(SeekFrom::Start(frame.compressed))?;
+    file.read_exact(&mut compressed)?;
+
+    let mut decoder = zstd::Decoder::with_buffer(&compressed[..])?.single_frame();
+    if let Some(limit) = memory_limit {
+        decoder.window_log_max(window_log_for(limit))?;
+    }
+    let mut data = Vec::with_capacity(frame.decompressed_size as usize);
+    // One byte past the table's size is enough to tell a lying frame apart
+    decoder
+        .take(frame.decompressed_size as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() != frame.decompressed_size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "frame at offset {} decodes to {} bytes; the seek table says {}",
+                frame.compressed,
+                data.len(),
+                frame.decompressed_size
+            ),
+        ));
+    }
+    Ok(data)
+}
+
+struct ParallelReader {
+    /// Frame `i` comes from decoder `i % decoded.len()`
+    decoded: Vec<Receiver<io::Result<Vec<u8>>>>,
+    next: usize,
+    frames: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ParallelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.next == self.frames {
+                return Ok(0);
+            }
+            let decoder = &self.decoded[self.next % self.decoded.len()];
+            self.buf = match decoder.recv() {
+                Ok(result) => result?,
+                Err(_) => return Err(io::Error::other("Frame decoder thread stopped")),
+            };
+            self.pos = 0;
+            self.next += 1;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
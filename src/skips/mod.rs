@@ -0,0 +1,199 @@
+//! Paths left out of an archive because they couldn't be read, under
+//! `--ignore-failed-read`, or walked. A dead NFS subtree can fail tens of
+//! thousands of them: stderr gets the first few of each directory and kind
+//! of error and a rollup at the end, `--skip-log` every one of them.
+//! Reads retried under `--read-retries` and files that changed while they
+//! were read (`--if-changed`) are counted and logged alongside.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Warnings printed for one directory and kind of error before the rest are only counted
+const SHOWN_PER_GROUP: u64 = 3;
+
+/// Warnings printed in all before every further one is only counted
+const SHOWN_TOTAL: u64 = 50;
+
+/// Directories listed in the rollup
+const ROLLUP_GROUPS: usize = 10;
+
+/// Skipped paths, shared by the scanner and the readers.
+#[derive(Clone)]
+pub struct SkipLog {
+    state: Arc<Mutex<Skips>>,
+}
+
+struct Skips {
+    /// Paths skipped per directory and kind of error
+    groups: HashMap<(PathBuf, String), u64>,
+    total: u64,
+    shown: u64,
+    /// Failed reads that were tried again
+    retries: u64,
+    /// Files stored although they changed while being read
+    changed: u64,
+    log: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl SkipLog {
+    /// Start counting, writing every skipped path to `log` if given.
+    pub fn new(log: Option<&Path>) -> Result<Self> {
+        let log = match log {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("Failed to create skip log {:?}", path))?;
+                Some((path.to_path_buf(), BufWriter::new(file)))
+            }
+            None => None,
+        };
+        Ok(SkipLog {
+            state: Arc::new(Mutex::new(Skips {
+                groups: HashMap::new(),
+                total: 0,
+                shown: 0,
+                retries: 0,
+                changed: 0,
+                log,
+            })),
+        })
+    }
+
+    /// Record that `path` was left out; `what` says why, e.g. "Skipping unreadable file".
+    pub fn skip(&self, what: &str, path: &Path, error: &(dyn std::error::Error + 'static)) {
+        let kind = error_kind(error);
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut state = self.state.lock().unwrap();
+        state.total += 1;
+        let count = state.groups.entry((dir, kind)).or_insert(0);
+        *count += 1;
+        let count = *count;
+
+        if count <= SHOWN_PER_GROUP && state.shown < SHOWN_TOTAL {
+            state.shown += 1;
+            eprintln!("Warning: {} {:?}: {}", what, path, error);
+            if count == SHOWN_PER_GROUP {
+                eprintln!(
+                    "Warning: Further errors like this in {:?} are only counted",
+                    path.parent().unwrap_or(path)
+                );
+            } else if state.shown == SHOWN_TOTAL {
+                eprintln!(
+                    "Warning: Further skipped paths are only counted; see the summary at the end"
+                );
+            }
+        }
+        if let Some((_, log)) = &mut state.log {
+            // A full disk shouldn't fail the archive over its side log
+            let _ = writeln!(log, "{}\t{}\t{}", path.display(), what, error);
+        }
+    }
+
+    /// Record that a read of `path` failed and is tried again after `delay`.
+    pub fn retry(&self, path: &Path, error: &std::io::Error, attempt: u32, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.retries += 1;
+        if state.retries <= SHOWN_TOTAL {
+            eprintln!(
+                "Warning: Retrying {:?} in {:?} (retry {}): {}",
+                path,
+                delay,
+                attempt + 1,
+                error
+            );
+        }
+        if let Some((_, log)) = &mut state.log {
+            let _ = writeln!(log, "{}\tRetrying read\t{}", path.display(), error);
+        }
+    }
+
+    /// Failed reads retried so far.
+    pub fn retries(&self) -> u64 {
+        self.state.lock().unwrap().retries
+    }
+
+    /// Record that `path` changed while it was read; `kept` if it is stored
+    /// as read rather than read again.
+    pub fn changed(&self, path: &Path, kept: bool) {
+        let mut state = self.state.lock().unwrap();
+        let what = if kept {
+            state.changed += 1;
+            "Changed while read, stored as read"
+        } else {
+            "Changed while read, reading again"
+        };
+        if state.changed <= SHOWN_TOTAL {
+            eprintln!("Warning: {:?}: {}", path, what);
+        }
+        if let Some((_, log)) = &mut state.log {
+            let _ = writeln!(log, "{}\t{}\t", path.display(), what);
+        }
+    }
+
+    /// Files stored although they changed while being read.
+    pub fn changed_files(&self) -> u64 {
+        self.state.lock().unwrap().changed
+    }
+
+    /// Print how many reads were retried and how many paths were skipped
+    /// where, if not every one was shown, and flush the log.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((path, log)) = &mut state.log
+            && let Err(e) = log.flush()
+        {
+            eprintln!("Warning: Failed to write skip log {:?}: {}", path, e);
+        }
+        if state.retries > 0 {
+            eprintln!("Retried {} failed reads (--read-retries)", state.retries);
+        }
+        if state.changed > 0 {
+            eprintln!(
+                "Stored {} files that changed while being read (--if-changed)",
+                state.changed
+            );
+        }
+        if state.total == state.shown {
+            return;
+        }
+
+        let mut groups: Vec<_> = state.groups.iter().collect();
+        groups.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        eprintln!("Skipped {} paths that couldn't be read:", state.total);
+        for ((dir, kind), count) in groups.iter().take(ROLLUP_GROUPS) {
+            eprintln!("  {:>10}  {:?}: {}", count, dir, kind);
+        }
+        if groups.len() > ROLLUP_GROUPS {
+            let rest: u64 = groups[ROLLUP_GROUPS..]
+                .iter()
+                .map(|(_, count)| **count)
+                .sum();
+            eprintln!(
+                "  {:>10}  in {} more directories",
+                rest,
+                groups.len() - ROLLUP_GROUPS
+            );
+        }
+        match &state.log {
+            Some((path, _)) => eprintln!("Every skipped path is listed in {:?}", path),
+            None => eprintln!("Pass --skip-log FILE to list every skipped path"),
+        }
+    }
+}
+
+/// What went wrong, without the path: the IO error kind if there is one
+/// in the chain, e.g. "permission denied".
+fn error_kind(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            return io.kind().to_string();
+        }
+        source = error.source();
+    }
+    error.to_string()
+}
@@ -0,0 +1,347 @@
+pub mod pipe;
+
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the status file and terminal title are refreshed
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a frame goes to `--progress-fd`, often enough for a smooth bar
+const PIPE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Directories reported in `largest_dirs`
+const LARGEST_DIRS: usize = 10;
+
+/// Counters the pack writer updates as entries go into the archive.
+#[derive(Clone, Default)]
+pub struct Progress {
+    entries: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+    current: Arc<Mutex<PathBuf>>,
+    scan: ScanStats,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What the scanner has found so far, usually well ahead of the writer.
+    pub fn scan(&self) -> &ScanStats {
+        &self.scan
+    }
+
+    /// Record an entry of `bytes` content bytes about to be written.
+    pub fn entry(&self, path: &Path, bytes: u64) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        let mut current = self.current.lock().unwrap();
+        current.clear();
+        current.push(path);
+    }
+}
+
+/// One directory listing as the scanner walks it, excluded children left out.
+#[derive(Default)]
+pub struct DirListing {
+    pub entries: u64,
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Sizes of the files directly in the directory
+    pub bytes: u64,
+}
+
+/// Totals of the source tree, accumulated by the walker's own threads while
+/// the scanner feeds paths to the readers. They stay meaningful when packing
+/// fails part way, as a picture of what was being packed.
+#[derive(Clone, Default)]
+pub struct ScanStats {
+    entries: Arc<AtomicU64>,
+    files: Arc<AtomicU64>,
+    dirs: Arc<AtomicU64>,
+    symlinks: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+    complete: Arc<AtomicBool>,
+    /// Smallest size in a full `largest_dirs`, so most directories skip the lock
+    largest_min: Arc<AtomicU64>,
+    largest_dirs: Arc<Mutex<Vec<(PathBuf, u64)>>>,
+}
+
+impl ScanStats {
+    pub fn directory(&self, path: &Path, listing: &DirListing) {
+        self.entries.fetch_add(listing.entries, Ordering::Relaxed);
+        self.files.fetch_add(listing.files, Ordering::Relaxed);
+        self.dirs.fetch_add(listing.dirs, Ordering::Relaxed);
+        self.symlinks.fetch_add(listing.symlinks, Ordering::Relaxed);
+        self.bytes.fetch_add(listing.bytes, Ordering::Relaxed);
+        if listing.bytes == 0 || listing.bytes <= self.largest_min.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut largest = self.largest_dirs.lock().unwrap();
+        largest.push((path.to_path_buf(), listing.bytes));
+        largest.sort_by_key(|&(_, bytes)| std::cmp::Reverse(bytes));
+        largest.truncate(LARGEST_DIRS);
+        if largest.len() == LARGEST_DIRS {
+            self.largest_min
+                .store(largest[LARGEST_DIRS - 1].1, Ordering::Relaxed);
+        }
+    }
+
+    /// The walk reached the end of the tree; the totals are final.
+    pub fn finish(&self) {
+        self.complete.store(true, Ordering::Relaxed);
+    }
+
+    /// Print the totals and largest directories to stderr, with the
+    /// `--profile-pipeline` report.
+    pub fn report(&self) {
+        let scan = self.snapshot();
+        let walked = if scan.complete {
+            "whole tree"
+        } else {
+            "stopped early"
+        };
+        eprintln!(
+            "Scan ({}): {} entries, {} files ({}), {} directories, {} symlinks",
+            walked,
+            scan.entries,
+            scan.files,
+            indicatif::HumanBytes(scan.bytes),
+            scan.dirs,
+            scan.symlinks
+        );
+        for dir in &scan.largest_dirs {
+            let bytes = indicatif::HumanBytes(dir.bytes).to_string();
+            eprintln!("  {:>10}  {}", bytes, dir.path);
+        }
+    }
+
+    fn snapshot(&self) -> ScanStatus {
+        ScanStatus {
+            entries: self.entries.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            dirs: self.dirs.load(Ordering::Relaxed),
+            symlinks: self.symlinks.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            complete: self.complete.load(Ordering::Relaxed),
+            largest_dirs: self
+                .largest_dirs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(path, bytes)| DirSize {
+                    path: path.to_string_lossy().to_string(),
+                    bytes: *bytes,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Prints the scan totals when dropped, so they are reported however packing
+/// ends. A run that fails part way still shows what it was packing.
+pub struct ScanReport(pub ScanStats);
+
+impl Drop for ScanReport {
+    fn drop(&mut self) {
+        self.0.report();
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Status {
+    entries: u64,
+    bytes: u64,
+    elapsed_secs: u64,
+    current_path: String,
+    /// Known once the scan is complete, or earlier when an earlier archive of
+    /// the same profile gives an estimate
+    percent: Option<f64>,
+    eta_secs: Option<u64>,
+    done: bool,
+    scan: ScanStatus,
+}
+
+#[derive(serde::Serialize)]
+struct ScanStatus {
+    entries: u64,
+    files: u64,
+    dirs: u64,
+    symlinks: u64,
+    /// Only counted with a status file or `--profile-pipeline`; sizing costs a
+    /// stat per file
+    bytes: u64,
+    /// Whether the whole tree has been walked
+    complete: bool,
+    /// By the size of the files directly in them, largest first
+    largest_dirs: Vec<DirSize>,
+}
+
+#[derive(serde::Serialize)]
+struct DirSize {
+    path: String,
+    bytes: u64,
+}
+
+/// Periodically publishes `Progress` as JSON to an optional status file, as
+/// frames to an optional progress pipe and, when stderr is a terminal, to
+/// the window title.
+pub struct StatusReporter {
+    stop: Arc<AtomicBool>,
+    completed: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl StatusReporter {
+    /// `expected_entries` is an estimate of the total, e.g. the entry count
+    /// of the previous archive of the same profile.
+    pub fn start(
+        progress: Progress,
+        status_file: Option<PathBuf>,
+        mut progress_pipe: Option<File>,
+        expected_entries: Option<u64>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let completed = Arc::new(AtomicBool::new(false));
+        let title = std::io::stderr().is_terminal();
+        if status_file.is_none() && progress_pipe.is_none() && !title {
+            return StatusReporter {
+                stop,
+                completed,
+                handle: None,
+            };
+        }
+        let (stop_flag, completed_flag) = (stop.clone(), completed.clone());
+        let started = Instant::now();
+        let interval = if progress_pipe.is_some() {
+            PIPE_INTERVAL
+        } else {
+            STATUS_INTERVAL
+        };
+        let handle = thread::spawn(move || {
+            let mut published = started;
+            loop {
+                thread::park_timeout(interval);
+                let stopping = stop_flag.load(Ordering::Relaxed);
+                let done = completed_flag.load(Ordering::Relaxed);
+                let status = snapshot(&progress, started.elapsed(), expected_entries, done);
+                if let Some(pipe) = &mut progress_pipe
+                    && let Err(e) = pipe::write_frame(pipe, &status)
+                {
+                    // The reader went away; packing goes on without it
+                    eprintln!("Warning: Failed to write progress to --progress-fd: {}", e);
+                    progress_pipe = None;
+                }
+                if !stopping && published.elapsed() < STATUS_INTERVAL {
+                    continue;
+                }
+                published = Instant::now();
+                if let Some(path) = &status_file
+                    && let Err(e) = write_status(path, &status)
+                {
+                    eprintln!("Warning: Failed to write status file {:?}: {}", path, e);
+                }
+                if title {
+                    set_title(&status);
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+        StatusReporter {
+            stop,
+            completed,
+            handle: Some(handle),
+        }
+    }
+
+    /// Publish a final status marked `done`.
+    pub fn finish(self) {
+        self.completed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StatusReporter {
+    /// Publishes one last status; it only says `done` after `finish`.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+fn snapshot(progress: &Progress, elapsed: Duration, expected: Option<u64>, done: bool) -> Status {
+    let entries = progress.entries.load(Ordering::Relaxed);
+    let scan = progress.scan.snapshot();
+    // A finished scan counts exactly what is left to pack, barring read errors
+    let expected = if scan.complete {
+        Some(scan.entries)
+    } else {
+        expected
+    };
+    let fraction = expected
+        .filter(|&total| total > 0 && !done)
+        .map(|total| (entries as f64 / total as f64).min(0.99));
+    let eta_secs = fraction
+        .filter(|&f| f > 0.0)
+        .map(|f| (elapsed.as_secs_f64() * (1.0 - f) / f) as u64);
+    Status {
+        entries,
+        bytes: progress.bytes.load(Ordering::Relaxed),
+        elapsed_secs: elapsed.as_secs(),
+        current_path: progress
+            .current
+            .lock()
+            .unwrap()
+            .to_string_lossy()
+            .to_string(),
+        percent: if done {
+            Some(100.0)
+        } else {
+            fraction.map(|f| (f * 1000.0).round() / 10.0)
+        },
+        eta_secs: if done { Some(0) } else { eta_secs },
+        done,
+        scan,
+    }
+}
+
+/// Replace the file atomically so readers never see a partial document.
+fn write_status(path: &Path, status: &Status) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, serde_json::to_vec_pretty(status)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn set_title(status: &Status) {
+    let title = match (status.done, status.percent, status.eta_secs) {
+        (true, ..) => "zstar: done".to_string(),
+        (false, Some(percent), Some(eta)) => format!(
+            "zstar: ~{:.0}%, {} entries, ETA {}m{:02}s",
+            percent,
+            status.entries,
+            eta / 60,
+            eta % 60
+        ),
+        _ => format!(
+            "zstar: {} entries, {}",
+            status.entries,
+            indicatif::HumanBytes(status.bytes)
+        ),
+    };
+    // OSC 0: set icon name and window title; tmux passes it on as the pane title
+    let mut stderr = std::io::stderr();
+    let _ = write!(stderr, "\x1b]0;{}\x07", title);
+    let _ = stderr.flush();
+}
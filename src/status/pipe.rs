@@ -0,0 +1,103 @@
+//! `pack --progress-fd N`: progress as length-prefixed binary frames on a
+//! pipe the parent process (the GUI) created and let zstar inherit, several
+//! times a second and without parsing stderr. N is a file descriptor on Unix
+//! and the inherited handle's value on Windows, which has no fd 3 convention.
+//!
+//! Each frame is a little-endian `u32` payload length, then the payload,
+//! all integers little-endian:
+//!
+//! | bytes | field                                        |
+//! |-------|----------------------------------------------|
+//! | 1     | version, currently 1                         |
+//! | 1     | flags: 1 done, 2 scan complete               |
+//! | 8     | entries written                              |
+//! | 8     | content bytes written                        |
+//! | 8     | elapsed seconds                              |
+//! | 2     | percent × 10, `u16::MAX` while unknown       |
+//! | 8     | ETA in seconds, `u64::MAX` while unknown     |
+//! | rest  | current path, UTF-8                          |
+//!
+//! Readers skip frames of a version they don't know by their length.
+
+use anyhow::{Result, bail};
+use std::fs::File;
+use std::io::Write;
+
+use super::Status;
+
+const VERSION: u8 = 1;
+const DONE: u8 = 1;
+const SCAN_COMPLETE: u8 = 2;
+
+/// Take ownership of the inherited pipe `fd`.
+#[cfg(unix)]
+pub fn open(fd: u64) -> Result<File> {
+    use std::os::fd::FromRawFd;
+    let fd = match i32::try_from(fd) {
+        Ok(fd) if fd > 2 => fd,
+        _ => bail!(
+            "--progress-fd {} is not usable; pass a pipe other than stdin, stdout or stderr",
+            fd
+        ),
+    };
+    // A closed number would be reused by the next file we open, and written over
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        bail!("--progress-fd {}: {}", fd, std::io::Error::last_os_error());
+    }
+    // Keep it from external compressors, or the parent sees no EOF until they exit
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Take ownership of the inherited pipe handle numbered `handle`.
+#[cfg(windows)]
+pub fn open(handle: u64) -> Result<File> {
+    use std::os::windows::io::FromRawHandle;
+    use windows_sys::Win32::Foundation::{
+        GetHandleInformation, HANDLE, HANDLE_FLAG_INHERIT, SetHandleInformation,
+    };
+
+    let raw = handle as HANDLE;
+    let mut flags = 0;
+    if unsafe { GetHandleInformation(raw, &mut flags) } == 0 {
+        bail!(
+            "--progress-fd {}: {}",
+            handle,
+            std::io::Error::last_os_error()
+        );
+    }
+    // Keep it from external compressors, or the parent sees no EOF until they exit
+    unsafe { SetHandleInformation(raw, HANDLE_FLAG_INHERIT, 0) };
+    Ok(unsafe { File::from_raw_handle(raw as _) })
+}
+
+/// Write `status` as one frame.
+pub(super) fn write_frame(pipe: &mut File, status: &Status) -> std::io::Result<()> {
+    let mut flags = 0;
+    if status.done {
+        flags |= DONE;
+    }
+    if status.scan.complete {
+        flags |= SCAN_COMPLETE;
+    }
+    let permille = status
+        .percent
+        .map_or(u16::MAX, |percent| (percent * 10.0).round() as u16);
+    let path = status.current_path.as_bytes();
+
+    let mut payload = Vec::with_capacity(36 + path.len());
+    payload.push(VERSION);
+    payload.push(flags);
+    payload.extend_from_slice(&status.entries.to_le_bytes());
+    payload.extend_from_slice(&status.bytes.to_le_bytes());
+    payload.extend_from_slice(&status.elapsed_secs.to_le_bytes());
+    payload.extend_from_slice(&permille.to_le_bytes());
+    payload.extend_from_slice(&status.eta_secs.unwrap_or(u64::MAX).to_le_bytes());
+    payload.extend_from_slice(path);
+
+    // One write per frame, so a reader never waits on half of one
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    pipe.write_all(&frame)
+}
@@ -0,0 +1,241 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::cli::IoBackend;
+
+/// Bytes written (and synced) to measure destination throughput (16MB)
+const PROBE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Below this the destination is the bottleneck, so spend CPU on ratio (MB/s)
+const SLOW_DESTINATION: f64 = 100.0;
+
+/// Above this the compressor is the bottleneck, so favour speed (MB/s)
+const FAST_DESTINATION: f64 = 1000.0;
+
+const LEVEL_SLOW: i32 = 9;
+const LEVEL_DEFAULT: i32 = 3;
+const LEVEL_FAST: i32 = 1;
+
+pub struct LevelChoice {
+    pub level: i32,
+    /// Human readable explanation, printed with the pack summary
+    pub reason: String,
+}
+
+/// Choose a compression level for an archive written to `output`.
+/// Network filesystems and slow disks get a higher level (the link can't
+/// keep up with fast compression anyway), tmpfs and fast disks a lower one.
+pub fn auto_level(output: &Path) -> LevelChoice {
+    if crate::utils::is_stdio(output) {
+        return LevelChoice {
+            level: LEVEL_DEFAULT,
+            reason: "writing to stdout, which can't be probed".to_string(),
+        };
+    }
+    let dir = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    match filesystem_kind(dir) {
+        Some(FsKind::Memory) => {
+            return LevelChoice {
+                level: LEVEL_FAST,
+                reason: "destination is in memory (tmpfs)".to_string(),
+            };
+        }
+        Some(FsKind::Network(name)) => {
+            return LevelChoice {
+                level: LEVEL_SLOW,
+                reason: format!("destination is a network share ({})", name),
+            };
+        }
+        None => {}
+    }
+
+    match probe_throughput(dir) {
+        Ok(mbps) if mbps < SLOW_DESTINATION => LevelChoice {
+            level: LEVEL_SLOW,
+            reason: format!("slow destination (~{:.0} MB/s)", mbps),
+        },
+        Ok(mbps) if mbps > FAST_DESTINATION => LevelChoice {
+            level: LEVEL_FAST,
+            reason: format!("fast destination (~{:.0} MB/s)", mbps),
+        },
+        Ok(mbps) => LevelChoice {
+            level: LEVEL_DEFAULT,
+            reason: format!("destination at ~{:.0} MB/s", mbps),
+        },
+        Err(e) => LevelChoice {
+            level: LEVEL_DEFAULT,
+            reason: format!("could not probe destination: {}", e),
+        },
+    }
+}
+
+/// Write and sync a scratch file next to the output; returns MB/s.
+fn probe_throughput(dir: &Path) -> std::io::Result<f64> {
+    let probe = dir.join(format!(".zstar-probe-{}", std::process::id()));
+    let data = vec![0xA5u8; PROBE_SIZE];
+    let start = Instant::now();
+    let result = (|| {
+        let mut file = File::create(&probe)?;
+        file.write_all(&data)?;
+        file.sync_all()
+    })();
+    let elapsed = start.elapsed().as_secs_f64();
+    let _ = fs::remove_file(&probe);
+    result?;
+    Ok(PROBE_SIZE as f64 / (1024.0 * 1024.0) / elapsed.max(1e-6))
+}
+
+#[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+enum FsKind {
+    Memory,
+    Network(&'static str),
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem_kind(dir: &Path) -> Option<FsKind> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    const TMPFS_MAGIC: i64 = 0x0102_1994;
+    const RAMFS_MAGIC: i64 = 0x8584_58F6;
+    const NFS_SUPER_MAGIC: i64 = 0x6969;
+    const SMB_SUPER_MAGIC: i64 = 0x517B;
+    const CIFS_MAGIC_NUMBER: i64 = 0xFF53_4D42;
+    const SMB2_MAGIC_NUMBER: i64 = 0xFE53_4D42;
+    const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+
+    let c_path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return None;
+    }
+    match buf.f_type as i64 {
+        TMPFS_MAGIC | RAMFS_MAGIC => Some(FsKind::Memory),
+        NFS_SUPER_MAGIC => Some(FsKind::Network("nfs")),
+        SMB_SUPER_MAGIC | CIFS_MAGIC_NUMBER | SMB2_MAGIC_NUMBER => Some(FsKind::Network("smb")),
+        // sshfs, rclone and friends; local FUSE filesystems are rare targets
+        FUSE_SUPER_MAGIC => Some(FsKind::Network("fuse")),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+fn filesystem_kind(dir: &Path) -> Option<FsKind> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetDriveTypeW, GetVolumePathNameW};
+
+    const DRIVE_REMOTE: u32 = 4;
+    const DRIVE_RAMDISK: u32 = 6;
+
+    let abs = dir.canonicalize().ok()?;
+    let wide: Vec<u16> = abs.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = [0u16; 261];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return None;
+    }
+    match unsafe { GetDriveTypeW(root.as_ptr()) } {
+        DRIVE_REMOTE => Some(FsKind::Network("smb")),
+        DRIVE_RAMDISK => Some(FsKind::Memory),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn filesystem_kind(_dir: &Path) -> Option<FsKind> {
+    None
+}
+
+/// Reader backend for `--io-backend auto`. compio picks io_uring, IOCP or
+/// polling itself, so it is the default wherever its driver can start.
+pub fn auto_io_backend() -> IoBackend {
+    if compio_driver_usable() {
+        IoBackend::Compio
+    } else {
+        IoBackend::Threaded
+    }
+}
+
+/// compio's Linux driver is io_uring only. The kernel version says little
+/// about whether that works: `kernel.io_uring_disabled` and container seccomp
+/// profiles reject it on new kernels too, so try to create a ring.
+#[cfg(target_os = "linux")]
+fn compio_driver_usable() -> bool {
+    let mut params = [0u8; 120]; // struct io_uring_params
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_io_uring_setup,
+            1 as libc::c_uint,
+            params.as_mut_ptr(),
+        )
+    };
+    if fd < 0 {
+        return false;
+    }
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn compio_driver_usable() -> bool {
+    true
+}
+
+/// Descriptors kept free for stdio, the catalog, io_uring rings and the like
+const FD_RESERVE: u64 = 32;
+
+/// Descriptors one reader or unpack worker may hold at once (file + xattr lookups)
+const FDS_PER_WORKER: u64 = 2;
+
+pub struct FdLimit {
+    /// Soft limit in effect after raising it
+    pub current: u64,
+    /// Soft limit the process started with
+    pub original: u64,
+}
+
+/// Raise the soft open-file limit as far as the hard limit allows.
+/// Returns `None` where there is no meaningful per-process limit (Windows).
+#[cfg(unix)]
+pub fn raise_fd_limit() -> Option<FdLimit> {
+    let mut rlim: libc::rlimit = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+    let original = rlim.rlim_cur as u64;
+    if rlim.rlim_cur < rlim.rlim_max {
+        let mut raised = rlim;
+        raised.rlim_cur = rlim.rlim_max;
+        // macOS rejects values above OPEN_MAX even when the hard limit is unlimited
+        #[cfg(target_os = "macos")]
+        {
+            raised.rlim_cur = raised.rlim_cur.min(libc::OPEN_MAX as libc::rlim_t);
+        }
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            rlim = raised;
+        }
+    }
+    Some(FdLimit {
+        current: rlim.rlim_cur as u64,
+        original,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn raise_fd_limit() -> Option<FdLimit> {
+    None
+}
+
+/// Cap the number of concurrent workers so they cannot run out of descriptors.
+pub fn cap_workers(requested: usize, limit: Option<&FdLimit>) -> usize {
+    let Some(limit) = limit else {
+        return requested;
+    };
+    let budget = limit.current.saturating_sub(FD_RESERVE) / FDS_PER_WORKER;
+    requested.min(budget as usize).max(1)
+}
@@ -1,5 +1,9 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::i18n::t;
 
 #[cfg(unix)]
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
@@ -10,6 +14,10 @@ pub struct FileId {
     pub dev: u64,
     #[cfg(unix)]
     pub ino: u64,
+    /// Mount the file was reached through, so bind mounts and filesystems
+    /// with reused device numbers can't alias (0 if statx is unavailable)
+    #[cfg(target_os = "linux")]
+    pub mnt_id: u64,
     #[cfg(windows)]
     pub volume_serial_number: u32,
     #[cfg(windows)]
@@ -19,10 +27,13 @@ pub struct FileId {
 pub fn get_file_id(path: &Path, meta: &fs::Metadata) -> Option<FileId> {
     #[cfg(unix)]
     {
+        #[cfg(not(target_os = "linux"))]
         let _ = path; // Unused on Unix
         Some(FileId {
             dev: meta.dev(),
             ino: meta.ino(),
+            #[cfg(target_os = "linux")]
+            mnt_id: mount_id(path).unwrap_or(0),
         })
     }
     #[cfg(windows)]
@@ -97,6 +108,236 @@ pub fn get_file_id(path: &Path, meta: &fs::Metadata) -> Option<FileId> {
     }
 }
 
+/// Mount id from statx(STATX_MNT_ID), available since Linux 5.8.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn mount_id(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_MNT_ID,
+            &mut stx,
+        )
+    };
+    (ret == 0 && stx.stx_mask & libc::STATX_MNT_ID != 0).then_some(stx.stx_mnt_id)
+}
+
+#[cfg(all(target_os = "linux", not(target_env = "gnu")))]
+fn mount_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether another path may refer to the same file. Files with a single
+/// link can never be hardlink targets, so they skip the inode cache.
+pub fn has_other_links(meta: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        meta.nlink() > 1
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        true
+    }
+}
+
+pub struct FreeSpace {
+    /// Bytes available to unprivileged users
+    pub bytes: u64,
+    /// Free inodes, `None` where the filesystem allocates them on demand
+    pub inodes: Option<u64>,
+}
+
+/// Free space on the filesystem that holds `path`, or would hold it once
+/// created. `None` when it can't be determined.
+pub fn free_space(path: &Path) -> Option<FreeSpace> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+        let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+            return None;
+        }
+        Some(FreeSpace {
+            bytes: buf.f_bavail as u64 * buf.f_frsize as u64,
+            // btrfs and friends report no inode table at all
+            inodes: (buf.f_files != 0).then_some(buf.f_favail as u64),
+        })
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+        let wide: Vec<u16> = existing.as_os_str().encode_wide().chain(Some(0)).collect();
+        let mut available = 0u64;
+        let ok = unsafe {
+            GetDiskFreeSpaceExW(
+                wide.as_ptr(),
+                &mut available,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        (ok != 0).then_some(FreeSpace {
+            bytes: available,
+            // NTFS grows its MFT as needed
+            inodes: None,
+        })
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        None
+    }
+}
+
+/// Whether an archive path is `-`: `pack` then writes to stdout and the
+/// commands reading archives take them from stdin.
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Open an archive for reading, from stdin for `-`.
+pub fn open_input(path: &Path) -> Result<Box<dyn std::io::Read + Send>> {
+    if is_stdio(path) {
+        return Ok(Box::new(std::io::stdin()));
+    }
+    let file = fs::File::open(path).context("Failed to open input file")?;
+    Ok(Box::new(file))
+}
+
+/// A duplicate of the stdout descriptor as a plain file. `Stdout` flushes at
+/// every newline, which archive data is full of, so archives written to `-`
+/// go through a buffer over this instead.
+pub fn stdout_file() -> Result<fs::File> {
+    #[cfg(unix)]
+    let owned = {
+        use std::os::fd::AsFd;
+        std::io::stdout().as_fd().try_clone_to_owned()
+    };
+    #[cfg(windows)]
+    let owned = {
+        use std::os::windows::io::AsHandle;
+        std::io::stdout().as_handle().try_clone_to_owned()
+    };
+    Ok(fs::File::from(owned.context("Failed to open stdout")?))
+}
+
+/// Split a command line into words the way a POSIX shell would, without
+/// expanding anything: whitespace separates words, single quotes keep their
+/// contents as is, and a backslash escapes the next character (inside double
+/// quotes only `"`, `\`, `$` and `` ` ``).
+pub fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("Unterminated ' in {:?}", command),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => word.extend(['\\', c]),
+                            None => anyhow::bail!("Unterminated \" in {:?}", command),
+                        },
+                        Some(c) => word.push(c),
+                        None => anyhow::bail!("Unterminated \" in {:?}", command),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars
+                    .next()
+                    .with_context(|| format!("Trailing \\ in {:?}", command))?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// An advisory lock on `<output>.lock`, held while pack or merge writes
+/// `output` directly or through its `.partial`. Both ways lock the same
+/// file, so runs started on one output exclude each other whichever mode
+/// they write in. The lock file is removed when this is dropped.
+pub struct OutputLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+/// Take the lock for writing `output`, before anything is created or
+/// truncated, so a second job fails without touching the archive the
+/// first one is writing.
+pub fn lock_output(output: &Path) -> Result<OutputLock> {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".lock");
+    let path = PathBuf::from(path);
+    // A lock freed by a run that finished removes its lock file, and
+    // locking that file wouldn't keep out a run that created a new one
+    for _ in 0..3 {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to create lock file {:?}", path))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                anyhow::bail!(t!("output-locked", output = format!("{:?}", output)))
+            }
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", path));
+            }
+        }
+        let locked = same_file::Handle::from_file(file.try_clone()?)?;
+        if same_file::Handle::from_path(&path).is_ok_and(|current| current == locked) {
+            return Ok(OutputLock { file, path });
+        }
+    }
+    anyhow::bail!(t!("output-locked", output = format!("{:?}", output)))
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so nobody locks a file that is going away
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
 pub fn get_mode(meta: &fs::Metadata) -> u32 {
     #[cfg(unix)]
     {
@@ -114,12 +355,93 @@ pub fn get_mode(meta: &fs::Metadata) -> u32 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Where archived paths start.
+#[derive(Clone, Copy)]
+pub enum Rooting {
+    /// Under the input's own name: packing `photos` stores `photos/2024/a.jpg`
+    TopDir,
+    /// Relative to the input itself: `2024/a.jpg`
+    Contents,
+}
+
+/// Archive path of `path`, found while walking `input`. Every reader goes
+/// through here so the same input always produces the same paths.
+pub fn relativize(path: &Path, input: &Path, rooting: Rooting) -> PathBuf {
+    let base = match rooting {
+        Rooting::TopDir => input.parent().unwrap_or(input),
+        Rooting::Contents => input,
+    };
+    match path.strip_prefix(base) {
+        Ok(p) => p.to_path_buf(),
+        Err(_) => path
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("unknown")),
+    }
+}
+
+/// Compile `--exclude` patterns. As with `tar --exclude`, a pattern matches
+/// any trailing part of an entry path (`*.iso`, `node_modules`, `build/cache`),
+/// and excluding a directory excludes everything below it.
+pub fn build_excludes(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_end_matches('/');
+        for glob in [format!("**/{}", pattern), format!("**/{}/**", pattern)] {
+            builder.add(
+                GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid exclude pattern {:?}", pattern))?,
+            );
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Whether an archive entry path is matched by the exclude set.
+pub fn is_excluded(excludes: &GlobSet, path: &Path) -> bool {
+    if excludes.is_empty() {
+        return false;
+    }
+    let path = path.to_string_lossy();
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    excludes.is_match(path)
+}
+
+/// Whether an archive path is selected by `--include` patterns, compiled by
+/// `build_excludes` since they match the same way. No patterns select everything.
+pub fn is_included(includes: &GlobSet, path: &Path) -> bool {
+    includes.is_empty() || is_excluded(includes, path)
+}
+
+/// Extended attributes as (name, value) pairs
+pub type Xattrs = Vec<(String, Vec<u8>)>;
+
+/// Prefix of the macOS metadata xattrs (FinderInfo, quarantine, ResourceFork, ...)
+pub const MAC_XATTR_PREFIX: &str = "com.apple.";
+
+/// Linux file capabilities (`setcap cap_net_raw+ep /usr/bin/ping`)
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+/// SELinux security context (`system_u:object_r:bin_t:s0`)
+pub const SELINUX_XATTR: &str = "security.selinux";
+
+/// PAX record prefix for xattrs, as used by GNU tar and bsdtar
+pub const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// PAX record marking a file that changed while `pack` read it (`--if-changed warn`)
+pub const PAX_CHANGED: &str = "ZSTAR.changed";
+
+#[derive(Debug, Clone)]
 pub struct FileMetadata {
     pub mode: u32,
     pub mtime: u64,
     pub uid: u64,
     pub gid: u64,
+    pub xattrs: Xattrs,
+    /// Stored although it changed while being read
+    pub changed: bool,
 }
 
 pub fn get_file_metadata(path: &Path, meta: &fs::Metadata) -> FileMetadata {
@@ -141,6 +463,8 @@ pub fn get_file_metadata(path: &Path, meta: &fs::Metadata) -> FileMetadata {
             mtime,
             uid: meta.uid() as u64,
             gid: meta.gid() as u64,
+            xattrs: Vec::new(),
+            changed: false,
         }
     }
 
@@ -154,6 +478,8 @@ pub fn get_file_metadata(path: &Path, meta: &fs::Metadata) -> FileMetadata {
             mtime,
             uid: 0,
             gid: 0,
+            xattrs: Vec::new(),
+            changed: false,
         }
     }
 
@@ -165,6 +491,161 @@ pub fn get_file_metadata(path: &Path, meta: &fs::Metadata) -> FileMetadata {
             mtime,
             uid: 0,
             gid: 0,
+            xattrs: Vec::new(),
+            changed: false,
         }
     }
 }
+
+/// Read the extended attributes of `path` (not following symlinks) whose
+/// names are accepted by `filter`. Unreadable attributes are skipped.
+pub fn read_xattrs(path: &Path, filter: impl Fn(&str) -> bool) -> Xattrs {
+    #[cfg(unix)]
+    {
+        let Ok(names) = xattr::list(path) else {
+            return Vec::new();
+        };
+        names
+            .filter_map(|name| {
+                let name = name.to_str()?.to_string();
+                if !filter(&name) {
+                    return None;
+                }
+                let value = xattr::get(path, &name).ok()??;
+                Some((name, value))
+            })
+            .collect()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, filter);
+        Vec::new()
+    }
+}
+
+/// Set extended attributes on `path` (not following symlinks).
+pub fn write_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    for (name, value) in xattrs {
+        xattr::set(path, name, value)?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, xattrs);
+    Ok(())
+}
+
+/// Copy `src` to `dst` as a reflink (shared extents, copy-on-write) when the
+/// filesystem supports it, falling back to a regular copy. The copy keeps the
+/// source's permissions and modification time. Neither path is followed if it
+/// is a symlink and `dst` must not exist yet, so links planted by an archive
+/// can't point the copy at files outside the output.
+pub fn clone_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let no_follow = || {
+        let mut options = fs::OpenOptions::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        options
+    };
+    // Also refused where there is no O_NOFOLLOW
+    if fs::symlink_metadata(src)?.file_type().is_symlink() {
+        return Err(std::io::Error::other(format!("{:?} is a symlink", src)));
+    }
+    let mut src_file = no_follow().read(true).open(src)?;
+    let meta = src_file.metadata()?;
+    if !meta.is_file() {
+        let message = format!("{:?} is not a regular file", src);
+        return Err(std::io::Error::other(message));
+    }
+    let mtime = meta.modified()?;
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        const CLONE_NOFOLLOW: u32 = 0x0001;
+        let c_src = CString::new(src.as_os_str().as_bytes())?;
+        let c_dst = CString::new(dst.as_os_str().as_bytes())?;
+        // clonefile copies permissions itself and requires dst not to exist
+        if unsafe { libc::clonefile(c_src.as_ptr(), c_dst.as_ptr(), CLONE_NOFOLLOW) } == 0 {
+            let dst_file = no_follow().write(true).open(dst)?;
+            let _ = dst_file.set_modified(mtime);
+            return Ok(());
+        }
+    }
+
+    let mut dst_file = no_follow().write(true).create_new(true).open(dst)?;
+
+    #[cfg(target_os = "linux")]
+    let cloned = {
+        use std::os::unix::io::AsRawFd;
+        // FICLONE = _IOW(0x94, 9, int)
+        const FICLONE: libc::c_ulong = 0x4004_9409;
+        unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) == 0 }
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let cloned = false;
+
+    if !cloned {
+        // std::io::copy uses copy_file_range between files where available
+        std::io::copy(&mut src_file, &mut dst_file)?;
+    }
+    dst_file.set_permissions(meta.permissions())?;
+    let _ = dst_file.set_modified(mtime);
+    Ok(())
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (H. Hinnant's algorithm).
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// (year, month, day) for a number of days since 1970-01-01.
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+// Little-endian fields of the binary formats zstar writes and reads (seek
+// tables, entry indexes, ZIP headers)
+
+pub fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+pub fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+pub fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result, bail};
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::dialect::{self, EntryKind};
+use crate::sanitize;
+
+/// Caps that stop a small archive from expanding without bound.
+#[derive(Default)]
+pub struct ExtractLimits {
+    /// Total bytes of file contents
+    pub max_output_size: Option<u64>,
+    pub max_entries: Option<u64>,
+    /// Path components per entry
+    pub max_path_depth: Option<usize>,
+}
+
+impl ExtractLimits {
+    /// Defaults for `--untrusted`: generous for real backups, fatal for bombs.
+    pub fn untrusted() -> Self {
+        ExtractLimits {
+            max_output_size: Some(64 * 1024 * 1024 * 1024), // 64GB
+            max_entries: Some(1_000_000),
+            max_path_depth: Some(64),
+        }
+    }
+
+    /// Check the `entries`th entry (counting from 1) and its path.
+    pub fn check_entry(&self, entries: u64, path: &Path) -> Result<()> {
+        if let Some(max) = self.max_entries
+            && entries > max
+        {
+            bail!("Archive has more than {} entries, aborting extraction", max);
+        }
+        if let Some(max) = self.max_path_depth
+            && path.components().count() > max
+        {
+            bail!(
+                "{:?} is nested deeper than {} levels, aborting extraction",
+                path,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the running total of file contents.
+    pub fn check_output(&self, bytes: u64) -> Result<()> {
+        if let Some(max) = self.max_output_size
+            && bytes > max
+        {
+            bail!(
+                "Archive expands to more than {}, aborting extraction",
+                indicatif::HumanBytes(max)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What a validated stream would extract to.
+#[derive(Default)]
+pub struct StreamSummary {
+    pub entries: u64,
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub hardlinks: u64,
+    pub bytes: u64,
+    /// Entries unpack would skip because their path or link target escapes the output
+    pub unsafe_paths: u64,
+}
+
+/// Largest zstd window log whose window fits in `limit` bytes, within the
+/// range zstd accepts (1KB to 2GB).
+pub fn window_log_for(limit: u64) -> u32 {
+    let log = 63 - limit.max(1).leading_zeros();
+    log.clamp(10, 31)
+}
+
+/// Parse a tar stream to the end without touching the filesystem. Headers,
+/// PAX records, paths, link targets and data are all read and checked the
+/// way unpack uses them, so a stream that passes here extracts without
+/// parse errors. Fails on the first malformed entry or exceeded limit.
+pub fn validate_tar<R: Read>(input: R, limits: &ExtractLimits) -> Result<StreamSummary> {
+    let mut archive = tar::Archive::new(input);
+    let mut summary = StreamSummary::default();
+
+    for entry in archive.entries()? {
+        let index = summary.entries;
+        let mut entry = entry.with_context(|| format!("Entry {}: bad header", index))?;
+
+        let path = entry
+            .path()
+            .with_context(|| format!("Entry {}: bad path", index))?
+            .into_owned();
+        if let Some(extensions) = entry
+            .pax_extensions()
+            .with_context(|| format!("{:?}: bad PAX records", path))?
+        {
+            for ext in extensions {
+                ext.with_context(|| format!("{:?}: bad PAX record", path))?;
+            }
+        }
+        let kind = dialect::classify(&mut entry)?;
+        // Sparse files are checked at their expanded size, which is what reading yields
+        let size = entry.size();
+        if kind == EntryKind::Metadata {
+            io::copy(&mut entry, &mut io::sink())
+                .with_context(|| format!("{:?}: bad data", path))?;
+            continue;
+        }
+        summary.entries += 1;
+        limits.check_entry(summary.entries, &path)?;
+
+        let header = entry.header();
+        header
+            .size()
+            .with_context(|| format!("{:?}: bad size", path))?;
+        header
+            .mode()
+            .with_context(|| format!("{:?}: bad mode", path))?;
+        header
+            .mtime()
+            .with_context(|| format!("{:?}: bad mtime", path))?;
+
+        if let Some(reason) = sanitize::reject_reason(&path) {
+            eprintln!("Unsafe path ({}): {:?}", reason, path);
+            summary.unsafe_paths += 1;
+        }
+
+        match kind {
+            EntryKind::Metadata => {}
+            EntryKind::Unsupported(what) => eprintln!("Unsupported entry ({}): {:?}", what, path),
+            EntryKind::Directory => summary.dirs += 1,
+            EntryKind::Hardlink | EntryKind::Symlink => {
+                let target = entry
+                    .link_name()
+                    .with_context(|| format!("{:?}: bad link target", path))?
+                    .with_context(|| format!("{:?}: link without a target", path))?;
+                if kind == EntryKind::Hardlink {
+                    if let Some(reason) = sanitize::reject_reason(&target) {
+                        eprintln!(
+                            "Unsafe hardlink target ({}): {:?} -> {:?}",
+                            reason, path, target
+                        );
+                        summary.unsafe_paths += 1;
+                    }
+                    summary.hardlinks += 1;
+                } else {
+                    summary.symlinks += 1;
+                }
+            }
+            EntryKind::File => {
+                summary.files += 1;
+                summary.bytes += size;
+                limits.check_output(summary.bytes)?;
+            }
+        }
+
+        // A short read means the stream ended inside the entry's data
+        let read = io::copy(&mut entry, &mut io::sink())
+            .with_context(|| format!("{:?}: bad data", path))?;
+        if read != size {
+            bail!("{:?}: data is {} bytes, header says {}", path, read, size);
+        }
+    }
+    Ok(summary)
+}
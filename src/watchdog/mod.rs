@@ -0,0 +1,123 @@
+use anyhow::{Result, bail};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Warn once nothing has moved for this long
+pub const STALL_WARNING: Duration = Duration::from_secs(30);
+
+/// How often the watchdog (and the code calling `check`) looks at the heartbeat
+pub const WATCHDOG_POLL: Duration = Duration::from_secs(1);
+
+/// Last sign of life of a pipeline, shared by all of its stages.
+#[derive(Clone)]
+pub struct Heartbeat {
+    state: Arc<Mutex<Beat>>,
+}
+
+struct Beat {
+    at: Instant,
+    stage: &'static str,
+    path: Option<PathBuf>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            state: Arc::new(Mutex::new(Beat {
+                at: Instant::now(),
+                stage: "start",
+                path: None,
+            })),
+        }
+    }
+
+    /// Record that `stage` just made progress on `path`.
+    pub fn beat(&self, stage: &'static str, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.at = Instant::now();
+        state.stage = stage;
+        match &mut state.path {
+            Some(p) if p == path => {}
+            p => *p = Some(path.to_path_buf()),
+        }
+    }
+
+    /// Time since the last beat and a description of what it was.
+    fn idle(&self) -> (Duration, String) {
+        let state = self.state.lock().unwrap();
+        let activity = match &state.path {
+            Some(path) => format!("{} {:?}", state.stage, path),
+            None => state.stage.to_string(),
+        };
+        (state.at.elapsed(), activity)
+    }
+}
+
+/// Warns on stderr when a heartbeat goes quiet, and reports a stall once
+/// it has been quiet for longer than the optional timeout.
+pub struct Watchdog {
+    heartbeat: Heartbeat,
+    timeout: Option<Duration>,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn start(heartbeat: Heartbeat, timeout: Option<Duration>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let hb = heartbeat.clone();
+        let stop_flag = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut warned = false;
+            while !stop_flag.load(Ordering::Relaxed) {
+                thread::park_timeout(WATCHDOG_POLL);
+                let (idle, activity) = hb.idle();
+                if idle < STALL_WARNING {
+                    warned = false;
+                } else if !warned {
+                    eprintln!(
+                        "Warning: No progress for {}s, last activity: {}",
+                        idle.as_secs(),
+                        activity
+                    );
+                    warned = true;
+                }
+            }
+        });
+        Watchdog {
+            heartbeat,
+            timeout,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Fails once the heartbeat has been quiet for longer than the stall timeout.
+    pub fn check(&self) -> Result<()> {
+        let Some(timeout) = self.timeout else {
+            return Ok(());
+        };
+        let (idle, activity) = self.heartbeat.idle();
+        if idle > timeout {
+            bail!(
+                "Stalled: no progress for {}s, last activity: {}",
+                idle.as_secs(),
+                activity
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
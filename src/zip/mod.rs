@@ -0,0 +1,751 @@
+//! `pack --format zip` and ZIP input to `unpack`, for recipients on Windows
+//! without zstd tools. Entries are deflated one by one as the pack writer
+//! hands them over, with the sizes and CRC in a data descriptor after each
+//! one, so the archive streams to any sink. Reading goes through the central
+//! directory at the end of the file; the entries come out as a tar stream,
+//! which the rest of the extraction pipeline takes like any other archive.
+//!
+//! Only files, directories and symlinks exist in ZIP: owners, xattrs and
+//! hard links (packed as copies) are not kept. Unix modes are stored in the
+//! external attributes, modification times as DOS times plus the extended
+//! timestamp field, which has seconds and no time zone. A file that changed
+//! while it was read (`--if-changed warn`) gets `CHANGED_COMMENT` as its
+//! comment in the central directory, which `unzip -v` and `zstar list` show.
+
+use anyhow::{Context, Result, bail};
+use flate2::{Crc, CrcReader};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread;
+
+use crate::sanitize;
+use crate::utils::{
+    FileMetadata, PAX_CHANGED, civil_from_days, days_from_civil, put_u16, put_u32, put_u64, u16_at,
+    u32_at, u64_at,
+};
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIR: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+
+const ZIP64_EXTRA: u16 = 0x0001;
+/// Info-ZIP extended timestamp: Unix mtime in seconds
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// General purpose flags
+const FLAG_ENCRYPTED: u16 = 1;
+/// Sizes and CRC follow the data
+const FLAG_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// "Made by" Unix, spec version 4.5 (ZIP64), so readers look at the mode
+const VERSION_MADE_BY: u16 = (3 << 8) | 45;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+/// Files from this size up get ZIP64 sizes. Deflate can grow incompressible
+/// data a little, so this stays clear of the 4 GiB the plain fields hold.
+const ZIP64_THRESHOLD: u64 = 0xF000_0000;
+
+/// Entry comment of a file stored although it changed while being read
+const CHANGED_COMMENT: &[u8] = b"changed while packed";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+/// MS-DOS directory attribute, for readers that ignore Unix modes
+const DOS_DIRECTORY: u32 = 0x10;
+
+/// Size of the chunks the reader thread hands over
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// What the central directory needs to know about an entry already written.
+struct CentralRecord {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    mtime: u64,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    external: u32,
+    /// The local header carries ZIP64 sizes
+    zip64: bool,
+    /// Stored although it changed while being read
+    changed: bool,
+}
+
+/// Writes ZIP entries to `inner` as they come, and the central directory on `finish`.
+pub struct ZipWriter<W: Write> {
+    inner: W,
+    written: u64,
+    level: u32,
+    records: Vec<CentralRecord>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    /// `level` is the deflate level, 0-9.
+    pub fn new(inner: W, level: u32) -> Self {
+        ZipWriter {
+            inner,
+            written: 0,
+            level,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn directory(&mut self, path: &Path, metadata: &FileMetadata) -> io::Result<()> {
+        let mut name = entry_name(path);
+        if !name.ends_with(b"/") {
+            name.push(b'/');
+        }
+        let external = (S_IFDIR | metadata.mode & 0o7777) << 16 | DOS_DIRECTORY;
+        self.stored(name, metadata.mtime, external, &[])
+    }
+
+    /// A symlink is a file holding the target, marked as a link by its mode.
+    pub fn symlink(
+        &mut self,
+        path: &Path,
+        target: &Path,
+        metadata: &FileMetadata,
+    ) -> io::Result<()> {
+        let external = (S_IFLNK | 0o777) << 16;
+        self.stored(
+            entry_name(path),
+            metadata.mtime,
+            external,
+            &entry_name(target),
+        )
+    }
+
+    /// Deflate `size` bytes from `data`; fewer or more is an error.
+    pub fn file<R: Read>(
+        &mut self,
+        path: &Path,
+        metadata: &FileMetadata,
+        size: u64,
+        data: R,
+    ) -> io::Result<()> {
+        let name = entry_name(path);
+        let zip64 = size >= ZIP64_THRESHOLD;
+        let offset = self.written;
+        let flags = FLAG_DESCRIPTOR | FLAG_UTF8;
+        // Sizes and CRC are zero here and follow in the data descriptor
+        let sizes = if zip64 { u32::MAX } else { 0 };
+        let mut extra = timestamp_extra(metadata.mtime);
+        if zip64 {
+            push_extra(&mut extra, ZIP64_EXTRA, &[0u8; 16]);
+        }
+        self.local_header(
+            &name,
+            flags,
+            DEFLATED,
+            metadata.mtime,
+            0,
+            sizes,
+            sizes,
+            &extra,
+            zip64,
+        )?;
+
+        let before = self.written;
+        let mut data = CrcReader::new(data.take(size));
+        let level = flate2::Compression::new(self.level);
+        let mut encoder = flate2::write::DeflateEncoder::new(&mut *self, level);
+        let copied = io::copy(&mut data, &mut encoder)?;
+        encoder.finish()?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{:?} ended after {} of {} bytes", path, copied, size),
+            ));
+        }
+        let compressed = self.written - before;
+        if !zip64 && compressed > u32::MAX as u64 {
+            return Err(io::Error::other(format!(
+                "{:?} grew past 4 GiB compressed",
+                path
+            )));
+        }
+        let crc = data.crc().sum();
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc);
+        if zip64 {
+            put_u64(&mut descriptor, compressed);
+            put_u64(&mut descriptor, size);
+        } else {
+            put_u32(&mut descriptor, compressed as u32);
+            put_u32(&mut descriptor, size as u32);
+        }
+        self.write_all(&descriptor)?;
+
+        self.records.push(CentralRecord {
+            name,
+            flags,
+            method: DEFLATED,
+            mtime: metadata.mtime,
+            crc,
+            compressed,
+            size,
+            offset,
+            external: (S_IFREG | metadata.mode & 0o7777) << 16,
+            zip64,
+            changed: false,
+        });
+        Ok(())
+    }
+
+    /// Flag the file written last as changed while it was read. A streamed
+    /// file is only known to have changed once its data is written.
+    pub fn mark_changed(&mut self) {
+        if let Some(record) = self.records.last_mut() {
+            record.changed = true;
+        }
+    }
+
+    /// An entry whose contents are at hand: no compression, no descriptor.
+    fn stored(&mut self, name: Vec<u8>, mtime: u64, external: u32, data: &[u8]) -> io::Result<()> {
+        let offset = self.written;
+        let mut crc = Crc::new();
+        crc.update(data);
+        let (crc, size) = (crc.sum(), data.len() as u32);
+        let extra = timestamp_extra(mtime);
+        self.local_header(
+            &name, FLAG_UTF8, STORED, mtime, crc, size, size, &extra, false,
+        )?;
+        self.write_all(data)?;
+        self.records.push(CentralRecord {
+            name,
+            flags: FLAG_UTF8,
+            method: STORED,
+            mtime,
+            crc,
+            compressed: data.len() as u64,
+            size: data.len() as u64,
+            offset,
+            external,
+            zip64: false,
+            changed: false,
+        });
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn local_header(
+        &mut self,
+        name: &[u8],
+        flags: u16,
+        method: u16,
+        mtime: u64,
+        crc: u32,
+        compressed: u32,
+        size: u32,
+        extra: &[u8],
+        zip64: bool,
+    ) -> io::Result<()> {
+        let (time, date) = dos_time(mtime);
+        let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(
+            &mut header,
+            if zip64 {
+                VERSION_ZIP64
+            } else {
+                VERSION_DEFAULT
+            },
+        );
+        put_u16(&mut header, flags);
+        put_u16(&mut header, method);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        put_u32(&mut header, crc);
+        put_u32(&mut header, compressed);
+        put_u32(&mut header, size);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, extra.len() as u16);
+        header.extend_from_slice(name);
+        header.extend_from_slice(extra);
+        self.write_all(&header)
+    }
+
+    /// Write the central directory and hand back the sink.
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.written;
+        let records = std::mem::take(&mut self.records);
+        for record in &records {
+            // Only the fields that overflow go into the ZIP64 field, in this order
+            let mut zip64 = Vec::new();
+            if record.zip64 || record.size >= u32::MAX as u64 {
+                put_u64(&mut zip64, record.size);
+            }
+            if record.zip64 || record.compressed >= u32::MAX as u64 {
+                put_u64(&mut zip64, record.compressed);
+            }
+            if record.offset >= u32::MAX as u64 {
+                put_u64(&mut zip64, record.offset);
+            }
+            let mut extra = timestamp_extra(record.mtime);
+            if !zip64.is_empty() {
+                push_extra(&mut extra, ZIP64_EXTRA, &zip64);
+            }
+            let clamp = |value: u64, zip64: bool| if zip64 { u32::MAX } else { value as u32 };
+            let needs_zip64 = !zip64.is_empty();
+            let sizes_zip64 = record.zip64 || record.size >= u32::MAX as u64;
+            let (time, date) = dos_time(record.mtime);
+            let comment = if record.changed { CHANGED_COMMENT } else { &[] };
+
+            let mut header = Vec::with_capacity(46 + record.name.len() + extra.len());
+            put_u32(&mut header, CENTRAL_HEADER);
+            put_u16(&mut header, VERSION_MADE_BY);
+            put_u16(
+                &mut header,
+                if needs_zip64 {
+                    VERSION_ZIP64
+                } else {
+                    VERSION_DEFAULT
+                },
+            );
+            put_u16(&mut header, record.flags);
+            put_u16(&mut header, record.method);
+            put_u16(&mut header, time);
+            put_u16(&mut header, date);
+            put_u32(&mut header, record.crc);
+            put_u32(&mut header, clamp(record.compressed, sizes_zip64));
+            put_u32(&mut header, clamp(record.size, sizes_zip64));
+            put_u16(&mut header, record.name.len() as u16);
+            put_u16(&mut header, extra.len() as u16);
+            put_u16(&mut header, comment.len() as u16);
+            put_u16(&mut header, 0); // Disk number
+            put_u16(&mut header, 0); // Internal attributes
+            put_u32(&mut header, record.external);
+            put_u32(
+                &mut header,
+                clamp(record.offset, record.offset >= u32::MAX as u64),
+            );
+            header.extend_from_slice(&record.name);
+            header.extend_from_slice(&extra);
+            header.extend_from_slice(comment);
+            self.write_all(&header)?;
+        }
+        let (end, size) = (self.written, self.written - start);
+        let count = records.len() as u64;
+
+        let mut tail = Vec::with_capacity(98);
+        let zip64 = count >= u16::MAX as u64 || size >= u32::MAX as u64 || start >= u32::MAX as u64;
+        if zip64 {
+            put_u32(&mut tail, ZIP64_END_OF_CENTRAL_DIR);
+            put_u64(&mut tail, 44); // Size of the rest of this record
+            put_u16(&mut tail, VERSION_MADE_BY);
+            put_u16(&mut tail, VERSION_ZIP64);
+            put_u32(&mut tail, 0); // This disk
+            put_u32(&mut tail, 0); // Disk with the central directory
+            put_u64(&mut tail, count);
+            put_u64(&mut tail, count);
+            put_u64(&mut tail, size);
+            put_u64(&mut tail, start);
+            put_u32(&mut tail, ZIP64_LOCATOR);
+            put_u32(&mut tail, 0);
+            put_u64(&mut tail, end);
+            put_u32(&mut tail, 1); // Total disks
+        }
+        put_u32(&mut tail, END_OF_CENTRAL_DIR);
+        put_u16(&mut tail, 0);
+        put_u16(&mut tail, 0);
+        put_u16(&mut tail, count.min(u16::MAX as u64) as u16);
+        put_u16(&mut tail, count.min(u16::MAX as u64) as u16);
+        put_u32(&mut tail, size.min(u32::MAX as u64) as u32);
+        put_u32(&mut tail, start.min(u32::MAX as u64) as u32);
+        put_u16(&mut tail, 0); // Comment length
+        self.write_all(&tail)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Archive path as a ZIP name: UTF-8 with forward slashes.
+fn entry_name(path: &Path) -> Vec<u8> {
+    let name = path.to_string_lossy();
+    #[cfg(windows)]
+    let name = name.replace('\\', "/");
+    name.into_owned().into_bytes()
+}
+
+fn timestamp_extra(mtime: u64) -> Vec<u8> {
+    let mut extra = Vec::with_capacity(9);
+    let mut field = vec![1u8]; // Flags: mtime present
+    put_u32(&mut field, mtime.min(u32::MAX as u64) as u32);
+    push_extra(&mut extra, TIMESTAMP_EXTRA, &field);
+    extra
+}
+
+fn push_extra(extra: &mut Vec<u8>, id: u16, data: &[u8]) {
+    put_u16(extra, id);
+    put_u16(extra, data.len() as u16);
+    extra.extend_from_slice(data);
+}
+
+/// MS-DOS time and date of a Unix time. DOS times have no zone, so this is
+/// UTC; readers that know the extended timestamp use that instead.
+fn dos_time(mtime: u64) -> (u16, u16) {
+    let days = mtime / 86400;
+    let secs = mtime % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    if year < 1980 {
+        return (0, (1 << 5) | 1); // 1980-01-01, the earliest there is
+    }
+    let year = year.min(2107);
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2);
+    let date = ((year - 1980) as u64) << 9 | (month as u64) << 5 | day as u64;
+    (time as u16, date as u16)
+}
+
+/// Unix time of a DOS time and date, taken as UTC.
+fn unix_time(time: u16, date: u16) -> u64 {
+    let (year, month, day) = (
+        1980 + (date >> 9) as i64,
+        (date >> 5 & 0xF) as u32,
+        (date & 0x1F) as u32,
+    );
+    let days = days_from_civil(year, month.max(1), day.max(1));
+    let secs =
+        (time >> 11) as u64 * 3600 + (time >> 5 & 0x3F) as u64 * 60 + (time & 0x1F) as u64 * 2;
+    (days.max(0) as u64) * 86400 + secs
+}
+
+/// Whether a stream starting with `head` is a ZIP archive (or an empty one).
+pub fn is_zip(head: &[u8]) -> bool {
+    head.starts_with(&LOCAL_HEADER.to_le_bytes())
+        || head.starts_with(&END_OF_CENTRAL_DIR.to_le_bytes())
+}
+
+/// An entry as listed in the central directory.
+struct Entry {
+    name: String,
+    flags: u16,
+    method: u16,
+    mtime: u64,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    /// Unix mode with type bits, when the writer recorded one
+    mode: Option<u32>,
+    dos_directory: bool,
+    /// Flagged by `pack` as changed while it was read
+    changed: bool,
+}
+
+/// The entries of the ZIP archive at `path` as a tar stream, converted on a
+/// thread of its own. A read error, a CRC mismatch or an unsupported entry
+/// ends the stream with that error.
+pub fn open_as_tar(path: &Path) -> Result<Box<dyn Read + Send>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let entries = central_directory(&mut file)
+        .with_context(|| format!("Failed to read ZIP archive {:?}", path))?;
+    let (tx, rx) = sync_channel(16);
+    thread::spawn(move || {
+        let chunks = BufWriter::with_capacity(CHUNK_SIZE, ChunkSender { tx: tx.clone() });
+        let mut tar = tar::Builder::new(chunks);
+        match write_tar(BufReader::new(file), entries, &mut tar) {
+            // Nobody reads on when this fails
+            Ok(()) => drop(tar.into_inner().and_then(|mut chunks| chunks.flush())),
+            // Sent before the builder's drop ends the stream, which would look complete
+            Err(e) => drop(tx.send(Err(io::Error::other(format!("{:#}", e))))),
+        }
+    });
+    Ok(Box::new(ChunkReader {
+        rx,
+        chunk: Vec::new(),
+        pos: 0,
+    }))
+}
+
+fn central_directory(file: &mut File) -> Result<Vec<Entry>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    // The end record is 22 bytes plus a comment of up to 64 KiB
+    let tail_len = len.min(22 + 0xFFFF);
+    let mut tail = vec![0; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| tail[i..i + 4] == END_OF_CENTRAL_DIR.to_le_bytes())
+        .context("No end of central directory record")?;
+    let record = &tail[end..];
+    let mut count = u16_at(record, 10) as u64;
+    let mut size = u32_at(record, 12) as u64;
+    let mut start = u32_at(record, 16) as u64;
+
+    if count == u16::MAX as u64 || size == u32::MAX as u64 || start == u32::MAX as u64 {
+        let locator = end
+            .checked_sub(20)
+            .map(|i| &tail[i..i + 20])
+            .filter(|locator| u32_at(locator, 0) == ZIP64_LOCATOR)
+            .context("No ZIP64 end of central directory locator")?;
+        let mut record = [0u8; 56];
+        file.seek(SeekFrom::Start(u64_at(locator, 8)))?;
+        file.read_exact(&mut record)?;
+        if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIR {
+            bail!("No ZIP64 end of central directory record");
+        }
+        count = u64_at(&record, 32);
+        size = u64_at(&record, 40);
+        start = u64_at(&record, 48);
+    }
+
+    let mut directory = Vec::new();
+    file.seek(SeekFrom::Start(start))?;
+    file.take(size).read_to_end(&mut directory)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    for _ in 0..count {
+        let header = directory
+            .get(pos..pos + 46)
+            .filter(|header| u32_at(header, 0) == CENTRAL_HEADER)
+            .context("Central directory is truncated")?;
+        let (name_len, extra_len, comment_len) = (
+            u16_at(header, 28) as usize,
+            u16_at(header, 30) as usize,
+            u16_at(header, 32) as usize,
+        );
+        let name = directory
+            .get(pos + 46..pos + 46 + name_len)
+            .context("Central directory is truncated")?;
+        let extra = directory
+            .get(pos + 46 + name_len..pos + 46 + name_len + extra_len)
+            .context("Central directory is truncated")?;
+        let comment = directory
+            .get(pos + 46 + name_len + extra_len..pos + 46 + name_len + extra_len + comment_len)
+            .context("Central directory is truncated")?;
+
+        let made_by_unix = u16_at(header, 4) >> 8 == 3;
+        let external = u32_at(header, 38);
+        let mut entry = Entry {
+            // Old Windows tools wrote backslashes
+            name: String::from_utf8_lossy(name).replace('\\', "/"),
+            flags: u16_at(header, 8),
+            method: u16_at(header, 10),
+            mtime: unix_time(u16_at(header, 12), u16_at(header, 14)),
+            crc: u32_at(header, 16),
+            compressed: u32_at(header, 20) as u64,
+            size: u32_at(header, 24) as u64,
+            offset: u32_at(header, 42) as u64,
+            mode: (made_by_unix && external >> 16 != 0).then_some(external >> 16),
+            dos_directory: external & DOS_DIRECTORY != 0,
+            changed: comment == CHANGED_COMMENT,
+        };
+        read_extra(&mut entry, extra);
+        entries.push(entry);
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// Apply the ZIP64 sizes and the extended timestamp from an entry's extra field.
+fn read_extra(entry: &mut Entry, mut extra: &[u8]) {
+    while extra.len() >= 4 {
+        let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+        let Some(data) = extra.get(4..4 + len) else {
+            break;
+        };
+        match id {
+            ZIP64_EXTRA => {
+                let mut fields = data.chunks_exact(8).map(|field| u64_at(field, 0));
+                for value in [&mut entry.size, &mut entry.compressed, &mut entry.offset] {
+                    if *value == u32::MAX as u64 {
+                        match fields.next() {
+                            Some(field) => *value = field,
+                            None => break,
+                        }
+                    }
+                }
+            }
+            TIMESTAMP_EXTRA if data.len() >= 5 && data[0] & 1 != 0 => {
+                entry.mtime = u32_at(data, 1) as u64;
+            }
+            _ => {}
+        }
+        extra = &extra[4 + len..];
+    }
+}
+
+fn write_tar<R: Read + Seek, W: Write>(
+    mut file: R,
+    entries: Vec<Entry>,
+    tar: &mut tar::Builder<W>,
+) -> Result<()> {
+    for entry in entries {
+        // The tar builder refuses these outright; skip them like unpack does
+        if let Some(reason) = sanitize::reject_reason(Path::new(&entry.name)) {
+            eprintln!("Skipping unsafe path ({}): {:?}", reason, entry.name);
+            continue;
+        }
+        if entry.flags & FLAG_ENCRYPTED != 0 {
+            bail!("{}: encrypted ZIP entries are not supported", entry.name);
+        }
+        let kind = match entry.mode {
+            Some(mode) => mode & S_IFMT,
+            None if entry.dos_directory => S_IFDIR,
+            None => S_IFREG,
+        };
+        let kind = if entry.name.ends_with('/') {
+            S_IFDIR
+        } else {
+            kind
+        };
+        let mode = entry
+            .mode
+            .map(|mode| mode & 0o7777)
+            .filter(|&mode| mode != 0);
+
+        // Local headers may have a different extra field than the central directory
+        let mut local = [0u8; 30];
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.read_exact(&mut local)?;
+        if u32_at(&local, 0) != LOCAL_HEADER {
+            bail!("{}: no local header at offset {}", entry.name, entry.offset);
+        }
+        let data_offset = entry.offset + 30 + u16_at(&local, 26) as u64 + u16_at(&local, 28) as u64;
+        file.seek(SeekFrom::Start(data_offset))?;
+        let compressed = (&mut file).take(entry.compressed);
+        let data: Box<dyn Read + '_> = match entry.method {
+            STORED => Box::new(compressed),
+            DEFLATED => Box::new(flate2::read::DeflateDecoder::new(compressed)),
+            method => bail!(
+                "{}: compression method {} is not supported",
+                entry.name,
+                method
+            ),
+        };
+        let mut data = CrcReader::new(data);
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.mtime);
+        match kind {
+            S_IFDIR => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(mode.unwrap_or(0o755));
+                header.set_size(0);
+                tar.append_data(&mut header, &entry.name, io::empty())?;
+                continue;
+            }
+            S_IFLNK => {
+                let mut target = String::new();
+                data.read_to_string(&mut target)
+                    .with_context(|| format!("{}: symlink target is not UTF-8", entry.name))?;
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                tar.append_link(&mut header, &entry.name, &target)?;
+            }
+            _ => {
+                if entry.changed {
+                    tar.append_pax_extensions([(PAX_CHANGED, &b"1"[..])])?;
+                }
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(mode.unwrap_or(0o644));
+                header.set_size(entry.size);
+                tar.append_data(&mut header, &entry.name, Exact::new(&mut data, entry.size))?;
+            }
+        }
+        if data.crc().sum() != entry.crc {
+            bail!("{}: CRC mismatch, the archive is damaged", entry.name);
+        }
+    }
+    Ok(())
+}
+
+/// Yields exactly `len` bytes of `inner`, failing if it ends early, so a
+/// short entry can't shift the tar stream.
+struct Exact<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Exact<R> {
+    fn new(inner: R, len: u64) -> Self {
+        Exact { inner, left: len }
+    }
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "ZIP entry is shorter than its size",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+struct ChunkSender {
+    tx: SyncSender<io::Result<Vec<u8>>>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Archive reader went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ChunkReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk?, 0),
+                // The converter finished and hung up
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
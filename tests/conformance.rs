@@ -0,0 +1,315 @@
+//! Archives as other tar implementations write them (GNU, pax, libarchive,
+//! star, V7), built header by header so each quirk is pinned down. Every
+//! case must pass `--parse-only`, extract, and then compare clean.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tar::{Builder, EntryType, Header};
+
+const ZSTAR: &str = env!("CARGO_BIN_EXE_zstar");
+
+const MTIME: u64 = 1_600_000_000;
+
+fn scratch_dir() -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "conformance-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A header with the fields every case shares; the caller sets path and checksum.
+fn header(mut header: Header, entry_type: EntryType, size: u64) -> Header {
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(if entry_type == EntryType::Directory {
+        0o755
+    } else {
+        0o644
+    });
+    header.set_mtime(MTIME);
+    header
+}
+
+/// Append `header` exactly as built, without tar-rs adding long-name records.
+fn append_raw(builder: &mut Builder<Vec<u8>>, mut header: Header, path: &str, data: &[u8]) {
+    header.set_path(path).unwrap();
+    header.set_cksum();
+    builder.append(&header, data).unwrap();
+}
+
+/// Octal field, NUL-terminated, as old GNU tar writes sparse maps.
+fn octal(field: &mut [u8], value: u64) {
+    let text = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(text.as_bytes());
+}
+
+/// Run zstar, fail the test unless it succeeds, and return its stderr.
+fn zstar(args: &[&std::ffi::OsStr]) -> String {
+    let output = Command::new(ZSTAR).args(args).output().unwrap();
+    assert!(
+        output.status.success(),
+        "zstar {:?} failed: {}{}",
+        args,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Compress `tar`, check it, extract it and check the result against the
+/// archive. Returns the output directory and the warnings of the check and
+/// the extraction.
+fn extract(tar: Vec<u8>) -> (PathBuf, String) {
+    let scratch = scratch_dir();
+    let archive = scratch.join("archive.tar.zst");
+    let output = scratch.join("out");
+    fs::write(&archive, zstd::encode_all(tar.as_slice(), 3).unwrap()).unwrap();
+
+    let mut stderr = zstar(&[
+        "unpack".as_ref(),
+        archive.as_os_str(),
+        "--parse-only".as_ref(),
+    ]);
+    stderr += &zstar(&[
+        "unpack".as_ref(),
+        archive.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    zstar(&[
+        "unpack".as_ref(),
+        archive.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+        "--compare-extract".as_ref(),
+    ]);
+    (output, stderr)
+}
+
+fn entries(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn gnu_long_names() {
+    let dir = "d".repeat(120);
+    let file = format!("{}/{}.txt", dir, "f".repeat(150));
+    let target = format!("{}/{}", dir, "t".repeat(150));
+    let mut builder = Builder::new(Vec::new());
+    let mut h = header(Header::new_gnu(), EntryType::Directory, 0);
+    builder.append_data(&mut h, &dir, &[][..]).unwrap();
+    let mut h = header(Header::new_gnu(), EntryType::Regular, 5);
+    builder.append_data(&mut h, &file, &b"hello"[..]).unwrap();
+    // Long link target: GNU tar writes a 'K' record before the link
+    let mut h = header(Header::new_gnu(), EntryType::Link, 0);
+    builder.append_link(&mut h, &target, &file).unwrap();
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert_eq!(fs::read(out.join(&file)).unwrap(), b"hello");
+    assert_eq!(fs::read(out.join(&target)).unwrap(), b"hello");
+}
+
+#[test]
+fn pax_global_header_is_not_extracted() {
+    // `git archive` starts every tarball with one of these
+    let record = b"52 comment=0123456789abcdef0123456789abcdef01234567\n";
+    let mut builder = Builder::new(Vec::new());
+    let h = header(
+        Header::new_ustar(),
+        EntryType::XGlobalHeader,
+        record.len() as u64,
+    );
+    append_raw(&mut builder, h, "pax_global_header", record);
+    let h = header(Header::new_ustar(), EntryType::Directory, 0);
+    append_raw(&mut builder, h, "repo/", &[]);
+    let h = header(Header::new_ustar(), EntryType::Regular, 3);
+    append_raw(&mut builder, h, "repo/README", b"hi\n");
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert_eq!(entries(&out), ["repo"]);
+    assert_eq!(fs::read(out.join("repo/README")).unwrap(), b"hi\n");
+}
+
+#[test]
+fn pax_local_records_with_long_path() {
+    // libarchive's pax writer: long paths and its own keys in an 'x' record
+    let path = format!("{}/file", "p".repeat(200));
+    let mut builder = Builder::new(Vec::new());
+    builder
+        .append_pax_extensions([
+            ("path", path.as_bytes()),
+            ("LIBARCHIVE.creationtime", b"1600000000".as_slice()),
+            ("SCHILY.dev", b"2049".as_slice()),
+            ("SCHILY.ino", b"1234".as_slice()),
+            ("SCHILY.nlink", b"1".as_slice()),
+        ])
+        .unwrap();
+    let h = header(Header::new_ustar(), EntryType::Regular, 4);
+    append_raw(&mut builder, h, "truncated-name", b"data");
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert_eq!(fs::read(out.join(&path)).unwrap(), b"data");
+    assert!(!out.join("truncated-name").exists());
+}
+
+#[test]
+fn old_gnu_sparse_file() {
+    // 2KB file: 512 bytes of 'A', a 1KB hole, 512 bytes of 'B'
+    let mut h = header(Header::new_gnu(), EntryType::GNUSparse, 1024);
+    let gnu = h.as_gnu_mut().unwrap();
+    octal(&mut gnu.sparse[0].offset, 0);
+    octal(&mut gnu.sparse[0].numbytes, 512);
+    octal(&mut gnu.sparse[1].offset, 1536);
+    octal(&mut gnu.sparse[1].numbytes, 512);
+    octal(&mut gnu.realsize, 2048);
+    let mut data = vec![b'A'; 512];
+    data.extend(vec![b'B'; 512]);
+    let mut builder = Builder::new(Vec::new());
+    append_raw(&mut builder, h, "sparse.img", &data);
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    let mut expected = vec![b'A'; 512];
+    expected.extend(vec![0; 1024]);
+    expected.extend(vec![b'B'; 512]);
+    assert_eq!(fs::read(out.join("sparse.img")).unwrap(), expected);
+}
+
+#[test]
+fn v7_directory_with_trailing_slash() {
+    // Pre-POSIX tar had no directory type, only the trailing slash
+    let mut builder = Builder::new(Vec::new());
+    let h = header(Header::new_old(), EntryType::Regular, 0);
+    append_raw(&mut builder, h, "old/", &[]);
+    let h = header(Header::new_old(), EntryType::Regular, 2);
+    append_raw(&mut builder, h, "old/file", b"v7");
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert!(out.join("old").is_dir());
+    assert_eq!(fs::read(out.join("old/file")).unwrap(), b"v7");
+}
+
+#[test]
+fn bsdtar_dot_slash_paths() {
+    // `bsdtar -C dir -cf - .` names the root "./" and prefixes everything
+    let mut builder = Builder::new(Vec::new());
+    let h = header(Header::new_ustar(), EntryType::Directory, 0);
+    append_raw(&mut builder, h, "./", &[]);
+    let h = header(Header::new_ustar(), EntryType::Directory, 0);
+    append_raw(&mut builder, h, "./sub/", &[]);
+    let h = header(Header::new_ustar(), EntryType::Regular, 3);
+    append_raw(&mut builder, h, "./sub/a.txt", b"abc");
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert_eq!(fs::read(out.join("sub/a.txt")).unwrap(), b"abc");
+}
+
+#[test]
+fn gnu_dumpdir_is_a_directory() {
+    // GNU --listed-incremental stores directories as 'D' with their listing as data
+    let listing = b"Yfile\0\0";
+    let mut builder = Builder::new(Vec::new());
+    let h = header(
+        Header::new_gnu(),
+        EntryType::new(b'D'),
+        listing.len() as u64,
+    );
+    append_raw(&mut builder, h, "inc/", listing);
+    let h = header(Header::new_gnu(), EntryType::Regular, 1);
+    append_raw(&mut builder, h, "inc/file", b"x");
+    let (out, _) = extract(builder.into_inner().unwrap());
+
+    assert!(out.join("inc").is_dir());
+    assert_eq!(fs::read(out.join("inc/file")).unwrap(), b"x");
+}
+
+#[test]
+fn special_files_are_skipped_with_a_warning() {
+    let mut builder = Builder::new(Vec::new());
+    for (path, entry_type) in [
+        ("dev/null", EntryType::Char),
+        ("dev/sda", EntryType::Block),
+        ("run/pipe", EntryType::Fifo),
+    ] {
+        let h = header(Header::new_gnu(), entry_type, 0);
+        append_raw(&mut builder, h, path, &[]);
+    }
+    let h = header(Header::new_gnu(), EntryType::Regular, 2);
+    append_raw(&mut builder, h, "run/ok", b"ok");
+    let h = header(Header::new_gnu(), EntryType::new(b'V'), 0);
+    append_raw(&mut builder, h, "VOLUME LABEL", &[]);
+    let (out, stderr) = extract(builder.into_inner().unwrap());
+
+    assert_eq!(entries(&out), ["run"]);
+    assert_eq!(entries(&out.join("run")), ["ok"]);
+    for what in ["character device", "block device", "FIFO"] {
+        assert!(stderr.contains(what), "no warning for {}: {}", what, stderr);
+    }
+    assert!(
+        !stderr.contains("VOLUME"),
+        "volume label reported: {}",
+        stderr
+    );
+}
+
+#[test]
+fn pax_sparse_is_refused_not_garbled() {
+    // GNU tar --format=posix keeps the sparse map in the data; extracting it
+    // as-is would write the map into the file
+    let mut builder = Builder::new(Vec::new());
+    builder
+        .append_pax_extensions([
+            ("GNU.sparse.major", b"1".as_slice()),
+            ("GNU.sparse.minor", b"0".as_slice()),
+            ("GNU.sparse.name", b"disk.img".as_slice()),
+            ("GNU.sparse.realsize", b"4096".as_slice()),
+        ])
+        .unwrap();
+    let mut data = b"1\n0\n512\n".to_vec();
+    data.resize(512, 0);
+    data.extend(vec![b'Z'; 512]);
+    let h = header(Header::new_ustar(), EntryType::Regular, data.len() as u64);
+    append_raw(&mut builder, h, "GNUSparseFile.0/disk.img", &data);
+    let (out, stderr) = extract(builder.into_inner().unwrap());
+
+    assert!(!out.join("GNUSparseFile.0").exists());
+    assert!(stderr.contains("pax sparse file"), "{}", stderr);
+}
+
+#[test]
+fn plain_and_gzip_archives_are_detected() {
+    let mut builder = Builder::new(Vec::new());
+    let h = header(Header::new_ustar(), EntryType::Regular, 5);
+    append_raw(&mut builder, h, "dist/app.js", b"hello");
+    let tar = builder.into_inner().unwrap();
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut gzip, &tar).unwrap();
+    let scratch = scratch_dir();
+
+    for (name, data) in [
+        ("plain.tar", tar.clone()),
+        ("app.tar.gz", gzip.finish().unwrap()),
+    ] {
+        let archive = scratch.join(name);
+        let output = scratch.join(format!("{}.out", name));
+        fs::write(&archive, data).unwrap();
+        zstar(&[
+            "unpack".as_ref(),
+            archive.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ]);
+        assert_eq!(fs::read(output.join("dist/app.js")).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,1887 @@
+//! Pack and unpack random trees with every reader backend and check that
+//! contents and metadata come back unchanged.
+//!
+//! Unix only: Windows approximates modes and needs privileges for symlinks.
+
+#![cfg(unix)]
+
+use filetime::FileTime;
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const ZSTAR: &str = env!("CARGO_BIN_EXE_zstar");
+
+const BACKENDS: &[&str] = &["compio", "threaded"];
+
+const FILE_MODES: &[u32] = &[0o644, 0o600, 0o755, 0o444];
+const DIR_MODES: &[u32] = &[0o755, 0o750, 0o700];
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Dir {
+        mode: u32,
+    },
+    File {
+        data: Vec<u8>,
+        mode: u32,
+    },
+    /// Link to the `n`th file created so far (modulo their count)
+    Hardlink(usize),
+    Symlink(usize),
+}
+
+#[derive(Clone, Debug)]
+struct Spec {
+    path: Vec<String>,
+    kind: Kind,
+    mtime: i64,
+}
+
+fn kind() -> impl Strategy<Value = Kind> {
+    prop_oneof![
+        2 => prop::sample::select(DIR_MODES).prop_map(|mode| Kind::Dir { mode }),
+        6 => (
+            // Mostly small files, some past the 1MB mmap threshold
+            prop_oneof![
+                prop::collection::vec(any::<u8>(), 0..4096),
+                (1024 * 1024..3 * 1024 * 1024usize).prop_map(|len| vec![0x5A; len]),
+            ],
+            prop::sample::select(FILE_MODES),
+        )
+            .prop_map(|(data, mode)| Kind::File { data, mode }),
+        1 => any::<usize>().prop_map(Kind::Hardlink),
+        1 => any::<usize>().prop_map(Kind::Symlink),
+    ]
+}
+
+fn spec() -> impl Strategy<Value = Spec> {
+    (
+        // Lowercase only, so trees survive case-insensitive filesystems
+        prop::collection::vec("[a-z0-9_-]{1,8}", 1..4),
+        kind(),
+        0..4_000_000_000i64,
+    )
+        .prop_map(|(path, kind, mtime)| Spec { path, kind, mtime })
+}
+
+fn scratch_dir() -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "roundtrip-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Create the tree under `root`, skipping specs that collide with an earlier
+/// path. Directory modes and times are applied last, deepest first.
+fn build(root: &Path, specs: &[Spec]) {
+    let mut taken: BTreeMap<PathBuf, bool> = BTreeMap::new(); // path -> is_dir
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut dirs: Vec<(PathBuf, u32, i64)> = Vec::new();
+
+    'specs: for spec in specs {
+        let rel: PathBuf = spec.path.iter().collect();
+        if taken.contains_key(&rel) {
+            continue;
+        }
+        for parent in rel.ancestors().skip(1) {
+            if !parent.as_os_str().is_empty() && taken.get(parent) == Some(&false) {
+                continue 'specs;
+            }
+        }
+        let path = root.join(&rel);
+        let is_link = matches!(spec.kind, Kind::Hardlink(_) | Kind::Symlink(_));
+        if is_link && files.is_empty() {
+            continue;
+        }
+        for parent in rel.ancestors().skip(1) {
+            if !parent.as_os_str().is_empty() {
+                taken.insert(parent.to_path_buf(), true);
+            }
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        match &spec.kind {
+            Kind::Dir { mode } => {
+                fs::create_dir_all(&path).unwrap();
+                dirs.push((path, *mode, spec.mtime));
+                taken.insert(rel, true);
+                continue;
+            }
+            Kind::File { data, mode } => {
+                fs::write(&path, data).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+                filetime::set_file_mtime(&path, FileTime::from_unix_time(spec.mtime, 0)).unwrap();
+                files.push(rel.clone());
+            }
+            Kind::Hardlink(n) => {
+                let target = root.join(&files[n % files.len()]);
+                fs::hard_link(&target, &path).unwrap();
+            }
+            Kind::Symlink(n) => {
+                let target = &files[n % files.len()];
+                let up: PathBuf = rel.parent().unwrap().components().map(|_| "..").collect();
+                symlink(up.join(target), &path).unwrap();
+                let mtime = FileTime::from_unix_time(spec.mtime, 0);
+                filetime::set_symlink_file_times(&path, mtime, mtime).unwrap();
+            }
+        }
+        taken.insert(rel, false);
+    }
+
+    dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+    for (path, mode, mtime) in dirs {
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Dir {
+        mode: u32,
+        mtime: i64,
+    },
+    File {
+        data: Vec<u8>,
+        mode: u32,
+        mtime: i64,
+        links: u64,
+    },
+    Symlink {
+        target: PathBuf,
+        mtime: i64,
+    },
+}
+
+/// Everything below `root` that a round trip has to preserve, by relative path.
+fn snapshot(root: &Path) -> BTreeMap<PathBuf, Node> {
+    fn walk(root: &Path, dir: &Path, out: &mut BTreeMap<PathBuf, Node>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let meta = fs::symlink_metadata(&path).unwrap();
+            let rel = path.strip_prefix(root).unwrap().to_path_buf();
+            let mode = meta.mode() & 0o7777;
+            let node = if meta.file_type().is_symlink() {
+                Node::Symlink {
+                    target: fs::read_link(&path).unwrap(),
+                    mtime: meta.mtime(),
+                }
+            } else if meta.is_dir() {
+                walk(root, &path, out);
+                Node::Dir {
+                    mode,
+                    mtime: meta.mtime(),
+                }
+            } else {
+                Node::File {
+                    data: fs::read(&path).unwrap(),
+                    mode,
+                    mtime: meta.mtime(),
+                    links: meta.nlink(),
+                }
+            };
+            out.insert(rel, node);
+        }
+    }
+    let mut out = BTreeMap::new();
+    walk(root, root, &mut out);
+    out
+}
+
+/// Read-only directories would stop the scratch dir from being removed.
+fn remove_tree(dir: &Path) {
+    let _ = Command::new("chmod").arg("-R").arg("u+w").arg(dir).status();
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn zstar(args: &[&std::ffi::OsStr]) -> Result<(), TestCaseError> {
+    let output = Command::new(ZSTAR).args(args).output().unwrap();
+    prop_assert!(
+        output.status.success(),
+        "zstar {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn pack_unpack_preserves_tree(specs in prop::collection::vec(spec(), 1..40)) {
+        let scratch = scratch_dir();
+        let input = scratch.join("tree");
+        fs::create_dir(&input).unwrap();
+        build(&input, &specs);
+        let expected = snapshot(&input);
+        // The input directory itself is not an entry; an empty tree packs to nothing
+        if expected.is_empty() {
+            remove_tree(&scratch);
+        }
+        prop_assume!(!expected.is_empty());
+
+        for backend in BACKENDS {
+            let archive = scratch.join(format!("{}.tar.zst", backend));
+            let output = scratch.join(format!("{}.out", backend));
+            zstar(&[
+                "pack".as_ref(),
+                input.as_os_str(),
+                "-o".as_ref(),
+                archive.as_os_str(),
+                "--io-backend".as_ref(),
+                backend.as_ref(),
+            ])?;
+            zstar(&[
+                "unpack".as_ref(),
+                archive.as_os_str(),
+                "-o".as_ref(),
+                output.as_os_str(),
+            ])?;
+            let actual = snapshot(&output.join("tree"));
+            prop_assert_eq!(&actual, &expected, "backend {}", backend);
+        }
+
+        // Naming a path extracts through the entry index of a seekable archive
+        let archive = scratch.join("seekable.tar.zst");
+        let output = scratch.join("seekable.out");
+        zstar(&[
+            "pack".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            archive.as_os_str(),
+            "--seekable=1".as_ref(),
+        ])?;
+        zstar(&[
+            "unpack".as_ref(),
+            archive.as_os_str(),
+            "tree".as_ref(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ])?;
+        prop_assert_eq!(&snapshot(&output.join("tree")), &expected, "seekable");
+
+        // Encrypted archives are decrypted on the way in, given the same key file
+        let archive = scratch.join("encrypted.tar.zst");
+        let output = scratch.join("encrypted.out");
+        let key_file = scratch.join("key");
+        fs::write(&key_file, "correct horse battery staple").unwrap();
+        zstar(&[
+            "pack".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            archive.as_os_str(),
+            "--encrypt".as_ref(),
+            "--key-file".as_ref(),
+            key_file.as_os_str(),
+        ])?;
+        zstar(&[
+            "unpack".as_ref(),
+            archive.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+            "--key-file".as_ref(),
+            key_file.as_os_str(),
+        ])?;
+        prop_assert_eq!(&snapshot(&output.join("tree")), &expected, "encrypted");
+
+        // ZIP has no hard links; every path comes back as a file of its own
+        let archive = scratch.join("tree.zip");
+        let output = scratch.join("zip.out");
+        zstar(&[
+            "pack".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            archive.as_os_str(),
+            "--format".as_ref(),
+            "zip".as_ref(),
+        ])?;
+        zstar(&[
+            "unpack".as_ref(),
+            archive.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ])?;
+        let mut expected = expected;
+        for node in expected.values_mut() {
+            if let Node::File { links, .. } = node {
+                *links = 1;
+            }
+        }
+        prop_assert_eq!(&snapshot(&output.join("tree")), &expected, "zip");
+        remove_tree(&scratch);
+    }
+}
+
+fn run(args: &[&std::ffi::OsStr]) -> std::process::Output {
+    Command::new(ZSTAR).args(args).output().unwrap()
+}
+
+/// Pack `input` into `archive`, failing the test unless it succeeds.
+fn pack(input: &Path, archive: &Path, extra: &[&str]) {
+    let mut args = vec![
+        "pack".as_ref(),
+        input.as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+    ];
+    args.extend(extra.iter().map(|arg| std::ffi::OsStr::new(*arg)));
+    let output = run(&args);
+    assert!(
+        output.status.success(),
+        "pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Where an entry's data starts in the tar stream, and the data
+type Stored = (u64, Vec<u8>);
+
+/// Decompressed tar stream of `archive`, and its entries by path.
+fn tar_of(archive: &Path) -> (Vec<u8>, BTreeMap<String, Stored>) {
+    let tar = zstd::decode_all(fs::File::open(archive).unwrap()).unwrap();
+    let mut entries = BTreeMap::new();
+    let mut reader = tar::Archive::new(&tar[..]);
+    for entry in reader.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = String::from_utf8(entry.path_bytes().into_owned()).unwrap();
+        let offset = entry.raw_file_position();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+        entries.insert(path, (offset, data));
+    }
+    (tar, entries)
+}
+
+#[test]
+fn manifest_catches_damaged_contents() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    let weird = "we\\ird\nname";
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("small"), b"small file").unwrap();
+    // Past the mmap threshold, and past the streaming one that a 1000MB
+    // budget sets somewhere between them
+    fs::write(input.join("mapped"), vec![0x4D; 2 * 1024 * 1024]).unwrap();
+    fs::write(input.join("streamed"), vec![0x53; 12 * 1024 * 1024]).unwrap();
+    fs::write(input.join(weird), b"escaped in the manifest").unwrap();
+    let archive = scratch.join("tree.tar.zst");
+    pack(&input, &archive, &["--memory-budget", "1000"]);
+
+    let (mut tar, entries) = tar_of(&archive);
+    let manifest = String::from_utf8(entries[".zstar-manifest"].1.clone()).unwrap();
+    let lines: Vec<&str> = manifest.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", manifest);
+    assert!(
+        lines.contains(&format!("{}  tree/small", blake3::hash(b"small file")).as_str()),
+        "{}",
+        manifest
+    );
+    // b3sum's escaping: a leading backslash, then `\\` and `\n`
+    let escaped = format!(
+        "\\{}  tree/we\\\\ird\\nname",
+        blake3::hash(b"escaped in the manifest")
+    );
+    assert!(lines.contains(&escaped.as_str()), "{}", manifest);
+
+    let output = scratch.join("intact.out");
+    let result = run(&[
+        "unpack".as_ref(),
+        archive.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    for name in ["tree/mapped", "tree/streamed", "tree/we\\ird\nname"] {
+        tar[entries[name].0 as usize + 7] ^= 0xFF;
+    }
+    let damaged = scratch.join("damaged.tar.zst");
+    fs::write(&damaged, zstd::encode_all(&tar[..], 3).unwrap()).unwrap();
+    let output = scratch.join("damaged.out");
+    let result = run(&[
+        "unpack".as_ref(),
+        damaged.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!result.status.success(), "damage went unnoticed");
+    assert!(stderr.contains("3 extracted files don't"), "{}", stderr);
+    for name in ["mapped", "streamed", "we\\\\ird\\nname"] {
+        assert!(stderr.contains(name), "{} not reported: {}", name, stderr);
+    }
+    assert!(!stderr.contains("small"), "{}", stderr);
+    remove_tree(&scratch);
+}
+
+/// Run zstar and return whether it succeeded, and its stderr.
+fn try_zstar(args: &[&str]) -> (bool, String) {
+    let output = Command::new(ZSTAR).args(args).output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+/// A repository under `scratch` with one snapshot of a small tree.
+fn repo_with_snapshot(scratch: &Path) -> (String, String) {
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("sub")).unwrap();
+    fs::write(input.join("sub/file"), b"backed up").unwrap();
+    let repo = scratch.join("repo").to_str().unwrap().to_string();
+    let input = input.to_str().unwrap().to_string();
+    assert!(try_zstar(&["repo", "init", &repo]).0);
+    let (ok, stderr) = try_zstar(&["repo", "backup", &input, "--repo", &repo]);
+    assert!(ok, "{}", stderr);
+    (repo, input)
+}
+
+#[test]
+fn repo_prune_needs_keep_none_to_forget_everything() {
+    let scratch = scratch_dir();
+    let (repo, _) = repo_with_snapshot(&scratch);
+    let dir = Path::new(&repo).join("snapshots");
+    let snapshots = || fs::read_dir(&dir).unwrap().count();
+    let prune = ["repo", "prune", "--repo", &repo];
+    let nothing = ["--keep-daily=0", "--keep-weekly=0", "--keep-monthly=0"];
+    let (ok, stderr) = try_zstar(&[&prune[..], &nothing].concat());
+    assert!(!ok && stderr.contains("--keep-none"), "{}", stderr);
+    assert_eq!(snapshots(), 1);
+
+    let (ok, stderr) = try_zstar(&[&prune[..], &nothing, &["--keep-none"]].concat());
+    assert!(ok, "{}", stderr);
+    assert_eq!(snapshots(), 0);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn repo_prune_and_backup_exclude_each_other() {
+    let scratch = scratch_dir();
+    let (repo, input) = repo_with_snapshot(&scratch);
+    // What a running backup holds
+    let lock = fs::File::open(Path::new(&repo).join("lock")).unwrap();
+    lock.lock_shared().unwrap();
+
+    let (ok, stderr) = try_zstar(&["repo", "prune", "--repo", &repo]);
+    assert!(!ok && stderr.contains("in use"), "{}", stderr);
+    let (ok, stderr) = try_zstar(&["repo", "compact", "--repo", &repo]);
+    assert!(!ok && stderr.contains("in use"), "{}", stderr);
+    // Backups share the lock
+    let (ok, stderr) = try_zstar(&["repo", "backup", &input, "--repo", &repo]);
+    assert!(ok, "{}", stderr);
+
+    lock.unlock().unwrap();
+    lock.lock().unwrap();
+    let (ok, stderr) = try_zstar(&["repo", "backup", &input, "--repo", &repo]);
+    assert!(!ok && stderr.contains("pruned or compacted"), "{}", stderr);
+    drop(lock);
+    let (ok, stderr) = try_zstar(&["repo", "compact", "--repo", &repo]);
+    assert!(ok, "{}", stderr);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn repo_restore_stays_in_the_output() {
+    let scratch = scratch_dir();
+    let (repo, _) = repo_with_snapshot(&scratch);
+    let output = scratch.join("out");
+    let out = output.to_str().unwrap();
+    fs::write(scratch.join("secret.json.zst"), b"not a snapshot").unwrap();
+    for id in ["../../secret", "../snapshots/x", ""] {
+        let (ok, stderr) = try_zstar(&["repo", "restore", id, "--repo", &repo, "-o", out]);
+        assert!(!ok && stderr.contains("Invalid snapshot id"), "{}", stderr);
+    }
+
+    // A symlink out of the tree, then a file below it
+    let outside = scratch.join("outside");
+    fs::create_dir(&outside).unwrap();
+    let snapshot = format!(
+        r#"{{"id":"evil","source":"/","created":0,"entries":[
+            {{"path":"link","kind":{{"Symlink":{{"target":{:?}}}}},"mode":511,"mtime":0}},
+            {{"path":"link/planted","kind":{{"File":{{"size":0,"chunks":[]}}}},"mode":420,"mtime":0}}
+        ]}}"#,
+        outside.to_str().unwrap()
+    );
+    fs::write(
+        Path::new(&repo).join("snapshots/evil.json.zst"),
+        zstd::encode_all(snapshot.as_bytes(), 3).unwrap(),
+    )
+    .unwrap();
+    let (ok, stderr) = try_zstar(&["repo", "restore", "evil", "--repo", &repo, "-o", out]);
+    assert!(!ok, "{}", stderr);
+    assert!(!outside.join("planted").exists());
+    let link = fs::symlink_metadata(output.join("link")).unwrap();
+    assert!(link.is_dir());
+    remove_tree(&scratch);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn large_files_get_only_the_selected_xattrs() {
+    let scratch = scratch_dir();
+    let probe = scratch.join("probe");
+    fs::write(&probe, b"").unwrap();
+    // Capabilities need root, and both need a filesystem with xattrs
+    if unsafe { libc::geteuid() } != 0 || xattr::set(&probe, "user.probe", b"1").is_err() {
+        remove_tree(&scratch);
+        return;
+    }
+
+    // cap_net_raw, as `setcap cap_net_raw+ep` stores it
+    let capability = [
+        1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let data = vec![0x58; 11 * 1024 * 1024];
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_pax_extensions([
+            ("SCHILY.xattr.security.capability", &capability[..]),
+            ("SCHILY.xattr.user.origin", b"untrusted".as_slice()),
+        ])
+        .unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o755);
+    header.set_cksum();
+    builder.append_data(&mut header, "ping", &data[..]).unwrap();
+    let archive = scratch.join("caps.tar");
+    fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+    for (flags, restored) in [(&[][..], false), (&["--capabilities"][..], true)] {
+        let output = scratch.join(format!("out{}", flags.len()));
+        let mut args = vec![
+            "unpack",
+            archive.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        args.extend(flags);
+        let (ok, stderr) = try_zstar(&args);
+        assert!(ok, "{}", stderr);
+        let ping = output.join("ping");
+        assert_eq!(fs::metadata(&ping).unwrap().len(), data.len() as u64);
+        let has = |name: &str| xattr::get(&ping, name).unwrap().is_some();
+        assert_eq!(has("security.capability"), restored, "{:?}", flags);
+        assert!(!has("user.origin"), "{:?}", flags);
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn scrub_merge_and_info_read_every_compression() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("file"), b"compressed").unwrap();
+    let db = scratch.join("no-catalog.db");
+    let db = db.to_str().unwrap();
+
+    for (compression, extension) in [("gzip", "tar.gz"), ("xz", "tar.xz"), ("lz4", "tar.lz4")] {
+        let archive = scratch.join(format!("a.{}", extension));
+        pack(&input, &archive, &["--compression", compression]);
+        let archive = archive.to_str().unwrap();
+
+        let (ok, stderr) = try_zstar(&["scrub", archive]);
+        assert!(ok, "scrub {}: {}", compression, stderr);
+
+        let merged = scratch.join(format!("merged-{}.tar.zst", compression));
+        let merged = merged.to_str().unwrap();
+        let (ok, stderr) = try_zstar(&["merge", archive, archive, "-o", merged]);
+        assert!(ok, "merge {}: {}", compression, stderr);
+        assert!(tar_of(Path::new(merged)).1.contains_key("tree/file"));
+
+        let output = Command::new(ZSTAR)
+            .args(["info", archive, "--db", db])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "info {}", compression);
+        let format = format!("tar + {}", compression);
+        assert!(stdout.contains(&format), "{}", stdout);
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn encrypted_archives_read_everywhere() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("file"), b"sealed").unwrap();
+    // Written by `echo`: the newline isn't part of the passphrase
+    let key = scratch.join("key");
+    fs::write(&key, b"secret\n").unwrap();
+    let archive = scratch.join("a.tar.zst");
+    let key = key.to_str().unwrap();
+    pack(&input, &archive, &["--encrypt", "--key-file", key]);
+    let archive = archive.to_str().unwrap();
+
+    let (ok, stderr) = try_zstar(&["scrub", archive, "--password", "secret"]);
+    assert!(ok, "scrub: {}", stderr);
+    let merged = scratch.join("merged.tar.zst");
+    let merged = merged.to_str().unwrap();
+    let (ok, stderr) = try_zstar(&["merge", archive, "-o", merged, "--password", "secret"]);
+    assert!(ok, "merge: {}", stderr);
+    assert!(tar_of(Path::new(merged)).1.contains_key("tree/file"));
+    let db = scratch.join("no-catalog.db");
+    let output = Command::new(ZSTAR)
+        .args(["info", archive, "--db", db.to_str().unwrap()])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Format:       encrypted"), "{}", stdout);
+
+    // Key derivation costs come from the archive, so they are capped
+    let mut data = fs::read(archive).unwrap();
+    data[13..17].copy_from_slice(&u32::MAX.to_le_bytes());
+    let costly = scratch.join("costly.tar.zst");
+    fs::write(&costly, data).unwrap();
+    let output = scratch.join("costly.out");
+    let (ok, stderr) = try_zstar(&[
+        "unpack",
+        costly.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+        "--password",
+        "secret",
+    ]);
+    assert!(!ok);
+    assert!(stderr.contains("passes"), "{}", stderr);
+    remove_tree(&scratch);
+}
+
+/// A zstd-compressed tar of (path, type, link target, data) entries, the
+/// names written as given so nothing sanitizes them on the way in.
+/// Directories are read-only.
+fn crafted(entries: &[(&str, tar::EntryType, &str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for &(path, kind, link, data) in entries {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(if kind.is_dir() { 0o555 } else { 0o644 });
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+    zstd::encode_all(&builder.into_inner().unwrap()[..], 3).unwrap()
+}
+
+#[test]
+fn unpack_keeps_crafted_paths_in_the_output() {
+    use tar::EntryType::{Link, Regular, Symlink};
+
+    let scratch = scratch_dir();
+    let output = scratch.join("deep/out");
+    let outside = scratch.join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    let secret = scratch.join("secret");
+    fs::write(&secret, b"secret").unwrap();
+    fs::write(outside.join("x"), b"mine").unwrap();
+    let absolute = format!("{}/absolute", outside.to_str().unwrap());
+    let archive = scratch.join("evil.tar.zst");
+    fs::write(
+        &archive,
+        crafted(&[
+            ("../escaped", Regular, "", b"x"),
+            ("safe/../../../climbed", Regular, "", b"x"),
+            (&absolute, Regular, "", b"x"),
+            ("\\rooted", Regular, "", b"x"),
+            ("C:drive", Regular, "", b"x"),
+            ("safe\\..\\..\\backslashed", Regular, "", b"x"),
+            ("stolen", Link, "../../secret", b""),
+            ("link", Symlink, outside.to_str().unwrap(), b""),
+            ("link/planted", Regular, "", b"x"),
+            ("safe/kept", Regular, "", b"kept"),
+            // Links are created last, after this one and through it
+            ("hop", Symlink, outside.to_str().unwrap(), b""),
+            ("hop/evil", Symlink, "anywhere", b""),
+            ("hop/x", Link, "safe/kept", b""),
+            ("secrets", Symlink, scratch.to_str().unwrap(), b""),
+            ("leaked", Link, "secrets/secret", b""),
+        ]),
+    )
+    .unwrap();
+
+    let (_, stderr) = try_zstar(&[
+        "unpack",
+        archive.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    for name in ["escaped", "climbed", "absolute", "planted"] {
+        assert!(!outside.join(name).exists(), "{} escaped: {}", name, stderr);
+    }
+    for name in ["escaped", "climbed", "rooted", "drive", "backslashed"] {
+        assert!(!scratch.join("deep").join(name).exists(), "{}", name);
+        assert!(!scratch.join(name).exists(), "{}", name);
+    }
+    assert!(!output.join("stolen").exists(), "{}", stderr);
+    assert_eq!(fs::read(&secret).unwrap(), b"secret");
+    assert!(stderr.contains("absolute path"), "{}", stderr);
+    assert!(stderr.contains("parent directory component"), "{}", stderr);
+    assert!(stderr.contains("drive letter"), "{}", stderr);
+    // Symlinks come last, so the file went into a real directory
+    let link = fs::symlink_metadata(output.join("link")).unwrap();
+    assert!(link.is_dir());
+    assert!(output.join("link/planted").exists());
+    assert_eq!(fs::read(output.join("safe/kept")).unwrap(), b"kept");
+    assert!(
+        fs::symlink_metadata(outside.join("evil")).is_err(),
+        "{}",
+        stderr
+    );
+    assert_eq!(fs::read(outside.join("x")).unwrap(), b"mine");
+    assert!(
+        fs::symlink_metadata(output.join("leaked")).is_err(),
+        "{}",
+        stderr
+    );
+    remove_tree(&scratch);
+}
+
+/// Run zstar, failing the test if it doesn't finish within `secs` seconds.
+fn zstar_within(secs: u64, args: &[&std::ffi::OsStr]) {
+    let mut child = Command::new(ZSTAR)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(secs);
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "zstar {:?} failed", args);
+            return;
+        }
+        if std::time::Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            panic!("zstar {:?} hung for {}s", args, secs);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+/// `len` bytes that differ from file to file, so swapped contents show.
+fn patterned(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / 4096 + seed) as u8).collect()
+}
+
+#[test]
+fn pack_survives_adversarial_size_mixes() {
+    const MB: usize = 1024 * 1024;
+    // (name, sizes, extra pack args). A 1MB budget streams everything from
+    // the mmap threshold up, so each streamed file takes a reader until the
+    // writer gets to it while small files keep filling the content channel.
+    let mixes: [(&str, Vec<usize>, &[&str]); 4] = [
+        (
+            "streams-between-small",
+            (0..400)
+                .map(|i| if i % 20 == 10 { 3 * MB } else { i % 7 })
+                .collect(),
+            &["--memory-budget", "1"],
+        ),
+        (
+            "streams-only",
+            (0..40).map(|i| MB + i * 4099).collect(),
+            &["--memory-budget", "1"],
+        ),
+        (
+            "streams-then-small",
+            (0..300)
+                .map(|i| if i < 12 { 5 * MB } else { 100 })
+                .collect(),
+            &["--memory-budget", "1"],
+        ),
+        (
+            "mapped-and-small",
+            (0..300)
+                .map(|i| if i % 3 == 0 { MB + i } else { i })
+                .collect(),
+            &[],
+        ),
+    ];
+
+    for (name, sizes, extra) in mixes {
+        let scratch = scratch_dir();
+        let input = scratch.join("tree");
+        fs::create_dir(&input).unwrap();
+        for (i, &size) in sizes.iter().enumerate() {
+            fs::write(input.join(format!("{:04}", i)), patterned(i, size)).unwrap();
+        }
+        let expected = snapshot(&input);
+
+        for backend in BACKENDS {
+            let archive = scratch.join(format!("{}.tar.zst", backend));
+            let output = scratch.join(format!("{}.out", backend));
+            let mut args = vec![
+                "pack".as_ref(),
+                input.as_os_str(),
+                "-o".as_ref(),
+                archive.as_os_str(),
+                "--io-backend".as_ref(),
+                backend.as_ref(),
+            ];
+            args.extend(extra.iter().map(|arg| std::ffi::OsStr::new(*arg)));
+            zstar_within(120, &args);
+            zstar_within(
+                120,
+                &[
+                    "unpack".as_ref(),
+                    archive.as_os_str(),
+                    "-o".as_ref(),
+                    output.as_os_str(),
+                ],
+            );
+            assert!(
+                snapshot(&output.join("tree")) == expected,
+                "{} with {} came back different",
+                name,
+                backend
+            );
+        }
+        remove_tree(&scratch);
+    }
+}
+
+#[test]
+fn directory_entries_keep_their_own_metadata() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    // (path, mode, mtime), deepest last so setting a child's mtime doesn't touch it
+    let dirs = [
+        ("open", 0o755, 1_000_000_000),
+        ("private", 0o700, 1_100_000_000),
+        ("private/group", 0o750, 1_200_000_000),
+        ("sealed", 0o555, 1_300_000_000),
+    ];
+    for (path, _, _) in dirs {
+        fs::create_dir_all(input.join(path)).unwrap();
+    }
+    fs::write(input.join("sealed/file"), b"inside").unwrap();
+    for (path, mode, mtime) in dirs.iter().rev() {
+        let dir = input.join(path);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(*mode)).unwrap();
+        filetime::set_file_mtime(&dir, FileTime::from_unix_time(*mtime, 0)).unwrap();
+    }
+    let archive = scratch.join("tree.tar.zst");
+    pack(&input, &archive, &[]);
+
+    let tar = zstd::decode_all(fs::File::open(&archive).unwrap()).unwrap();
+    let mut headers = BTreeMap::new();
+    for entry in tar::Archive::new(&tar[..]).entries().unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let header = entry.header();
+        let stamp = (
+            header.mode().unwrap() & 0o7777,
+            header.mtime().unwrap() as i64,
+            header.uid().unwrap(),
+        );
+        headers.insert(path.trim_end_matches('/').to_string(), stamp);
+    }
+    let uid = fs::metadata(&input).unwrap().uid() as u64;
+    for (path, mode, mtime) in dirs {
+        let stamp = headers[&format!("tree/{}", path)];
+        assert_eq!(stamp, (mode, mtime, uid), "archived {}", path);
+    }
+
+    let output = scratch.join("out");
+    let result = run(&[
+        "unpack".as_ref(),
+        archive.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    assert!(result.status.success());
+    let expected = snapshot(&input);
+    assert_eq!(snapshot(&output.join("tree")), expected);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn unpack_fills_read_only_directories() {
+    use tar::EntryType::{Directory, Regular};
+
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("ro/inner")).unwrap();
+    fs::write(input.join("ro/file"), b"outer").unwrap();
+    fs::write(input.join("ro/inner/file"), b"inner").unwrap();
+    for dir in ["ro/inner", "ro"] {
+        fs::set_permissions(input.join(dir), fs::Permissions::from_mode(0o555)).unwrap();
+    }
+    let archive = scratch.join("tree.tar.zst");
+    pack(&input, &archive, &[]);
+    let expected = snapshot(&input);
+
+    // The second time over, the read-only directories are already there
+    let output = scratch.join("out");
+    for _ in 0..2 {
+        let (ok, stderr) = try_zstar(&[
+            "unpack",
+            archive.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--merge",
+        ]);
+        assert!(ok, "{}", stderr);
+        assert_eq!(snapshot(&output.join("tree")), expected);
+    }
+
+    // A directory's entry may come after its children
+    let archive = scratch.join("late.tar.zst");
+    fs::write(
+        &archive,
+        crafted(&[
+            ("late/sub/file", Regular, "", b"early"),
+            ("late/sub/", Directory, "", b""),
+            ("late/", Directory, "", b""),
+        ]),
+    )
+    .unwrap();
+    let output = scratch.join("late.out");
+    let (ok, stderr) = try_zstar(&[
+        "unpack",
+        archive.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+    ]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(fs::read(output.join("late/sub/file")).unwrap(), b"early");
+    let mode = fs::metadata(output.join("late/sub")).unwrap().mode();
+    assert_eq!(mode & 0o777, 0o555);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn archive_paths_dont_depend_on_how_the_input_is_named() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("sub")).unwrap();
+    fs::write(input.join("sub/file"), b"nested").unwrap();
+    fs::write(input.join("top"), b"top").unwrap();
+
+    let absolute = input.to_str().unwrap().to_string();
+    let spellings = [
+        ("tree", scratch.as_path()),
+        ("tree/", scratch.as_path()),
+        ("./tree", scratch.as_path()),
+        ("../tree", input.as_path()),
+        (".", input.as_path()),
+        (absolute.as_str(), Path::new("/")),
+    ];
+    // The input directory itself is never an entry
+    let rootings = [(None, "tree/"), (Some("--no-top-dir"), "")];
+    for (extra, prefix) in rootings {
+        let expected: Vec<String> = ["sub", "sub/file", "top"]
+            .iter()
+            .map(|path| format!("{}{}", prefix, path))
+            .collect();
+        for (spelling, cwd) in spellings {
+            let archive = scratch.join("paths.tar.zst");
+            let mut args = vec![spelling, "-o", archive.to_str().unwrap()];
+            args.extend(extra);
+            let output = Command::new(ZSTAR)
+                .arg("pack")
+                .args(&args)
+                .current_dir(cwd)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "pack {}", spelling);
+            let mut paths: Vec<String> = tar_of(&archive)
+                .1
+                .into_keys()
+                .filter(|path| path != ".zstar-manifest")
+                .map(|path| path.trim_end_matches('/').to_string())
+                .collect();
+            paths.sort();
+            assert_eq!(paths, expected, "{} {:?}", spelling, extra);
+        }
+    }
+    remove_tree(&scratch);
+}
+
+/// Run `rotate` over empty archives named after `dates` and return the
+/// dates that are left.
+fn rotate(scratch: &Path, dates: &[&str], keep: &[&str]) -> (bool, Vec<String>) {
+    let dir = scratch.join(format!("rotate-{}{}", dates.join(","), keep.join("")));
+    fs::create_dir_all(&dir).unwrap();
+    for (i, date) in dates.iter().enumerate() {
+        let archive = dir.join(format!("backup-{}.tar.zst", date));
+        fs::write(&archive, b"").unwrap();
+        // Same-day archives are told apart by mtime, earlier ones newer
+        let mtime = FileTime::from_unix_time(2_000_000_000 - i as i64, 0);
+        filetime::set_file_mtime(&archive, mtime).unwrap();
+    }
+    let mut args = vec!["rotate", "--dir", dir.to_str().unwrap()];
+    args.extend(keep);
+    let (ok, _) = try_zstar(&args);
+    let mut left: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .map(|name| name["backup-".len()..name.len() - ".tar.zst".len()].to_string())
+        .collect();
+    left.sort();
+    (ok, left)
+}
+
+#[test]
+fn rotate_buckets_by_calendar_day_week_and_month() {
+    let scratch = scratch_dir();
+    let only = |daily: &'static str, weekly: &'static str, monthly: &'static str| {
+        [
+            "--keep-daily",
+            daily,
+            "--keep-weekly",
+            weekly,
+            "--keep-monthly",
+            monthly,
+        ]
+    };
+
+    // Two archives of the same day count once, the newest kept; 2000 was a leap year
+    let dates = ["2000-03-01", "2000-02-29", "20000229", "2000-02-28"];
+    let (ok, left) = rotate(&scratch, &dates, &only("2", "0", "0"));
+    assert!(ok);
+    assert_eq!(left, ["2000-02-29", "2000-03-01"]);
+
+    // Weeks start on Monday, also before the epoch (1969-12-29 was one)
+    let dates = [
+        "2024-01-07",
+        "2024-01-01",
+        "2023-12-31",
+        "2023-12-25",
+        "1970-01-04",
+        "1969-12-29",
+        "1969-12-28",
+    ];
+    let (ok, left) = rotate(&scratch, &dates, &only("0", "2", "0"));
+    assert!(ok);
+    assert_eq!(left, ["2023-12-31", "2024-01-07"]);
+    let (ok, left) = rotate(&scratch, &dates[4..], &only("0", "2", "0"));
+    assert!(ok);
+    assert_eq!(left, ["1969-12-28", "1970-01-04"]);
+
+    // Months end on their last day, whatever its number
+    let dates = [
+        "2024-03-01",
+        "2024-02-29",
+        "2024-02-01",
+        "2024-01-31",
+        "2023-12-31",
+    ];
+    let (ok, left) = rotate(&scratch, &dates, &only("0", "0", "3"));
+    assert!(ok);
+    assert_eq!(left, ["2024-01-31", "2024-02-29", "2024-03-01"]);
+
+    // Keeping nothing has to be asked for
+    let dates = ["2024-03-01", "2024-02-29"];
+    let (ok, left) = rotate(&scratch, &dates, &only("0", "0", "0"));
+    assert!(!ok);
+    assert_eq!(left.len(), 2);
+    let mut keep_none = only("0", "0", "0").to_vec();
+    keep_none.push("--keep-none");
+    let (ok, left) = rotate(&scratch, &dates, &keep_none);
+    assert!(ok);
+    assert!(left.is_empty());
+    remove_tree(&scratch);
+}
+
+#[test]
+fn merge_keeps_hardlinks_with_their_data_and_every_pax_record() {
+    use tar::EntryType::{Link, Regular};
+
+    let scratch = scratch_dir();
+    let older = scratch.join("older.tar.zst");
+    let newer = scratch.join("newer.tar.zst");
+    fs::write(
+        &older,
+        crafted(&[
+            ("t/data", Regular, "", b"older"),
+            ("t/link", Link, "t/data", b""),
+            ("t/chain", Link, "t/link", b""),
+            ("u/data", Regular, "", b"untouched"),
+            ("u/link", Link, "u/data", b""),
+        ]),
+    )
+    .unwrap();
+    // Only `t/data` is replaced, and it comes after the links to the old one
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_pax_extensions([
+            ("SCHILY.xattr.user.origin", b"newer".as_slice()),
+            ("mtime", b"1700000000.5".as_slice()),
+            ("comment", b"kept".as_slice()),
+        ])
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_mtime(1_700_000_000);
+    builder
+        .append_data(&mut header, "t/data", &b"newer"[..])
+        .unwrap();
+    let tar = builder.into_inner().unwrap();
+    fs::write(&newer, zstd::encode_all(&tar[..], 3).unwrap()).unwrap();
+
+    let merged = scratch.join("merged.tar.zst");
+    let (ok, stderr) = try_zstar(&[
+        "merge",
+        older.to_str().unwrap(),
+        newer.to_str().unwrap(),
+        "-o",
+        merged.to_str().unwrap(),
+    ]);
+    assert!(ok, "{}", stderr);
+
+    let tar = zstd::decode_all(fs::File::open(&merged).unwrap()).unwrap();
+    let mut kinds = BTreeMap::new();
+    for entry in tar::Archive::new(&tar[..]).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        if path == "t/data" {
+            let records: Vec<(String, Vec<u8>)> = entry
+                .pax_extensions()
+                .unwrap()
+                .unwrap()
+                .map(|ext| ext.unwrap())
+                .map(|ext| (ext.key().unwrap().to_string(), ext.value_bytes().to_vec()))
+                .collect();
+            for key in ["SCHILY.xattr.user.origin", "mtime", "comment"] {
+                assert!(records.iter().any(|(k, _)| k == key), "{} dropped", key);
+            }
+        }
+        let link = entry.link_name().unwrap().map(|l| l.into_owned());
+        kinds.insert(path, (entry.header().entry_type(), link));
+    }
+    // Untouched links stay links; links to the replaced file keep its old data
+    assert_eq!(kinds["u/link"], (Link, Some(PathBuf::from("u/data"))));
+    assert_eq!(kinds["t/data"].0, Regular);
+
+    let output = scratch.join("out");
+    let result = run(&[
+        "unpack".as_ref(),
+        merged.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert_eq!(fs::read(output.join("t/data")).unwrap(), b"newer");
+    assert_eq!(fs::read(output.join("t/link")).unwrap(), b"older");
+    assert_eq!(fs::read(output.join("t/chain")).unwrap(), b"older");
+    let (link, chain) = (output.join("t/link"), output.join("t/chain"));
+    assert_eq!(
+        fs::metadata(link).unwrap().ino(),
+        fs::metadata(chain).unwrap().ino()
+    );
+    assert_eq!(fs::read(output.join("u/link")).unwrap(), b"untouched");
+    remove_tree(&scratch);
+}
+
+#[test]
+fn synthesize_applies_deletions_and_opaque_directories() {
+    use tar::EntryType::{Directory, Regular};
+
+    let scratch = scratch_dir();
+    let base = scratch.join("base.tar.zst");
+    fs::write(
+        &base,
+        crafted(&[
+            ("tree/", Directory, "", b""),
+            ("tree/kept", Regular, "", b"kept"),
+            ("tree/gone", Regular, "", b"gone"),
+            ("tree/cache/", Directory, "", b""),
+            ("tree/cache/old", Regular, "", b"old"),
+        ]),
+    )
+    .unwrap();
+    let incremental = scratch.join("incremental.tar.zst");
+    fs::write(
+        &incremental,
+        crafted(&[
+            ("tree/.wh.gone", Regular, "", b""),
+            ("tree/cache/.wh..wh..opq", Regular, "", b""),
+            ("tree/cache/new", Regular, "", b"new"),
+        ]),
+    )
+    .unwrap();
+    let synthesized = scratch.join("full.tar.zst");
+    let (ok, stderr) = try_zstar(&[
+        "synthesize",
+        base.to_str().unwrap(),
+        incremental.to_str().unwrap(),
+        "-o",
+        synthesized.to_str().unwrap(),
+        "--apply-whiteouts",
+    ]);
+    assert!(ok, "{}", stderr);
+    let paths: Vec<String> = tar_of(&synthesized).1.into_keys().collect();
+    assert_eq!(
+        paths,
+        ["tree/", "tree/cache/", "tree/cache/new", "tree/kept"]
+    );
+    remove_tree(&scratch);
+}
+
+#[test]
+fn pack_output_is_locked_and_cleaned_up() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("file"), b"data").unwrap();
+    let archive = scratch.join("out.tar.zst");
+    let partial = scratch.join("out.tar.zst.partial");
+    let lock = scratch.join("out.tar.zst.lock");
+
+    // Another run holding the lock keeps its partial file, contents and all,
+    // whichever mode the second run writes in
+    let held = fs::File::create(&lock).unwrap();
+    held.try_lock().unwrap();
+    fs::write(&partial, b"in progress").unwrap();
+    let (input, archive_arg) = (input.to_str().unwrap(), archive.to_str().unwrap());
+    let (ok, _) = try_zstar(&["pack", input, "-o", archive_arg]);
+    assert!(!ok);
+    let (ok, _) = try_zstar(&["pack", input, "-o", archive_arg, "--no-partial"]);
+    assert!(!ok);
+    assert_eq!(fs::read(&partial).unwrap(), b"in progress");
+    assert!(!archive.exists());
+    drop(held);
+
+    // A run that fails doesn't leave its partial file behind
+    let (ok, _) = try_zstar(&[
+        "pack",
+        input,
+        "-o",
+        archive_arg,
+        "--use-external-compressor",
+        "false",
+    ]);
+    assert!(!ok);
+    assert!(!partial.exists());
+    assert!(!archive.exists());
+
+    pack(Path::new(input), &archive, &[]);
+    assert!(!partial.exists());
+    assert!(!lock.exists());
+    assert!(tar_of(&archive).1.contains_key("tree/file"));
+    remove_tree(&scratch);
+}
+
+/// `len` bytes that zstd can't shrink, so each file fills its own frames.
+fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn salvage_recovers_around_damage() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir(&input).unwrap();
+    let files: Vec<(String, Vec<u8>)> = (0..12)
+        .map(|i| (format!("{:02}.bin", i), noise(i, 300 * 1024)))
+        .collect();
+    for (name, data) in &files {
+        fs::write(input.join(name), data).unwrap();
+    }
+    fs::write(input.join("skip.log"), b"excluded").unwrap();
+    // 1MB frames, so damage in one leaves the others readable
+    let archive = scratch.join("tree.tar.zst");
+    pack(&input, &archive, &["--seekable=1"]);
+    let intact = fs::read(&archive).unwrap();
+
+    let salvage = |name: &str, data: &[u8], extra: &[&str]| {
+        let damaged = scratch.join(format!("{}.tar.zst", name));
+        fs::write(&damaged, data).unwrap();
+        let output = scratch.join(format!("{}.out", name));
+        let mut args = vec![
+            "unpack",
+            damaged.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--salvage",
+        ];
+        args.extend(extra);
+        let result = Command::new(ZSTAR).args(&args).output().unwrap();
+        (result.status.code(), output.join("tree"))
+    };
+    let restored = |tree: &Path| {
+        files
+            .iter()
+            .filter(|(name, data)| fs::read(tree.join(name)).is_ok_and(|read| read == *data))
+            .count()
+    };
+
+    // Overwritten in the middle
+    let mut corrupted = intact.clone();
+    let middle = corrupted.len() / 2;
+    corrupted[middle..middle + 4096].fill(0xA5);
+    let (code, tree) = salvage("corrupted", &corrupted, &["--exclude", "*.log"]);
+    assert_eq!(code, Some(4));
+    let count = restored(&tree);
+    assert!((6..12).contains(&count), "{} restored", count);
+    assert!(!tree.join("skip.log").exists());
+
+    // Cut off in the middle
+    let (code, tree) = salvage("truncated", &intact[..middle], &[]);
+    assert_eq!(code, Some(4));
+    let count = restored(&tree);
+    assert!((3..12).contains(&count), "{} restored", count);
+
+    // The extraction limits still hold
+    let (code, _) = salvage("limited", &corrupted, &["--max-entries", "3"]);
+    assert_eq!(code, Some(1));
+    remove_tree(&scratch);
+}
+
+#[test]
+fn salvage_keeps_directories_in_the_output() {
+    use tar::EntryType::{Directory, Symlink};
+
+    let scratch = scratch_dir();
+    let outside = scratch.join("outside");
+    fs::create_dir(&outside).unwrap();
+    fs::set_permissions(&outside, fs::Permissions::from_mode(0o700)).unwrap();
+    let archive = scratch.join("evil.tar.zst");
+    fs::write(
+        &archive,
+        crafted(&[
+            ("l", Symlink, outside.to_str().unwrap(), b""),
+            ("l/x/", Directory, "", b""),
+            ("l/", Directory, "", b""),
+        ]),
+    )
+    .unwrap();
+    let output = scratch.join("out");
+    try_zstar(&[
+        "unpack",
+        archive.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap(),
+        "--salvage",
+    ]);
+    assert!(!outside.join("x").exists());
+    let mode = fs::metadata(&outside).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn files_changed_while_packed_are_flagged() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("small"), patterned(1, 512 * 1024)).unwrap();
+    // Past the 1MB stream threshold of --memory-budget 1, in two chunks
+    fs::write(input.join("streamed"), patterned(2, 6 << 20)).unwrap();
+    fs::write(input.join("steady"), b"steady").unwrap();
+
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut second = 1_000_000_000;
+            while !stop.load(Ordering::Relaxed) {
+                for name in ["small", "streamed"] {
+                    let mtime = FileTime::from_unix_time(second, 0);
+                    filetime::set_file_mtime(input.join(name), mtime).unwrap();
+                }
+                second += 1;
+            }
+        });
+        // A small file is read in a moment, which the changes can miss; pack
+        // again until both were caught
+        for (format, name) in [("tar", "out.tar.zst"), ("zip", "out.zip")] {
+            let archive = scratch.join(name);
+            let (input, archive) = (input.to_str().unwrap(), archive.to_str().unwrap());
+            let caught = (0..50).any(|_| {
+                let (ok, stderr) = try_zstar(&[
+                    "pack",
+                    input,
+                    "-o",
+                    archive,
+                    "--format",
+                    format,
+                    "--if-changed",
+                    "warn",
+                    "--memory-budget",
+                    "1",
+                ]);
+                assert!(ok, "{}", stderr);
+                ["small", "streamed"]
+                    .iter()
+                    .all(|name| stderr.contains(&format!("{}\": Changed while read, stored", name)))
+            });
+            assert!(caught, "{} changes went unnoticed", format);
+        }
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    // Streamed files are only flagged in the manifest at the end of the archive
+    let output = run(&["list".as_ref(), scratch.join("out.tar.zst").as_os_str()]);
+    let (stdout, stderr) = (
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    );
+    assert!(
+        stdout.contains("tree/small (changed while packed)"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stderr.contains("tree/streamed changed while it was packed"),
+        "{}",
+        stderr
+    );
+    assert!(!stdout.contains("steady (changed"), "{}", stdout);
+
+    // ZIP flags them in the central directory
+    let output = run(&["list".as_ref(), scratch.join("out.zip").as_os_str()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for name in ["small", "streamed"] {
+        assert!(
+            stdout.contains(&format!("tree/{} (changed while packed)", name)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!stdout.contains("steady (changed"), "{}", stdout);
+
+    // The manifest hashes what was stored, so the files still verify
+    let output = scratch.join("output");
+    let archive = scratch.join("out.tar.zst");
+    let (archive, output) = (archive.to_str().unwrap(), output.to_str().unwrap());
+    let (ok, stderr) = try_zstar(&["unpack", archive, "-o", output]);
+    assert!(ok, "{}", stderr);
+    for name in ["small", "streamed"] {
+        assert!(
+            stderr.contains(&format!("\"tree/{}\" changed while it was packed", name)),
+            "{}",
+            stderr
+        );
+    }
+    assert!(!stderr.contains("steady"), "{}", stderr);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn list_json_reads_the_index_or_the_stream() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("sub")).unwrap();
+    fs::write(input.join("sub/file"), b"twelve bytes").unwrap();
+    symlink("file", input.join("sub/link")).unwrap();
+
+    for (name, indexed, extra) in [
+        ("plain.tar.zst", false, &[][..]),
+        ("seekable.tar.zst", true, &["--seekable"][..]),
+    ] {
+        let archive = scratch.join(name);
+        pack(&input, &archive, extra);
+        let output = run(&["list".as_ref(), archive.as_os_str(), "--json".as_ref()]);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.starts_with(&format!("{{\"indexed\":{},\"entries\":[", indexed)),
+            "{}",
+            stdout
+        );
+        for entry in [
+            r#"{"kind":"dir","size":0,"path":"tree/sub","link":null,"changed":false}"#,
+            r#"{"kind":"file","size":12,"path":"tree/sub/file","link":null,"changed":false}"#,
+            r#"{"kind":"symlink","size":0,"path":"tree/sub/link","link":"file","changed":false}"#,
+        ] {
+            assert!(stdout.contains(entry), "{}", stdout);
+        }
+        assert!(
+            stdout.ends_with("],\"total_entries\":3,\"total_bytes\":12}\n"),
+            "{}",
+            stdout
+        );
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn seekable_index_out_of_order_is_corrupt() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("a"), b"a").unwrap();
+    fs::write(input.join("b"), b"b").unwrap();
+    let archive = scratch.join("tree.tar.zst");
+    pack(&input, &archive, &["--seekable"]);
+
+    // Move the tar trailer in the index ahead of every entry. The index is
+    // the last frame before the seek table, whose footer is 9 bytes.
+    let mut data = fs::read(&archive).unwrap();
+    let u32_at = |data: &[u8], at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    let len = data.len();
+    let count = u32_at(&data, len - 9) as usize;
+    let record = if data[len - 5] & 0x80 != 0 { 12 } else { 8 };
+    let table = len - 9 - count * record - 8;
+    let last = 8 + (count - 1) * record;
+    let index = table - u32_at(&data, table + last) as usize;
+    let mut records = zstd::decode_all(&data[index + 13..table]).unwrap();
+    records[..8].copy_from_slice(&0u64.to_le_bytes());
+    let records = zstd::encode_all(&records[..], 3).unwrap();
+    let mut frame = data[index..index + 13].to_vec();
+    frame[4..8].copy_from_slice(&(5 + records.len() as u32).to_le_bytes());
+    frame.extend(records);
+    let mut tail = data.split_off(table);
+    tail[last..last + 4].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    data.truncate(index);
+    data.extend(frame);
+    data.extend(tail);
+    fs::write(&archive, data).unwrap();
+
+    let output = scratch.join("out");
+    let (archive, output) = (archive.to_str().unwrap(), output.to_str().unwrap());
+    for args in [
+        &["list", archive][..],
+        &["unpack", archive, "tree/a", "-o", output],
+    ] {
+        let (ok, stderr) = try_zstar(args);
+        assert!(!ok, "{:?}", args);
+        assert!(stderr.contains("Entry index is corrupt"), "{}", stderr);
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn rootless_idmap_reads_pax_owners_and_skips_blank_ones() {
+    let scratch = scratch_dir();
+    let mut builder = tar::Builder::new(Vec::new());
+    let file = |path: &str, uid: u64, gid: u64| {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_mode(0o644);
+        header.set_uid(uid);
+        header.set_gid(gid);
+        header.set_size(4);
+        header
+    };
+    builder
+        .append_pax_extensions([("uid", &b"1000"[..]), ("gid", &b"1001"[..])])
+        .unwrap();
+    let mut header = file("pax", 7, 7);
+    header.set_cksum();
+    builder.append(&header, &b"data"[..]).unwrap();
+    let mut header = file("plain", 5, 6);
+    header.set_cksum();
+    builder.append(&header, &b"data"[..]).unwrap();
+    let mut header = file("blank", 0, 0);
+    header.as_old_mut().uid = [b' '; 8];
+    header.set_cksum();
+    builder.append(&header, &b"data"[..]).unwrap();
+    let archive = scratch.join("owners.tar.zst");
+    let tar = builder.into_inner().unwrap();
+    fs::write(&archive, zstd::encode_all(&tar[..], 3).unwrap()).unwrap();
+
+    let output = scratch.join("output");
+    let (archive, output_arg) = (archive.to_str().unwrap(), output.to_str().unwrap());
+    let (ok, stderr) = try_zstar(&[
+        "unpack",
+        archive,
+        "-o",
+        output_arg,
+        "--rootless-idmap",
+        "100000:65536",
+    ]);
+    assert!(ok, "{}", stderr);
+    assert!(
+        stderr.contains("1 entries have no numeric owner"),
+        "{}",
+        stderr
+    );
+
+    // Only root can hand files to other IDs; others get an xattr instead
+    let owner = |name: &str| {
+        let meta = fs::metadata(output.join(name)).unwrap();
+        (meta.uid(), meta.gid())
+    };
+    let user = owner("blank");
+    if user.0 == 0 {
+        assert_eq!(owner("pax"), (101000, 101001));
+        assert_eq!(owner("plain"), (100005, 100006));
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn unpack_selects_whole_path_components() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("keep/sub")).unwrap();
+    fs::write(input.join("keep/sub/file"), b"wanted").unwrap();
+    fs::write(input.join("keepsake"), b"sibling").unwrap();
+    fs::write(input.join("other"), b"a file, not a directory").unwrap();
+
+    for (name, extra) in [
+        ("plain.tar.zst", &[][..]),
+        ("seekable.tar.zst", &["--seekable"][..]),
+    ] {
+        let archive = scratch.join(name);
+        pack(&input, &archive, extra);
+        let output = scratch.join(format!("{}.out", name));
+        let (archive, output_arg) = (archive.to_str().unwrap(), output.to_str().unwrap());
+
+        let (ok, stderr) = try_zstar(&["unpack", archive, "-o", output_arg, "tree/keep"]);
+        assert!(ok, "{}", stderr);
+        assert_eq!(
+            fs::read(output.join("tree/keep/sub/file")).unwrap(),
+            b"wanted"
+        );
+        assert!(!output.join("tree/keepsake").exists(), "{}", name);
+        assert!(!output.join("tree/other").exists(), "{}", name);
+        remove_tree(&output);
+
+        // A file is no directory leading to a path below it
+        let (ok, stderr) = try_zstar(&["unpack", archive, "-o", output_arg, "tree/other/inner"]);
+        assert!(!ok);
+        assert!(stderr.contains("Not found in archive"), "{}", stderr);
+        assert!(!output.join("tree/other").exists(), "{}", name);
+        remove_tree(&output);
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn external_compressor_takes_quoted_arguments() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir(&input).unwrap();
+    fs::write(input.join("file"), b"piped").unwrap();
+
+    // The quoted script is one argument
+    let archive = scratch.join("quoted.tgz");
+    pack(
+        &input,
+        &archive,
+        &[
+            "--use-external-compressor",
+            "sh -c 'exec gzip -c \"$0\"' -9",
+        ],
+    );
+    let unpacked = scratch.join("unpacked");
+    let (ok, stderr) = try_zstar(&[
+        "unpack",
+        archive.to_str().unwrap(),
+        "-o",
+        unpacked.to_str().unwrap(),
+    ]);
+    assert!(ok, "{}", stderr);
+    assert_eq!(fs::read(unpacked.join("tree/file")).unwrap(), b"piped");
+
+    // The default name has the extension of the compressor's format
+    let output = Command::new(ZSTAR)
+        .current_dir(&scratch)
+        .args(["pack", "tree", "--use-external-compressor", "gzip -c"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(scratch.join("tree.tar.gz").exists());
+
+    // Commands that don't parse, or whose format is unknown, write nothing
+    for command in ["sh -c 'exec gzip", "sh -c 'gzip -c'"] {
+        let (ok, stderr) = try_zstar(&[
+            "pack",
+            input.to_str().unwrap(),
+            "--use-external-compressor",
+            command,
+        ]);
+        assert!(!ok, "{}", command);
+        assert!(stderr.contains(command), "{}", stderr);
+    }
+    remove_tree(&scratch);
+}
+
+#[test]
+fn catalog_search_matches_wildcards_literally() {
+    let scratch = scratch_dir();
+    let db = scratch.join("catalog.db");
+    for (name, file) in [("a_b", "x_y"), ("axb", "xzy")] {
+        let input = scratch.join(name);
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join(file), name).unwrap();
+        let output = Command::new(ZSTAR)
+            .env("ZSTAR_CATALOG", &db)
+            .args(["pack", "--catalog", "-o"])
+            .arg(scratch.join(format!("{}.tar.zst", name)))
+            .arg(&input)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    let catalog = |args: &[&str]| {
+        let output = Command::new(ZSTAR)
+            .args(["catalog", "--db"])
+            .arg(&db)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let found = catalog(&["search", "a_b"]);
+    assert!(found.contains("a_b.tar.zst"), "{}", found);
+    assert!(!found.contains("axb.tar.zst"), "{}", found);
+    assert_eq!(catalog(&["search", "%"]), "");
+    let found = catalog(&["where-is", "x_y"]);
+    assert!(found.contains("a_b.tar.zst"), "{}", found);
+    assert!(!found.contains("axb.tar.zst"), "{}", found);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn profile_report_includes_the_scan_even_when_pack_fails() {
+    let scratch = scratch_dir();
+    let input = scratch.join("tree");
+    fs::create_dir_all(input.join("big")).unwrap();
+    fs::write(input.join("big/file"), vec![7u8; 4096]).unwrap();
+    fs::write(input.join("small"), b"x").unwrap();
+    let (input, archive) = (input.to_str().unwrap(), scratch.join("out.tar.zst"));
+    let archive = archive.to_str().unwrap();
+
+    let (ok, stderr) = try_zstar(&["pack", input, "-o", archive, "--profile-pipeline"]);
+    assert!(ok, "{}", stderr);
+    assert!(
+        stderr.contains("Scan (whole tree): 3 entries, 2 files (4.00 KiB), 1 directories"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("4.00 KiB  tree/big"), "{}", stderr);
+
+    // The walk may not have got far before the compressor went away
+    let (ok, stderr) = try_zstar(&[
+        "pack",
+        input,
+        "-o",
+        archive,
+        "--profile-pipeline",
+        "--use-external-compressor",
+        "false",
+    ]);
+    assert!(!ok);
+    assert!(stderr.contains("Scan ("), "{}", stderr);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn reflink_dups_stay_in_the_output_and_the_size_limit() {
+    use tar::EntryType::{Link, Regular, Symlink};
+
+    let scratch = scratch_dir();
+    let evil = scratch.join("evil");
+    let secret = scratch.join("secret");
+    fs::write(&secret, b"secret").unwrap();
+    let unpack = |name: &str, entries: &[(&str, tar::EntryType, &str, &[u8])], extra: &[&str]| {
+        let archive = scratch.join(format!("{}.tar.zst", name));
+        fs::write(&archive, crafted(entries)).unwrap();
+        let output = scratch.join(name);
+        let mut args = vec![
+            "unpack",
+            archive.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--reflink-dups",
+        ];
+        args.extend(extra);
+        let (ok, stderr) = try_zstar(&args);
+        (ok, stderr, output)
+    };
+
+    // A dangling symlink in the way is replaced, not written through
+    let (_, stderr, output) = unpack(
+        "write",
+        &[
+            ("x", Symlink, evil.to_str().unwrap(), b""),
+            ("data", Regular, "", b"payload"),
+            ("x", Link, "data", b""),
+        ],
+        &[],
+    );
+    assert!(!evil.exists(), "{}", stderr);
+    assert_eq!(fs::read(output.join("x")).unwrap(), b"payload");
+
+    // A link to a symlink doesn't copy what it points to
+    let (_, stderr, output) = unpack(
+        "read",
+        &[
+            ("s", Symlink, secret.to_str().unwrap(), b""),
+            ("copy", Link, "s", b""),
+        ],
+        &[],
+    );
+    assert!(!output.join("copy").exists(), "{}", stderr);
+
+    // Copies count towards --max-output-size
+    let data = vec![7u8; 512 * 1024];
+    let names: Vec<String> = (0..8).map(|i| format!("copy{}", i)).collect();
+    let mut entries = vec![("big", Regular, "", &data[..])];
+    entries.extend(
+        names
+            .iter()
+            .map(|name| (name.as_str(), Link, "big", &b""[..])),
+    );
+    let (ok, stderr, _) = unpack("bomb", &entries, &["--max-output-size", "2"]);
+    assert!(!ok);
+    assert!(stderr.contains("expands to more than"), "{}", stderr);
+    remove_tree(&scratch);
+}
+
+#[test]
+fn symlinks_in_the_way_of_a_directory_are_failures() {
+    use tar::EntryType::{Directory, Symlink};
+
+    let scratch = scratch_dir();
+    let archive = scratch.join("a.tar.zst");
+    fs::write(
+        &archive,
+        crafted(&[("d", Directory, "", b""), ("d", Symlink, "elsewhere", b"")]),
+    )
+    .unwrap();
+    let output = Command::new(ZSTAR)
+        .args(["unpack", archive.to_str().unwrap(), "-o"])
+        .arg(scratch.join("out"))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stdout.contains("0 symlinks"), "{}", stdout);
+    assert!(
+        stderr.contains("1 link(s) could not be restored"),
+        "{}",
+        stderr
+    );
+    remove_tree(&scratch);
+}